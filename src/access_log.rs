@@ -0,0 +1,300 @@
+//! One line per request in `ACCESS_LOG`, apart from the application logs, in the
+//! combined format that log analyzers read or as JSON. The line is written once
+//! the response body has been sent, or the client went away, so the byte count
+//! and latency cover the whole transfer. Query strings are left out, since owner
+//! and admin tokens may travel in them.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+use tracing_subscriber::fmt::writer::EitherWriter;
+
+use crate::{
+    AppError, AppState,
+    audit::write_lines,
+    config::{AccessLogConfig, AccessLogFormat, AccessLogTarget},
+    live::client_address,
+    metadata::unix_seconds,
+    request_id::RequestId,
+};
+
+/// Lines waiting to be written; further ones are dropped, with a warning, while
+/// it is full.
+const QUEUE_LEN: usize = 4096;
+
+/// Set once the access log writes to stdout.
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sends application logs to stderr from now on if the access log is to take
+/// stdout, so the two never mix.
+pub fn claim_stdout(config: Option<&AccessLogConfig>) {
+    if let Some(AccessLogConfig {
+        target: AccessLogTarget::Stdout,
+        ..
+    }) = config
+    {
+        STDOUT_TAKEN.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where application logs go: stdout, unless the access log took it.
+pub fn application_output() -> EitherWriter<io::Stdout, io::Stderr> {
+    if STDOUT_TAKEN.load(Ordering::Relaxed) {
+        EitherWriter::B(io::stderr())
+    } else {
+        EitherWriter::A(io::stdout())
+    }
+}
+
+pub struct AccessLog {
+    queue: mpsc::Sender<String>,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    /// Opens a log file for appending, so a path that cannot be written fails at
+    /// startup rather than with the first request.
+    pub fn open(config: &AccessLogConfig) -> Result<Self, AppError> {
+        let (queue, lines) = mpsc::channel(QUEUE_LEN);
+        match &config.target {
+            AccessLogTarget::Stdout => {
+                claim_stdout(Some(config));
+                tokio::spawn(write_lines(tokio::io::stdout(), lines, "access log"));
+            }
+            AccessLogTarget::File(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                let file = tokio::fs::File::from_std(file);
+                tokio::spawn(write_lines(file, lines, "access log"));
+            }
+        }
+        Ok(Self {
+            queue,
+            format: config.format,
+        })
+    }
+
+    fn append(&self, line: String) {
+        if self.queue.try_send(line).is_err() {
+            warn!("access log is falling behind, dropping a line");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line {
+    /// Unix seconds at which the request came in.
+    at: u64,
+    client: String,
+    method: String,
+    path: String,
+    protocol: String,
+    status: u16,
+    bytes: u64,
+    latency_ms: f64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    #[serde(skip)]
+    received: SystemTime,
+}
+
+impl Line {
+    fn encode(&self, format: AccessLogFormat) -> Option<String> {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).ok(),
+            AccessLogFormat::Combined => Some(self.combined()),
+        }
+    }
+
+    /// The combined format, followed by the latency in seconds as nginx's
+    /// `$request_time` would log it.
+    fn combined(&self) -> String {
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => escape(value),
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {:.3}",
+            self.client,
+            clf_time(self.received),
+            self.method,
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            bytes,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            self.latency_ms / 1000.0,
+        )
+    }
+}
+
+/// Such as `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_time(at: SystemTime) -> String {
+    // `Tue, 10 Oct 2000 13:55:36 GMT`
+    let date = httpdate::fmt_http_date(at);
+    let parts: Vec<&str> = date.split(' ').collect();
+    match parts.as_slice() {
+        [_, day, month, year, time, _] => format!("{}/{}/{}:{} +0000", day, month, year, time),
+        _ => date,
+    }
+}
+
+/// Escapes quotes, backslashes and control characters the way nginx does.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn header_text(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+/// Middleware that logs every request once its response has been sent.
+pub async fn record(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.access_log.is_none() {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let received = SystemTime::now();
+    let headers = request.headers();
+    let mut line = Line {
+        at: unix_seconds(received),
+        client: client_address(headers, peer).to_canonical().to_string(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        protocol: format!("{:?}", request.version()),
+        status: 0,
+        bytes: 0,
+        latency_ms: 0.0,
+        referer: header_text(headers, header::REFERER),
+        user_agent: header_text(headers, header::USER_AGENT),
+        request_id: request.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        received,
+    };
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    line.status = parts.status.as_u16();
+    let body = Counted {
+        inner: body,
+        pending: Some(Pending {
+            state,
+            line,
+            started,
+        }),
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+struct Pending {
+    state: Arc<AppState>,
+    line: Line,
+    started: Instant,
+}
+
+impl Pending {
+    fn finish(mut self) {
+        let Some(log) = &self.state.access_log else {
+            return;
+        };
+        self.line.latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        if let Some(encoded) = self.line.encode(log.format) {
+            log.append(encoded);
+        }
+    }
+}
+
+/// A response body that counts what it sends and logs the request when it is
+/// done, or dropped unfinished.
+struct Counted {
+    inner: Body,
+    pending: Option<Pending>,
+}
+
+impl http_body::Body for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(pending)) = (frame.data_ref(), &mut self.pending) {
+                    pending.line.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(_) => {
+                if let Some(pending) = self.pending.take() {
+                    pending.finish();
+                }
+            }
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.finish();
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Certificates for `ACME_DOMAINS` from an ACME CA (RFC 8555), Let's Encrypt
+//! unless `ACME_DIRECTORY_URL` names another. The CA checks each domain by
+//! fetching a token over plain HTTP from `ACME_HTTP_ADDRESS`, which answers
+//! those and redirects everything else to HTTPS. The account key and the
+//! current certificate are kept in `ACME_CACHE_DIR`, so a restart serves the
+//! certificate it already has and renews it only once it is due.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use tokio_rustls::rustls::{
+    crypto::ring::sign::any_ecdsa_type,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    AppError,
+    config::AcmeConfig,
+    metadata::unix_epoch,
+    systemd::{self, Socket},
+};
+
+/// The account's key and URL, as instant-acme saves them.
+const ACCOUNT_FILE: &str = "account.json";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+/// The domains the cached certificate was issued for, one per line.
+const DOMAINS_FILE: &str = "domains.txt";
+
+/// Let's Encrypt suggests renewing once a third of the 90 days is left.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How the order is polled while the CA validates it and issues the
+/// certificate, before issuing is given up.
+const POLL_POLICY: RetryPolicy = RetryPolicy::new()
+    .initial_delay(Duration::from_secs(1))
+    .backoff(1.5)
+    .timeout(Duration::from_secs(2 * 60));
+
+/// Hands rustls whichever certificate was obtained last.
+#[derive(Default)]
+struct Resolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+pub struct Acme {
+    config: AcmeConfig,
+    /// The port of `ADDRESS`, where plain HTTP requests are redirected.
+    https_port: u16,
+    resolver: Arc<Resolver>,
+    /// Key authorizations of the challenges in flight, by token.
+    challenges: Mutex<HashMap<String, String>>,
+    /// When the certificate being served runs out.
+    expires_at: Mutex<Option<SystemTime>>,
+}
+
+impl Acme {
+    /// Prepares `config.cache_dir` and starts serving the certificate cached
+    /// there, if it is still for the configured domains.
+    pub async fn open(config: AcmeConfig, https_port: u16) -> Result<Arc<Self>, AppError> {
+        tokio::fs::create_dir_all(&config.cache_dir).await?;
+        let acme = Arc::new(Self {
+            config,
+            https_port,
+            resolver: Arc::new(Resolver::default()),
+            challenges: Mutex::new(HashMap::new()),
+            expires_at: Mutex::new(None),
+        });
+        if let Err(err) = acme.load_cached().await {
+            warn!(%err, "ignoring the cached ACME certificate");
+        }
+        Ok(acme)
+    }
+
+    /// Certificates this resolver serves, for the TLS acceptor of `ADDRESS`.
+    pub fn resolver(&self) -> Arc<dyn ResolvesServerCert> {
+        self.resolver.clone()
+    }
+
+    /// Binds `ACME_HTTP_ADDRESS` and keeps the certificate current from then on.
+    pub async fn start(self: &Arc<Self>) -> Result<(), AppError> {
+        let listener = systemd::listen(Socket::Acme, self.config.http_address).await?;
+        info!(
+            domains = %self.config.domains.join(","),
+            "answering ACME challenges on {}",
+            listener.local_addr()?
+        );
+        let app = Router::new()
+            .route("/.well-known/acme-challenge/:token", get(challenge))
+            .fallback(redirect)
+            .with_state(self.clone());
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                error!(%err, "ACME challenge listener failed");
+            }
+        });
+        tokio::spawn(self.clone().renew());
+        Ok(())
+    }
+
+    async fn renew(self: Arc<Self>) {
+        loop {
+            let wait = match self.renew_if_due().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(err) => {
+                    warn!(%err, "failed to obtain a certificate, retrying in an hour");
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn renew_if_due(&self) -> Result<(), AppError> {
+        let expires_at = *self
+            .expires_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if expires_at.is_some_and(|at| at > SystemTime::now() + RENEW_BEFORE) {
+            return Ok(());
+        }
+        let (chain, key) = self.issue().await?;
+        self.install(chain.as_bytes(), key.as_bytes())?;
+        let dir = &self.config.cache_dir;
+        write_private(&dir.join(KEY_FILE), key.as_bytes()).await?;
+        tokio::fs::write(dir.join(CERT_FILE), &chain).await?;
+        tokio::fs::write(dir.join(DOMAINS_FILE), self.config.domains.join("\n")).await?;
+        Ok(())
+    }
+
+    /// Installs the cached certificate if it is for the configured domains.
+    async fn load_cached(&self) -> Result<(), AppError> {
+        let dir = &self.config.cache_dir;
+        let Ok(domains) = tokio::fs::read_to_string(dir.join(DOMAINS_FILE)).await else {
+            info!("no cached certificate, requesting one from the ACME CA");
+            return Ok(());
+        };
+        if domains.lines().ne(self.config.domains.iter().map(String::as_str)) {
+            info!("ACME_DOMAINS changed, requesting a new certificate");
+            return Ok(());
+        }
+        let chain = tokio::fs::read(dir.join(CERT_FILE)).await?;
+        let key = tokio::fs::read(dir.join(KEY_FILE)).await?;
+        self.install(&chain, &key)
+    }
+
+    /// Starts serving `chain` with `key`, both PEM.
+    fn install(&self, chain: &[u8], key: &[u8]) -> Result<(), AppError> {
+        let certs = CertificateDer::pem_slice_iter(chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| AppError::Acme(format!("unreadable certificate: {}", err)))?;
+        let expires_at = certs
+            .first()
+            .and_then(|cert| not_after(cert))
+            .ok_or_else(|| AppError::Acme("certificate without a readable expiry".to_string()))?;
+        let key = PrivateKeyDer::from_pem_slice(key)
+            .map_err(|err| AppError::Acme(format!("unreadable certificate key: {}", err)))?;
+        let signing_key = any_ecdsa_type(&key)
+            .map_err(|err| AppError::Acme(format!("unusable certificate key: {}", err)))?;
+        let certified = Arc::new(CertifiedKey::new(certs, signing_key));
+        *self
+            .resolver
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(certified);
+        *self
+            .expires_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(expires_at);
+        info!(
+            expires_at = %httpdate::fmt_http_date(expires_at),
+            "serving certificate for {}",
+            self.config.domains.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Orders a certificate and returns its chain along with its new key, both
+    /// PEM.
+    async fn issue(&self) -> Result<(String, String), AppError> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder::new(&identifiers))
+            .await
+            .map_err(acme_error)?;
+
+        // Answered on `ACME_HTTP_ADDRESS` until the order is ready or has failed.
+        let mut tokens = Vec::new();
+        let ready = match self.authorize(&mut order, &mut tokens).await {
+            Ok(()) => order.poll_ready(&POLL_POLICY).await.map_err(acme_error),
+            Err(err) => Err(err),
+        };
+        {
+            let mut challenges = self
+                .challenges
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for token in &tokens {
+                challenges.remove(token);
+            }
+        }
+        match ready? {
+            OrderStatus::Ready => {}
+            status => {
+                return Err(AppError::Acme(format!("order ended {:?}", status)));
+            }
+        }
+
+        let key = order.finalize().await.map_err(acme_error)?;
+        let chain = order
+            .poll_certificate(&POLL_POLICY)
+            .await
+            .map_err(acme_error)?;
+        Ok((chain, key))
+    }
+
+    /// Offers the key authorization of each domain's HTTP-01 challenge and tells
+    /// the CA to check it, noting the tokens in `tokens`.
+    async fn authorize(&self, order: &mut Order, tokens: &mut Vec<String>) -> Result<(), AppError> {
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization.map_err(acme_error)?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => {
+                    return Err(AppError::Acme(format!(
+                        "authorization for {} is {:?}",
+                        authorization.identifier(),
+                        status
+                    )));
+                }
+            }
+            let domain = authorization.identifier().to_string();
+            let Some(mut challenge) = authorization.challenge(ChallengeType::Http01) else {
+                return Err(AppError::Acme(format!(
+                    "no HTTP-01 challenge offered for {}",
+                    domain
+                )));
+            };
+            let key_authorization = challenge.key_authorization().as_str().to_string();
+            self.challenges
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(challenge.token.clone(), key_authorization);
+            tokens.push(challenge.token.clone());
+            challenge.set_ready().await.map_err(acme_error)?;
+        }
+        Ok(())
+    }
+
+    /// The account from the cached credentials, registered and saved on first
+    /// use.
+    async fn account(&self) -> Result<Account, AppError> {
+        let path = self.config.cache_dir.join(ACCOUNT_FILE);
+        let builder = Account::builder().map_err(acme_error)?;
+        match tokio::fs::read(&path).await {
+            Ok(saved) => {
+                let credentials: AccountCredentials =
+                    serde_json::from_slice(&saved).map_err(|err| {
+                        AppError::Acme(format!("unreadable {}: {}", path.display(), err))
+                    })?;
+                builder
+                    .from_credentials(credentials)
+                    .await
+                    .map_err(acme_error)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let contact: Vec<String> = self
+                    .config
+                    .email
+                    .iter()
+                    .map(|email| format!("mailto:{}", email))
+                    .collect();
+                let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+                let new_account = NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                };
+                let (account, credentials) = builder
+                    .create(&new_account, self.config.directory_url.clone(), None)
+                    .await
+                    .map_err(acme_error)?;
+                let saved = serde_json::to_vec(&credentials)
+                    .map_err(|err| AppError::Acme(format!("unsavable account: {}", err)))?;
+                write_private(&path, &saved).await?;
+                info!(account = %account.id(), "registered with the ACME CA");
+                Ok(account)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// `GET /.well-known/acme-challenge/:token` on `ACME_HTTP_ADDRESS`.
+async fn challenge(State(acme): State<Arc<Acme>>, Path(token): Path<String>) -> Response {
+    let challenges = acme
+        .challenges
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match challenges.get(&token) {
+        Some(key_authorization) => key_authorization.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Sends everything else on `ACME_HTTP_ADDRESS` to the same path over HTTPS. Only
+/// the configured domains are redirected to, whatever `Host` says.
+async fn redirect(State(acme): State<Arc<Acme>>, headers: HeaderMap, uri: Uri) -> Redirect {
+    let requested = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|host| host.split(':').next().unwrap_or(host).to_ascii_lowercase());
+    let domains = &acme.config.domains;
+    let host = requested
+        .filter(|host| domains.contains(host))
+        .unwrap_or_else(|| domains[0].clone());
+    let port = match acme.https_port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Redirect::permanent(&format!("https://{}{}{}", host, port, path))
+}
+
+fn acme_error(err: instant_acme::Error) -> AppError {
+    AppError::Acme(err.to_string())
+}
+
+/// Keys are only readable by the server's own user.
+async fn write_private(path: &std::path::Path, contents: &[u8]) -> Result<(), AppError> {
+    tokio::fs::write(path, contents).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+}
+
+/// The notAfter of an X.509 certificate.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let secs = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(unix_epoch() + Duration::from_secs(secs))
+}
+
+/// Days from 1970-01-01 to the given date, which must not be earlier.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+/// The year, month and day of the day `days` after 1970-01-01.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
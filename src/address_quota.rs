@@ -0,0 +1,79 @@
+//! Daily upload quotas per client address, from `DAILY_UPLOADS_PER_IP` and
+//! `DAILY_UPLOAD_BYTES_PER_IP`. Every upload in the last 24 hours counts, so the
+//! quota frees up bit by bit rather than all at midnight. Uploads with API keys,
+//! user or tenant tokens and upload tokens have limits of their own and are not
+//! counted. The counts are kept in memory by each instance.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::AppError;
+
+const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct AddressQuota {
+    max_uploads: Option<usize>,
+    max_bytes: Option<u64>,
+    /// When each upload of the window happened and how large it was, oldest
+    /// first, by uploader hash.
+    recent: Mutex<HashMap<String, VecDeque<(Instant, u64)>>>,
+}
+
+impl AddressQuota {
+    pub fn new(max_uploads: Option<usize>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_uploads,
+            max_bytes,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts an upload of `bytes` from `uploader` unless it would go over one
+    /// of the quotas. The upload is counted right away, so concurrent ones cannot
+    /// slip past together.
+    pub fn admit(&self, uploader: &str, bytes: u64) -> Result<(), AppError> {
+        if self.max_uploads.is_none() && self.max_bytes.is_none() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recent.retain(|_, uploads| {
+            while uploads.front().is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW) {
+                uploads.pop_front();
+            }
+            !uploads.is_empty()
+        });
+        let uploads = recent.entry(uploader.to_string()).or_default();
+
+        // However many uploads have to age out of the window for this one to fit.
+        let mut excess_uploads = self
+            .max_uploads
+            .map_or(0, |max| (uploads.len() + 1).saturating_sub(max));
+        let used: u64 = uploads.iter().map(|(_, size)| size).sum();
+        let mut excess_bytes = self
+            .max_bytes
+            .map_or(0, |max| (used + bytes).saturating_sub(max));
+        if excess_uploads == 0 && excess_bytes == 0 {
+            uploads.push_back((now, bytes));
+            return Ok(());
+        }
+        if self.max_bytes.is_some_and(|max| bytes > max) {
+            return Err(AppError::DailyQuotaExceeded { retry_after: None });
+        }
+        let mut frees_at = now;
+        for (at, size) in uploads.iter() {
+            if excess_uploads == 0 && excess_bytes == 0 {
+                break;
+            }
+            excess_uploads = excess_uploads.saturating_sub(1);
+            excess_bytes = excess_bytes.saturating_sub(*size);
+            frees_at = *at + WINDOW;
+        }
+        Err(AppError::DailyQuotaExceeded {
+            retry_after: Some(frees_at.saturating_duration_since(now)),
+        })
+    }
+}
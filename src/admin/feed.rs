@@ -0,0 +1,221 @@
+//! `GET /admin/api/events`, a WebSocket over which the server pushes a JSON text
+//! message whenever something changes: an entry is uploaded, downloaded, expires
+//! or is deleted, a cleanup run finishes, or a warning or error is logged. The
+//! admin token goes in the usual headers or, since browsers cannot set headers on
+//! a WebSocket, in `?token=`.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{
+        Query, State,
+        ws::{self, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{Level, Subscriber, field::Field, warn};
+use tracing_subscriber::layer::{Context, Layer};
+
+use super::{AdminEntry, admin_token};
+use crate::{AppError, AppState, FileEntry, metadata::unix_seconds, secret, webhook::Event};
+
+/// Messages buffered for a slow client; one that falls further behind is told
+/// how many it missed.
+const CAPACITY: usize = 1024;
+/// Keeps idle connections from being cut by proxies.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest message accepted from a client, which has nothing to say but pings.
+const MAX_CLIENT_MESSAGE: usize = 64 * 1024;
+
+/// Where logged warnings and errors go, once the server has a feed.
+static LOG_FEED: OnceLock<broadcast::Sender<Arc<str>>> = OnceLock::new();
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Message<'a> {
+    Entry {
+        event: Event,
+        id: &'a str,
+        entry: Box<AdminEntry>,
+        at: u64,
+    },
+    Purge {
+        expired: usize,
+        freed_bytes: u64,
+        at: u64,
+    },
+    Log {
+        level: &'a str,
+        target: &'a str,
+        message: &'a str,
+        at: u64,
+    },
+    /// Sent to a client in place of messages it was too slow to receive.
+    Lagged { missed: u64 },
+}
+
+impl Message<'_> {
+    fn encode(&self) -> Option<Arc<str>> {
+        serde_json::to_string(self).ok().map(Arc::from)
+    }
+}
+
+pub struct AdminFeed {
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+impl AdminFeed {
+    /// Also becomes the feed that `LogLayer` forwards to.
+    pub fn new() -> Self {
+        let sender = LOG_FEED.get_or_init(|| broadcast::channel(CAPACITY).0).clone();
+        Self { sender }
+    }
+
+    pub fn entry(&self, event: Event, id: &str, entry: &FileEntry) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        self.send(Message::Entry {
+            event,
+            id,
+            entry: Box::new(AdminEntry::new(id.to_string(), entry.clone())),
+            at: unix_seconds(SystemTime::now()),
+        });
+    }
+
+    pub fn purge(&self, expired: usize, freed_bytes: u64) {
+        self.send(Message::Purge {
+            expired,
+            freed_bytes,
+            at: unix_seconds(SystemTime::now()),
+        });
+    }
+
+    fn send(&self, message: Message) {
+        if self.sender.receiver_count() > 0
+            && let Some(encoded) = message.encode()
+        {
+            let _ = self.sender.send(encoded);
+        }
+    }
+}
+
+/// Forwards warnings and errors, after the log filter, to the admin feed.
+pub struct LogLayer;
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN {
+            return;
+        }
+        let Some(sender) = LOG_FEED.get().filter(|sender| sender.receiver_count() > 0) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = Message::Log {
+            level: if *metadata.level() == Level::ERROR {
+                "error"
+            } else {
+                "warn"
+            },
+            target: metadata.target(),
+            message: &fields.text,
+            at: unix_seconds(SystemTime::now()),
+        };
+        if let Some(encoded) = message.encode() {
+            let _ = sender.send(encoded);
+        }
+    }
+}
+
+/// An event's message followed by its other fields, as `key=value`.
+#[derive(Default)]
+struct Fields {
+    text: String,
+}
+
+impl tracing::field::Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let separator = if self.text.is_empty() { "" } else { " " };
+        let _ = if field.name() == "message" {
+            write!(self.text, "{}{:?}", separator, value)
+        } else {
+            write!(self.text, "{}{}={:?}", separator, field.name(), value)
+        };
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            let separator = if self.text.is_empty() { "" } else { " " };
+            let _ = write!(self.text, "{}{}", separator, value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct FeedParams {
+    token: Option<String>,
+}
+
+/// Checks the admin token itself, as it may come in the query string.
+pub(super) async fn connect(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<FeedParams>,
+    headers: HeaderMap,
+    upgrade: Option<WebSocketUpgrade>,
+) -> Result<Response, AppError> {
+    let expected = state.config.admin_token.as_deref().ok_or(AppError::NotFound)?;
+    let provided = admin_token(&headers).map(str::to_string).or(params.token);
+    if !provided.is_some_and(|provided| secret::matches(expected, &provided)) {
+        return Err(AppError::InvalidToken);
+    }
+    let upgrade = upgrade
+        .ok_or_else(|| AppError::BadRequest("expected a WebSocket handshake".to_string()))?;
+    let messages = state.feed.sender.subscribe();
+    Ok(upgrade
+        .max_message_size(MAX_CLIENT_MESSAGE)
+        .on_failed_upgrade(|err| warn!(%err, "WebSocket upgrade failed"))
+        .on_upgrade(move |socket| relay(socket, messages)))
+}
+
+/// Sends feed messages to one client until either side goes away.
+async fn relay(socket: WebSocket, mut messages: broadcast::Receiver<Arc<str>>) {
+    let (mut writer, mut reader) = socket.split();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    loop {
+        let sent = tokio::select! {
+            message = messages.recv() => match message {
+                Ok(message) => writer.send(ws::Message::Text(message.to_string())).await,
+                Err(RecvError::Lagged(missed)) => {
+                    let Some(notice) = (Message::Lagged { missed }).encode() else {
+                        continue;
+                    };
+                    writer.send(ws::Message::Text(notice.to_string())).await
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Pings are answered while reading; the stream ends when the client closes.
+            received = reader.next() => match received {
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => break,
+            },
+            _ = ping.tick() => writer.send(ws::Message::Ping(Vec::new())).await,
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+    let _ = writer.close().await;
+}
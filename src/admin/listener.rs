@@ -0,0 +1,58 @@
+//! The admin API on a listener of its own (`ADMIN_ADDRESS`), optionally over TLS
+//! and, with `ADMIN_CLIENT_CA`, only for clients presenting a certificate that CA
+//! issued. Without one the handshake fails before any request is read, so a
+//! leaked admin token alone does not reach the API.
+
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+use crate::{
+    AppError,
+    config::AdminListenerConfig,
+    systemd::{self, Socket},
+    tls::{self, Listener, PemFile, Serving},
+};
+
+pub struct AdminListener {
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+}
+
+impl AdminListener {
+    /// Binds the address and loads the certificates, so that a mistake in either
+    /// stops the server from starting.
+    pub async fn bind(config: &AdminListenerConfig, http2: bool) -> Result<Self, AppError> {
+        let acceptor = match &config.tls {
+            Some(files) => Some(tls::acceptor(
+                PemFile {
+                    var: "ADMIN_TLS_CERT",
+                    path: &files.cert,
+                },
+                PemFile {
+                    var: "ADMIN_TLS_KEY",
+                    path: &files.key,
+                },
+                files.client_ca.as_deref().map(|path| PemFile {
+                    var: "ADMIN_CLIENT_CA",
+                    path,
+                }),
+                http2,
+            )?),
+            None => None,
+        };
+        let listener = systemd::listen(Socket::Admin, config.address).await?;
+        info!(
+            tls = acceptor.is_some(),
+            client_certificates = config.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some()),
+            "admin API listening on {}",
+            listener.local_addr()?
+        );
+        Ok(Self { listener, acceptor })
+    }
+
+    pub async fn serve(self, app: Router, serving: Serving) -> std::io::Result<()> {
+        tls::serve(Listener::Tcp(self.listener), self.acceptor, app, serving).await
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, patch, post},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppError, AppState, FileEntry, bandwidth, blocklist, history,
+    ip_filter::{Ban, RouteGroup},
+    keys::ApiKey,
+    load::Concurrency,
+    metadata::{EntryPatch, unix_seconds},
+    parse_duration, presign,
+    proxy::Network,
+    scan::ScanStatus,
+    scrub::{self, ScrubReport},
+    secret,
+    upload_tokens::{self, UploadToken},
+    webhook::Event,
+};
+
+mod feed;
+mod listener;
+mod reports;
+mod subjects;
+mod tenants;
+mod users;
+
+pub use feed::{AdminFeed, LogLayer};
+pub use listener::AdminListener;
+pub use subjects::uploader_hash;
+
+const DEFAULT_UPLOAD_URL_TTL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Routes mounted under `/admin/api`. Every request must carry `ADMIN_TOKEN`
+/// as a bearer token or in `X-Admin-Token`; the event feed also takes it in the
+/// query string and checks it itself.
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/entries", get(list_entries))
+        .route("/entries/:id", delete(delete_entry))
+        .route("/entries/:id/downloads", get(history::admin_view))
+        .route("/quarantine", get(list_quarantine).delete(purge_quarantine))
+        .route("/quarantine/:id/release", post(release_entry))
+        .route("/reports", get(reports::list))
+        .route("/reports/:id/disable", post(reports::disable))
+        .route("/reports/:id/dismiss", post(reports::dismiss))
+        .route("/reports/:id/purge", post(reports::purge))
+        .route("/blocklist", get(list_blocked).post(block_hash))
+        .route("/blocklist/:sha256", delete(unblock_hash))
+        .route("/bans", get(list_bans).post(ban_address))
+        .route("/bans/:id", delete(lift_ban))
+        .route("/scrub", get(scrub_report).post(start_scrub))
+        .route("/stats", get(stats))
+        .route("/keys", get(list_keys).post(create_key))
+        .route("/keys/:id", delete(revoke_key))
+        .route("/users", get(users::list).post(users::create))
+        .route("/users/:id", patch(users::update).delete(users::remove))
+        .route("/tenants", get(tenants::list).post(tenants::create))
+        .route("/tenants/:id", patch(tenants::update).delete(tenants::remove))
+        .route("/tenants/:id/token", post(tenants::replace_token))
+        .route("/uploaders", get(subjects::export).delete(subjects::erase))
+        .route("/upload-urls", post(create_upload_url))
+        .route("/upload-tokens", post(create_upload_token))
+        .layer(middleware::from_fn_with_state(state, require_admin))
+        .route("/events", get(feed::connect))
+}
+
+async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let expected = state.config.admin_token.as_deref().ok_or(AppError::NotFound)?;
+    match admin_token(request.headers()) {
+        Some(provided) if secret::matches(expected, provided) => Ok(next.run(request).await),
+        _ => Err(AppError::InvalidToken),
+    }
+}
+
+fn admin_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-admin-token").and_then(|value| value.to_str().ok()))
+        .map(str::trim)
+}
+
+#[derive(Serialize)]
+struct AdminEntry {
+    id: String,
+    filename: String,
+    content_type: Option<String>,
+    size: u64,
+    /// Set when the blob is stored compressed.
+    compressed_size: Option<u64>,
+    created_at: u64,
+    expires_at: u64,
+    remaining_downloads: u32,
+    scan: ScanStatus,
+    threat: Option<String>,
+    /// Taken down after a report; see `/admin/api/reports`.
+    disabled: bool,
+    /// Id of the API key it was uploaded with.
+    api_key: Option<String>,
+    /// Hash of the uploader's address; see `/admin/api/uploaders`.
+    uploader: Option<String>,
+    /// The signed-in account it was uploaded from.
+    subject: Option<String>,
+    /// Id of the user it was uploaded by.
+    user: Option<String>,
+    /// The tenant whose namespace it is in, also the start of `id`.
+    tenant: Option<String>,
+}
+
+impl AdminEntry {
+    fn new(id: String, entry: FileEntry) -> Self {
+        Self {
+            tenant: crate::tenants::split(&id).0.map(str::to_string),
+            id,
+            filename: entry.filename,
+            content_type: entry.content_type,
+            size: entry.size,
+            compressed_size: entry.compressed_size,
+            created_at: unix_seconds(entry.created_at),
+            expires_at: unix_seconds(entry.expires_at),
+            remaining_downloads: entry.remaining_hits,
+            scan: entry.scan,
+            threat: entry.threat,
+            disabled: entry.disabled,
+            api_key: entry.api_key,
+            uploader: entry.uploader,
+            subject: entry.subject,
+            user: entry.user,
+        }
+    }
+}
+
+/// `GET /admin/api/entries` lists every entry, newest first.
+async fn list_entries(State(state): State<Arc<AppState>>) -> Result<Json<Vec<AdminEntry>>, AppError> {
+    let mut entries = state.metadata.list().await?;
+    entries.sort_by_key(|(_, entry)| Reverse(entry.created_at));
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|(id, entry)| AdminEntry::new(id, entry))
+            .collect(),
+    ))
+}
+
+/// `DELETE /admin/api/entries/:id` removes an entry without needing its tokens.
+async fn delete_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    let removed = state.metadata.remove(&id).await?.ok_or(AppError::NotFound)?;
+    state.notify(Event::Delete, &id, &removed);
+    state.discard(&removed).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /admin/api/quarantine` lists entries the malware scanner flagged or
+/// could not scan, newest first.
+async fn list_quarantine(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<AdminEntry>>, AppError> {
+    let mut entries = state.metadata.list().await?;
+    entries.retain(|(_, entry)| entry.scan.is_quarantined());
+    entries.sort_by_key(|(_, entry)| Reverse(entry.created_at));
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|(id, entry)| AdminEntry::new(id, entry))
+            .collect(),
+    ))
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+/// `DELETE /admin/api/quarantine` removes every quarantined entry.
+async fn purge_quarantine(State(state): State<Arc<AppState>>) -> Result<Json<Purged>, AppError> {
+    let mut purged = 0;
+    for (id, entry) in state.metadata.list().await? {
+        if !entry.scan.is_quarantined() {
+            continue;
+        }
+        if let Some(removed) = state.metadata.remove(&id).await? {
+            state.notify(Event::Delete, &id, &removed);
+            state.discard(&removed).await;
+            purged += 1;
+        }
+    }
+    Ok(Json(Purged { purged }))
+}
+
+/// `POST /admin/api/quarantine/:id/release` clears an entry for download after
+/// review, e.g. a false positive or a file clamd could not scan.
+async fn release_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AdminEntry>, AppError> {
+    let patch = EntryPatch {
+        scan: Some(ScanStatus::Clean),
+        threat: None,
+        ..EntryPatch::default()
+    };
+    let entry = state
+        .metadata
+        .update(&id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(AdminEntry::new(id, entry)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockedHash {
+    sha256: String,
+    note: Option<String>,
+}
+
+/// `GET /admin/api/blocklist` lists the banned digests.
+async fn list_blocked(State(state): State<Arc<AppState>>) -> Json<Vec<BlockedHash>> {
+    Json(
+        state
+            .blocklist
+            .list()
+            .into_iter()
+            .map(|(sha256, note)| BlockedHash { sha256, note })
+            .collect(),
+    )
+}
+
+/// `POST /admin/api/blocklist` bans a digest. Matching uploads are refused right
+/// away; stored copies go with the next cleanup run.
+async fn block_hash(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BlockedHash>,
+) -> Result<StatusCode, AppError> {
+    let sha256 = blocklist::normalize(&request.sha256).ok_or_else(|| {
+        AppError::BadRequest("sha256 must be 64 hexadecimal characters".to_string())
+    })?;
+    state.blocklist.add(sha256, request.note).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /admin/api/blocklist/:sha256` lifts a ban.
+async fn unblock_hash(
+    Path(sha256): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    let sha256 = blocklist::normalize(&sha256).ok_or(AppError::NotFound)?;
+    if !state.blocklist.remove(&sha256).await? {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    /// An address or a CIDR range such as `203.0.113.0/24`.
+    address: String,
+    /// How long the ban lasts, e.g. `30m` or `7d`; one hour by default.
+    duration: Option<String>,
+    /// Uploads and downloads when left out.
+    #[serde(default)]
+    groups: Vec<RouteGroup>,
+    note: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AddressBan {
+    id: u64,
+    address: String,
+    groups: Vec<RouteGroup>,
+    expires_at: u64,
+    note: Option<String>,
+}
+
+impl From<Ban> for AddressBan {
+    fn from(ban: Ban) -> Self {
+        Self {
+            id: ban.id,
+            address: ban.network.to_string(),
+            groups: ban.groups,
+            expires_at: unix_seconds(ban.expires_at),
+            note: ban.note,
+        }
+    }
+}
+
+/// `GET /admin/api/bans` lists the address bans still in force.
+async fn list_bans(State(state): State<Arc<AppState>>) -> Json<Vec<AddressBan>> {
+    Json(state.bans.list().into_iter().map(AddressBan::from).collect())
+}
+
+/// `POST /admin/api/bans` keeps an address or range out of some routes for a
+/// while, on top of the `*_DENIED_IPS` lists. Bans do not survive a restart.
+async fn ban_address(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BanRequest>,
+) -> Result<(StatusCode, Json<AddressBan>), AppError> {
+    let network = Network::parse(request.address.trim()).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "'{}' is not an address or a range such as 203.0.113.0/24",
+            request.address
+        ))
+    })?;
+    let duration = match request.duration.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => parse_duration("duration", value)?,
+        _ => DEFAULT_BAN_DURATION,
+    };
+    let ban = state.bans.add(
+        network,
+        request.groups,
+        SystemTime::now() + duration,
+        request.note.filter(|note| !note.trim().is_empty()),
+    );
+    Ok((StatusCode::CREATED, Json(ban.into())))
+}
+
+/// `DELETE /admin/api/bans/:id` lifts a ban before it expires.
+async fn lift_ban(
+    Path(id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    if !state.bans.remove(id) {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /admin/api/scrub` reports on the last (or current) integrity scrub.
+async fn scrub_report(State(state): State<Arc<AppState>>) -> Json<ScrubReport> {
+    Json(state.scrub.report())
+}
+
+/// `POST /admin/api/scrub` starts a scrub now; 409 if one is already running.
+async fn start_scrub(State(state): State<Arc<AppState>>) -> StatusCode {
+    if scrub::spawn(state) {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::CONFLICT
+    }
+}
+
+#[derive(Serialize)]
+struct Stats {
+    entries: usize,
+    expired_entries: usize,
+    total_bytes: u64,
+    /// `total_bytes` after compression at rest.
+    stored_bytes: u64,
+    tracked_bytes: u64,
+    max_total_storage_bytes: Option<u64>,
+    remaining_downloads: u64,
+    corrupt_entries: usize,
+    /// The current month, such as `2026-10`.
+    transfer_month: String,
+    /// What downloads sent this month, by `key:<id>` and `tenant:<id>`.
+    transferred_bytes: HashMap<String, u64>,
+    concurrent_uploads: Concurrency,
+    concurrent_downloads: Concurrency,
+}
+
+/// `GET /admin/api/stats` summarises what is currently stored.
+async fn stats(State(state): State<Arc<AppState>>) -> Result<Json<Stats>, AppError> {
+    let entries = state.metadata.list().await?;
+    let now = SystemTime::now();
+    let transfer_month = bandwidth::month(now);
+    let transferred_bytes = state.metadata.transfers(&transfer_month).await?;
+    Ok(Json(Stats {
+        entries: entries.len(),
+        expired_entries: entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at <= now)
+            .count(),
+        total_bytes: entries.iter().map(|(_, entry)| entry.size).sum(),
+        stored_bytes: entries.iter().map(|(_, entry)| entry.stored_bytes()).sum(),
+        tracked_bytes: state.usage.stored(),
+        max_total_storage_bytes: state.config.max_total_storage_bytes,
+        remaining_downloads: entries
+            .iter()
+            .map(|(_, entry)| u64::from(entry.remaining_hits))
+            .sum(),
+        corrupt_entries: entries
+            .iter()
+            .filter(|(_, entry)| entry.scan == ScanStatus::Corrupt)
+            .count(),
+        transfer_month,
+        transferred_bytes,
+        concurrent_uploads: state.load.uploads.view(),
+        concurrent_downloads: state.load.downloads.view(),
+    }))
+}
+
+#[derive(Serialize)]
+struct KeyView {
+    id: String,
+    name: String,
+    created_at: u64,
+    revoked_at: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    max_uploads: Option<u64>,
+    uploads: u64,
+    uploaded_bytes: u64,
+    last_used_at: Option<u64>,
+    monthly_transfer_bytes: Option<u64>,
+}
+
+impl From<ApiKey> for KeyView {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            created_at: unix_seconds(key.created_at),
+            revoked_at: key.revoked_at.map(unix_seconds),
+            max_upload_bytes: key.max_upload_bytes,
+            max_uploads: key.max_uploads,
+            uploads: key.uploads,
+            uploaded_bytes: key.uploaded_bytes,
+            last_used_at: key.last_used_at.map(unix_seconds),
+            monthly_transfer_bytes: key.monthly_transfer_bytes,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    name: String,
+    max_upload_bytes: Option<u64>,
+    max_uploads: Option<u64>,
+    /// Bytes downloads of the key's uploads may send per month.
+    monthly_transfer_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CreatedKey {
+    /// The plaintext key; it cannot be recovered later.
+    key: String,
+    #[serde(flatten)]
+    details: KeyView,
+}
+
+/// `GET /admin/api/keys` lists API keys with their usage, oldest first.
+async fn list_keys(State(state): State<Arc<AppState>>) -> Result<Json<Vec<KeyView>>, AppError> {
+    let mut keys = state.metadata.list_keys().await?;
+    keys.sort_by_key(|key| key.created_at);
+    Ok(Json(keys.into_iter().map(KeyView::from).collect()))
+}
+
+/// `POST /admin/api/keys` issues a key that can upload via `Authorization: Bearer`.
+async fn create_key(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<NewKey>,
+) -> Result<(StatusCode, Json<CreatedKey>), AppError> {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".to_string()));
+    }
+
+    let (mut record, key) = ApiKey::generate(
+        name.to_string(),
+        request.max_upload_bytes,
+        request.max_uploads,
+    );
+    record.monthly_transfer_bytes = request.monthly_transfer_bytes;
+    state.metadata.insert_key(&record).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedKey {
+            key,
+            details: record.into(),
+        }),
+    ))
+}
+
+/// `DELETE /admin/api/keys/:id` revokes a key; its record and usage are kept.
+async fn revoke_key(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<KeyView>, AppError> {
+    let key = state
+        .metadata
+        .revoke_key(&id, SystemTime::now())
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(key.into()))
+}
+
+#[derive(Deserialize)]
+struct UploadUrlRequest {
+    /// How long the URL stays valid, e.g. `30m` or `2d`; one hour by default.
+    valid_for: Option<String>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UploadUrl {
+    url: String,
+    expires_at: u64,
+    max_bytes: Option<u64>,
+}
+
+/// `POST /admin/api/upload-urls` mints a signed `/upload` URL that works without
+/// the password until it expires.
+async fn create_upload_url(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<UploadUrlRequest>,
+) -> Result<Json<UploadUrl>, AppError> {
+    let valid_for = match request.valid_for.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => parse_duration("valid_for", value)?,
+        _ => DEFAULT_UPLOAD_URL_TTL,
+    };
+    let expires_at = unix_seconds(SystemTime::now() + valid_for);
+    let query = presign::query(
+        &state.config.upload_signing_key,
+        expires_at,
+        request.max_bytes,
+    );
+
+    Ok(Json(UploadUrl {
+        url: state.config.build_url(&format!("/upload?{}", query)),
+        expires_at,
+        max_bytes: request.max_bytes,
+    }))
+}
+
+#[derive(Deserialize)]
+struct UploadTokenRequest {
+    /// How long the token stays valid, e.g. `30m` or `7d`; one hour by default.
+    valid_for: Option<String>,
+    max_bytes: Option<u64>,
+    max_uploads: Option<u32>,
+    /// Such as `application/pdf` or `image/*`; any type when left out.
+    #[serde(default)]
+    content_types: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct IssuedUploadToken {
+    token: String,
+    /// `/upload` with the token in the query string.
+    url: String,
+    #[serde(flatten)]
+    claims: UploadToken,
+}
+
+/// `POST /admin/api/upload-tokens` mints a signed upload token carrying its own
+/// limits. Nothing is stored until it is first used.
+async fn create_upload_token(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<UploadTokenRequest>,
+) -> Result<Json<IssuedUploadToken>, AppError> {
+    let valid_for = match request.valid_for.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => parse_duration("valid_for", value)?,
+        _ => DEFAULT_UPLOAD_URL_TTL,
+    };
+    let mut content_types = Vec::new();
+    for content_type in &request.content_types {
+        let content_type = content_type.trim().to_ascii_lowercase();
+        match content_type.split_once('/') {
+            Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() => {
+                content_types.push(content_type)
+            }
+            _ => {
+                return Err(AppError::BadRequest(format!(
+                    "'{}' is not a content type such as application/pdf or image/*",
+                    content_type
+                )));
+            }
+        }
+    }
+
+    let mut claims = UploadToken::new(valid_for);
+    claims.max_bytes = request.max_bytes;
+    claims.max_uploads = request.max_uploads;
+    claims.content_types = content_types;
+    let token = upload_tokens::issue(&state.config.upload_signing_key, &claims);
+    Ok(Json(IssuedUploadToken {
+        url: state.config.build_url(&format!("/upload?token={}", token)),
+        token,
+        claims,
+    }))
+}
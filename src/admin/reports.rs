@@ -0,0 +1,116 @@
+//! What the operator does about abuse reports: take the entry down, purge it or
+//! dismiss the reports. Each clears the reports and tells reporters who left an
+//! address how it ended.
+
+use std::{cmp::Reverse, sync::Arc};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use super::AdminEntry;
+use crate::{
+    AppError, AppState,
+    metadata::EntryPatch,
+    report::{self, Outcome, Report},
+    webhook::Event,
+};
+
+#[derive(Serialize)]
+pub(super) struct FlaggedEntry {
+    #[serde(flatten)]
+    entry: AdminEntry,
+    /// Oldest first.
+    reports: Vec<Report>,
+}
+
+/// `GET /admin/api/reports` lists entries with reports waiting, most recently
+/// reported first, followed by the ones taken down.
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<FlaggedEntry>>, AppError> {
+    let mut entries = state.metadata.list().await?;
+    entries.retain(|(_, entry)| !entry.reports.is_empty() || entry.disabled);
+    entries.sort_by_key(|(_, entry)| {
+        let reported = entry.reports.last().map(|report| report.at);
+        (entry.disabled, Reverse(reported), Reverse(entry.created_at))
+    });
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|(id, mut entry)| FlaggedEntry {
+                reports: std::mem::take(&mut entry.reports),
+                entry: AdminEntry::new(id, entry),
+            })
+            .collect(),
+    ))
+}
+
+/// `POST /admin/api/reports/:id/disable` takes an entry down: it stays stored
+/// but is answered with 451 until dismissed or purged.
+pub(super) async fn disable(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AdminEntry>, AppError> {
+    resolve(&state, &id, true, Outcome::Disabled).await
+}
+
+/// `POST /admin/api/reports/:id/dismiss` drops the reports of an entry found
+/// fine, serving it again if it was taken down.
+pub(super) async fn dismiss(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AdminEntry>, AppError> {
+    resolve(&state, &id, false, Outcome::Dismissed).await
+}
+
+async fn resolve(
+    state: &AppState,
+    id: &str,
+    disabled: bool,
+    outcome: Outcome,
+) -> Result<Json<AdminEntry>, AppError> {
+    let reported = state.metadata.get(id).await?.ok_or(AppError::NotFound)?;
+    let patch = EntryPatch {
+        disabled: Some(disabled),
+        clear_reports: true,
+        ..EntryPatch::default()
+    };
+    let entry = state
+        .metadata
+        .update(id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    report::notify_reporters(state, id, &reported, outcome);
+    Ok(Json(AdminEntry::new(id.to_string(), entry)))
+}
+
+#[derive(Deserialize)]
+pub(super) struct PurgeParams {
+    /// Also puts the content on the blocklist, so it cannot be uploaded again.
+    #[serde(default)]
+    block: bool,
+}
+
+/// `POST /admin/api/reports/:id/purge` deletes a reported entry and its file.
+pub(super) async fn purge(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PurgeParams>,
+) -> Result<StatusCode, AppError> {
+    let removed = state.metadata.remove(&id).await?.ok_or(AppError::NotFound)?;
+    state.notify(Event::Delete, &id, &removed);
+    state.discard(&removed).await;
+    report::notify_reporters(&state, &id, &removed, Outcome::Purged);
+    // Copies under other links go with the next cleanup run.
+    if params.block
+        && let Some(sha256) = removed.sha256
+    {
+        let note = format!("reported link {}", id);
+        state.blocklist.add(sha256, Some(note)).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
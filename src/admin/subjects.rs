@@ -0,0 +1,196 @@
+//! Data-subject requests about one uploader, named by `?key=<api key id>`,
+//! `?ip=<address>`, `?ip_hash=<hash>` or `?subject=<sub>` for a signed-in
+//! account. `GET /admin/api/uploaders` exports what the server holds about them:
+//! the API key's record and every entry they uploaded, with its download history. `DELETE` erases the same: the entries and
+//! their blobs, the histories and the key record itself.
+//!
+//! Addresses are only kept as a hash keyed with `UPLOAD_SIGNING_KEY`, so an
+//! uploader can be found by address without the address being stored.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{AdminEntry, KeyView};
+use crate::{
+    AppError, AppState, FileEntry, live::client_address, metadata::Download, secret, webhook::Event,
+};
+
+/// Hex digits of the address hash that are kept.
+const UPLOADER_HASH_LEN: usize = 32;
+
+/// The hash an upload from this client is recorded under.
+pub fn uploader_hash(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> String {
+    address_hash(state, client_address(headers, peer))
+}
+
+fn address_hash(state: &AppState, address: IpAddr) -> String {
+    let address = address.to_canonical().to_string();
+    let mac = secret::hmac_sha256(&state.config.upload_signing_key, &["uploader", &address]);
+    let mut hash = hex::encode(mac);
+    hash.truncate(UPLOADER_HASH_LEN);
+    hash
+}
+
+#[derive(Deserialize)]
+pub(super) struct SubjectParams {
+    key: Option<String>,
+    ip: Option<String>,
+    ip_hash: Option<String>,
+    subject: Option<String>,
+}
+
+enum Subject {
+    Key(String),
+    Address(String),
+    /// The identity provider's `sub` of a signed-in uploader.
+    Account(String),
+}
+
+impl Subject {
+    fn new(state: &AppState, params: SubjectParams) -> Result<Self, AppError> {
+        match (params.key, params.ip, params.ip_hash, params.subject) {
+            (Some(key), None, None, None) => Ok(Self::Key(key)),
+            (None, Some(ip), None, None) => {
+                let address = ip.trim().parse::<IpAddr>().map_err(|_| {
+                    AppError::BadRequest(format!("'{}' is not an IP address", ip))
+                })?;
+                Ok(Self::Address(address_hash(state, address)))
+            }
+            (None, None, Some(hash), None) => {
+                Ok(Self::Address(hash.trim().to_ascii_lowercase()))
+            }
+            (None, None, None, Some(subject)) => Ok(Self::Account(subject)),
+            _ => Err(AppError::BadRequest(
+                "name the uploader with exactly one of key, ip, ip_hash or subject".to_string(),
+            )),
+        }
+    }
+
+    fn uploaded(&self, entry: &FileEntry) -> bool {
+        match self {
+            Self::Key(id) => entry.api_key.as_deref() == Some(id),
+            Self::Address(hash) => entry.uploader.as_deref() == Some(hash),
+            Self::Account(subject) => entry.subject.as_deref() == Some(subject),
+        }
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        match self {
+            Self::Key(id) => Some(id),
+            Self::Address(_) | Self::Account(_) => None,
+        }
+    }
+
+    async fn entries(&self, state: &AppState) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let mut entries = state.metadata.list().await?;
+        entries.retain(|(_, entry)| self.uploaded(entry));
+        entries.sort_by_key(|(_, entry)| entry.created_at);
+        Ok(entries)
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct SubjectExport {
+    /// Absent unless the uploader is named by key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<KeyView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+    /// Oldest first.
+    entries: Vec<ExportedEntry>,
+}
+
+#[derive(Serialize)]
+struct ExportedEntry {
+    #[serde(flatten)]
+    entry: AdminEntry,
+    sha256: Option<String>,
+    downloads: Vec<Download>,
+}
+
+pub(super) async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SubjectParams>,
+) -> Result<Json<SubjectExport>, AppError> {
+    let subject = Subject::new(&state, params)?;
+    let key = match subject.key_id() {
+        Some(id) => state
+            .metadata
+            .list_keys()
+            .await?
+            .into_iter()
+            .find(|key| key.id == id)
+            .map(KeyView::from),
+        None => None,
+    };
+
+    let mut entries = Vec::new();
+    for (id, entry) in subject.entries(&state).await? {
+        let downloads = state
+            .metadata
+            .download_history(&id)
+            .await?
+            .filter(|history| history.owner_token == entry.owner_token)
+            .map(|history| history.downloads)
+            .unwrap_or_default();
+        entries.push(ExportedEntry {
+            sha256: entry.sha256.clone(),
+            entry: AdminEntry::new(id, entry),
+            downloads,
+        });
+    }
+    let (ip_hash, subject) = match subject {
+        Subject::Address(hash) => (Some(hash), None),
+        Subject::Account(subject) => (None, Some(subject)),
+        Subject::Key(_) => (None, None),
+    };
+    Ok(Json(SubjectExport {
+        key,
+        ip_hash,
+        subject,
+        entries,
+    }))
+}
+
+#[derive(Serialize)]
+pub(super) struct Erased {
+    entries: usize,
+    freed_bytes: u64,
+    key_deleted: bool,
+}
+
+pub(super) async fn erase(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SubjectParams>,
+) -> Result<Json<Erased>, AppError> {
+    let subject = Subject::new(&state, params)?;
+    let mut erased = Erased {
+        entries: 0,
+        freed_bytes: 0,
+        key_deleted: false,
+    };
+    for (id, _) in subject.entries(&state).await? {
+        state.metadata.remove_download_history(&id).await?;
+        // Already gone if it was downloaded for the last time in the meantime.
+        if let Some(removed) = state.metadata.remove(&id).await? {
+            state.notify(Event::Delete, &id, &removed);
+            erased.freed_bytes += state.discard(&removed).await;
+            erased.entries += 1;
+        }
+    }
+    if let Some(id) = subject.key_id() {
+        erased.key_deleted = state.metadata.remove_key(id).await?.is_some();
+    }
+    Ok(Json(erased))
+}
@@ -0,0 +1,219 @@
+//! `/admin/api/tenants`: creating tenants, changing their limits, replacing
+//! their tokens and removing them along with everything they stored.
+
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use super::users::{parse_ttl, present, validate_name};
+use crate::{
+    AppError, AppState, FileEntry,
+    metadata::unix_seconds,
+    tenants::{self, Tenant},
+    webhook::Event,
+};
+
+#[derive(Deserialize)]
+pub(super) struct NewTenant {
+    /// Lowercase letters, digits and dashes; names the tenant under `/t/`.
+    id: String,
+    /// Defaults to the id.
+    name: Option<String>,
+    storage_quota_bytes: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    /// Such as `30m`, `12h` or `7d`.
+    default_ttl: Option<String>,
+    /// Bytes downloads of the tenant's entries may send per month.
+    monthly_transfer_bytes: Option<u64>,
+}
+
+/// A missing field leaves the setting alone and `null` removes the limit.
+#[derive(Deserialize)]
+pub(super) struct TenantUpdate {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    storage_quota_bytes: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    max_upload_bytes: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    default_ttl: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    monthly_transfer_bytes: Option<Option<u64>>,
+}
+
+#[derive(Serialize)]
+pub(super) struct TenantView {
+    id: String,
+    name: String,
+    created_at: u64,
+    storage_quota_bytes: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    default_ttl_secs: Option<u64>,
+    monthly_transfer_bytes: Option<u64>,
+    /// Where its downloads are served, e.g. `/t/acme/d/`.
+    url_prefix: String,
+    storage_prefix: String,
+    used_bytes: u64,
+    uploads: usize,
+}
+
+impl TenantView {
+    fn new(state: &AppState, tenant: Tenant, entries: &[(String, FileEntry)]) -> Self {
+        let owned = tenants::owned(&tenant, entries);
+        Self {
+            url_prefix: state.config.build_url(&format!("/t/{}/d/", tenant.id)),
+            storage_prefix: tenant.storage_prefix(),
+            id: tenant.id,
+            name: tenant.name,
+            created_at: unix_seconds(tenant.created_at),
+            storage_quota_bytes: tenant.storage_quota_bytes,
+            max_upload_bytes: tenant.max_upload_bytes,
+            default_ttl_secs: tenant.default_ttl_secs,
+            monthly_transfer_bytes: tenant.monthly_transfer_bytes,
+            used_bytes: owned.iter().map(|(_, entry)| entry.size).sum(),
+            uploads: owned.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct IssuedToken {
+    /// The plaintext token; it cannot be recovered later.
+    token: String,
+    #[serde(flatten)]
+    details: TenantView,
+}
+
+#[derive(Serialize)]
+pub(super) struct RemovedTenant {
+    id: String,
+    entries: usize,
+    freed_bytes: u64,
+}
+
+async fn view(state: &AppState, tenant: Tenant) -> Result<TenantView, AppError> {
+    let entries = state.metadata.list().await?;
+    Ok(TenantView::new(state, tenant, &entries))
+}
+
+/// `GET /admin/api/tenants` lists tenants with their usage, oldest first.
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<TenantView>>, AppError> {
+    let mut all = state.metadata.list_tenants().await?;
+    all.sort_by_key(|tenant| tenant.created_at);
+    let entries = state.metadata.list().await?;
+    Ok(Json(
+        all.into_iter()
+            .map(|tenant| TenantView::new(&state, tenant, &entries))
+            .collect(),
+    ))
+}
+
+/// `POST /admin/api/tenants` creates a tenant, whose token uploads into it via
+/// `Authorization: Bearer`.
+pub(super) async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<NewTenant>,
+) -> Result<(StatusCode, Json<IssuedToken>), AppError> {
+    let id = tenants::validate_id(&request.id)?;
+    let name = match request.name {
+        Some(name) => validate_name(&name)?,
+        None => id.clone(),
+    };
+    let default_ttl_secs = parse_ttl(request.default_ttl)?;
+    if state.metadata.get_tenant(&id).await?.is_some() {
+        return Err(AppError::BadRequest(format!("tenant '{}' already exists", id)));
+    }
+
+    let (mut tenant, token) = Tenant::generate(id, name);
+    tenant.storage_quota_bytes = request.storage_quota_bytes;
+    tenant.max_upload_bytes = request.max_upload_bytes;
+    tenant.default_ttl_secs = default_ttl_secs;
+    tenant.monthly_transfer_bytes = request.monthly_transfer_bytes;
+    state.metadata.save_tenant(&tenant).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(IssuedToken {
+            token,
+            details: TenantView::new(&state, tenant, &[]),
+        }),
+    ))
+}
+
+/// `PATCH /admin/api/tenants/:id` renames a tenant or changes its limits. Lower
+/// limits apply to new uploads; nothing already stored is removed.
+pub(super) async fn update(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<TenantUpdate>,
+) -> Result<Json<TenantView>, AppError> {
+    let mut tenant = state.metadata.get_tenant(&id).await?.ok_or(AppError::NotFound)?;
+    if let Some(name) = update.name {
+        tenant.name = validate_name(&name)?;
+    }
+    if let Some(quota) = update.storage_quota_bytes {
+        tenant.storage_quota_bytes = quota;
+    }
+    if let Some(max) = update.max_upload_bytes {
+        tenant.max_upload_bytes = max;
+    }
+    if let Some(ttl) = update.default_ttl {
+        tenant.default_ttl_secs = parse_ttl(ttl)?;
+    }
+    if let Some(cap) = update.monthly_transfer_bytes {
+        tenant.monthly_transfer_bytes = cap;
+    }
+    state.metadata.save_tenant(&tenant).await?;
+    Ok(Json(view(&state, tenant).await?))
+}
+
+/// `POST /admin/api/tenants/:id/token` issues a new token; the old one stops
+/// working at once.
+pub(super) async fn replace_token(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<IssuedToken>, AppError> {
+    let mut tenant = state.metadata.get_tenant(&id).await?.ok_or(AppError::NotFound)?;
+    let token = tenant.replace_token();
+    state.metadata.save_tenant(&tenant).await?;
+    Ok(Json(IssuedToken {
+        token,
+        details: view(&state, tenant).await?,
+    }))
+}
+
+/// `DELETE /admin/api/tenants/:id` removes a tenant with its token, entries,
+/// blobs and download histories, so the id can be given out again cleanly.
+pub(super) async fn remove(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<RemovedTenant>, AppError> {
+    let tenant = state
+        .metadata
+        .remove_tenant(&id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let mut removed = RemovedTenant {
+        id: tenant.id,
+        entries: 0,
+        freed_bytes: 0,
+    };
+    let mut entries = state.metadata.list().await?;
+    entries.retain(|(entry_id, _)| tenants::owns(&removed.id, entry_id));
+    for (entry_id, _) in entries {
+        state.metadata.remove_download_history(&entry_id).await?;
+        if let Some(entry) = state.metadata.remove(&entry_id).await? {
+            state.notify(Event::Delete, &entry_id, &entry);
+            removed.freed_bytes += state.discard(&entry).await;
+            removed.entries += 1;
+        }
+    }
+    Ok(Json(removed))
+}
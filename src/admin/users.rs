@@ -0,0 +1,154 @@
+//! `/admin/api/users`: creating users, changing their limits and removing them.
+//! Removing a user revokes their token; what they uploaded stays until it
+//! expires or is deleted through `/admin/api/entries`.
+
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    AppError, AppState, parse_duration,
+    users::{self, User, UserView},
+};
+
+#[derive(Deserialize)]
+pub(super) struct NewUser {
+    name: String,
+    storage_quota_bytes: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    /// Such as `30m`, `12h` or `7d`.
+    default_ttl: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(super) struct CreatedUser {
+    /// The plaintext token; it cannot be recovered later.
+    token: String,
+    #[serde(flatten)]
+    details: UserView,
+}
+
+/// A missing field leaves the setting alone and `null` removes the limit.
+#[derive(Deserialize)]
+pub(super) struct UserUpdate {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    storage_quota_bytes: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    max_upload_bytes: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    default_ttl: Option<Option<String>>,
+}
+
+/// Tells a field set to `null` apart from one left out, which stays `None`.
+pub(super) fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+pub(super) fn validate_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".to_string()));
+    }
+    Ok(name.to_string())
+}
+
+pub(super) fn parse_ttl(value: Option<String>) -> Result<Option<u64>, AppError> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| parse_duration("default_ttl", value).map(|ttl| ttl.as_secs()))
+        .transpose()
+}
+
+async fn view(state: &AppState, user: User) -> Result<UserView, AppError> {
+    let entries = users::uploads(state, &user).await?;
+    Ok(UserView::new(user, &entries))
+}
+
+/// `GET /admin/api/users` lists users with their usage, oldest first.
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<UserView>>, AppError> {
+    let mut all = state.metadata.list_users().await?;
+    all.sort_by_key(|user| user.created_at);
+    let entries = state.metadata.list().await?;
+    Ok(Json(
+        all.into_iter()
+            .map(|user| {
+                let owned = users::owned(&user, &entries);
+                UserView::new(user, &owned)
+            })
+            .collect(),
+    ))
+}
+
+/// `POST /admin/api/users` creates a user, whose token uploads via
+/// `Authorization: Bearer`.
+pub(super) async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<NewUser>,
+) -> Result<(StatusCode, Json<CreatedUser>), AppError> {
+    let name = validate_name(&request.name)?;
+    let default_ttl_secs = parse_ttl(request.default_ttl)?;
+
+    let (mut user, token) = User::generate(name);
+    user.storage_quota_bytes = request.storage_quota_bytes;
+    user.max_upload_bytes = request.max_upload_bytes;
+    user.default_ttl_secs = default_ttl_secs;
+    state.metadata.save_user(&user).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedUser {
+            token,
+            details: UserView::new(user, &[]),
+        }),
+    ))
+}
+
+/// `PATCH /admin/api/users/:id` renames a user or changes their limits. Lower
+/// limits apply to new uploads; nothing already stored is removed.
+pub(super) async fn update(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<UserUpdate>,
+) -> Result<Json<UserView>, AppError> {
+    let mut user = state.metadata.get_user(&id).await?.ok_or(AppError::NotFound)?;
+    if let Some(name) = update.name {
+        user.name = validate_name(&name)?;
+    }
+    if let Some(quota) = update.storage_quota_bytes {
+        user.storage_quota_bytes = quota;
+    }
+    if let Some(max) = update.max_upload_bytes {
+        user.max_upload_bytes = max;
+    }
+    if let Some(ttl) = update.default_ttl {
+        user.default_ttl_secs = parse_ttl(ttl)?;
+    }
+    state.metadata.save_user(&user).await?;
+    Ok(Json(view(&state, user).await?))
+}
+
+/// `DELETE /admin/api/users/:id` removes a user and with them their token.
+pub(super) async fn remove(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<UserView>, AppError> {
+    let user = state
+        .metadata
+        .remove_user(&id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(view(&state, user).await?))
+}
@@ -0,0 +1,182 @@
+//! An audit trail in `AUDIT_LOG_FILE`, one JSON object per line, kept apart from
+//! the tracing output: every upload, download, expiry and deletion with the entry
+//! it concerns, and every request refused for a wrong password or token. Each
+//! record names the client as coarsely as live download events do (see `live`).
+
+use std::{
+    fs::OpenOptions,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::warn;
+
+use crate::{AppError, AppState, FileEntry, live::Visitor, metadata::unix_seconds, webhook::Event};
+
+/// Records waiting to be written; further ones are dropped, with a warning, while
+/// it is full.
+const QUEUE_LEN: usize = 4096;
+
+tokio::task_local! {
+    /// The client whose request is being handled, for records made along the way.
+    static CLIENT: Visitor;
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    at: u64,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_downloads: Option<u32>,
+    /// For refused requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<Refused<'a>>,
+    /// Absent for what the server does by itself, such as expiring entries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<Visitor>,
+}
+
+#[derive(Serialize)]
+struct Refused<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+}
+
+pub struct AuditLog {
+    queue: Option<mpsc::Sender<String>>,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, so a path that cannot be written fails at
+    /// startup rather than with the first record.
+    pub fn open(path: Option<&Path>) -> Result<Self, AppError> {
+        let Some(path) = path else {
+            return Ok(Self { queue: None });
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (queue, records) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_lines(tokio::fs::File::from_std(file), records, "audit log"));
+        Ok(Self { queue: Some(queue) })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    pub fn entry(&self, event: Event, id: &str, entry: &FileEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.append(&Record {
+            at: unix_seconds(SystemTime::now()),
+            event: event.as_str(),
+            id: Some(id),
+            filename: Some(&entry.filename),
+            size: Some(entry.size),
+            sha256: entry.sha256.as_deref(),
+            remaining_downloads: Some(entry.remaining_hits),
+            request: None,
+            client: CLIENT.try_with(Visitor::clone).ok(),
+        });
+    }
+
+    fn refused(&self, request: Refused, client: Visitor) {
+        self.append(&Record {
+            at: unix_seconds(SystemTime::now()),
+            event: "auth_failed",
+            id: None,
+            filename: None,
+            size: None,
+            sha256: None,
+            remaining_downloads: None,
+            request: Some(request),
+            client: Some(client),
+        });
+    }
+
+    fn append(&self, record: &Record) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!(%err, "failed to encode an audit record");
+                return;
+            }
+        };
+        if queue.try_send(line).is_err() {
+            warn!("audit log is falling behind, dropping a {} record", record.event);
+        }
+    }
+}
+
+/// Writes each line as it comes, so a crash loses none that were queued long ago.
+pub(crate) async fn write_lines<W: AsyncWrite + Unpin>(
+    mut out: W,
+    mut lines: mpsc::Receiver<String>,
+    log: &'static str,
+) {
+    while let Some(mut line) = lines.recv().await {
+        line.push('\n');
+        let written = match out.write_all(line.as_bytes()).await {
+            Ok(()) => out.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            warn!(%err, "failed to write to the {}", log);
+        }
+    }
+}
+
+/// Middleware that names the client for records made while handling a request,
+/// and records requests refused with `401` or `403`.
+pub async fn track(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let audit = &state.audit;
+    if !audit.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let client = Visitor::of(&state, request.headers(), peer);
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = CLIENT.scope(client.clone(), next.run(request)).await;
+
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        let refused = Refused {
+            method: method.as_str(),
+            path: &path,
+            status: status.as_u16(),
+        };
+        audit.refused(refused, client);
+    }
+    response
+}
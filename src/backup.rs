@@ -0,0 +1,204 @@
+//! `export` and `import` subcommands for moving an instance to another host. The
+//! archive is an uncompressed zip holding `manifest.json` (every live entry with
+//! its expiry and remaining downloads, plus the API keys, users and tenants the
+//! entries may belong to) and one `blobs/<key>`
+//! per stored blob. Import writes into whatever backends the importing
+//! instance is configured with, so it doubles as a way to change backends.
+
+use std::{collections::HashSet, fs::File, io::Write, time::SystemTime};
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    AppError, FileEntry,
+    config::AppConfig,
+    is_shared_key,
+    keys::ApiKey,
+    metadata::{self, MetadataStore},
+    storage::{self, StorageBackend},
+    tenants::Tenant,
+    users::User,
+};
+
+const MANIFEST: &str = "manifest.json";
+const BLOBS_DIR: &str = "blobs";
+/// Version 2 added users and tenants; version 1 archives still import.
+const FORMAT_VERSION: u32 = 2;
+/// The most memory set aside for a blob up front, whatever size the archive
+/// claims for it; larger blobs grow the buffer as they are read.
+const MAX_PREALLOCATION: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    entries: Vec<ExportedEntry>,
+    keys: Vec<ApiKey>,
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    tenants: Vec<Tenant>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+    id: String,
+    entry: FileEntry,
+}
+
+/// Writes every live entry and its blob to `path`.
+pub async fn export(config: &AppConfig, path: &str) -> Result<(), AppError> {
+    let storage = storage::from_config(config).await?;
+    let metadata = metadata::from_config(config).await?;
+
+    let now = SystemTime::now();
+    let entries: Vec<ExportedEntry> = metadata
+        .list()
+        .await?
+        .into_iter()
+        .filter(|(_, entry)| entry.expires_at > now)
+        .map(|(id, entry)| ExportedEntry { id, entry })
+        .collect();
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        entries,
+        keys: metadata.list_keys().await?,
+        users: metadata.list_users().await?,
+        tenants: metadata.list_tenants().await?,
+    };
+
+    let file = File::create(path)?;
+    let handle = Handle::current();
+    let count = manifest.entries.len();
+    tokio::task::spawn_blocking(move || write_archive(file, &manifest, &*storage, &handle))
+        .await
+        .map_err(std::io::Error::other)??;
+    info!(entries = count, "exported to {}", path);
+    Ok(())
+}
+
+/// Runs on a blocking thread; blobs are streamed from storage into the archive
+/// a chunk at a time.
+fn write_archive(
+    file: File,
+    manifest: &Manifest,
+    storage: &dyn StorageBackend,
+    handle: &Handle,
+) -> Result<(), AppError> {
+    let mut writer = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    writer.start_file(MANIFEST, options).map_err(zip_error)?;
+    serde_json::to_writer(&mut writer, manifest).map_err(std::io::Error::other)?;
+
+    let mut written = HashSet::new();
+    for exported in &manifest.entries {
+        let key = &exported.entry.key;
+        if !written.insert(key.clone()) {
+            continue;
+        }
+        let mut stream = handle.block_on(storage.stream(key, None))?;
+        writer
+            .start_file(format!("{}/{}", BLOBS_DIR, key), options)
+            .map_err(zip_error)?;
+        while let Some(chunk) = handle.block_on(stream.next()) {
+            writer.write_all(&chunk?)?;
+        }
+    }
+
+    writer.finish().map_err(zip_error)?;
+    Ok(())
+}
+
+/// Restores an archive written by `export`. Entries whose id is already taken,
+/// and entries that expired since the export, are skipped.
+pub async fn import(config: &AppConfig, path: &str) -> Result<(), AppError> {
+    let storage = storage::from_config(config).await?;
+    let metadata = metadata::from_config(config).await?;
+
+    let file = File::open(path)?;
+    let handle = Handle::current();
+    let (imported, skipped) = tokio::task::spawn_blocking(move || {
+        read_archive(file, &*storage, &*metadata, &handle)
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    info!(imported, skipped, "imported from {}", path);
+    Ok(())
+}
+
+fn read_archive(
+    file: File,
+    storage: &dyn StorageBackend,
+    metadata: &dyn MetadataStore,
+    handle: &Handle,
+) -> Result<(usize, usize), AppError> {
+    let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+    let manifest: Manifest = {
+        let reader = archive.by_name(MANIFEST).map_err(zip_error)?;
+        serde_json::from_reader(reader).map_err(std::io::Error::other)?
+    };
+    if manifest.version == 0 || manifest.version > FORMAT_VERSION {
+        return Err(AppError::BadRequest(format!(
+            "unsupported backup format version {}",
+            manifest.version
+        )));
+    }
+
+    let known: Vec<String> = handle
+        .block_on(metadata.list_keys())?
+        .into_iter()
+        .map(|key| key.id)
+        .collect();
+    for key in manifest.keys {
+        if !known.contains(&key.id) {
+            handle.block_on(metadata.insert_key(&key))?;
+        }
+    }
+    // Restored before the entries, which name their user or tenant.
+    for user in manifest.users {
+        if handle.block_on(metadata.get_user(&user.id))?.is_none() {
+            handle.block_on(metadata.save_user(&user))?;
+        }
+    }
+    for tenant in manifest.tenants {
+        if handle.block_on(metadata.get_tenant(&tenant.id))?.is_none() {
+            handle.block_on(metadata.save_tenant(&tenant))?;
+        }
+    }
+
+    let now = SystemTime::now();
+    let (mut imported, mut skipped) = (0, 0);
+    for ExportedEntry { id, entry } in manifest.entries {
+        if entry.expires_at <= now || handle.block_on(metadata.get(&id))?.is_some() {
+            warn!("skipping entry {}, which has expired or already exists", id);
+            skipped += 1;
+            continue;
+        }
+
+        if !handle.block_on(storage.exists(&entry.key))? {
+            let mut blob = archive
+                .by_name(&format!("{}/{}", BLOBS_DIR, entry.key))
+                .map_err(zip_error)?;
+            let mut data = Vec::with_capacity(blob.size().min(MAX_PREALLOCATION) as usize);
+            std::io::copy(&mut blob, &mut data)?;
+            handle.block_on(storage.put(&entry.key, storage::once(data.into())))?;
+        }
+        // Deduplicated blobs are named after their digest and reference counted.
+        if is_shared_key(&entry.key) {
+            handle.block_on(metadata.retain_blob(&entry.key))?;
+        }
+        handle.block_on(metadata.insert(&id, &entry))?;
+        imported += 1;
+    }
+    Ok((imported, skipped))
+}
+
+fn zip_error(err: zip::result::ZipError) -> AppError {
+    AppError::Io(std::io::Error::other(err))
+}
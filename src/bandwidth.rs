@@ -0,0 +1,150 @@
+//! Bandwidth accounting. The bytes sent for downloads of an entry uploaded with
+//! an API key, or into a tenant, are counted against that key or tenant per
+//! calendar month (UTC). Keys and tenants with a `monthly_transfer_bytes` cap
+//! get 509 for their downloads once they reached it, until the month is over; a
+//! download already under way when the cap is reached is sent in full.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tracing::warn;
+
+use crate::{
+    AppError, AppState, FileEntry,
+    acme::{civil_from_days, days_from_civil},
+    metadata::unix_seconds,
+    tenants,
+};
+
+/// What the usage of API key `id` is recorded under.
+pub fn key_meter(id: &str) -> String {
+    format!("key:{}", id)
+}
+
+/// What the usage of tenant `id` is recorded under.
+pub fn tenant_meter(id: &str) -> String {
+    format!("tenant:{}", id)
+}
+
+/// Everything downloads of entry `id` count against.
+fn meters(id: &str, entry: &FileEntry) -> Vec<String> {
+    let mut meters = Vec::new();
+    if let Some(key) = &entry.api_key {
+        meters.push(key_meter(key));
+    }
+    if let (Some(tenant), _) = tenants::split(id) {
+        meters.push(tenant_meter(tenant));
+    }
+    meters
+}
+
+/// The month `time` falls in, such as `2026-10`.
+pub fn month(time: SystemTime) -> String {
+    let (year, month) = year_month(unix_seconds(time) / 86_400);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// The year and month of the day `days` after 1970-01-01.
+fn year_month(days: u64) -> (u64, u64) {
+    let (year, month, _) = civil_from_days(days);
+    (year, month)
+}
+
+/// How long until the month after the one of `now` starts.
+fn until_next_month(now: SystemTime) -> Duration {
+    let secs = unix_seconds(now);
+    let (year, month) = year_month(secs / 86_400);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let starts = days_from_civil(year, month, 1).unwrap_or_default() * 86_400;
+    Duration::from_secs(starts.saturating_sub(secs))
+}
+
+/// Refuses a download of entry `id` once a key or tenant it counts against has
+/// used up its cap for the month.
+pub async fn check(state: &AppState, id: &str, entry: &FileEntry) -> Result<(), AppError> {
+    let mut caps = Vec::new();
+    if let Some(key_id) = &entry.api_key
+        && let Some(cap) = state
+            .metadata
+            .list_keys()
+            .await?
+            .into_iter()
+            .find(|key| &key.id == key_id)
+            .and_then(|key| key.monthly_transfer_bytes)
+    {
+        caps.push((key_meter(key_id), cap));
+    }
+    if let (Some(tenant), _) = tenants::split(id)
+        && let Some(cap) = state
+            .metadata
+            .get_tenant(tenant)
+            .await?
+            .and_then(|tenant| tenant.monthly_transfer_bytes)
+    {
+        caps.push((tenant_meter(tenant), cap));
+    }
+    if caps.is_empty() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    let used = state.metadata.transfers(&month(now)).await?;
+    if caps
+        .iter()
+        .any(|(meter, cap)| used.get(meter).copied().unwrap_or(0) >= *cap)
+    {
+        return Err(AppError::TransferCapExceeded {
+            retry_after: until_next_month(now),
+        });
+    }
+    Ok(())
+}
+
+/// Counts the bytes of a download as they are sent and records them once the
+/// body is dropped, whether it was sent in full or the client went away.
+pub struct Metered {
+    state: Arc<AppState>,
+    meters: Vec<String>,
+    sent: u64,
+}
+
+impl Metered {
+    /// Nothing is metered for entries that count against no key or tenant.
+    pub fn new(state: &Arc<AppState>, id: &str, entry: &FileEntry) -> Option<Self> {
+        let meters = meters(id, entry);
+        (!meters.is_empty()).then(|| Self {
+            state: state.clone(),
+            meters,
+            sent: 0,
+        })
+    }
+
+    pub fn count(&mut self, bytes: usize) {
+        self.sent += bytes as u64;
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        if self.sent == 0 {
+            return;
+        }
+        let state = self.state.clone();
+        let meters = std::mem::take(&mut self.meters);
+        let sent = self.sent;
+        tokio::spawn(async move {
+            let month = month(SystemTime::now());
+            for meter in meters {
+                if let Err(err) = state.metadata.record_transfer(&meter, &month, sent).await {
+                    warn!(%err, meter = %meter, "failed to record download bytes");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,178 @@
+//! Banned content, by SHA-256. The list lives in `BLOCKLIST_FILE`, one hex digest
+//! per line with an optional `# note`, and can also be edited through the admin
+//! API, which rewrites the file. Uploads of a listed digest are refused, and the
+//! cleanup task purges existing entries once a digest is added.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+use tokio::fs;
+use tracing::warn;
+
+use crate::AppError;
+
+pub struct Blocklist {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+    /// Set whenever the list changes, until the cleanup task has purged for it.
+    changed: AtomicBool,
+    /// Serialises rewrites of the file.
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Digest to note.
+    hashes: BTreeMap<String, Option<String>>,
+    modified: Option<SystemTime>,
+}
+
+/// Lowercases `value` if it is a hex SHA-256 digest.
+pub fn normalize(value: &str) -> Option<String> {
+    let value = value.trim().to_ascii_lowercase();
+    (value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit())).then_some(value)
+}
+
+impl Blocklist {
+    /// Reads the list if the file exists; a missing file is an empty list.
+    pub async fn open(path: PathBuf) -> Result<Self, AppError> {
+        let blocklist = Self {
+            path,
+            inner: Mutex::new(Inner::default()),
+            // Entries stored before the server started may already be listed.
+            changed: AtomicBool::new(true),
+            write_lock: tokio::sync::Mutex::new(()),
+        };
+        blocklist.reload().await?;
+        Ok(blocklist)
+    }
+
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.lock().hashes.contains_key(sha256)
+    }
+
+    pub fn list(&self) -> Vec<(String, Option<String>)> {
+        self.lock()
+            .hashes
+            .iter()
+            .map(|(hash, note)| (hash.clone(), note.clone()))
+            .collect()
+    }
+
+    pub async fn add(&self, sha256: String, note: Option<String>) -> Result<(), AppError> {
+        // A note has to stay on its digest's line.
+        let note = note
+            .map(|note| note.replace(['\r', '\n'], " ").trim().to_string())
+            .filter(|note| !note.is_empty());
+        let _write = self.write_lock.lock().await;
+        self.lock().hashes.insert(sha256, note);
+        self.changed.store(true, Ordering::SeqCst);
+        self.save().await
+    }
+
+    /// Returns whether the digest was listed.
+    pub async fn remove(&self, sha256: &str) -> Result<bool, AppError> {
+        let _write = self.write_lock.lock().await;
+        if self.lock().hashes.remove(sha256).is_none() {
+            return Ok(false);
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    /// Picks up edits made to the file by hand and reports whether the list
+    /// changed since the last call, i.e. whether stored entries need checking.
+    pub async fn refresh(&self) -> bool {
+        let modified = fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let known = self.lock().modified;
+        if modified != known
+            && let Err(err) = self.reload().await
+        {
+            warn!(?err, "failed to reload blocklist {:?}", self.path);
+        }
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+
+    async fn reload(&self) -> Result<(), AppError> {
+        let _write = self.write_lock.lock().await;
+        let raw = match fs::read_to_string(&self.path).await {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let modified = fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        let mut hashes = BTreeMap::new();
+        for (number, line) in raw.lines().enumerate() {
+            let (hash, note) = match line.split_once('#') {
+                Some((hash, note)) => (hash, Some(note.trim().to_string())),
+                None => (line, None),
+            };
+            if hash.trim().is_empty() {
+                continue;
+            }
+            match normalize(hash) {
+                Some(hash) => {
+                    hashes.insert(hash, note.filter(|note| !note.is_empty()));
+                }
+                None => warn!(
+                    "ignoring invalid sha256 on line {} of {:?}",
+                    number + 1,
+                    self.path
+                ),
+            }
+        }
+
+        let mut inner = self.lock();
+        if inner.hashes != hashes {
+            self.changed.store(true, Ordering::SeqCst);
+        }
+        inner.hashes = hashes;
+        inner.modified = modified;
+        Ok(())
+    }
+
+    /// Writes the list back out; the caller holds `write_lock`.
+    async fn save(&self) -> Result<(), AppError> {
+        let raw: String = self
+            .list()
+            .into_iter()
+            .map(|(hash, note)| match note {
+                Some(note) => format!("{}  # {}\n", hash, note),
+                None => format!("{}\n", hash),
+            })
+            .collect();
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, raw).await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        let modified = fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        self.lock().modified = modified;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,55 @@
+use std::{collections::HashSet, fs::File, io, path::PathBuf};
+
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{AppError, filename, spool::Spool};
+
+pub const BUNDLE_CONTENT_TYPE: &str = "application/zip";
+
+/// Packs several uploaded files into one uncompressed zip, written to `target`
+/// in the spool, so they can share a single link. Names are sanitized and made
+/// unique. Blocks.
+pub fn zip_files(target: PathBuf, files: Vec<(String, Spool)>) -> Result<Spool, AppError> {
+    if let Err(err) = write_zip(File::create(&target)?, files) {
+        let _ = std::fs::remove_file(&target);
+        return Err(err);
+    }
+    Ok(Spool::adopt(target)?)
+}
+
+fn write_zip(file: File, files: Vec<(String, Spool)>) -> Result<(), AppError> {
+    let mut writer = ZipWriter::new(file);
+    let mut used = HashSet::new();
+
+    for (filename, data) in files {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(data.len() >= u32::MAX as u64);
+        writer
+            .start_file(unique_name(&filename, &mut used), options)
+            .map_err(zip_error)?;
+        io::copy(&mut File::open(data.path())?, &mut writer)?;
+    }
+
+    writer.finish().map_err(zip_error)?;
+    Ok(())
+}
+
+fn unique_name(filename: &str, used: &mut HashSet<String>) -> String {
+    let base = filename::sanitize(filename).unwrap_or_else(|| "file".to_string());
+
+    let mut name = base.clone();
+    let mut counter = 1;
+    while !used.insert(name.clone()) {
+        counter += 1;
+        name = match base.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => format!("{} ({}).{}", stem, counter, ext),
+            _ => format!("{} ({})", base, counter),
+        };
+    }
+    name
+}
+
+fn zip_error(err: zip::result::ZipError) -> AppError {
+    AppError::Io(std::io::Error::other(err))
+}
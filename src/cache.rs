@@ -0,0 +1,100 @@
+//! Keeps small, frequently downloaded blobs in memory under `BLOB_CACHE_BYTES`, so
+//! pastes and screenshots are not read from storage on every request. Blob keys
+//! are never reused for different bytes (they are random, or the content's own
+//! digest), so a cached copy can only ever be stale by outliving its blob; it is
+//! dropped whenever the blob is deleted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+
+pub struct BlobCache {
+    capacity: u64,
+    max_file: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    blobs: HashMap<String, Cached>,
+    /// Last use to key, oldest first.
+    recency: BTreeMap<u64, String>,
+    used: u64,
+    clock: u64,
+}
+
+struct Cached {
+    data: Bytes,
+    used_at: u64,
+}
+
+impl BlobCache {
+    /// `capacity` bounds the cached bytes in total, `max_file` each blob.
+    pub fn new(capacity: u64, max_file: u64) -> Self {
+        Self {
+            capacity,
+            max_file: max_file.min(capacity),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Whether a blob of this size is worth caching.
+    pub fn admits(&self, size: u64) -> bool {
+        size > 0 && size <= self.max_file
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let mut inner = self.lock();
+        inner.clock += 1;
+        let now = inner.clock;
+        let cached = inner.blobs.get_mut(key)?;
+        let previous = std::mem::replace(&mut cached.used_at, now);
+        let data = cached.data.clone();
+        inner.recency.remove(&previous);
+        inner.recency.insert(now, key.to_string());
+        Some(data)
+    }
+
+    /// Caches a blob, evicting the least recently used ones to make room.
+    pub fn insert(&self, key: &str, data: Bytes) {
+        let size = data.len() as u64;
+        if !self.admits(size) {
+            return;
+        }
+        let mut inner = self.lock();
+        inner.remove(key);
+        while inner.used + size > self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = inner.blobs.remove(&oldest) {
+                inner.used -= evicted.data.len() as u64;
+            }
+        }
+        inner.clock += 1;
+        let used_at = inner.clock;
+        inner.recency.insert(used_at, key.to_string());
+        inner.blobs.insert(key.to_string(), Cached { data, used_at });
+        inner.used += size;
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(cached) = self.blobs.remove(key) {
+            self.recency.remove(&cached.used_at);
+            self.used -= cached.data.len() as u64;
+        }
+    }
+}
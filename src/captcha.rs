@@ -0,0 +1,134 @@
+//! hCaptcha or Cloudflare Turnstile in front of uploads that carry nothing but
+//! the shared password, or nothing at all on a password-less instance. The
+//! upload page shows the provider's widget and sends what it solved in
+//! `X-Captcha-Token`, which is checked with the provider before the upload is
+//! read. API keys, upload tokens, signed URLs and signed-in users skip it.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::http::HeaderMap;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{AppError, config::CaptchaConfig, live::client_address};
+
+pub const TOKEN_HEADER: &str = "x-captcha-token";
+/// An upload waits on the check, so a provider that hangs fails it instead.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptchaProvider {
+    HCaptcha,
+    Turnstile,
+}
+
+impl CaptchaProvider {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "hcaptcha" => Some(Self::HCaptcha),
+            "turnstile" => Some(Self::Turnstile),
+            _ => None,
+        }
+    }
+
+    pub fn script_url(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+        }
+    }
+
+    /// The class the provider's script renders a widget into.
+    pub fn widget_class(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "h-captcha",
+            Self::Turnstile => "cf-turnstile",
+        }
+    }
+
+    pub fn verify_url(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        }
+    }
+
+    /// Where the widget loads its script, styles and frame from, for the page's
+    /// `Content-Security-Policy`.
+    pub fn sources(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://hcaptcha.com https://*.hcaptcha.com",
+            Self::Turnstile => "https://challenges.cloudflare.com",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+pub struct Captcha {
+    config: CaptchaConfig,
+    client: reqwest::Client,
+}
+
+impl Captcha {
+    pub fn new(config: CaptchaConfig) -> Result<Self, AppError> {
+        let client = reqwest::Client::builder()
+            .timeout(VERIFY_TIMEOUT)
+            .build()
+            .map_err(|err| AppError::Config(format!("invalid CAPTCHA settings: {}", err)))?;
+        Ok(Self { config, client })
+    }
+
+    /// Has the provider check the token in `X-Captcha-Token`. Tokens are good for
+    /// one check only, so the page asks for a new one after every upload.
+    pub async fn verify(&self, headers: &HeaderMap, peer: SocketAddr) -> Result<(), AppError> {
+        let token = headers
+            .get(TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .ok_or(AppError::CaptchaFailed)?;
+        let remote_ip = client_address(headers, peer).to_canonical().to_string();
+        let mut form = vec![
+            ("secret", self.config.secret.as_str()),
+            ("response", token),
+            ("remoteip", remote_ip.as_str()),
+        ];
+        // hCaptcha also makes sure the token was solved for this site.
+        if self.config.provider == CaptchaProvider::HCaptcha {
+            form.push(("sitekey", self.config.site_key.as_str()));
+        }
+        let unreachable = |err: reqwest::Error| AppError::CaptchaUnavailable(err.to_string());
+        let response = self
+            .client
+            .post(&self.config.verify_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(unreachable)?;
+        let verdict: Verdict = serde_json::from_slice(&body).map_err(|err| {
+            AppError::CaptchaUnavailable(format!("unreadable answer ({}): {}", status, err))
+        })?;
+        if verdict.success {
+            return Ok(());
+        }
+        // A misconfigured secret fails every upload, so it is worth a warning.
+        if verdict
+            .error_codes
+            .iter()
+            .any(|code| code.contains("secret") || code.contains("sitekey"))
+        {
+            warn!(codes = ?verdict.error_codes, "CAPTCHA provider rejected the server's keys");
+        } else {
+            debug!(codes = ?verdict.error_codes, "CAPTCHA not solved");
+        }
+        Err(AppError::CaptchaFailed)
+    }
+}
@@ -0,0 +1,548 @@
+//! A simpler alternative to tus for browsers: `POST /upload/init` opens a session,
+//! numbered parts are `PUT` independently (and retried individually), and
+//! `POST /upload/:session/complete` joins them into one entry.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppError, AppState, Credential, EntryKind, NewUpload, UploadParams, UploadResponse, admin,
+    check_captcha, check_password, credential,
+    expected_sha256, metadata,
+    progress::{self, Phase, Progress, ProgressBoard},
+    reply_format, store_upload, upload_reply,
+    upload_tokens::UploadToken,
+};
+
+/// Part numbers run from 1 up to this, as with S3 multipart uploads.
+const MAX_PARTS: u32 = 10_000;
+
+/// Sessions and their received parts, one directory per session on local disk.
+pub struct ChunkStore {
+    dir: PathBuf,
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Session>>>>,
+    progress: ProgressBoard,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    filename: String,
+    content_type: Option<String>,
+    expires: Option<String>,
+    /// Hash of the API key that opened the session, so usage is counted on completion.
+    key_hash: Option<String>,
+    /// See `FileEntry::uploader`.
+    #[serde(default)]
+    uploader: Option<String>,
+    /// See `FileEntry::subject`.
+    #[serde(default)]
+    subject: Option<String>,
+    /// Id of the user who created the session, whose quota applies on completion.
+    #[serde(default)]
+    user: Option<String>,
+    /// Id of the tenant whose token created the session.
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Claims of the upload token that created the session, which are signed and
+    /// so need no lookup on completion.
+    #[serde(default)]
+    token: Option<UploadToken>,
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Total size announced at init, for reporting progress.
+    #[serde(default)]
+    size: Option<u64>,
+    /// Byte budget of the credential that opened the session, across all parts.
+    limit: u64,
+    /// Size of every part received so far, by part number.
+    parts: BTreeMap<u32, u64>,
+    #[serde(with = "metadata::unix_time")]
+    updated_at: SystemTime,
+}
+
+impl Session {
+    fn received(&self) -> u64 {
+        self.parts.values().sum()
+    }
+
+    fn progress(&self) -> Progress {
+        Progress::new(self.received(), self.size, Phase::Receiving)
+    }
+}
+
+impl ChunkStore {
+    pub async fn open(dir: PathBuf) -> Result<Self, AppError> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut sessions = HashMap::new();
+        let progress = ProgressBoard::default();
+        let mut items = fs::read_dir(&dir).await?;
+        while let Some(item) = items.next_entry().await? {
+            let path = item.path().join("session.json");
+            let Some(id) = item.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+
+            match fs::read(&path)
+                .await
+                .map(|raw| serde_json::from_slice::<Session>(&raw))
+            {
+                Ok(Ok(session)) => {
+                    progress.track(&id, session.progress());
+                    sessions.insert(id, Arc::new(tokio::sync::Mutex::new(session)));
+                }
+                Ok(Err(err)) => warn!(%err, "skipping corrupt chunked upload {:?}", path),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => warn!(%err, "failed to read chunked upload {:?}", path),
+            }
+        }
+
+        Ok(Self {
+            dir,
+            sessions: Mutex::new(sessions),
+            progress,
+        })
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<Session>>> {
+        self.lock().get(id).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<tokio::sync::Mutex<Session>>>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn session_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn part_path(&self, id: &str, part: u32) -> PathBuf {
+        self.session_dir(id).join(format!("{}.part", part))
+    }
+
+    async fn save(&self, id: &str, session: &Session) -> Result<(), AppError> {
+        let raw = serde_json::to_vec(session)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        let path = self.session_dir(id).join("session.json");
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, raw).await?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) {
+        self.lock().remove(id);
+        self.progress.forget(id);
+        let path = self.session_dir(id);
+        if let Err(err) = fs::remove_dir_all(&path).await
+            && err.kind() != ErrorKind::NotFound
+        {
+            warn!(%err, "failed to remove chunked upload {:?}", path);
+        }
+    }
+
+    /// Drops sessions that have not received a part for more than `max_idle`.
+    pub async fn purge_stale(&self, now: SystemTime, max_idle: Duration) {
+        let sessions: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect();
+
+        for (id, session) in sessions {
+            let Ok(session) = session.try_lock() else {
+                continue;
+            };
+            if session.updated_at + max_idle <= now {
+                drop(session);
+                info!(id = %id, "removing abandoned chunked upload");
+                self.remove(&id).await;
+            }
+        }
+    }
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/upload/init", post(init))
+        .route("/upload/:session", get(status).delete(abort))
+        .route("/upload/:session/events", get(events))
+        .route("/upload/:session/complete", post(complete))
+        .route("/upload/:session/:part", put(upload_part))
+}
+
+#[derive(Deserialize)]
+struct InitRequest {
+    filename: String,
+    content_type: Option<String>,
+    expires: Option<String>,
+    slug: Option<String>,
+    /// Digest of the assembled file, checked on completion.
+    sha256: Option<String>,
+    /// Total size, when known, so an oversized upload is refused before any part is sent.
+    size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SessionView {
+    session: String,
+    max_bytes: u64,
+    max_parts: u32,
+    received_bytes: u64,
+    parts: BTreeMap<u32, u64>,
+}
+
+impl SessionView {
+    fn new(id: &str, session: &Session) -> Self {
+        Self {
+            session: id.to_string(),
+            max_bytes: session.limit,
+            max_parts: MAX_PARTS,
+            received_bytes: session.received(),
+            parts: session.parts.clone(),
+        }
+    }
+}
+
+/// `POST /upload/init` opens a session. Credentials work as for `PUT` uploads; the
+/// returned id is all later requests need.
+async fn init(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<InitRequest>,
+) -> Result<Response, AppError> {
+    let credential = credential(&state, &headers, &params).await?;
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    if request.size.is_some_and(|size| size > limit as u64) {
+        return Err(AppError::PayloadTooLarge { limit });
+    }
+
+    let filename = Some(request.filename)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "upload.bin".to_string());
+    let session = Session {
+        filename,
+        content_type: request.content_type.filter(|t| !t.is_empty()),
+        expires: request.expires.or(params.expires),
+        key_hash: credential.api_key().map(|key| key.key_hash.clone()),
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().map(|user| user.id.clone()),
+        tenant: credential.tenant().map(|tenant| tenant.id.clone()),
+        token: credential.token().cloned(),
+        slug: request.slug.or(params.slug),
+        sha256: request.sha256.or_else(|| expected_sha256(&headers)),
+        size: request.size,
+        limit: limit as u64,
+        parts: BTreeMap::new(),
+        updated_at: SystemTime::now(),
+    };
+
+    let id = Uuid::new_v4().simple().to_string();
+    let chunks = &state.chunks;
+    fs::create_dir_all(chunks.session_dir(&id)).await?;
+    chunks.save(&id, &session).await?;
+    let view = SessionView::new(&id, &session);
+    chunks.progress.track(&id, session.progress());
+    chunks
+        .lock()
+        .insert(id, Arc::new(tokio::sync::Mutex::new(session)));
+
+    Ok((StatusCode::CREATED, Json(view)).into_response())
+}
+
+/// `GET /upload/:session` lists the parts received so far, so a client can tell
+/// which ones to resend.
+async fn status(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SessionView>, AppError> {
+    let session = state.chunks.get(&id).ok_or(AppError::NotFound)?;
+    let session = session.lock().await;
+    Ok(Json(SessionView::new(&id, &session)))
+}
+
+/// `GET /upload/:session/events` streams the session's progress.
+async fn events(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    match state.chunks.progress.subscribe(&id) {
+        Some(receiver) => progress::events(receiver).into_response(),
+        None => AppError::NotFound.into_response(),
+    }
+}
+
+/// `PUT /upload/:session/:part` stores the body as part `part`, replacing an
+/// earlier attempt at the same part. Parts may arrive in any order and in parallel.
+async fn upload_part(
+    Path((id, part)): Path<(String, u32)>,
+    State(state): State<Arc<AppState>>,
+    body: Body,
+) -> Result<Json<SessionView>, AppError> {
+    if part == 0 || part > MAX_PARTS {
+        return Err(AppError::BadRequest(format!(
+            "part number must be between 1 and {}",
+            MAX_PARTS
+        )));
+    }
+    let chunks = &state.chunks;
+    let session = chunks.get(&id).ok_or(AppError::NotFound)?;
+
+    // Only parts other than this one count against the budget while streaming;
+    // the exact total is checked again once the part is complete.
+    let budget = {
+        let session = session.lock().await;
+        let others = session.received() - session.parts.get(&part).copied().unwrap_or(0);
+        session.limit.saturating_sub(others)
+    };
+
+    let tmp = chunks
+        .session_dir(&id)
+        .join(format!("{}.{}.tmp", part, Uuid::new_v4().simple()));
+    let mut streamed = 0;
+    let written = write_part(&tmp, body, budget, |bytes| {
+        streamed += bytes;
+        chunks.progress.advance(&id, bytes);
+    })
+    .await;
+
+    let mut session = session.lock().await;
+    let result = store_part(chunks, &id, &mut session, part, &tmp, written).await;
+    chunks.progress.settle(&id, streamed, session.received());
+    result?;
+
+    Ok(Json(SessionView::new(&id, &session)))
+}
+
+/// Keeps a part that streamed in whole and within the session's budget.
+async fn store_part(
+    chunks: &ChunkStore,
+    id: &str,
+    session: &mut Session,
+    part: u32,
+    tmp: &FsPath,
+    written: Result<Option<u64>, AppError>,
+) -> Result<(), AppError> {
+    let too_large = || AppError::PayloadTooLarge {
+        limit: session.limit as usize,
+    };
+    let written = match written {
+        Ok(Some(written)) => written,
+        Ok(None) => {
+            remove_tmp(tmp).await;
+            return Err(too_large());
+        }
+        Err(err) => {
+            remove_tmp(tmp).await;
+            return Err(err);
+        }
+    };
+
+    let others = session.received() - session.parts.get(&part).copied().unwrap_or(0);
+    if others + written > session.limit {
+        remove_tmp(tmp).await;
+        return Err(too_large());
+    }
+    // The session may have completed or been aborted while the part streamed in.
+    if chunks.get(id).is_none() {
+        remove_tmp(tmp).await;
+        return Err(AppError::NotFound);
+    }
+    fs::rename(tmp, chunks.part_path(id, part)).await?;
+    session.parts.insert(part, written);
+    session.updated_at = SystemTime::now();
+    chunks.save(id, session).await
+}
+
+/// Streams `body` into `path`, giving up with `None` once it passes `budget` bytes.
+/// `received` is told the length of every chunk as it arrives.
+async fn write_part(
+    path: &FsPath,
+    body: Body,
+    budget: u64,
+    mut received: impl FnMut(u64),
+) -> Result<Option<u64>, AppError> {
+    let mut file = fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut written = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .map_err(|err| AppError::BadRequest(format!("failed to read upload body: {}", err)))?;
+        received(chunk.len() as u64);
+        written += chunk.len() as u64;
+        if written > budget {
+            return Ok(None);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(Some(written))
+}
+
+async fn remove_tmp(path: &FsPath) {
+    if let Err(err) = fs::remove_file(path).await
+        && err.kind() != ErrorKind::NotFound
+    {
+        warn!(%err, "failed to remove partial chunk {:?}", path);
+    }
+}
+
+#[derive(Deserialize)]
+struct CompleteParams {
+    /// Expected number of parts; guards against completing before the last one landed.
+    parts: Option<u32>,
+    format: Option<String>,
+}
+
+/// `POST /upload/:session/complete` joins parts `1..=n` in order and stores the
+/// result, replying like the other upload endpoints.
+async fn complete(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CompleteParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let chunks = &state.chunks;
+    let session = chunks.get(&id).ok_or(AppError::NotFound)?;
+    let session = session.lock().await;
+
+    let count = session.parts.len() as u32;
+    if count == 0 {
+        return Err(AppError::NoFileProvided);
+    }
+    if let Some(missing) = (1..=count).find(|part| !session.parts.contains_key(part)) {
+        return Err(AppError::BadRequest(format!("part {} is missing", missing)));
+    }
+    if let Some(expected) = params.parts
+        && expected != count
+    {
+        return Err(AppError::BadRequest(format!(
+            "expected {} parts but received {}",
+            expected, count
+        )));
+    }
+
+    chunks.progress.enter(&id, Phase::Assembling);
+    let response = assemble(&state, &id, &session, &headers)
+        .await
+        .inspect_err(|err| {
+            let error = err.to_string();
+            chunks.progress.enter(&id, Phase::Failed { error });
+        })?;
+    chunks.progress.enter(
+        &id,
+        Phase::Completed {
+            url: response.url.clone(),
+        },
+    );
+    drop(session);
+    chunks.remove(&id).await;
+
+    let upload_params = UploadParams {
+        format: params.format,
+        ..UploadParams::default()
+    };
+    let format = reply_format(&headers, &upload_params);
+    Ok(upload_reply(&state.config, &headers, response, format))
+}
+
+/// Joins the parts of a session and stores them as an entry.
+async fn assemble(
+    state: &Arc<AppState>,
+    id: &str,
+    session: &Session,
+    headers: &HeaderMap,
+) -> Result<UploadResponse, AppError> {
+    let chunks = &state.chunks;
+    let count = session.parts.len() as u32;
+    let mut data = state.spool.create().await?;
+    for part in 1..=count {
+        data.append(&chunks.part_path(id, part)).await?;
+    }
+
+    let api_key = match &session.key_hash {
+        Some(hash) => Some(
+            state
+                .metadata
+                .find_key(hash)
+                .await?
+                .ok_or(AppError::InvalidToken)?,
+        ),
+        None => None,
+    };
+    let user = match &session.user {
+        Some(id) => Some(state.metadata.get_user(id).await?.ok_or(AppError::InvalidToken)?),
+        None => None,
+    };
+    let tenant = match &session.tenant {
+        Some(id) => Some(
+            state
+                .metadata
+                .get_tenant(id)
+                .await?
+                .ok_or(AppError::InvalidToken)?,
+        ),
+        None => None,
+    };
+    let upload = NewUpload {
+        filename: session.filename.clone(),
+        content_type: session.content_type.clone(),
+        data: data.finish().await?,
+        expires: session.expires.clone(),
+        kind: EntryKind::File,
+        slug: session.slug.clone(),
+        sha256: expected_sha256(headers).or_else(|| session.sha256.clone()),
+        notify_email: None,
+        uploader: session.uploader.clone(),
+        subject: session.subject.clone(),
+        user,
+        tenant,
+        token: session.token.clone(),
+    };
+    store_upload(state, upload, api_key.as_ref()).await
+}
+
+/// `DELETE /upload/:session` abandons an upload and frees its parts.
+async fn abort(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    let session = state.chunks.get(&id).ok_or(AppError::NotFound)?;
+    let _session = session.lock().await;
+    state.chunks.remove(&id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,238 @@
+//! Optional compression of blobs at rest under `STORAGE_COMPRESSION`. Text-heavy
+//! uploads such as logs and SQL dumps shrink several times over, so they are
+//! stored compressed and expanded again on the fly while being downloaded;
+//! formats that are compressed already are stored as they are.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+use bytes::Bytes;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    FileEntry,
+    config::AppConfig,
+    media_type,
+    spool::{Spool, Spooler},
+    storage::{ByteStream, StorageBackend},
+};
+
+/// Below this, the gzip framing eats most of what could be saved.
+const MIN_SIZE: u64 = 512;
+
+/// Formats that carry their own compression, beyond `image/*`, `audio/*` and
+/// `video/*`.
+const PRECOMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/x-xz",
+    "application/x-bzip2",
+    "application/zstd",
+    "application/x-zstd",
+    "application/x-lz4",
+    "application/java-archive",
+    "application/epub+zip",
+    "application/pdf",
+    "application/vnd.android.package-archive",
+];
+
+/// Uncompressed media formats, which are worth compressing after all.
+const RAW_MEDIA_TYPES: &[&str] = &[
+    "image/bmp",
+    "image/svg+xml",
+    "image/tiff",
+    "audio/wav",
+    "audio/x-wav",
+];
+
+/// How a stored blob is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+}
+
+impl Codec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "gzip" => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Appended to content-addressed keys, so the same upload stored plain and
+    /// compressed never ends up sharing one blob.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "gz" => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+}
+
+fn is_precompressed(content_type: Option<&str>) -> bool {
+    let Some(value) = media_type(content_type) else {
+        return false;
+    };
+    if RAW_MEDIA_TYPES.contains(&value.as_str()) {
+        return false;
+    }
+    PRECOMPRESSED_TYPES.contains(&value.as_str())
+        || ["image/", "audio/", "video/"]
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+}
+
+/// Compresses a spooled upload for storage when that is enabled and pays off,
+/// into another spooled file. Returns the file to store and the codec it is
+/// encoded with, if any.
+pub async fn encode(
+    config: &AppConfig,
+    spooler: &Spooler,
+    content_type: Option<&str>,
+    data: Spool,
+) -> io::Result<(Spool, Option<Codec>)> {
+    let Some(codec) = config.storage_compression else {
+        return Ok((data, None));
+    };
+    if data.len() < MIN_SIZE || is_precompressed(content_type) {
+        return Ok((data, None));
+    }
+
+    let level = config.storage_compression_level;
+    let raw = data.path().to_path_buf();
+    let target = spooler.temp_path();
+    let compressed = tokio::task::spawn_blocking(move || {
+        if let Err(err) = compress(codec, level, &raw, &target) {
+            let _ = std::fs::remove_file(&target);
+            return Err(err);
+        }
+        Spool::adopt(target)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    // Unlabelled archives and the like are caught here instead.
+    if compressed.len() < data.len() - data.len() / 10 {
+        Ok((compressed, Some(codec)))
+    } else {
+        Ok((data, None))
+    }
+}
+
+fn compress(codec: Codec, level: u32, raw: &Path, target: &Path) -> io::Result<()> {
+    match codec {
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(File::create(target)?, Compression::new(level));
+            io::copy(&mut File::open(raw)?, &mut encoder)?;
+            encoder.finish().map(drop)
+        }
+    }
+}
+
+/// Expands a whole stored blob in memory.
+pub fn decompress(codec: Codec, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::Gzip => {
+            let mut raw = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut raw)?;
+            Ok(raw)
+        }
+    }
+}
+
+/// Streams an entry's content as it was uploaded, or only the given half-open
+/// byte span of it. A span of a compressed blob is found by expanding it from
+/// the start.
+pub async fn open(
+    storage: &dyn StorageBackend,
+    entry: &FileEntry,
+    span: Option<Range<u64>>,
+) -> io::Result<ByteStream> {
+    let Some(codec) = entry.compression else {
+        return storage.stream(&entry.key, span).await;
+    };
+    let raw = expand(codec, storage.stream(&entry.key, None).await?);
+    Ok(match span {
+        Some(span) => slice(raw, span),
+        None => raw,
+    })
+}
+
+fn expand(codec: Codec, stored: ByteStream) -> ByteStream {
+    let decoder = match codec {
+        Codec::Gzip => flate2::write::GzDecoder::new(Vec::new()),
+    };
+    stream::try_unfold(
+        (stored, Some(decoder)),
+        |(mut stored, mut decoder)| async move {
+            loop {
+                let Some(active) = decoder.as_mut() else {
+                    return Ok(None);
+                };
+                let finished = match stored.next().await {
+                    Some(chunk) => {
+                        active.write_all(&chunk?)?;
+                        false
+                    }
+                    // Fails on a truncated blob rather than ending the body early.
+                    None => {
+                        active.try_finish()?;
+                        true
+                    }
+                };
+                let raw = std::mem::take(active.get_mut());
+                if finished {
+                    decoder = None;
+                }
+                if !raw.is_empty() {
+                    return Ok(Some((Bytes::from(raw), (stored, decoder))));
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Cuts `span` out of a stream, without reading past its end.
+fn slice(raw: ByteStream, span: Range<u64>) -> ByteStream {
+    let Range { start, end } = span;
+    stream::try_unfold((raw, 0u64), move |(mut raw, mut offset)| async move {
+        while offset < end {
+            let Some(chunk) = raw.next().await else {
+                break;
+            };
+            let chunk = chunk?;
+            let len = chunk.len() as u64;
+            let from = start.saturating_sub(offset).min(len);
+            let to = end.saturating_sub(offset).min(len);
+            offset += len;
+            if from < to {
+                return Ok(Some((chunk.slice(from as usize..to as usize), (raw, offset))));
+            }
+        }
+        Ok(None)
+    })
+    .boxed()
+}
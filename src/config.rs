@@ -1,37 +1,1181 @@
-use std::{env, io::ErrorKind, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
 
-use dotenvy::dotenv;
+use axum::http::{HeaderName, HeaderValue, Method};
+use dotenvy::{dotenv, dotenv_iter};
+use regex::Regex;
 use tracing::warn;
 
-use crate::AppError;
+use crate::{
+    AppError,
+    captcha::CaptchaProvider,
+    compression::Codec,
+    file_types::FileTypeRules,
+    ids::IdStrategy,
+    ip_filter::{AccessList, RouteGroup},
+    proxy::{self, TrustedProxies},
+    secret, slug, tenants,
+    webhook::Hook,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+    Local,
+    S3,
+    Azure,
+    Gcs,
+}
+
+impl StorageKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "local" | "fs" => Some(Self::Local),
+            "s3" | "minio" => Some(Self::S3),
+            "azure" | "azblob" => Some(Self::Azure),
+            "gcs" | "gcp" => Some(Self::Gcs),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::S3 => "s3",
+            Self::Azure => "azure",
+            Self::Gcs => "gcs",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataKind {
+    Json,
+    Sqlite,
+    Redis,
+}
+
+impl MetadataKind {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "sqlite" => Some(Self::Sqlite),
+            "redis" => Some(Self::Redis),
+            _ => None,
+        }
+    }
+}
+
+/// What an upload does when it would push usage past `MAX_TOTAL_STORAGE_BYTES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageFullPolicy {
+    Reject,
+    EvictOldest,
+    EvictExpiring,
+}
+
+impl StorageFullPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "reject" => Some(Self::Reject),
+            "evict-oldest" => Some(Self::EvictOldest),
+            "evict-expiring" => Some(Self::EvictExpiring),
+            _ => None,
+        }
+    }
+}
+
+/// How hard local storage works to keep a finished upload across a power loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave flushing to the operating system.
+    Off,
+    /// Flush the file's data before it is renamed into place.
+    File,
+    /// Also flush the directory, so the rename itself survives.
+    Full,
+}
+
+impl Durability {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "file" => Some(Self::File),
+            "full" => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+/// How local storage arranges blobs under `STORAGE_DIR`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageLayout {
+    /// Every blob directly in the directory.
+    Flat,
+    /// Blobs spread over two levels of subdirectories, e.g. `8c/4f/8c4f4649-…`.
+    Sharded,
+}
+
+impl StorageLayout {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "flat" => Some(Self::Flat),
+            "sharded" => Some(Self::Sharded),
+            _ => None,
+        }
+    }
+}
+
+/// What the integrity scrub does with an entry whose blob is damaged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrubAction {
+    /// Quarantine it, so the admin API can inspect, release or purge it.
+    Flag,
+    Remove,
+}
+
+impl ScrubAction {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "flag" => Some(Self::Flag),
+            "remove" => Some(Self::Remove),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct AppConfig {
+pub struct S3Config {
+    pub bucket: String,
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub prefix: Option<String>,
+    pub allow_http: bool,
+}
+
+impl S3Config {
+    fn from_env() -> Option<Self> {
+        let bucket = non_empty_var("S3_BUCKET")?;
+        Some(Self {
+            bucket,
+            region: non_empty_var("S3_REGION"),
+            endpoint: non_empty_var("S3_ENDPOINT"),
+            access_key_id: non_empty_var("S3_ACCESS_KEY_ID"),
+            secret_access_key: non_empty_var("S3_SECRET_ACCESS_KEY"),
+            prefix: non_empty_var("S3_PREFIX")
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+            allow_http: env::var("S3_ALLOW_HTTP")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct AzureConfig {
+    pub account: Option<String>,
+    pub container: String,
+    /// A SAS token; without one (or an account key from the standard `AZURE_*`
+    /// variables) the managed identity of the host is used.
+    pub sas_token: Option<String>,
+    /// Picks a user-assigned managed identity.
+    pub client_id: Option<String>,
+    pub endpoint: Option<String>,
+    pub prefix: Option<String>,
+    pub allow_http: bool,
+}
+
+impl AzureConfig {
+    fn from_env() -> Option<Self> {
+        let container = non_empty_var("AZURE_CONTAINER")?;
+        Some(Self {
+            account: non_empty_var("AZURE_STORAGE_ACCOUNT"),
+            container,
+            sas_token: non_empty_var("AZURE_SAS_TOKEN"),
+            client_id: non_empty_var("AZURE_CLIENT_ID"),
+            endpoint: non_empty_var("AZURE_ENDPOINT"),
+            prefix: non_empty_var("AZURE_PREFIX")
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+            allow_http: env::var("AZURE_ALLOW_HTTP")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct GcsConfig {
+    pub bucket: String,
+    pub prefix: Option<String>,
+    /// A service account key file; without one (or application default
+    /// credentials), tokens come from the metadata server (workload identity on
+    /// GKE), at `GCE_METADATA_HOST` if set.
+    pub service_account_path: Option<String>,
+}
+
+impl GcsConfig {
+    fn from_env() -> Option<Self> {
+        let bucket = non_empty_var("GCS_BUCKET")?;
+        Some(Self {
+            bucket,
+            prefix: non_empty_var("GCS_PREFIX")
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+            service_account_path: non_empty_var("GCS_SERVICE_ACCOUNT_PATH"),
+        })
+    }
+}
+
+/// How the connection to the SMTP relay is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Upgrades a plain connection with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// TLS from the first byte, usually on port 465.
+    Tls,
+    /// No encryption, for a relay on the same host or network.
+    None,
+}
+
+impl SmtpSecurity {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "starttls" => Some(Self::StartTls),
+            "tls" | "smtps" => Some(Self::Tls),
+            "none" | "off" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+/// Where access log lines go.
+#[derive(Clone, Debug)]
+pub enum AccessLogTarget {
+    /// Application logs move to stderr to keep the two apart.
+    Stdout,
+    File(PathBuf),
+}
+
+/// How application logs are written, from `LOG_FORMAT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, with the fields of the request it belongs to.
+    Json,
+}
+
+impl LogFormat {
+    /// Read before logging starts, so with the `.env` file loaded but nothing else.
+    pub fn from_env() -> Result<Self, AppError> {
+        match non_empty_var("LOG_FORMAT") {
+            Some(value) => match value.to_ascii_lowercase().as_str() {
+                "text" | "pretty" => Ok(Self::Text),
+                "json" => Ok(Self::Json),
+                _ => Err(AppError::Config(format!("unknown LOG_FORMAT '{}'", value))),
+            },
+            None => Ok(Self::Text),
+        }
+    }
+}
+
+/// How each access log line is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The Apache/nginx "combined" format most log analyzers read.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl AccessLogFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "combined" | "clf" => Some(Self::Combined),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub target: AccessLogTarget,
+    pub format: AccessLogFormat,
+}
+
+impl AccessLogConfig {
+    /// Reads `ACCESS_LOG` (a path, or `-` for stdout) and `ACCESS_LOG_FORMAT`.
+    fn from_env() -> Result<Option<Self>, AppError> {
+        let Some(target) = non_empty_var("ACCESS_LOG") else {
+            return Ok(None);
+        };
+        let target = match target.as_str() {
+            "-" | "stdout" => AccessLogTarget::Stdout,
+            path => AccessLogTarget::File(PathBuf::from(path)),
+        };
+        let format = match non_empty_var("ACCESS_LOG_FORMAT") {
+            Some(value) => AccessLogFormat::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown ACCESS_LOG_FORMAT '{}'", value))
+            })?,
+            None => AccessLogFormat::Combined,
+        };
+        Ok(Some(Self { target, format }))
+    }
+}
+
+/// How traces are exported with OTLP; where to is left to the exporter, which
+/// reads the endpoint and the rest of the `OTEL_EXPORTER_OTLP_*` variables.
+#[derive(Clone, Debug)]
+pub struct OtlpConfig {
+    pub protocol: opentelemetry_otlp::Protocol,
+}
+
+impl OtlpConfig {
+    /// Reads the standard `OTEL_*` variables. Like `LOG_FORMAT`, they are read
+    /// before logging starts.
+    pub fn from_env() -> Result<Option<Self>, AppError> {
+        let endpoint = non_empty_var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .or_else(|| non_empty_var("OTEL_EXPORTER_OTLP_ENDPOINT"));
+        let disabled = non_empty_var("OTEL_SDK_DISABLED")
+            .is_some_and(|disabled| disabled.eq_ignore_ascii_case("true"));
+        if endpoint.is_none() || disabled {
+            return Ok(None);
+        }
+        let protocol = non_empty_var("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL")
+            .or_else(|| non_empty_var("OTEL_EXPORTER_OTLP_PROTOCOL"));
+        let protocol = match protocol.as_deref() {
+            None | Some("http/protobuf") => opentelemetry_otlp::Protocol::HttpBinary,
+            Some("http/json") => opentelemetry_otlp::Protocol::HttpJson,
+            Some("grpc") => opentelemetry_otlp::Protocol::Grpc,
+            Some(other) => {
+                return Err(AppError::Config(format!(
+                    "unknown OTLP protocol '{}', expected grpc, http/protobuf or http/json",
+                    other
+                )));
+            }
+        };
+        Ok(Some(Self { protocol }))
+    }
+}
+
+/// Where errors and panics are reported, from `SENTRY_DSN`.
+#[derive(Clone, Debug)]
+pub struct SentryConfig {
+    pub dsn: sentry::types::Dsn,
+    pub environment: Option<String>,
+    pub release: String,
+    /// Events sent per minute at most; the rest are dropped.
+    pub max_events_per_minute: u32,
+}
+
+impl SentryConfig {
+    /// Read before logging starts, like `LOG_FORMAT`.
+    pub fn from_env() -> Result<Option<Self>, AppError> {
+        let Some(dsn) = non_empty_var("SENTRY_DSN") else {
+            return Ok(None);
+        };
+        let dsn = dsn
+            .parse()
+            .map_err(|err| AppError::Config(format!("invalid SENTRY_DSN '{}': {}", dsn, err)))?;
+        Ok(Some(Self {
+            dsn,
+            environment: non_empty_var("SENTRY_ENVIRONMENT"),
+            release: non_empty_var("SENTRY_RELEASE").unwrap_or_else(|| {
+                format!("{}@{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            }),
+            max_events_per_minute: env::var("SENTRY_MAX_EVENTS_PER_MINUTE")
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(30),
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: lettre::message::Mailbox,
+    pub subject: String,
+    /// Body of the email, with the same placeholders as the subject.
+    pub template: String,
+    /// Emails sent in any one hour, to anyone.
+    pub max_per_hour: usize,
+    /// Emails sent in any one hour to the same address.
+    pub max_per_recipient: usize,
+}
+
+const DEFAULT_EMAIL_SUBJECT: &str = "{filename} was shared with you";
+const DEFAULT_EMAIL_TEMPLATE: &str = "\
+{filename} ({size}) was shared with you. Download it here:
+
+{url}
+
+The link expires {expires_at} and allows {remaining_downloads} download(s).
+";
+
+impl SmtpConfig {
+    fn from_env(url_prefix: Option<&str>) -> Result<Option<Self>, AppError> {
+        let Some(host) = non_empty_var("SMTP_HOST") else {
+            return Ok(None);
+        };
+        let from = non_empty_var("SMTP_FROM")
+            .ok_or_else(|| AppError::Config("SMTP_HOST requires SMTP_FROM".to_string()))?;
+        let from = from
+            .parse()
+            .map_err(|err| AppError::Config(format!("invalid SMTP_FROM '{}': {}", from, err)))?;
+        // A link a recipient cannot open is worse than none.
+        if url_prefix.is_none() {
+            return Err(AppError::Config(
+                "SMTP_HOST requires URL_PREFIX or BASE_URL, which emailed links start with"
+                    .to_string(),
+            ));
+        }
+        let security = match non_empty_var("SMTP_TLS") {
+            Some(value) => SmtpSecurity::parse(&value)
+                .ok_or_else(|| AppError::Config(format!("unknown SMTP_TLS '{}'", value)))?,
+            None => SmtpSecurity::StartTls,
+        };
+        let port = env::var("SMTP_PORT")
+            .ok()
+            .and_then(|v| v.parse::<u16>().ok())
+            .unwrap_or(match security {
+                SmtpSecurity::StartTls => 587,
+                SmtpSecurity::Tls => 465,
+                SmtpSecurity::None => 25,
+            });
+        let template = match non_empty_var("NOTIFY_EMAIL_TEMPLATE_FILE") {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => DEFAULT_EMAIL_TEMPLATE.to_string(),
+        };
+
+        Ok(Some(Self {
+            host,
+            port,
+            security,
+            username: non_empty_var("SMTP_USERNAME"),
+            password: non_empty_var("SMTP_PASSWORD"),
+            from,
+            subject: non_empty_var("NOTIFY_EMAIL_SUBJECT")
+                .unwrap_or_else(|| DEFAULT_EMAIL_SUBJECT.to_string()),
+            template,
+            max_per_hour: env::var("NOTIFY_EMAIL_MAX_PER_HOUR")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(100),
+            max_per_recipient: env::var("NOTIFY_EMAIL_MAX_PER_RECIPIENT")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(5),
+        }))
+    }
+}
+
+/// An OpenID Connect provider that the upload page signs in with.
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// The provider's issuer URL, under which its discovery document lives.
+    pub issuer: String,
+    pub client_id: String,
+    /// Absent for a public client, which proves itself with PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends the browser back, ending in `/auth/callback`.
+    pub redirect_url: String,
+    pub scopes: String,
+    pub session_lifetime: Duration,
+    /// Whether the shared password is still accepted from those not signed in.
+    pub allow_password: bool,
+}
+
+impl OidcConfig {
+    fn from_env(url_prefix: Option<&str>) -> Result<Option<Self>, AppError> {
+        let Some(issuer) = non_empty_var("OIDC_ISSUER") else {
+            return Ok(None);
+        };
+        let client_id = non_empty_var("OIDC_CLIENT_ID")
+            .ok_or_else(|| AppError::Config("OIDC_ISSUER requires OIDC_CLIENT_ID".to_string()))?;
+        let redirect_url = match (non_empty_var("OIDC_REDIRECT_URL"), url_prefix) {
+            (Some(url), _) => url,
+            (None, Some(prefix)) => format!("{}/auth/callback", prefix),
+            (None, None) => {
+                return Err(AppError::Config(
+                    "OIDC_ISSUER requires OIDC_REDIRECT_URL, URL_PREFIX or BASE_URL".to_string(),
+                ));
+            }
+        };
+
+        Ok(Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: non_empty_var("OIDC_CLIENT_SECRET"),
+            redirect_url,
+            scopes: non_empty_var("OIDC_SCOPES")
+                .unwrap_or_else(|| "openid email profile".to_string()),
+            session_lifetime: env::var("OIDC_SESSION_HOURS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|hours| *hours > 0)
+                .map(|hours| Duration::from_secs(hours.saturating_mul(3600)))
+                .unwrap_or_else(|| Duration::from_secs(12 * 3600)),
+            allow_password: env::var("OIDC_ALLOW_PASSWORD")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        }))
+    }
+}
+
+/// The CAPTCHA that password-only uploads have to solve.
+#[derive(Clone)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    pub site_key: String,
+    pub secret: String,
+    /// The provider's own unless `CAPTCHA_VERIFY_URL` points elsewhere.
+    pub verify_url: String,
+}
+
+impl CaptchaConfig {
+    fn from_env() -> Result<Option<Self>, AppError> {
+        let Some(provider) = non_empty_var("CAPTCHA_PROVIDER") else {
+            return Ok(None);
+        };
+        let provider = CaptchaProvider::parse(&provider).ok_or_else(|| {
+            AppError::Config(format!(
+                "invalid CAPTCHA_PROVIDER '{}', expected hcaptcha or turnstile",
+                provider
+            ))
+        })?;
+        let required = |var: &str| {
+            non_empty_var(var).ok_or_else(|| {
+                AppError::Config(format!("CAPTCHA_PROVIDER requires {}", var))
+            })
+        };
+        Ok(Some(Self {
+            provider,
+            site_key: required("CAPTCHA_SITE_KEY")?,
+            secret: required("CAPTCHA_SECRET")?,
+            verify_url: non_empty_var("CAPTCHA_VERIFY_URL")
+                .unwrap_or_else(|| provider.verify_url().to_string()),
+        }))
+    }
+}
+
+/// PEM files for serving `ADDRESS` over HTTPS.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    fn from_env() -> Result<Option<Self>, AppError> {
+        match (non_empty_var("TLS_CERT_PATH"), non_empty_var("TLS_KEY_PATH")) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(AppError::Config(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string(),
+            )),
+        }
+    }
+}
+
+/// How long a client may take over parts of a request; see `timeouts`. Each
+/// is off when set to 0.
+#[derive(Clone, Copy)]
+pub struct RequestTimeouts {
+    /// For the TLS handshake and the request headers, including those of the
+    /// next request on a kept-alive connection.
+    pub header: Option<Duration>,
+    /// Without any of the body arriving while a handler waits for it.
+    pub body_idle: Option<Duration>,
+    /// From the request arriving until the response starts; the body of a
+    /// download is not counted.
+    pub total: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    fn from_env() -> Self {
+        let timeout = |var: &str, default_secs: u64| {
+            let secs = env::var(var)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(default_secs);
+            (secs > 0).then(|| Duration::from_secs(secs))
+        };
+        Self {
+            header: timeout("REQUEST_HEADER_TIMEOUT_SECS", 30),
+            body_idle: timeout("REQUEST_BODY_TIMEOUT_SECS", 60),
+            total: timeout("REQUEST_TIMEOUT_SECS", 60 * 60),
+        }
+    }
+}
+
+/// Cross-origin access for browser apps on other sites, such as internal tools
+/// uploading with `fetch`.
+#[derive(Clone)]
+pub struct CorsConfig {
+    /// `None` for `*`, any origin.
+    pub origins: Option<Vec<HeaderValue>>,
+    pub methods: Vec<Method>,
+    /// `None` for `*`, whatever headers a preflight asks for.
+    pub headers: Option<Vec<HeaderName>>,
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    fn from_env() -> Result<Option<Self>, AppError> {
+        let Some(origins) = non_empty_var("CORS_ALLOWED_ORIGINS") else {
+            return Ok(None);
+        };
+        let invalid = |var: &str, value: &str| {
+            AppError::Config(format!("invalid {} entry '{}'", var, value))
+        };
+        let origins = match origins.trim() {
+            "*" => None,
+            origins => Some(
+                list(origins)
+                    .map(|origin| {
+                        let origin = origin.trim_end_matches('/');
+                        (origin.starts_with("https://") || origin.starts_with("http://"))
+                            .then(|| HeaderValue::from_str(origin).ok())
+                            .flatten()
+                            .ok_or_else(|| invalid("CORS_ALLOWED_ORIGINS", origin))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        let methods = non_empty_var("CORS_ALLOWED_METHODS")
+            .unwrap_or_else(|| "GET,HEAD,POST,PUT,PATCH,DELETE".to_string());
+        let methods = list(&methods)
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| invalid("CORS_ALLOWED_METHODS", method))
+            })
+            .collect::<Result<_, _>>()?;
+        let headers = match non_empty_var("CORS_ALLOWED_HEADERS") {
+            Some(headers) if headers.trim() != "*" => Some(
+                list(&headers)
+                    .map(|name| {
+                        HeaderName::from_bytes(name.as_bytes())
+                            .map_err(|_| invalid("CORS_ALLOWED_HEADERS", name))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => None,
+        };
+        let max_age = env::var("CORS_MAX_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(600));
+        Ok(Some(Self {
+            origins,
+            methods,
+            headers,
+            max_age,
+        }))
+    }
+}
+
+/// For the HTML pages this server renders, which keep their script and styles
+/// inline.
+const DEFAULT_CSP: &str = "default-src 'none'; script-src 'self' 'unsafe-inline'; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data: blob:; media-src 'self' blob:; \
+    connect-src 'self'; form-action 'self'; frame-ancestors 'none'; base-uri 'none'";
+
+/// Headers added to every response that does not set its own; each one is left
+/// out with the value `off`.
+#[derive(Clone)]
+pub struct SecurityHeadersConfig {
+    /// Only sent with HTML pages.
+    pub content_security_policy: Option<HeaderValue>,
+    pub referrer_policy: Option<HeaderValue>,
+    pub frame_options: Option<HeaderValue>,
+    pub strict_transport_security: Option<HeaderValue>,
+}
+
+impl SecurityHeadersConfig {
+    /// `url_prefix` may be another host than the pages, and its images, such as
+    /// the QR code of a new upload, are still shown; so is a CAPTCHA widget.
+    fn from_env(
+        url_prefix: Option<&str>,
+        captcha: Option<&CaptchaConfig>,
+    ) -> Result<Option<Self>, AppError> {
+        let enabled = env::var("SECURITY_HEADERS")
+            .ok()
+            .map(|v| !v.eq_ignore_ascii_case("false"))
+            .unwrap_or(true);
+        if !enabled {
+            return Ok(None);
+        }
+        let mut default_csp = match url_prefix.and_then(origin) {
+            Some(origin) => {
+                DEFAULT_CSP.replace("img-src 'self'", &format!("img-src 'self' {}", origin))
+            }
+            None => DEFAULT_CSP.to_string(),
+        };
+        if let Some(captcha) = captcha {
+            let sources = captcha.provider.sources();
+            for directive in ["script-src", "style-src", "connect-src"] {
+                default_csp = default_csp.replace(
+                    &format!("{} 'self'", directive),
+                    &format!("{} 'self' {}", directive, sources),
+                );
+            }
+            default_csp.push_str(&format!("; frame-src {}", sources));
+        }
+        let strict_transport_security = match non_empty_var("HSTS_MAX_AGE_SECS") {
+            Some(secs) => {
+                let secs = secs.parse::<u64>().map_err(|_| {
+                    AppError::Config(format!("invalid HSTS_MAX_AGE_SECS '{}'", secs))
+                })?;
+                HeaderValue::try_from(format!("max-age={}", secs)).ok()
+            }
+            None => None,
+        };
+        Ok(Some(Self {
+            content_security_policy: header_var("CONTENT_SECURITY_POLICY", &default_csp)?,
+            referrer_policy: header_var("REFERRER_POLICY", "no-referrer")?,
+            frame_options: header_var("X_FRAME_OPTIONS", "DENY")?,
+            strict_transport_security,
+        }))
+    }
+}
+
+/// `<group>_ALLOWED_IPS`, `<group>_DENIED_IPS` and the same for `_COUNTRIES`.
+fn access_list(group: &str) -> Result<AccessList, AppError> {
+    let networks = |kind: &str| {
+        let var = format!("{}_{}_IPS", group, kind);
+        match non_empty_var(&var) {
+            Some(value) => proxy::parse_networks(&var, &value),
+            None => Ok(Vec::new()),
+        }
+    };
+    let countries = |kind: &str| {
+        let var = format!("{}_{}_COUNTRIES", group, kind);
+        list(&non_empty_var(&var).unwrap_or_default())
+            .map(|code| {
+                let code = code.to_ascii_uppercase();
+                if code.len() == 2 && code.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+                    Ok(code)
+                } else {
+                    Err(AppError::Config(format!(
+                        "invalid {} entry '{}', expected a two-letter country code such as DE",
+                        var, code
+                    )))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(AccessList {
+        allow: networks("ALLOWED")?,
+        deny: networks("DENIED")?,
+        allow_countries: countries("ALLOWED")?,
+        deny_countries: countries("DENIED")?,
+    })
+}
+
+/// A header value from `var`, else `default`; `off` sends no header at all.
+fn header_var(var: &str, default: &str) -> Result<Option<HeaderValue>, AppError> {
+    let value = non_empty_var(var).unwrap_or_else(|| default.to_string());
+    match value.trim() {
+        "off" => Ok(None),
+        value => HeaderValue::from_str(value)
+            .map(Some)
+            .map_err(|_| AppError::Config(format!("invalid {} '{}'", var, value))),
+    }
+}
+
+/// `https://files.example.com:8443` of a URL under it.
+fn origin(url: &str) -> Option<&str> {
+    let scheme_end = url.find("://")? + 3;
+    let host_end = url[scheme_end..]
+        .find('/')
+        .map_or(url.len(), |end| scheme_end + end);
+    Some(&url[..host_end])
+}
+
+/// The non-empty entries of a comma-separated list.
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+}
+
+/// Let's Encrypt's production directory.
+const DEFAULT_ACME_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates for `ADDRESS` obtained and renewed from an ACME CA.
+#[derive(Clone)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    pub directory_url: String,
+    /// Where the CA fetches HTTP-01 challenges; everything else there is sent to
+    /// HTTPS.
+    pub http_address: SocketAddr,
+    /// Keeps the account key and the current certificate across restarts.
+    pub cache_dir: PathBuf,
+}
+
+impl AcmeConfig {
+    fn from_env(storage_dir: &std::path::Path) -> Result<Option<Self>, AppError> {
+        let Some(domains) = non_empty_var("ACME_DOMAINS") else {
+            return Ok(None);
+        };
+        let domains: Vec<String> = domains
+            .split(',')
+            .map(|domain| domain.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        if let Some(domain) = domains.iter().find(|domain| {
+            domain.starts_with("*.")
+                || !domain
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+        }) {
+            return Err(AppError::Config(format!(
+                "ACME_DOMAINS entry '{}' is not a domain name HTTP-01 can validate",
+                domain
+            )));
+        }
+        if domains.is_empty() {
+            return Err(AppError::Config("ACME_DOMAINS names no domain".to_string()));
+        }
+        let http_address = non_empty_var("ACME_HTTP_ADDRESS")
+            .unwrap_or_else(|| "0.0.0.0:80".to_string());
+        let http_address = http_address.parse().map_err(|_| {
+            AppError::Config(format!("invalid ACME_HTTP_ADDRESS '{}'", http_address))
+        })?;
+        Ok(Some(Self {
+            domains,
+            email: non_empty_var("ACME_EMAIL"),
+            directory_url: non_empty_var("ACME_DIRECTORY_URL")
+                .unwrap_or_else(|| DEFAULT_ACME_DIRECTORY.to_string()),
+            http_address,
+            cache_dir: non_empty_var("ACME_CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| storage_dir.join("acme")),
+        }))
+    }
+}
+
+/// One of the sockets in `ADDRESS`.
+#[derive(Clone)]
+pub struct Listen {
+    pub bind: Bind,
+    /// Whether it is served over TLS, which also makes its links `https://`.
+    pub tls: bool,
+}
+
+#[derive(Clone)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Listen {
+    /// Parses `ADDRESS`, a comma separated list of `http://host:port`,
+    /// `https://host:port`, `unix:/path` and bare `host:port`, which is served
+    /// over TLS when certificates are configured. Unix sockets are plain HTTP,
+    /// for a reverse proxy on the same host.
+    fn parse_all(value: &str, has_certificates: bool) -> Result<Vec<Self>, AppError> {
+        let mut listeners = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (bind, tls) = if let Some(path) = entry.strip_prefix("unix:") {
+                (Bind::Unix(PathBuf::from(path)), false)
+            } else {
+                let (address, tls) = match entry.split_once("://") {
+                    Some(("http", address)) => (address, false),
+                    Some(("https", address)) => (address, true),
+                    Some(_) => {
+                        return Err(AppError::Config(format!(
+                            "invalid ADDRESS '{}': the scheme must be http or https",
+                            entry
+                        )));
+                    }
+                    None => (entry, has_certificates),
+                };
+                match address.trim_end_matches('/').parse() {
+                    Ok(address) => (Bind::Tcp(address), tls),
+                    Err(err) => {
+                        warn!(%err, "ignoring invalid ADDRESS entry '{}'", entry);
+                        continue;
+                    }
+                }
+            };
+            if tls && !has_certificates {
+                return Err(AppError::Config(format!(
+                    "ADDRESS '{}' needs TLS_CERT_PATH and TLS_KEY_PATH or ACME_DOMAINS",
+                    entry
+                )));
+            }
+            listeners.push(Self { bind, tls });
+        }
+        if listeners.is_empty() {
+            warn!("no valid ADDRESS, falling back to the default");
+            listeners.push(Self {
+                bind: Bind::Tcp(SocketAddr::from(([0, 0, 0, 0], 8080))),
+                tls: has_certificates,
+            });
+        }
+        Ok(listeners)
+    }
+}
+
+/// A listener of its own for `/admin/api`, which is then not served on `ADDRESS`.
+#[derive(Clone)]
+pub struct AdminListenerConfig {
     pub address: SocketAddr,
+    pub tls: Option<AdminTlsConfig>,
+}
+
+/// PEM files for serving the admin API over TLS.
+#[derive(Clone)]
+pub struct AdminTlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Issuers of the client certificates that are let in; without it any client
+    /// may connect and only the admin token is checked.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl AdminListenerConfig {
+    fn from_env() -> Result<Option<Self>, AppError> {
+        let cert = non_empty_var("ADMIN_TLS_CERT").map(PathBuf::from);
+        let key = non_empty_var("ADMIN_TLS_KEY").map(PathBuf::from);
+        let client_ca = non_empty_var("ADMIN_CLIENT_CA").map(PathBuf::from);
+        let Some(address) = non_empty_var("ADMIN_ADDRESS") else {
+            if cert.is_some() || key.is_some() || client_ca.is_some() {
+                return Err(AppError::Config(
+                    "ADMIN_TLS_CERT, ADMIN_TLS_KEY and ADMIN_CLIENT_CA require ADMIN_ADDRESS"
+                        .to_string(),
+                ));
+            }
+            return Ok(None);
+        };
+        let address = address
+            .parse()
+            .map_err(|_| AppError::Config(format!("invalid ADMIN_ADDRESS '{}'", address)))?;
+        let tls = match (cert, key) {
+            (Some(cert), Some(key)) => Some(AdminTlsConfig {
+                cert,
+                key,
+                client_ca,
+            }),
+            (None, None) if client_ca.is_none() => None,
+            (None, None) => {
+                return Err(AppError::Config(
+                    "ADMIN_CLIENT_CA requires ADMIN_TLS_CERT and ADMIN_TLS_KEY".to_string(),
+                ));
+            }
+            _ => {
+                return Err(AppError::Config(
+                    "ADMIN_TLS_CERT and ADMIN_TLS_KEY must be set together".to_string(),
+                ));
+            }
+        };
+        Ok(Some(Self { address, tls }))
+    }
+}
+
+#[derive(Clone)]
+pub struct AppConfig {
+    pub listeners: Vec<Listen>,
+    pub tls: Option<TlsConfig>,
+    /// Whether HTTP/2 is offered to clients over TLS.
+    pub http2: bool,
+    pub timeouts: RequestTimeouts,
+    /// How long a shutdown waits for uploads and downloads under way.
+    pub shutdown_timeout: Duration,
+    pub acme: Option<AcmeConfig>,
+    pub storage_kind: StorageKind,
+    pub storage_mirror: Option<StorageKind>,
     pub storage_dir: PathBuf,
-    pub ttl: Duration,
+    pub s3: Option<S3Config>,
+    pub azure: Option<AzureConfig>,
+    pub gcs: Option<GcsConfig>,
+    pub smtp: Option<SmtpConfig>,
+    pub metadata_kind: MetadataKind,
+    pub metadata_dir: PathBuf,
+    pub sqlite_path: PathBuf,
+    pub redis_url: Option<String>,
+    pub redis_prefix: String,
     pub cleanup_interval: Duration,
-    pub max_downloads: u32,
+    pub scrub_interval: Option<Duration>,
+    pub scrub_action: ScrubAction,
     pub url_prefix: Option<String>,
+    /// Where the routes are mounted, such as `/share` from `BASE_URL`; empty at
+    /// the root.
+    pub base_path: String,
+    /// Peers whose `X-Forwarded-For`, `-Proto` and `-Host` are believed.
+    pub trusted_proxies: TrustedProxies,
+    pub upload_access: AccessList,
+    pub download_access: AccessList,
+    pub admin_access: AccessList,
+    pub geoip_database: Option<PathBuf>,
+    /// Abuse reports one client may send an hour; 0 turns `/report` off.
+    pub reports_per_hour: usize,
+    /// Uploads and bytes one client address may upload in a day; see
+    /// `address_quota`.
+    pub daily_uploads_per_ip: Option<usize>,
+    pub daily_upload_bytes_per_ip: Option<u64>,
+    /// Uploads and downloads that may be in flight at once; see `load`.
+    pub max_concurrent_uploads: Option<usize>,
+    pub max_concurrent_downloads: Option<usize>,
+    pub cors: Option<CorsConfig>,
+    pub security_headers: Option<SecurityHeadersConfig>,
     pub upload_page_enabled: bool,
-    pub upload_password: String,
+    pub oidc: Option<OidcConfig>,
+    pub captcha: Option<CaptchaConfig>,
     pub use_filename_suffix: bool,
+    pub deduplicate_uploads: bool,
     pub upload_debug_logs: bool,
     pub max_upload_bytes: usize,
+    pub max_total_storage_bytes: Option<u64>,
+    pub storage_full_policy: StorageFullPolicy,
+    pub storage_fsync: Durability,
+    /// Deletes blobs no entry refers to at startup, which is only safe when no
+    /// one else writes to the storage.
+    pub orphan_sweep: bool,
+    pub storage_layout: StorageLayout,
+    pub storage_compression: Option<Codec>,
+    pub storage_compression_level: u32,
+    pub admin_token: Option<String>,
+    pub admin_listener: Option<AdminListenerConfig>,
+    pub upload_signing_key: String,
+    pub remote_url_uploads: bool,
+    pub remote_fetch_timeout: Duration,
+    pub remote_fetch_allow_private: bool,
+    pub session_dir: PathBuf,
+    pub blocklist_file: PathBuf,
+    pub webhooks: Vec<Hook>,
+    pub audit_log: Option<PathBuf>,
+    pub access_log: Option<AccessLogConfig>,
+    pub log_format: LogFormat,
+    pub otlp: Option<OtlpConfig>,
+    pub upload_session_ttl: Duration,
+    /// How long an entry's download history is kept after it expires; `None`
+    /// records none.
+    pub download_history_retention: Option<Duration>,
+    pub slug_pattern: Regex,
+    pub slug_deny_pattern: Option<Regex>,
+    pub id_strategy: IdStrategy,
+    pub clamd_address: Option<String>,
+    pub clamd_timeout: Duration,
+    pub download_cache_control: String,
+    pub blob_cache_bytes: u64,
+    pub blob_cache_max_file_bytes: u64,
 }
 
 impl AppConfig {
     pub fn from_env() -> Result<Self, AppError> {
-        let address = env::var("ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+        let storage_kind = match env::var("STORAGE_BACKEND") {
+            Ok(value) if !value.is_empty() => StorageKind::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown STORAGE_BACKEND '{}'", value))
+            })?,
+            _ => StorageKind::Local,
+        };
+        let storage_mirror = match env::var("STORAGE_MIRROR") {
+            Ok(value) if !value.is_empty() => {
+                Some(StorageKind::parse(&value).ok_or_else(|| {
+                    AppError::Config(format!("unknown STORAGE_MIRROR '{}'", value))
+                })?)
+            }
+            _ => None,
+        };
+        if storage_mirror == Some(storage_kind) {
+            return Err(AppError::Config(
+                "STORAGE_MIRROR must name another backend than STORAGE_BACKEND".to_string(),
+            ));
+        }
+
+        let storage_dir = PathBuf::from(
+            env::var("STORAGE_DIR").unwrap_or_else(|_| "data".to_string()),
+        );
 
-        let storage_dir = env::var("STORAGE_DIR").unwrap_or_else(|_| "data".to_string());
+        let metadata_dir = env::var("METADATA_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| storage_dir.join("meta"));
+
+        // Partial data of resumable and chunked uploads lives here until completed,
+        // and every upload body is spooled here while it is received.
+        let session_dir = non_empty_var("UPLOAD_SESSION_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| storage_dir.join("sessions"));
+
+        let tls = TlsConfig::from_env()?;
+        let acme = AcmeConfig::from_env(&storage_dir)?;
+        if tls.is_some() && acme.is_some() {
+            return Err(AppError::Config(
+                "set either TLS_CERT_PATH and TLS_KEY_PATH or ACME_DOMAINS, not both".to_string(),
+            ));
+        }
+        let listeners = Listen::parse_all(
+            &env::var("ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string()),
+            tls.is_some() || acme.is_some(),
+        )?;
+
+        let blocklist_file = non_empty_var("BLOCKLIST_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| storage_dir.join("blocklist.txt"));
 
-        let ttl = env::var("DEFAULT_TTL_MINS")
+        // Unfinished uploads that have not received data for this long are dropped.
+        let upload_session_ttl = env::var("UPLOAD_SESSION_TTL_MINS")
             .ok()
             .and_then(|v| v.parse::<u64>().ok())
             .map(|minutes| minutes.saturating_mul(60))
             .map(Duration::from_secs)
-            .unwrap_or_else(|| Duration::from_secs(60 * 60));
+            .unwrap_or_else(|| Duration::from_secs(24 * 60 * 60));
+
+        let download_history_retention = match env::var("DOWNLOAD_HISTORY_DAYS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            Some(0) => None,
+            Some(days) => Some(Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+            None => Some(Duration::from_secs(7 * 24 * 60 * 60)),
+        };
+
+        let metadata_kind = match env::var("METADATA_BACKEND") {
+            Ok(value) if !value.is_empty() => MetadataKind::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown METADATA_BACKEND '{}'", value))
+            })?,
+            _ => MetadataKind::Json,
+        };
+
+        let sqlite_path = non_empty_var("SQLITE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| metadata_dir.join("entries.db"));
 
         let cleanup_interval = env::var("CLEANUP_INTERVAL_MINS")
             .ok()
@@ -40,23 +1184,87 @@ impl AppConfig {
             .map(Duration::from_secs)
             .unwrap_or_else(|| Duration::from_secs(60));
 
-        let max_downloads = env::var("MAX_DOWNLOADS")
+        // Re-reading every blob is expensive, so the scrub only runs when asked for.
+        let scrub_interval = env::var("SCRUB_INTERVAL_MINS")
             .ok()
-            .and_then(|v| v.parse::<u32>().ok())
-            .unwrap_or(3);
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::from_secs(minutes.saturating_mul(60)));
+        let scrub_action = match env::var("SCRUB_ACTION") {
+            Ok(value) if !value.is_empty() => ScrubAction::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown SCRUB_ACTION '{}'", value))
+            })?,
+            _ => ScrubAction::Flag,
+        };
+
+        let reports_per_hour = env::var("REPORTS_PER_HOUR")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(5);
+
+        let daily_uploads_per_ip = env::var("DAILY_UPLOADS_PER_IP")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|max| *max > 0);
+        let daily_upload_bytes_per_ip = env::var("DAILY_UPLOAD_BYTES_PER_IP")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|max| *max > 0);
+
+        let max_concurrent_uploads = env::var("MAX_CONCURRENT_UPLOADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|max| *max > 0);
+        let max_concurrent_downloads = env::var("MAX_CONCURRENT_DOWNLOADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|max| *max > 0);
 
         let url_prefix = env::var("URL_PREFIX")
             .ok()
             .map(|prefix| prefix.trim_end_matches('/').to_string())
             .filter(|prefix| !prefix.is_empty());
+        // BASE_URL is URL_PREFIX that also moves the routes under its path.
+        let (url_prefix, base_path) = match non_empty_var("BASE_URL") {
+            Some(_) if url_prefix.is_some() => {
+                return Err(AppError::Config(
+                    "set either URL_PREFIX or BASE_URL, not both".to_string(),
+                ));
+            }
+            Some(base_url) => {
+                let (base_url, base_path) = parse_base_url(&base_url)?;
+                (Some(base_url), base_path)
+            }
+            None => (url_prefix, String::new()),
+        };
+        let captcha = CaptchaConfig::from_env()?;
+        let security_headers =
+            SecurityHeadersConfig::from_env(url_prefix.as_deref(), captcha.as_ref())?;
+
+        let upload_access = access_list("UPLOAD")?;
+        let download_access = access_list("DOWNLOAD")?;
+        let admin_access = access_list("ADMIN")?;
+        let geoip_database = non_empty_var("GEOIP_DATABASE").map(PathBuf::from);
+        if geoip_database.is_none()
+            && [&upload_access, &download_access, &admin_access]
+                .iter()
+                .any(|list| list.has_countries())
+        {
+            return Err(AppError::Config(
+                "the *_COUNTRIES lists need a GEOIP_DATABASE".to_string(),
+            ));
+        }
+
+        let trusted_proxies = TrustedProxies::parse(
+            &non_empty_var("TRUSTED_PROXIES").unwrap_or_else(|| proxy::DEFAULT_TRUSTED.to_string()),
+        )?;
 
         let upload_page_enabled = env::var("UPLOAD_PAGE_ENABLED")
             .ok()
             .map(|v| v.eq_ignore_ascii_case("true"))
             .unwrap_or(true);
 
-        let upload_password =
-            env::var("UPLOAD_PASSWORD").unwrap_or_else(|_| "changeme".to_string());
+        let oidc = OidcConfig::from_env(url_prefix.as_deref())?;
 
         let use_filename_suffix = env::var("USE_FILENAME_SUFFIX")
             .ok()
@@ -68,43 +1276,370 @@ impl AppConfig {
             .map(|v| v.eq_ignore_ascii_case("true"))
             .unwrap_or(false);
 
-        let max_upload_bytes = env::var("MAX_UPLOAD_GB")
+        // MAX_UPLOAD_BYTES wins over the coarser MAX_UPLOAD_GB when both are set.
+        let max_upload_bytes = env::var("MAX_UPLOAD_BYTES")
             .ok()
             .and_then(|v| v.parse::<u64>().ok())
-            .map(|gb| gb.saturating_mul(1024 * 1024 * 1024))
-            .unwrap_or(1024 * 1024 * 1024) as usize;
+            .or_else(|| {
+                env::var("MAX_UPLOAD_GB")
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(|gb| gb.saturating_mul(1024 * 1024 * 1024))
+            })
+            .unwrap_or(1024 * 1024 * 1024)
+            .min(usize::MAX as u64) as usize;
+
+        let max_total_storage_bytes = env::var("MAX_TOTAL_STORAGE_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|bytes| *bytes > 0);
+
+        let storage_full_policy = match env::var("STORAGE_FULL_POLICY") {
+            Ok(value) if !value.is_empty() => StorageFullPolicy::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown STORAGE_FULL_POLICY '{}'", value))
+            })?,
+            _ => StorageFullPolicy::Reject,
+        };
+
+        let storage_fsync = match env::var("STORAGE_FSYNC") {
+            Ok(value) if !value.is_empty() => Durability::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown STORAGE_FSYNC '{}'", value))
+            })?,
+            _ => Durability::Off,
+        };
+
+        let storage_layout = match env::var("STORAGE_LAYOUT") {
+            Ok(value) if !value.is_empty() => StorageLayout::parse(&value).ok_or_else(|| {
+                AppError::Config(format!("unknown STORAGE_LAYOUT '{}'", value))
+            })?,
+            _ => StorageLayout::Flat,
+        };
+
+        let storage_compression = match env::var("STORAGE_COMPRESSION") {
+            Ok(value) if !value.is_empty() && !value.eq_ignore_ascii_case("off") => {
+                Some(Codec::parse(&value).ok_or_else(|| {
+                    AppError::Config(format!("unknown STORAGE_COMPRESSION '{}'", value))
+                })?)
+            }
+            _ => None,
+        };
+        let storage_compression_level = env::var("STORAGE_COMPRESSION_LEVEL")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .map(|level| level.min(9))
+            .unwrap_or(6);
+
+        let slug_pattern = slug::compile(
+            "SLUG_PATTERN",
+            &non_empty_var("SLUG_PATTERN").unwrap_or_else(|| slug::DEFAULT_PATTERN.to_string()),
+        )?;
+        let slug_deny_pattern = non_empty_var("SLUG_DENY_PATTERN")
+            .map(|pattern| slug::compile("SLUG_DENY_PATTERN", &pattern))
+            .transpose()?;
+
+        let id_strategy = IdStrategy::parse(
+            &non_empty_var("ID_STRATEGY").unwrap_or_else(|| "uuid".to_string()),
+            env::var("ID_LENGTH").ok().and_then(|v| v.parse::<usize>().ok()),
+            non_empty_var("ID_ALPHABET").as_deref(),
+        )?;
+
+        // Every download counts against the link, so shared caches must not keep
+        // copies unless the operator says otherwise.
+        let download_cache_control = non_empty_var("DOWNLOAD_CACHE_CONTROL")
+            .unwrap_or_else(|| "private, max-age=0".to_string());
+        if axum::http::HeaderValue::from_str(&download_cache_control).is_err() {
+            return Err(AppError::Config(format!(
+                "invalid DOWNLOAD_CACHE_CONTROL '{}'",
+                download_cache_control
+            )));
+        }
 
         Ok(Self {
-            address: address.parse().unwrap_or_else(|err| {
-                warn!(%err, "invalid ADDRESS value, falling back to default");
-                SocketAddr::from(([0, 0, 0, 0], 8080))
-            }),
-            storage_dir: PathBuf::from(storage_dir),
-            ttl,
+            listeners,
+            tls,
+            http2: env::var("HTTP2_ENABLED")
+                .ok()
+                .map(|v| !v.eq_ignore_ascii_case("false"))
+                .unwrap_or(true),
+            timeouts: RequestTimeouts::from_env(),
+            shutdown_timeout: env::var("SHUTDOWN_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| Duration::from_secs(30)),
+            acme,
+            storage_kind,
+            storage_mirror,
+            storage_dir,
+            s3: S3Config::from_env(),
+            azure: AzureConfig::from_env(),
+            gcs: GcsConfig::from_env(),
+            smtp: SmtpConfig::from_env(url_prefix.as_deref())?,
+            metadata_kind,
+            metadata_dir,
+            sqlite_path,
+            redis_url: non_empty_var("REDIS_URL"),
+            redis_prefix: env::var("REDIS_PREFIX").unwrap_or_else(|_| "newtemp:".to_string()),
             cleanup_interval,
-            max_downloads,
+            scrub_interval,
+            scrub_action,
             url_prefix,
+            base_path,
+            trusted_proxies,
+            upload_access,
+            download_access,
+            admin_access,
+            geoip_database,
+            reports_per_hour,
+            daily_uploads_per_ip,
+            daily_upload_bytes_per_ip,
+            max_concurrent_uploads,
+            max_concurrent_downloads,
+            cors: CorsConfig::from_env()?,
+            security_headers,
             upload_page_enabled,
-            upload_password,
+            oidc,
+            captcha,
             use_filename_suffix,
+            deduplicate_uploads: env::var("DEDUPLICATE_UPLOADS")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
             upload_debug_logs,
             max_upload_bytes,
+            max_total_storage_bytes,
+            storage_full_policy,
+            storage_fsync,
+            orphan_sweep: env::var("ORPHAN_SWEEP")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            storage_layout,
+            storage_compression,
+            storage_compression_level,
+            admin_token: non_empty_var("ADMIN_TOKEN"),
+            admin_listener: AdminListenerConfig::from_env()?,
+            // Left empty when not configured; startup then takes the key kept with
+            // the metadata (see `MetadataStore::signing_key`).
+            upload_signing_key: non_empty_var("UPLOAD_SIGNING_KEY").unwrap_or_default(),
+            // Fetching arbitrary URLs lets uploaders reach the server's network, so it is opt-in.
+            remote_url_uploads: env::var("REMOTE_URL_UPLOADS")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            remote_fetch_timeout: env::var("REMOTE_FETCH_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| Duration::from_secs(60)),
+            remote_fetch_allow_private: env::var("REMOTE_FETCH_ALLOW_PRIVATE")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            session_dir,
+            blocklist_file,
+            webhooks: Hook::from_env()?,
+            audit_log: non_empty_var("AUDIT_LOG_FILE").map(PathBuf::from),
+            access_log: AccessLogConfig::from_env()?,
+            log_format: LogFormat::from_env()?,
+            otlp: OtlpConfig::from_env()?,
+            upload_session_ttl,
+            download_history_retention,
+            slug_pattern,
+            slug_deny_pattern,
+            id_strategy,
+            clamd_address: non_empty_var("CLAMD_ADDRESS"),
+            clamd_timeout: env::var("CLAMD_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| Duration::from_secs(120)),
+            download_cache_control,
+            blob_cache_bytes: env::var("BLOB_CACHE_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0),
+            blob_cache_max_file_bytes: env::var("BLOB_CACHE_MAX_FILE_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(1024 * 1024),
         })
     }
 
     pub fn build_download_url(&self, id: &str) -> String {
+        self.build_url(&tenants::entry_path("d", id))
+    }
+
+    pub fn build_preview_url(&self, id: &str) -> String {
+        self.build_url(&tenants::entry_path("p", id))
+    }
+
+    /// Whether any of `ADDRESS` speaks HTTPS.
+    pub fn serves_https(&self) -> bool {
+        self.listeners.iter().any(|listener| listener.tls)
+    }
+
+    pub fn access_list(&self, group: RouteGroup) -> &AccessList {
+        match group {
+            RouteGroup::Upload => &self.upload_access,
+            RouteGroup::Download => &self.download_access,
+            RouteGroup::Admin => &self.admin_access,
+        }
+    }
+
+    /// `path` as a browser on this server asks for it, under `BASE_URL`'s path.
+    pub fn site_path(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    pub fn build_url(&self, path: &str) -> String {
         if let Some(prefix) = &self.url_prefix {
-            format!("{}/d/{}", prefix, id)
+            format!("{}{}", prefix, path)
         } else {
-            format!("/d/{}", id)
+            path.to_string()
         }
     }
 }
 
-pub fn load_env_file() {
-    if let Err(err) = dotenv() {
-        if !matches!(err, dotenvy::Error::Io(ref io_err) if io_err.kind() == ErrorKind::NotFound) {
-            warn!(%err, "failed to load .env file");
+/// The settings a running server reads again from the config file on SIGHUP;
+/// see `reload`. Everything in `AppConfig` takes a restart to change.
+pub struct Settings {
+    pub ttl: Duration,
+    pub max_ttl: Duration,
+    pub max_downloads: u32,
+    /// Bytes per second one download is sent at, at most; see `throttle`.
+    pub download_rate_limit: Option<u64>,
+    pub upload_password: String,
+    pub upload_password_hash: Option<String>,
+    pub file_types: FileTypeRules,
+    pub serve_active_content: bool,
+}
+
+impl Settings {
+    pub fn from_env() -> Result<Self, AppError> {
+        let ttl = var("DEFAULT_TTL_MINS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|minutes| minutes.saturating_mul(60))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(60 * 60));
+
+        // Uploads may ask for any lifetime up to this cap; it never undercuts the default.
+        let max_ttl = var("MAX_TTL_MINS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|minutes| minutes.saturating_mul(60))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(7 * 24 * 60 * 60))
+            .max(ttl);
+
+        // Given in megabits per second, as uplinks are.
+        let download_rate_limit = var("DOWNLOAD_RATE_LIMIT_MBPS")
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|mbps| mbps.is_finite() && *mbps > 0.0)
+            .map(|mbps| (mbps * 1_000_000.0 / 8.0).max(1.0) as u64);
+
+        // A hash takes precedence; the plaintext password is only kept for existing setups.
+        let upload_password_hash = non_empty_var("UPLOAD_PASSWORD_HASH");
+        if let Some(hash) = &upload_password_hash {
+            secret::validate_hash(hash).map_err(|err| {
+                AppError::Config(format!("invalid UPLOAD_PASSWORD_HASH: {}", err))
+            })?;
         }
+
+        Ok(Self {
+            ttl,
+            max_ttl,
+            max_downloads: var("MAX_DOWNLOADS")
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(3),
+            download_rate_limit,
+            upload_password: var("UPLOAD_PASSWORD").unwrap_or_else(|_| "changeme".to_string()),
+            upload_password_hash,
+            file_types: FileTypeRules::from_env(),
+            // Markup and scripts served as-is would run on this server's origin.
+            serve_active_content: var("SERVE_ACTIVE_CONTENT")
+                .ok()
+                .map(|v| v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        })
     }
 }
+
+/// Names set in the environment the server was started with. The config file
+/// does not override them, at startup or when it is read again.
+static INHERITED: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// The config file as last read again, once it has been.
+static RELOADED: RwLock<Option<HashMap<String, String>>> = RwLock::new(None);
+
+/// Loads the `.env` file, when there is one.
+pub fn load_env_file() -> Result<(), dotenvy::Error> {
+    INHERITED.get_or_init(|| {
+        env::vars_os()
+            .filter_map(|(key, _)| key.into_string().ok())
+            .collect()
+    });
+    match dotenv() {
+        Err(dotenvy::Error::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(()),
+        loaded => loaded.map(|_| ()),
+    }
+}
+
+/// Reads the config file again and the `Settings` from it. When they are
+/// invalid, the file is taken to be as it was before.
+pub fn reload_settings() -> Result<Settings, AppError> {
+    let vars = match dotenv_iter() {
+        Ok(iter) => iter.collect::<Result<HashMap<_, _>, _>>(),
+        Err(dotenvy::Error::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+    .map_err(|err| AppError::Config(format!("failed to read .env file: {}", err)))?;
+
+    let previous = write_reloaded().replace(vars);
+    Settings::from_env().inspect_err(|_| *write_reloaded() = previous)
+}
+
+fn write_reloaded() -> std::sync::RwLockWriteGuard<'static, Option<HashMap<String, String>>> {
+    RELOADED.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// `env::var`, except that once the config file was read again, what came from
+/// it is looked up in the file as it is now.
+pub fn var(key: &str) -> Result<String, env::VarError> {
+    let reloaded = RELOADED.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(vars) = reloaded.as_ref()
+        && !INHERITED.get().is_some_and(|inherited| inherited.contains(key))
+    {
+        return vars.get(key).cloned().ok_or(env::VarError::NotPresent);
+    }
+    env::var(key)
+}
+
+/// Splits `https://files.example.com/share/` into the link prefix without the
+/// trailing slash and the path the routes are mounted at, here `/share`.
+fn parse_base_url(value: &str) -> Result<(String, String), AppError> {
+    let invalid =
+        |reason: &str| AppError::Config(format!("invalid BASE_URL '{}': {}", value, reason));
+    let url = value.trim().trim_end_matches('/');
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| invalid("must start with http:// or https://"))?;
+    let path = rest.find('/').map_or("", |start| &rest[start..]);
+    if rest.len() == path.len() {
+        return Err(invalid("has no host"));
+    }
+    if path.contains(['?', '#', ' ', '\\']) || path.contains("//") {
+        return Err(invalid("the path must be plain segments such as /share"));
+    }
+    Ok((url.to_string(), path.to_string()))
+}
+
+fn non_empty_var(key: &str) -> Option<String> {
+    var(key).ok().filter(|value| !value.is_empty())
+}
@@ -0,0 +1,66 @@
+//! CORS for the public routes, from `CORS_ALLOWED_ORIGINS` and friends. Requests
+//! carry their credentials in headers such as `Authorization` or
+//! `X-Upload-Password`, never in cookies, so credentialed requests are not
+//! allowed; a signed-in session only works on this server's own pages.
+
+use axum::{
+    Router,
+    extract::Request,
+    http::{HeaderName, Method, header},
+};
+use tower::{ServiceExt, service_fn};
+use tower_http::cors::{AllowHeaders, AllowOrigin, Any, CorsLayer};
+
+use crate::config::CorsConfig;
+
+/// Response headers the upload APIs hand results back in, which scripts on
+/// other origins could not read otherwise.
+const EXPOSED: [HeaderName; 13] = [
+    header::LOCATION,
+    header::CONTENT_DISPOSITION,
+    HeaderName::from_static("tus-resumable"),
+    HeaderName::from_static("tus-version"),
+    HeaderName::from_static("tus-extension"),
+    HeaderName::from_static("tus-max-size"),
+    HeaderName::from_static("upload-offset"),
+    HeaderName::from_static("upload-length"),
+    HeaderName::from_static("x-download-url"),
+    HeaderName::from_static("x-delete-token"),
+    HeaderName::from_static("x-owner-token"),
+    HeaderName::from_static("x-token"),
+    HeaderName::from_static("x-expires"),
+];
+
+/// Wraps `app` so that preflights get their answer and other requests the CORS
+/// headers. tower-http takes any `OPTIONS` for a preflight, which would hide the
+/// tus discovery reply, so one without `Access-Control-Request-Method` skips it.
+pub fn apply(app: Router, config: &CorsConfig) -> Router {
+    let with_cors = app.clone().layer(layer(config));
+    Router::new().fallback_service(service_fn(move |request: Request| {
+        let through_cors = request.method() != Method::OPTIONS
+            || request
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        let service = if through_cors { with_cors.clone() } else { app.clone() };
+        service.oneshot(request)
+    }))
+}
+
+fn layer(config: &CorsConfig) -> CorsLayer {
+    let origins = match &config.origins {
+        Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+        None => AllowOrigin::from(Any),
+    };
+    // A literal `*` would not cover `Authorization`, so the preflight's own list
+    // is echoed instead.
+    let headers = match &config.headers {
+        Some(headers) => AllowHeaders::list(headers.iter().cloned()),
+        None => AllowHeaders::mirror_request(),
+    };
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(config.methods.clone())
+        .allow_headers(headers)
+        .expose_headers(EXPOSED)
+        .max_age(config.max_age)
+}
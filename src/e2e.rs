@@ -0,0 +1,147 @@
+//! End-to-end encrypted uploads. The upload page encrypts the file with AES-GCM in
+//! the browser and puts the key in the link's fragment, which browsers never send,
+//! so the server only ever holds ciphertext. `/d/:id` answers browsers with a page
+//! that fetches the ciphertext (`?raw=1`, which counts as the download) and
+//! decrypts it locally.
+//!
+//! The blob is a 12-byte IV followed by the AES-256-GCM ciphertext of a big-endian
+//! `u32` header length, a JSON header `{"name": ..., "type": ...}` and the file, so
+//! the real file name never reaches the server either.
+
+use axum::{
+    http::{HeaderValue, header},
+    response::{Html, IntoResponse, Response},
+};
+
+use crate::{FileEntry, metadata::unix_seconds, preview::format_size};
+
+pub const ENCRYPTED_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The decrypting download page. Serving it does not use up a download.
+pub fn decryptor(entry: &FileEntry) -> Response {
+    let size = if entry.size > 0 {
+        format_size(entry.size)
+    } else {
+        "unknown".to_string()
+    };
+
+    let body = format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <meta name="robots" content="noindex" />
+  <title>Encrypted file · newtemp.sh</title>
+  <style>
+    :root {{
+      color-scheme: light dark;
+      --bg: linear-gradient(145deg, #0d1117 0%, #0f172a 40%, #0b1221 100%);
+      --card: rgba(255, 255, 255, 0.08);
+      --border: rgba(255, 255, 255, 0.18);
+      --text: #f6f8fa;
+      --muted: #c9d1d9;
+    }}
+    * {{ box-sizing: border-box; }}
+    body {{
+      margin: 0;
+      min-height: 100vh;
+      font-family: 'Inter', 'Segoe UI', system-ui, -apple-system, sans-serif;
+      background: var(--bg);
+      color: var(--text);
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      padding: 2.5rem 1.5rem;
+    }}
+    .shell {{
+      width: min(560px, 100%);
+      background: var(--card);
+      border: 1px solid var(--border);
+      border-radius: 20px;
+      box-shadow: 0 24px 70px rgba(0, 0, 0, 0.45);
+      padding: 2rem 2.25rem;
+    }}
+    h1 {{ margin: 0 0 1rem; font-size: 1.4rem; }}
+    dl {{ display: grid; grid-template-columns: max-content 1fr; gap: 0.5rem 1rem; margin: 0 0 1.5rem; }}
+    dt {{ color: var(--muted); }}
+    dd {{ margin: 0; }}
+    button {{
+      font-size: 1rem;
+      font-weight: 750;
+      padding: 0.85rem 1.1rem;
+      border-radius: 12px;
+      border: none;
+      cursor: pointer;
+      background: linear-gradient(120deg, #4096ff, #6ec1ff);
+      color: #0b1221;
+    }}
+    button:disabled {{ opacity: 0.5; cursor: default; }}
+    p {{ color: var(--muted); font-size: 0.9rem; word-break: break-word; }}
+  </style>
+</head>
+<body>
+  <div class="shell">
+    <h1>Encrypted file</h1>
+    <dl>
+      <dt>Size</dt><dd>{size}</dd>
+      <dt>Expires</dt><dd id="expires" data-at="{expires_at}">{expires_at}</dd>
+      <dt>Downloads left</dt><dd>{remaining}</dd>
+    </dl>
+    <button type="button" id="decrypt">Decrypt &amp; download</button>
+    <p id="status">The file is decrypted in this browser; the server never sees its contents or the key.</p>
+  </div>
+  <script>
+    const expires = document.getElementById('expires');
+    expires.textContent = new Date(Number(expires.dataset.at) * 1000).toLocaleString();
+
+    const button = document.getElementById('decrypt');
+    const status = document.getElementById('status');
+    const fragment = location.hash.slice(1);
+    if (!fragment) {{
+      button.disabled = true;
+      status.textContent = 'This link is missing its decryption key (the part after #).';
+    }} else if (!window.crypto || !crypto.subtle) {{
+      button.disabled = true;
+      status.textContent = 'Decrypting needs a secure (https) connection.';
+    }}
+
+    button.addEventListener('click', async () => {{
+      button.disabled = true;
+      status.textContent = 'Downloading...';
+      try {{
+        const response = await fetch(location.pathname + '?raw=1', {{ cache: 'no-store' }});
+        if (!response.ok) {{
+          throw new Error(await response.text());
+        }}
+        const sealed = new Uint8Array(await response.arrayBuffer());
+        status.textContent = 'Decrypting...';
+        const raw = Uint8Array.from(atob(fragment.replace(/-/g, '+').replace(/_/g, '/')), (c) => c.charCodeAt(0));
+        const key = await crypto.subtle.importKey('raw', raw, 'AES-GCM', false, ['decrypt']);
+        const plain = new Uint8Array(await crypto.subtle.decrypt({{ name: 'AES-GCM', iv: sealed.slice(0, 12) }}, key, sealed.slice(12)));
+        const length = new DataView(plain.buffer).getUint32(0);
+        const meta = JSON.parse(new TextDecoder().decode(plain.subarray(4, 4 + length)));
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(new Blob([plain.subarray(4 + length)], {{ type: meta.type || 'application/octet-stream' }}));
+        link.download = meta.name || 'download';
+        link.click();
+        status.textContent = 'Decrypted ' + link.download + '.';
+      }} catch (err) {{
+        // A wrong key fails authentication with an OperationError and no message.
+        status.textContent = 'Could not decrypt this file: ' + (err.message || 'wrong key or damaged data');
+      }}
+    }});
+  </script>
+</body>
+</html>
+"#,
+        expires_at = unix_seconds(entry.expires_at),
+        remaining = entry.remaining_hits,
+    );
+
+    let mut response = Html(body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    response
+}
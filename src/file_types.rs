@@ -0,0 +1,117 @@
+//! Which files an instance accepts, by declared content type and file name
+//! extension. Lists are comma separated; content types may end in `/*`, and an
+//! extension may span several dots (`tar.gz`). Blocked entries win over allowed
+//! ones, and an empty allow list allows everything.
+
+use crate::{AppError, config};
+
+#[derive(Clone, Default)]
+pub struct FileTypeRules {
+    allowed_types: Vec<String>,
+    blocked_types: Vec<String>,
+    allowed_extensions: Vec<String>,
+    blocked_extensions: Vec<String>,
+}
+
+impl FileTypeRules {
+    pub fn from_env() -> Self {
+        Self {
+            allowed_types: list_var("ALLOWED_CONTENT_TYPES", normalize_type),
+            blocked_types: list_var("BLOCKED_CONTENT_TYPES", normalize_type),
+            allowed_extensions: list_var("ALLOWED_EXTENSIONS", normalize_extension),
+            blocked_extensions: list_var("BLOCKED_EXTENSIONS", normalize_extension),
+        }
+    }
+
+    /// Refuses a file with a 415 naming the rule it broke. Files without a
+    /// declared type count as `application/octet-stream`.
+    pub fn check(&self, filename: &str, content_type: Option<&str>) -> Result<(), AppError> {
+        let content_type = normalize_type(content_type.unwrap_or("application/octet-stream"));
+        let filename = filename.to_ascii_lowercase();
+
+        if self.blocked_types.iter().any(|rule| type_matches(rule, &content_type))
+            || (!self.allowed_types.is_empty()
+                && !self.allowed_types.iter().any(|rule| type_matches(rule, &content_type)))
+        {
+            return Err(AppError::UnsupportedFileType(format!(
+                "files of type {} are not accepted here",
+                content_type
+            )));
+        }
+
+        if let Some(extension) = self
+            .blocked_extensions
+            .iter()
+            .find(|extension| has_extension(&filename, extension))
+        {
+            return Err(AppError::UnsupportedFileType(format!(
+                "files ending in .{} are not accepted here",
+                extension
+            )));
+        }
+        if !self.allowed_extensions.is_empty()
+            && !self
+                .allowed_extensions
+                .iter()
+                .any(|extension| has_extension(&filename, extension))
+        {
+            let allowed: Vec<String> = self
+                .allowed_extensions
+                .iter()
+                .map(|extension| format!(".{}", extension))
+                .collect();
+            return Err(AppError::UnsupportedFileType(format!(
+                "only files ending in {} are accepted here",
+                allowed.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `content_type`, or `application/octet-stream` without one, matches any
+/// of `rules`.
+pub fn matches_any(rules: &[String], content_type: Option<&str>) -> bool {
+    let content_type = normalize_type(content_type.unwrap_or("application/octet-stream"));
+    rules
+        .iter()
+        .any(|rule| type_matches(&normalize_type(rule), &content_type))
+}
+
+fn list_var(key: &str, normalize: fn(&str) -> String) -> Vec<String> {
+    config::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(normalize)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Lowercases a content type and drops parameters such as `charset`.
+fn normalize_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn normalize_extension(value: &str) -> String {
+    value.trim().trim_start_matches('.').to_ascii_lowercase()
+}
+
+fn type_matches(rule: &str, content_type: &str) -> bool {
+    match rule.strip_suffix("/*") {
+        Some(prefix) => content_type
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/')),
+        None => rule == content_type,
+    }
+}
+
+fn has_extension(filename: &str, extension: &str) -> bool {
+    filename
+        .strip_suffix(extension)
+        .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+}
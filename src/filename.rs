@@ -0,0 +1,85 @@
+//! Uploaded file names end up in `Content-Disposition`, zip bundles and the
+//! pages that list them, so they are cleaned once when a file is stored.
+
+use axum::http::HeaderValue;
+
+/// Longest name kept, in bytes; about what common file systems allow.
+const MAX_FILENAME_BYTES: usize = 255;
+
+/// Reduces a client-supplied name to its last path component without control
+/// characters or quotes. Returns `None` when nothing usable is left.
+pub fn sanitize(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '"' => '\'',
+            '<' | '>' | ':' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim().trim_end_matches('.');
+    if cleaned.is_empty() {
+        return None;
+    }
+    Some(truncate(cleaned))
+}
+
+/// Shortens an overlong name from the end of its stem so the extension stays.
+fn truncate(name: &str) -> String {
+    if name.len() <= MAX_FILENAME_BYTES {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < 16 => (stem, &name[stem.len()..]),
+        _ => (name, ""),
+    };
+    let mut end = MAX_FILENAME_BYTES - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+/// `Content-Disposition` with an ASCII `filename` for old clients and the exact
+/// UTF-8 name in `filename*` (RFC 6266 / RFC 5987).
+pub fn content_disposition(disposition: &str, name: &str) -> HeaderValue {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '%' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    let value = format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, encoded
+    );
+    // Both parts are plain visible ASCII, so this cannot fail.
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("attachment"))
+}
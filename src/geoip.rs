@@ -0,0 +1,97 @@
+//! Countries of client addresses, from a MaxMind DB file (`GEOIP_DATABASE`)
+//! such as GeoLite2-Country or GeoIP2-City. Only the ISO code of the country is
+//! read, falling back to the registered country for addresses without one. The
+//! file is read into memory and read again when it changes on disk, so
+//! `geoipupdate` can replace it while the server runs.
+
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use maxminddb::{Reader, geoip2};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::AppError;
+
+pub struct GeoIp {
+    path: PathBuf,
+    inner: RwLock<Loaded>,
+}
+
+struct Loaded {
+    reader: Arc<Reader<Vec<u8>>>,
+    modified: Option<SystemTime>,
+}
+
+impl GeoIp {
+    /// Reads the database, so that a missing or damaged one stops startup.
+    pub fn open(path: PathBuf) -> Result<Self, AppError> {
+        let reader = Reader::open_readfile(&path)
+            .map_err(|err| AppError::Config(format!("GEOIP_DATABASE {:?}: {}", path, err)))?;
+        info!(
+            database = %reader.metadata().database_type,
+            "loaded GeoIP database {:?}", path
+        );
+        let modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        Ok(Self {
+            path,
+            inner: RwLock::new(Loaded {
+                reader: Arc::new(reader),
+                modified,
+            }),
+        })
+    }
+
+    /// The ISO 3166-1 code of the country `address` is in, such as `DE`.
+    pub fn country(&self, address: IpAddr) -> Option<String> {
+        let reader = self.read().reader.clone();
+        let record: geoip2::Country = reader
+            .lookup(address.to_canonical())
+            .ok()?
+            .decode()
+            .ok()??;
+        let code = record.country.iso_code.or(record.registered_country.iso_code)?;
+        Some(code.to_ascii_uppercase())
+    }
+
+    /// Picks up a replaced database; a broken replacement keeps the old one.
+    pub async fn refresh(&self) {
+        let modified = fs::metadata(&self.path)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified.is_none() || modified == self.read().modified {
+            return;
+        }
+        let parsed = match fs::read(&self.path).await {
+            Ok(raw) => Reader::from_source(raw).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match parsed {
+            Ok(reader) => {
+                info!(
+                    database = %reader.metadata().database_type,
+                    "reloaded GeoIP database {:?}", self.path
+                );
+                let mut inner = self.inner.write().unwrap_or_else(|err| err.into_inner());
+                *inner = Loaded {
+                    reader: Arc::new(reader),
+                    modified,
+                };
+            }
+            Err(err) => {
+                warn!(%err, "failed to reload GeoIP database {:?}", self.path);
+                // Not tried again until the file changes once more.
+                self.inner.write().unwrap_or_else(|err| err.into_inner()).modified = modified;
+            }
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Loaded> {
+        self.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,148 @@
+//! Who downloaded an entry and when, for answering "did they get it?". Each
+//! download is recorded with the downloader's network, country and client
+//! software, as coarsely as live download events name them, and a hash of their
+//! address that tells repeat downloads apart. The history outlives the entry by
+//! `DOWNLOAD_HISTORY_DAYS`, so it can still be read after the last download.
+//!
+//! `GET /d/:id/downloads` returns it to whoever holds the owner token, and
+//! `GET /admin/api/entries/:id/downloads` to the admin.
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::SystemTime};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::HeaderMap,
+};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    AppError, AppState, FileEntry, TokenParams,
+    live::{Visitor, client_address},
+    metadata::{Download, DownloadHistory},
+    provided_token, secret,
+};
+
+/// Hex digits of the address hash that are kept.
+const CLIENT_TAG_LEN: usize = 16;
+
+/// Adds a download to the entry's history, unless history is turned off. A
+/// failure is logged rather than failing the download.
+pub async fn record(
+    state: &AppState,
+    id: &str,
+    entry: &FileEntry,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) {
+    let Some(retention) = state.config.download_history_retention else {
+        return;
+    };
+    let download = Download {
+        at: SystemTime::now(),
+        client: client_tag(state, id, headers, peer),
+        visitor: Visitor::of(state, headers, peer),
+    };
+    if let Err(err) = state
+        .metadata
+        .record_download(
+            id,
+            entry.owner_token.as_deref(),
+            &download,
+            entry.expires_at + retention,
+        )
+        .await
+    {
+        warn!(?err, "failed to record a download of {}", id);
+    }
+}
+
+/// Keyed with the server's signing key and the entry id, so the tag cannot be
+/// reversed by trying addresses or used to follow a client across entries.
+fn client_tag(state: &AppState, id: &str, headers: &HeaderMap, peer: SocketAddr) -> String {
+    let address = client_address(headers, peer).to_canonical().to_string();
+    let mac = secret::hmac_sha256(&state.config.upload_signing_key, &[id, &address]);
+    let mut tag = hex::encode(mac);
+    tag.truncate(CLIENT_TAG_LEN);
+    tag
+}
+
+#[derive(Serialize)]
+pub struct HistoryView {
+    id: String,
+    /// Absent once the entry has expired, been deleted or used up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_downloads: Option<u32>,
+    /// Downloads per country, when a `GEOIP_DATABASE` named any.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    countries: BTreeMap<String, usize>,
+    /// Oldest first.
+    downloads: Vec<Download>,
+}
+
+impl HistoryView {
+    fn new(id: String, entry: Option<&FileEntry>, history: Option<DownloadHistory>) -> Self {
+        let downloads = history.map(|history| history.downloads).unwrap_or_default();
+        let mut countries = BTreeMap::new();
+        for country in downloads.iter().filter_map(|download| download.visitor.country.clone()) {
+            *countries.entry(country).or_default() += 1;
+        }
+        Self {
+            id,
+            remaining_downloads: entry.map(|entry| entry.remaining_hits),
+            countries,
+            downloads,
+        }
+    }
+}
+
+/// The entry, if it is still there, and its history, or `NotFound` for neither.
+async fn lookup(
+    state: &AppState,
+    id: &str,
+) -> Result<(Option<FileEntry>, Option<DownloadHistory>), AppError> {
+    let entry = state
+        .metadata
+        .get(id)
+        .await?
+        .filter(|entry| SystemTime::now() < entry.expires_at);
+    // A history left by an earlier entry under the same id is not this one's.
+    let history = state.metadata.download_history(id).await?.filter(|history| {
+        entry
+            .as_ref()
+            .is_none_or(|entry| entry.owner_token == history.owner_token)
+    });
+    if entry.is_none() && history.is_none() {
+        return Err(AppError::NotFound);
+    }
+    Ok((entry, history))
+}
+
+pub async fn owner_view(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TokenParams>,
+    headers: HeaderMap,
+) -> Result<Json<HistoryView>, AppError> {
+    let provided =
+        provided_token(&headers, &["x-owner-token"], params).ok_or(AppError::InvalidToken)?;
+    let (entry, history) = lookup(&state, &id).await?;
+    let owner = match (&entry, &history) {
+        (Some(entry), _) => entry.owner_token.as_deref(),
+        (None, Some(history)) => history.owner_token.as_deref(),
+        (None, None) => None,
+    };
+    if !owner.is_some_and(|owner| secret::matches(owner, &provided)) {
+        return Err(AppError::InvalidToken);
+    }
+    Ok(Json(HistoryView::new(id, entry.as_ref(), history)))
+}
+
+pub async fn admin_view(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HistoryView>, AppError> {
+    let (entry, history) = lookup(&state, &id).await?;
+    Ok(Json(HistoryView::new(id, entry.as_ref(), history)))
+}
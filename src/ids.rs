@@ -0,0 +1,137 @@
+//! Download id generators selected with `ID_STRATEGY`.
+
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::AppError;
+
+/// The nanoid project's URL-safe alphabet.
+pub const DEFAULT_ALPHABET: &str =
+    "_-0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DEFAULT_NANOID_LENGTH: usize = 10;
+const DEFAULT_WORD_COUNT: usize = 3;
+/// Word ids have no rate limit to hide behind, so they are padded with digits
+/// until there are at least this many bits of them to guess.
+const MIN_WORD_ID_BITS: f64 = 40.0;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdStrategy {
+    Uuid,
+    /// `length` characters drawn from `alphabet`.
+    NanoId {
+        length: usize,
+        alphabet: Vec<char>,
+    },
+    /// `count` words from [`WORDS`] joined with dashes, easy to read out loud,
+    /// then `digits` random digits if the words alone are too few to guess at.
+    Words {
+        count: usize,
+        digits: usize,
+    },
+}
+
+impl IdStrategy {
+    /// Builds a strategy from its name plus the optional `ID_LENGTH` and
+    /// `ID_ALPHABET` settings. Shorter ids are easier to guess, so lengths are
+    /// only accepted within sane bounds.
+    pub fn parse(
+        name: &str,
+        length: Option<usize>,
+        alphabet: Option<&str>,
+    ) -> Result<Self, AppError> {
+        match name.to_ascii_lowercase().as_str() {
+            "uuid" => Ok(Self::Uuid),
+            "nanoid" => {
+                let length = length.unwrap_or(DEFAULT_NANOID_LENGTH);
+                if !(6..=64).contains(&length) {
+                    return Err(AppError::Config(
+                        "ID_LENGTH must be between 6 and 64 for nanoid ids".to_string(),
+                    ));
+                }
+                let mut alphabet: Vec<char> =
+                    alphabet.unwrap_or(DEFAULT_ALPHABET).chars().collect();
+                alphabet.sort_unstable();
+                alphabet.dedup();
+                // Only characters that need no escaping in a URL path segment.
+                if alphabet.len() < 2
+                    || !alphabet
+                        .iter()
+                        .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.' | '~'))
+                {
+                    return Err(AppError::Config(
+                        "ID_ALPHABET needs at least two of A-Z, a-z, 0-9, '-', '_', '.', '~'"
+                            .to_string(),
+                    ));
+                }
+                Ok(Self::NanoId { length, alphabet })
+            }
+            "words" => {
+                let count = length.unwrap_or(DEFAULT_WORD_COUNT);
+                if !(2..=12).contains(&count) {
+                    return Err(AppError::Config(
+                        "ID_LENGTH must be between 2 and 12 for word ids".to_string(),
+                    ));
+                }
+                let bits = count as f64 * (WORDS.len() as f64).log2();
+                let digits = ((MIN_WORD_ID_BITS - bits) / 10f64.log2()).ceil().max(0.0) as usize;
+                Ok(Self::Words { count, digits })
+            }
+            _ => Err(AppError::Config(format!("unknown ID_STRATEGY '{}'", name))),
+        }
+    }
+
+    pub fn generate(&self) -> String {
+        let mut rng = rand::rng();
+        match self {
+            Self::Uuid => Uuid::new_v4().to_string(),
+            Self::NanoId { length, alphabet } => (0..*length)
+                .map(|_| alphabet[rng.random_range(0..alphabet.len())])
+                .collect(),
+            Self::Words { count, digits } => {
+                let mut id = (0..*count)
+                    .map(|_| WORDS[rng.random_range(0..WORDS.len())])
+                    .collect::<Vec<_>>()
+                    .join("-");
+                if *digits > 0 {
+                    let suffix = rng.random_range(0..10u64.pow(*digits as u32));
+                    id.push_str(&format!("-{:0width$}", suffix, width = *digits));
+                }
+                id
+            }
+        }
+    }
+}
+
+/// Short, distinct, easy-to-spell words.
+const WORDS: &[&str] = &[
+    "acorn", "actor", "agent", "alarm", "album", "alley", "amber", "angel", "ankle", "apple",
+    "apron", "arena", "arrow", "aspen", "atlas", "attic", "autumn", "award", "bacon", "badge",
+    "bagel", "baker", "bamboo", "banjo", "barn", "basil", "basin", "beach", "beard", "berry",
+    "bison", "blade", "blaze", "bloom", "board", "bonus", "boots", "brave", "bread", "brick",
+    "bride", "brook", "broom", "brush", "bucket", "buddy", "bugle", "cabin", "cable", "cactus",
+    "camel", "candle", "canoe", "canyon", "cargo", "carpet", "carrot", "castle", "cedar", "chalk",
+    "charm", "cherry", "chess", "chief", "cider", "cinema", "circus", "civic", "clerk", "cliff",
+    "clock", "cloud", "clover", "coach", "cobra", "cocoa", "comet", "coral", "cotton", "couch",
+    "cousin", "cradle", "crane", "crayon", "cricket", "crown", "crystal", "cycle", "daisy",
+    "dance", "delta", "denim", "desert", "diary", "dingo", "dinner", "disco", "dolphin", "donkey",
+    "dragon", "drum", "eagle", "earth", "echo", "elbow", "elder", "ember", "emerald", "engine",
+    "fabric", "falcon", "fancy", "feast", "fern", "ferry", "fiddle", "field", "flame", "flute",
+    "forest", "fossil", "fox", "frost", "fudge", "galaxy", "garden", "garlic", "gecko", "giant",
+    "ginger", "glacier", "globe", "goose", "grape", "gravel", "guitar", "hammer", "harbor",
+    "harvest", "hawk", "hazel", "helmet", "heron", "hippo", "honey", "hotel", "igloo", "island",
+    "ivory", "jacket", "jaguar", "jelly", "jewel", "jungle", "kayak", "kettle", "kitten", "koala",
+    "ladder", "lagoon", "lake", "lemon", "lily", "lion", "lizard", "llama", "lobster", "lotus",
+    "lunar", "magnet", "mango", "maple", "marble", "meadow", "melon", "metal", "meteor", "mint",
+    "mirror", "monkey", "moose", "mosaic", "motor", "mountain", "muffin", "nectar", "needle",
+    "noodle", "oasis", "ocean", "olive", "onion", "orange", "orbit", "orchid", "otter", "owl",
+    "paddle", "palace", "panda", "paper", "parrot", "pasta", "peach", "peanut", "pearl", "pebble",
+    "pepper", "piano", "pickle", "pilot", "pine", "pirate", "planet", "plum", "polar", "pony",
+    "poppy", "portal", "potato", "prairie", "prism", "puzzle", "quartz", "quiet", "rabbit",
+    "radar", "radio", "rain", "raven", "ribbon", "river", "robin", "rocket", "rose", "ruby",
+    "saddle", "salmon", "salt", "sand", "satin", "scarf", "seal", "shadow", "shell", "silver",
+    "sketch", "sleet", "snow", "socket", "sofa", "spider", "spoon", "spruce", "squid", "star",
+    "stone", "storm", "sugar", "summer", "sunset", "swan", "tango", "teapot", "tiger", "timber",
+    "toast", "tomato", "topaz", "torch", "tower", "tulip", "tunnel", "turtle", "umbrella",
+    "valley", "velvet", "violet", "walnut", "walrus", "wave", "whale", "willow", "window",
+    "winter",
+];
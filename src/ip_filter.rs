@@ -0,0 +1,182 @@
+//! Who may reach which routes, by client address. Each route group has allow
+//! and deny lists from the environment, of addresses (`UPLOAD_ALLOWED_IPS`,
+//! `DOWNLOAD_DENIED_IPS` and so on) and of countries (`*_COUNTRIES`), and the
+//! admin API can add bans that lift by themselves after a while. Bans are kept
+//! in memory, so a restart lifts them too; anything meant to last belongs in
+//! the deny lists.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, AppState, live::client_address, proxy::Network};
+
+/// The routes a rule applies to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteGroup {
+    /// Everything that creates uploads, along with the upload page, sign-in and
+    /// the signed-in user's pages.
+    Upload,
+    /// The links handed out: downloads, previews, managing an upload and
+    /// reporting one.
+    Download,
+    /// `/admin/api`, on whichever listener it is served.
+    Admin,
+}
+
+impl RouteGroup {
+    /// The group of a path as the routes see it, after tenant paths are mapped.
+    pub fn of(path: &str) -> Self {
+        if path == "/admin/api" || path.starts_with("/admin/api/") {
+            Self::Admin
+        } else if ["/d/", "/p/", "/report/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            Self::Download
+        } else {
+            Self::Upload
+        }
+    }
+}
+
+/// The allow and deny lists of one group, by address and by country. Anything
+/// denied is refused even when it is also allowed; with allow lists, a client
+/// has to match one of them, so an address without a known country (a private
+/// one, say) only gets in through the address list.
+#[derive(Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<Network>,
+    pub deny: Vec<Network>,
+    /// ISO 3166-1 codes such as `DE`, looked up in `GEOIP_DATABASE`.
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+}
+
+impl AccessList {
+    pub fn permits(&self, address: IpAddr, country: Option<&str>) -> bool {
+        let listed = |countries: &[String]| {
+            country.is_some_and(|country| countries.iter().any(|listed| listed == country))
+        };
+        let within =
+            |networks: &[Network]| networks.iter().any(|network| network.contains(address));
+        if within(&self.deny) || listed(&self.deny_countries) {
+            return false;
+        }
+        (self.allow.is_empty() && self.allow_countries.is_empty())
+            || within(&self.allow)
+            || listed(&self.allow_countries)
+    }
+
+    pub fn has_countries(&self) -> bool {
+        !self.allow_countries.is_empty() || !self.deny_countries.is_empty()
+    }
+}
+
+#[derive(Clone)]
+pub struct Ban {
+    pub id: u64,
+    pub network: Network,
+    pub groups: Vec<RouteGroup>,
+    pub expires_at: SystemTime,
+    pub note: Option<String>,
+}
+
+/// Bans added through the admin API, until they expire.
+#[derive(Default)]
+pub struct Bans {
+    bans: Mutex<Vec<Ban>>,
+    next_id: AtomicU64,
+}
+
+impl Bans {
+    /// Bans `network` from `groups` until `expires_at`; without groups the ban
+    /// covers uploads and downloads, but never locks anyone out of the admin API.
+    pub fn add(
+        &self,
+        network: Network,
+        groups: Vec<RouteGroup>,
+        expires_at: SystemTime,
+        note: Option<String>,
+    ) -> Ban {
+        let groups = if groups.is_empty() {
+            vec![RouteGroup::Upload, RouteGroup::Download]
+        } else {
+            groups
+        };
+        let ban = Ban {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            network,
+            groups,
+            expires_at,
+            note,
+        };
+        self.lock().push(ban.clone());
+        ban
+    }
+
+    /// Returns whether there was such a ban.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut bans = self.lock();
+        let before = bans.len();
+        bans.retain(|ban| ban.id != id);
+        bans.len() != before
+    }
+
+    /// The bans still in force.
+    pub fn list(&self) -> Vec<Ban> {
+        self.current().clone()
+    }
+
+    pub fn bans(&self, group: RouteGroup, address: IpAddr) -> bool {
+        self.current()
+            .iter()
+            .any(|ban| ban.groups.contains(&group) && ban.network.contains(address))
+    }
+
+    /// The list with expired bans dropped.
+    fn current(&self) -> std::sync::MutexGuard<'_, Vec<Ban>> {
+        let now = SystemTime::now();
+        let mut bans = self.lock();
+        bans.retain(|ban| ban.expires_at > now);
+        bans
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Ban>> {
+        self.bans.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Middleware refusing clients that the group's lists or a ban keep out, with a
+/// 403 before the route sees the request.
+pub async fn enforce(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let group = RouteGroup::of(request.uri().path());
+    let client = client_address(request.headers(), peer);
+    let list = state.config.access_list(group);
+    let country = match &state.geoip {
+        Some(geoip) if list.has_countries() => geoip.country(client),
+        _ => None,
+    };
+    if !list.permits(client, country.as_deref()) || state.bans.bans(group, client) {
+        return Err(AppError::AddressDenied);
+    }
+    Ok(next.run(request).await)
+}
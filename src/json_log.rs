@@ -0,0 +1,126 @@
+//! Application logs as JSON, with `LOG_FORMAT=json`: one object per line with
+//! `timestamp` (RFC 3339, UTC), `level`, `target`, `message` and the event's
+//! other fields, after the fields of every span it happened in. Requests run in
+//! a span of their own (see `request_id`), so each line logged while one is
+//! handled carries its `request_id`, `client_ip` and, if there is one, the
+//! `entry_id` it is about, which log collectors can index without parsing text.
+//! The `otel.*` fields meant for trace export (see `telemetry`) are left out.
+
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Map, Value};
+use tracing::{
+    Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+use crate::{access_log::application_output, acme::civil_from_days};
+
+/// Writes every event that passed the log filter as a line of JSON.
+pub struct JsonLayer;
+
+/// The fields recorded on a span so far, kept in its extensions.
+struct SpanFields(Map<String, Value>);
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, context: Context<'_, S>) {
+        let Some(span) = context.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attributes.record(&mut Fields(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, context: Context<'_, S>) {
+        let Some(span) = context.span(id) else {
+            return;
+        };
+        if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(&mut Fields(fields));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, context: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp(SystemTime::now()).into());
+        line.insert(
+            "level".into(),
+            metadata.level().as_str().to_ascii_lowercase().into(),
+        );
+        line.insert("target".into(), metadata.target().into());
+        if let Some(scope) = context.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    let fields = fields.iter().filter(|(name, _)| !name.starts_with("otel."));
+                    line.extend(fields.map(|(name, value)| (name.clone(), value.clone())));
+                }
+            }
+        }
+        event.record(&mut Fields(&mut line));
+
+        let Ok(mut encoded) = serde_json::to_vec(&line) else {
+            return;
+        };
+        encoded.push(b'\n');
+        let _ = application_output().write_all(&encoded);
+    }
+}
+
+/// Records fields as JSON values: numbers and booleans as they are, anything
+/// else as text.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
+
+/// Such as `2026-10-14T13:55:36.123Z`.
+fn timestamp(at: SystemTime) -> String {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
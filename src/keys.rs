@@ -0,0 +1,84 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{AppError, metadata};
+
+const KEY_PREFIX: &str = "ntk_";
+
+/// An upload credential handed out through the admin API. Only the SHA-256 of
+/// the key is kept; the plaintext is shown once when it is created.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    #[serde(with = "metadata::unix_time")]
+    pub created_at: SystemTime,
+    #[serde(default, with = "metadata::unix_time::option")]
+    pub revoked_at: Option<SystemTime>,
+    /// Per-file cap; the global `MAX_UPLOAD_BYTES` still applies on top.
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    /// Total number of uploads this key may ever make.
+    #[serde(default)]
+    pub max_uploads: Option<u64>,
+    #[serde(default)]
+    pub uploads: u64,
+    #[serde(default)]
+    pub uploaded_bytes: u64,
+    #[serde(default, with = "metadata::unix_time::option")]
+    pub last_used_at: Option<SystemTime>,
+    /// Bytes downloads of this key's uploads may send per month.
+    #[serde(default)]
+    pub monthly_transfer_bytes: Option<u64>,
+}
+
+impl ApiKey {
+    /// Builds a new key record and returns it along with the plaintext key.
+    pub fn generate(
+        name: String,
+        max_upload_bytes: Option<u64>,
+        max_uploads: Option<u64>,
+    ) -> (Self, String) {
+        let key = format!("{}{}", KEY_PREFIX, Uuid::new_v4().simple());
+        let record = Self {
+            id: Uuid::new_v4().simple().to_string(),
+            name,
+            key_hash: hash_key(&key),
+            created_at: SystemTime::now(),
+            revoked_at: None,
+            max_upload_bytes,
+            max_uploads,
+            uploads: 0,
+            uploaded_bytes: 0,
+            last_used_at: None,
+            monthly_transfer_bytes: None,
+        };
+        (record, key)
+    }
+
+    /// Rejects revoked keys and keys that used up their upload count.
+    pub fn check_usable(&self) -> Result<(), AppError> {
+        if self.revoked_at.is_some() {
+            return Err(AppError::InvalidToken);
+        }
+        if self.max_uploads.is_some_and(|max| self.uploads >= max) {
+            return Err(AppError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// The largest file this key may upload given the server-wide `limit`.
+    pub fn upload_limit(&self, limit: usize) -> usize {
+        self.max_upload_bytes
+            .map(|max| max.min(limit as u64) as usize)
+            .unwrap_or(limit)
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
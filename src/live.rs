@@ -0,0 +1,253 @@
+//! `GET /d/:id/events` streams what happens to one entry as server-sent events,
+//! for whoever holds its owner token (in `X-Owner-Token` or `?token=`, since
+//! `EventSource` cannot set headers). A `status` event with the remaining
+//! downloads and expiry comes first, then a `download` event for every download,
+//! naming the downloader's network and client only coarsely. The stream ends with
+//! the last download, or with an `expire` or `delete` event.
+//!
+//! Events are only seen by the instance that produced them, so with several
+//! instances behind a load balancer the stream misses downloads served elsewhere.
+
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, header},
+    response::sse::{self, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    AppError, AppState, FileEntry, TokenParams, live_entry, metadata::unix_seconds,
+    provided_token, webhook::Event,
+};
+
+/// Events buffered for slow streams; one that falls further behind skips ahead.
+const CAPACITY: usize = 256;
+const MAX_AGENT_LEN: usize = 64;
+
+/// Who downloaded an entry, no closer than their network, country and client
+/// software.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Visitor {
+    /// Such as `203.0.113.0/24`, or the `/48` of an IPv6 address.
+    pub network: Option<String>,
+    /// Such as `Firefox` or `curl`.
+    pub agent: Option<String>,
+    /// Such as `DE`, when there is a `GEOIP_DATABASE` that knows the address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+impl Visitor {
+    /// Takes the client address from `X-Forwarded-For` when a trusted proxy set it.
+    pub fn of(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> Self {
+        let agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(agent_family);
+        let address = client_address(headers, peer);
+        Self {
+            network: Some(network_of(address)),
+            agent,
+            country: state.geoip.as_ref().and_then(|geoip| geoip.country(address)),
+        }
+    }
+}
+
+/// The address in `X-Forwarded-For`, which `proxy::forwarded` only leaves when a
+/// trusted proxy set it, else the peer's.
+pub fn client_address(headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|first| first.trim().parse::<IpAddr>().ok())
+        .unwrap_or(peer.ip())
+}
+
+fn network_of(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => network_of(IpAddr::V4(v4)),
+            None => {
+                let [a, b, c, ..] = v6.segments();
+                format!("{:x}:{:x}:{:x}::/48", a, b, c)
+            }
+        },
+    }
+}
+
+/// The browser or tool behind a user agent string, without its version.
+fn agent_family(agent: &str) -> Option<String> {
+    const BROWSERS: &[(&str, &str)] = &[
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ];
+    if let Some((_, name)) = BROWSERS.iter().find(|(marker, _)| agent.contains(marker)) {
+        return Some(name.to_string());
+    }
+    let product = agent.split(['/', ' ']).next()?.trim();
+    (!product.is_empty()).then(|| product.chars().take(MAX_AGENT_LEN).collect())
+}
+
+struct Notice {
+    id: String,
+    event: Event,
+    remaining_downloads: u32,
+    expires_at: SystemTime,
+    visitor: Option<Visitor>,
+}
+
+#[derive(Serialize)]
+struct Update<'a> {
+    remaining_downloads: u32,
+    expires_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visitor: Option<&'a Visitor>,
+}
+
+pub struct LiveEvents {
+    sender: broadcast::Sender<Arc<Notice>>,
+}
+
+impl LiveEvents {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    /// Passes the event on to any stream watching the entry; uploads have none yet.
+    pub fn publish(&self, event: Event, id: &str, entry: &FileEntry, visitor: Option<Visitor>) {
+        if event == Event::Upload || self.sender.receiver_count() == 0 {
+            return;
+        }
+        let notice = Notice {
+            id: id.to_string(),
+            event,
+            remaining_downloads: entry.remaining_hits,
+            expires_at: entry.expires_at,
+            visitor,
+        };
+        let _ = self.sender.send(Arc::new(notice));
+    }
+}
+
+pub async fn stream(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TokenParams>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, AppError> {
+    let provided =
+        provided_token(&headers, &["x-owner-token"], params).ok_or(AppError::InvalidToken)?;
+    // Subscribed first, so nothing between the lookup and the stream is missed.
+    let receiver = state.live.sender.subscribe();
+    let entry = live_entry(&state, &id).await?;
+    if !entry.is_owner(&provided) {
+        return Err(AppError::InvalidToken);
+    }
+
+    let status = update_event(
+        "status",
+        &Update {
+            remaining_downloads: entry.remaining_hits,
+            expires_at: unix_seconds(entry.expires_at),
+            visitor: None,
+        },
+    );
+    let feed = Feed {
+        state,
+        id,
+        receiver,
+        expires_at: entry.expires_at,
+        done: false,
+    };
+    let rest = stream::unfold(feed, |mut feed| async move {
+        let event = feed.next().await?;
+        Some((Ok(event), feed))
+    });
+    let events = stream::iter([Ok(status)]).chain(rest);
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+struct Feed {
+    state: Arc<AppState>,
+    id: String,
+    receiver: broadcast::Receiver<Arc<Notice>>,
+    expires_at: SystemTime,
+    done: bool,
+}
+
+impl Feed {
+    async fn next(&mut self) -> Option<sse::Event> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let left = self
+                .expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Ok(notice) if notice.id == self.id => {
+                        self.expires_at = notice.expires_at;
+                        self.done =
+                            notice.event != Event::Download || notice.remaining_downloads == 0;
+                        return Some(notice_event(&notice));
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                },
+                // Expiry is only announced by the cleanup task, which may run on
+                // another instance or well after the fact.
+                _ = tokio::time::sleep(left) => match live_entry(&self.state, &self.id).await {
+                    Ok(extended) => self.expires_at = extended.expires_at,
+                    Err(_) => {
+                        self.done = true;
+                        return Some(bare_event(Event::Expire));
+                    }
+                },
+            }
+        }
+    }
+}
+
+fn notice_event(notice: &Notice) -> sse::Event {
+    match notice.event {
+        Event::Download => update_event(
+            Event::Download.as_str(),
+            &Update {
+                remaining_downloads: notice.remaining_downloads,
+                expires_at: unix_seconds(notice.expires_at),
+                visitor: notice.visitor.as_ref(),
+            },
+        ),
+        event => bare_event(event),
+    }
+}
+
+fn bare_event(event: Event) -> sse::Event {
+    sse::Event::default().event(event.as_str()).data("{}")
+}
+
+fn update_event(name: &str, update: &Update) -> sse::Event {
+    let data = serde_json::to_string(update).unwrap_or_else(|_| "{}".to_string());
+    sse::Event::default().event(name).data(data)
+}
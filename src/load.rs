@@ -0,0 +1,166 @@
+//! Load shedding. `MAX_CONCURRENT_UPLOADS` and `MAX_CONCURRENT_DOWNLOADS` cap
+//! how many of each may be in flight at once; past that, new ones are turned
+//! away with 503 and `Retry-After` rather than piling up until the server runs
+//! out of memory or file handles. An upload is in flight until it is answered,
+//! a download until its body has been sent or the client went away. The admin
+//! stats show how many there are at the moment.
+
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use serde::Serialize;
+
+use crate::{AppError, AppState, ip_filter::RouteGroup};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Traffic {
+    Upload,
+    Download,
+}
+
+impl Traffic {
+    /// Uploads are whatever sends data to the upload routes; downloads are
+    /// `GET /d/:id`, while the small routes under it are left alone.
+    fn of(method: &Method, path: &str) -> Option<Self> {
+        match RouteGroup::of(path) {
+            RouteGroup::Upload if [Method::POST, Method::PUT, Method::PATCH].contains(method) => {
+                Some(Self::Upload)
+            }
+            RouteGroup::Download
+                if method == Method::GET
+                    && path.strip_prefix("/d/").is_some_and(|id| !id.contains('/')) =>
+            {
+                Some(Self::Download)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What is in flight of one kind of request, and how much may be.
+pub struct Gauge {
+    max: Option<usize>,
+    active: AtomicUsize,
+    /// Requests turned away since the start.
+    shed: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct Concurrency {
+    in_flight: usize,
+    max: Option<usize>,
+    shed: u64,
+}
+
+impl Gauge {
+    fn new(max: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            active: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a slot, which is given back when dropped, unless all are taken.
+    fn enter(self: &Arc<Self>) -> Option<Slot> {
+        let active = self.active.fetch_add(1, Ordering::AcqRel);
+        if self.max.is_some_and(|max| active >= max) {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Slot(self.clone()))
+    }
+
+    pub fn view(&self) -> Concurrency {
+        Concurrency {
+            in_flight: self.active.load(Ordering::Acquire),
+            max: self.max,
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Slot(Arc<Gauge>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub struct Load {
+    pub uploads: Arc<Gauge>,
+    pub downloads: Arc<Gauge>,
+}
+
+impl Load {
+    pub fn new(max_uploads: Option<usize>, max_downloads: Option<usize>) -> Self {
+        Self {
+            uploads: Gauge::new(max_uploads),
+            downloads: Gauge::new(max_downloads),
+        }
+    }
+}
+
+/// A response body that keeps its download's slot until it is dropped.
+struct Held {
+    body: Body,
+    _slot: Slot,
+}
+
+impl http_body::Body for Held {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Middleware turning uploads and downloads away once too many are in flight.
+pub async fn shed(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(traffic) = Traffic::of(request.method(), request.uri().path()) else {
+        return Ok(next.run(request).await);
+    };
+    let gauge = match traffic {
+        Traffic::Upload => &state.load.uploads,
+        Traffic::Download => &state.load.downloads,
+    };
+    let slot = gauge.enter().ok_or(AppError::Overloaded)?;
+    let response = next.run(request).await;
+    Ok(match traffic {
+        Traffic::Upload => response,
+        Traffic::Download => response.map(|body| Body::new(Held { body, _slot: slot })),
+    })
+}
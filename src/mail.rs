@@ -0,0 +1,183 @@
+//! Emails the download link of an upload to the address given in `notify_email`,
+//! through the SMTP relay under `SMTP_HOST`. Sending happens after the upload
+//! has been answered. Uploaders choose the recipient, so every address gets a
+//! few emails an hour at most and the server as a whole a bounded number more;
+//! uploads asking for more are refused with a 429.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use lettre::{
+    Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::header::ContentType,
+    transport::smtp::{authentication::Credentials, extension::ClientId},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    AppError,
+    config::{SmtpConfig, SmtpSecurity},
+    preview::format_size,
+};
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+/// For a whole conversation with the relay.
+const TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_ADDRESS_LEN: usize = 254;
+
+/// What an email says about the upload it announces.
+pub struct Announcement {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub url: String,
+    pub expires_at: SystemTime,
+    pub remaining_downloads: u32,
+}
+
+pub struct Mailer {
+    config: SmtpConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sent: Mutex<Sent>,
+}
+
+/// When recent emails went out, overall and per recipient.
+#[derive(Default)]
+struct Sent {
+    all: VecDeque<Instant>,
+    by_recipient: HashMap<String, VecDeque<Instant>>,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, AppError> {
+        let invalid =
+            |err| AppError::Config(format!("invalid SMTP_HOST '{}': {}", config.host, err));
+        let builder = match config.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+            }
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+        };
+        let mut builder = builder
+            .map_err(invalid)?
+            .port(config.port)
+            .hello_name(ClientId::Domain(config.from.email.domain().to_string()));
+        if let Some(username) = &config.username {
+            let password = config.password.clone().unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            config,
+            sent: Mutex::new(Sent::default()),
+        })
+    }
+
+    /// Checks the address and counts an email to it against the hourly limits,
+    /// returning the address to send to.
+    pub fn admit(&self, address: &str) -> Result<String, AppError> {
+        let address = check_address(address)?;
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Sent { all, by_recipient } = &mut *sent;
+        let expire = |times: &mut VecDeque<Instant>| {
+            while times.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+                times.pop_front();
+            }
+        };
+        expire(all);
+        by_recipient.retain(|_, times| {
+            expire(times);
+            !times.is_empty()
+        });
+
+        let recipient = by_recipient.entry(address.to_ascii_lowercase()).or_default();
+        if all.len() >= self.config.max_per_hour || recipient.len() >= self.config.max_per_recipient
+        {
+            return Err(AppError::EmailLimited);
+        }
+        all.push_back(now);
+        recipient.push_back(now);
+        Ok(address)
+    }
+
+    /// Sends the email in the background; failures are only logged.
+    pub fn spawn_send(self: &Arc<Self>, to: String, announcement: Announcement) {
+        let mailer = self.clone();
+        tokio::spawn(async move {
+            let (subject, body) = mailer.compose(&announcement);
+            match tokio::time::timeout(TIMEOUT, mailer.send(&to, &subject, &body)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!(%err, "failed to email the link of {}", announcement.id),
+                Err(_) => warn!("timed out emailing the link of {}", announcement.id),
+            }
+        });
+    }
+
+    /// Sends a fixed email of the server's own, such as the outcome of an abuse
+    /// report, in the background.
+    pub fn spawn_notice(self: &Arc<Self>, to: String, subject: &str, body: &str) {
+        let mailer = self.clone();
+        let (subject, body) = (subject.to_string(), body.to_string());
+        tokio::spawn(async move {
+            match tokio::time::timeout(TIMEOUT, mailer.send(&to, &subject, &body)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!(%err, "failed to send a notice email"),
+                Err(_) => warn!("timed out sending a notice email"),
+            }
+        });
+    }
+
+    /// The subject and body of the email announcing an upload.
+    fn compose(&self, announcement: &Announcement) -> (String, String) {
+        let fill = |template: &str| {
+            template
+                .replace("{filename}", &announcement.filename)
+                .replace("{size}", &format_size(announcement.size))
+                .replace("{id}", &announcement.id)
+                .replace("{url}", &announcement.url)
+                .replace(
+                    "{expires_at}",
+                    &httpdate::fmt_http_date(announcement.expires_at),
+                )
+                .replace(
+                    "{remaining_downloads}",
+                    &announcement.remaining_downloads.to_string(),
+                )
+        };
+        (fill(&self.config.subject), fill(&self.config.template))
+    }
+
+    async fn send(&self, to: &str, subject: &str, body: &str) -> io::Result<()> {
+        let from = &self.config.from;
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to.parse().map_err(io::Error::other)?)
+            .subject(subject)
+            .message_id(Some(format!("<{}@{}>", Uuid::new_v4().simple(), from.email.domain())))
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())
+            .map_err(io::Error::other)?;
+        self.transport.send(message).await.map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Trims `address` and checks that it can be sent to.
+pub fn check_address(address: &str) -> Result<String, AppError> {
+    let address = address.trim();
+    match address.parse::<Address>() {
+        Ok(parsed) if address.len() <= MAX_ADDRESS_LEN => Ok(parsed.to_string()),
+        _ => Err(AppError::BadRequest(
+            "notify_email is not a valid email address".to_string(),
+        )),
+    }
+}
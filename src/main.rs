@@ -1,83 +1,746 @@
 use std::{
-    collections::HashMap,
-    path::{Path as FsPath, PathBuf},
-    sync::Arc,
-    time::Instant,
+    collections::HashSet,
+    net::SocketAddr,
+    ops::Range,
+    path::Path as FsPath,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
+mod access_log;
+mod address_quota;
+mod acme;
+mod admin;
+mod audit;
+mod backup;
+mod bandwidth;
+mod blocklist;
+mod bundle;
+mod cache;
+mod captcha;
+mod chunked;
+mod compression;
 mod config;
+mod cors;
+mod e2e;
+mod file_types;
+mod filename;
+mod geoip;
+mod history;
+mod ids;
+mod ip_filter;
+mod json_log;
+mod keys;
+mod live;
+mod load;
+mod mail;
+mod metadata;
+mod migrate;
+mod null_pointer;
+mod oidc;
+mod paste;
+mod presign;
+mod preview;
+mod progress;
+mod proxy;
+mod qr;
+mod range;
+mod reload;
+mod request_id;
+mod remote;
+mod report;
+mod scan;
+mod scrub;
+mod secret;
+mod security_headers;
+mod sentry;
+mod sharex;
+mod shorten;
+mod slug;
+mod spool;
+mod storage;
+mod systemd;
+mod telemetry;
+mod tenants;
+mod throttle;
+mod timeouts;
+mod tls;
+mod tus;
+mod upload_tokens;
+mod usage;
+mod users;
+mod webhook;
 
 use axum::{
     Json, Router,
+    body::Body,
     extract::{
-        multipart::MultipartError, DefaultBodyLimit, Multipart, Path, State,
+        multipart::MultipartError, ConnectInfo, DefaultBodyLimit, Multipart, Path, Query,
+        State,
     },
     http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware,
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
 };
 use bytes::Bytes;
-use serde::Serialize;
+use futures_util::{StreamExt, TryStreamExt, future};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::{fs, sync::Mutex, time::interval};
-use tracing::{error, info, warn};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, warn};
 use uuid::Uuid;
 
-use crate::config::{AppConfig, load_env_file};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    access_log::AccessLog,
+    address_quota::AddressQuota,
+    acme::Acme,
+    admin::{AdminFeed, AdminListener, LogLayer},
+    audit::AuditLog,
+    blocklist::Blocklist,
+    cache::BlobCache,
+    captcha::Captcha,
+    chunked::ChunkStore,
+    compression::Codec,
+    config::{
+        AppConfig, Bind, LogFormat, OtlpConfig, SentryConfig, Settings, StorageFullPolicy,
+        load_env_file,
+    },
+    geoip::GeoIp,
+    ip_filter::Bans,
+    json_log::JsonLayer,
+    keys::ApiKey,
+    live::{LiveEvents, Visitor},
+    load::Load,
+    mail::{Announcement, Mailer},
+    metadata::{EntryPatch, Hit, MetadataStore, unix_seconds},
+    oidc::{Account, Oidc},
+    report::{Report, ReportLimiter},
+    scan::{ScanStatus, Scanner},
+    scrub::Scrubber,
+    spool::{Spool, Spooler},
+    storage::{ByteStream, StorageBackend},
+    tenants::Tenant,
+    tls::{Listener, PemFile, Serving},
+    tus::TusStore,
+    upload_tokens::UploadToken,
+    usage::{Reservation, StorageUsage},
+    users::User,
+    webhook::{Event, Webhooks},
+};
+
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+/// Short generated ids can collide; a taken one is replaced this many times.
+const ID_ATTEMPTS: usize = 8;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
+    let loaded = load_env_file();
+    let log_format = LogFormat::from_env()?;
+    let (otlp, exporter) = match OtlpConfig::from_env()? {
+        Some(otlp) => {
+            let (layer, exporter) = telemetry::start(&otlp)?;
+            (Some(layer), Some(exporter))
+        }
+        None => (None, None),
+    };
+    let (sentry, reporter) = match SentryConfig::from_env()? {
+        Some(sentry) => {
+            let (layer, reporter) = sentry::start(&sentry);
+            (Some(layer), Some(reporter))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
         )
+        .with((log_format == LogFormat::Text).then(|| {
+            tracing_subscriber::fmt::layer().with_writer(access_log::application_output)
+        }))
+        .with((log_format == LogFormat::Json).then_some(JsonLayer))
+        .with(otlp)
+        .with(sentry)
+        .with(LogLayer)
         .init();
+    if let Err(err) = loaded {
+        warn!(%err, "failed to load .env file");
+    }
+
+    let mut config = AppConfig::from_env()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {}
+        [command, path] if command == "export" => return Ok(backup::export(&config, path).await?),
+        [command, path] if command == "import" => return Ok(backup::import(&config, path).await?),
+        [command, target] if command == "migrate-storage" => {
+            return Ok(migrate::migrate_storage(&config, target).await?);
+        }
+        _ => {
+            return Err("usage: newtemp_sh [export <archive> | import <archive> | \
+                        migrate-storage <local|s3|azure|gcs>]"
+                .into());
+        }
+    }
+    access_log::claim_stdout(config.access_log.as_ref());
 
-    load_env_file();
+    let storage = storage::from_config(&config).await?;
 
-    let config = AppConfig::from_env()?;
-    fs::create_dir_all(&config.storage_dir).await?;
+    let metadata = metadata::from_config(&config).await?;
+    // Address hashes and signed links must outlive a restart, so a key generated
+    // for lack of UPLOAD_SIGNING_KEY is kept with the metadata.
+    if config.upload_signing_key.is_empty() {
+        let generated = Uuid::new_v4().simple().to_string();
+        config.upload_signing_key = metadata.signing_key(&generated).await?;
+    }
+    let mut restored = 0;
+    let mut stored_bytes = 0;
+    // Deduplicated entries share a blob, which only takes up space once.
+    let mut counted = HashSet::new();
+    for (id, entry) in metadata.list().await? {
+        if storage.exists(&entry.key).await? {
+            restored += 1;
+            if counted.insert(entry.key.clone()) {
+                stored_bytes += entry.stored_bytes();
+            }
+        } else {
+            warn!("dropping entry {} whose stored file is missing", id);
+            metadata.remove(&id).await?;
+        }
+    }
+    info!(count = restored, bytes = stored_bytes, "restored file entries");
 
-    let state = Arc::new(AppState::new(config.clone()));
+    // Blobs no entry refers to are left over from crashes or manual tinkering,
+    // unless another instance or program shares the storage, hence opt-in.
+    if config.orphan_sweep {
+        let mut orphans = 0;
+        for key in storage.list_keys().await? {
+            if is_blob_key(&key) && !counted.contains(&key) {
+                warn!("removing stored file {} that no entry refers to", key);
+                storage.delete(&key).await?;
+                orphans += 1;
+            }
+        }
+        if orphans > 0 {
+            info!(count = orphans, "removed orphaned files");
+        }
+    }
+
+    let usage = StorageUsage::new(config.max_total_storage_bytes, stored_bytes);
+    let tus = TusStore::open(config.session_dir.join("tus")).await?;
+    let chunks = ChunkStore::open(config.session_dir.join("chunks")).await?;
+    let blocklist = Blocklist::open(config.blocklist_file.clone()).await?;
+    let state = Arc::new(AppState::new(
+        config.clone(),
+        storage,
+        metadata,
+        usage,
+        tus,
+        chunks,
+        blocklist,
+    )?);
     spawn_cleanup(state.clone());
+    scrub::spawn_periodic(state.clone());
+    reload::spawn(state.clone());
+    // Scans cut short by a restart start over.
+    if state.scanner.is_some() {
+        for (id, entry) in state.metadata.list().await? {
+            if entry.scan == ScanStatus::Pending {
+                scan::spawn(state.clone(), id);
+            }
+        }
+    }
 
-    let upload_limit = DefaultBodyLimit::max(config.max_upload_bytes);
+    // The file itself is capped while it is read; the request as a whole gets some
+    // slack for multipart boundaries and the other form fields.
+    let upload_limit =
+        DefaultBodyLimit::max(config.max_upload_bytes.saturating_add(MULTIPART_OVERHEAD));
 
-    let app = Router::new()
+    // Loaded before anything is served, so bad certificates stop startup.
+    let acceptor = match &config.tls {
+        Some(files) => Some(tls::acceptor(
+            PemFile {
+                var: "TLS_CERT_PATH",
+                path: &files.cert,
+            },
+            PemFile {
+                var: "TLS_KEY_PATH",
+                path: &files.key,
+            },
+            None,
+            config.http2,
+        )?),
+        None => match &config.acme {
+            Some(acme) => {
+                let https_port = config
+                    .listeners
+                    .iter()
+                    .find_map(|listen| match listen.bind {
+                        Bind::Tcp(address) if listen.tls => Some(address.port()),
+                        _ => None,
+                    })
+                    .unwrap_or(443);
+                let acme = Acme::open(acme.clone(), https_port).await?;
+                acme.start().await?;
+                Some(tls::resolving_acceptor(acme.resolver(), config.http2))
+            }
+            None => None,
+        },
+    };
+    let admin_listener = match &config.admin_listener {
+        Some(listener) => Some(AdminListener::bind(listener, config.http2).await?),
+        None => None,
+    };
+
+    let mut routes = Router::new()
         .route("/upload", post(upload))
-        .route("/", get(upload_page))
-        .route("/d/:id", get(download))
-        .layer(upload_limit)
-        .with_state(state);
+        .route("/fetch", post(remote::fetch_upload))
+        .route("/paste", post(paste::create))
+        .route("/shorten", post(shorten::create))
+        .route("/", get(upload_page).post(null_pointer::upload))
+        .route(
+            "/d/:id",
+            get(download)
+                .head(head_entry)
+                .post(null_pointer::manage)
+                .delete(delete_entry)
+                .patch(patch_entry),
+        )
+        .route("/d/:id/extend", post(extend_entry))
+        .route("/d/:id/info", get(entry_info))
+        .route("/d/:id/events", get(live::stream))
+        .route("/d/:id/downloads", get(history::owner_view))
+        .route("/d/:id/qr", get(qr::qr_code))
+        .route("/d/:id/delete", get(delete_link))
+        .route("/user", get(users::profile))
+        .route("/user/uploads/:id", delete(users::delete_upload))
+        .route("/sharex.sxcu", get(sharex::sxcu))
+        .route("/p/:id", get(preview::preview_page))
+        .route("/report/:id", post(report::create))
+        .route("/:filename", put(put_upload))
+        .merge(tus::router())
+        .merge(chunked::router())
+        .merge(oidc::router())
+        .layer(upload_limit);
+    // With a listener of its own the admin API is not reachable on `ADDRESS`.
+    if admin_listener.is_none() {
+        routes = routes.nest("/admin/api", admin::router(state.clone()));
+    }
+    let routes = routes
+        .route_layer(middleware::from_fn(request_id::name_span))
+        .layer(middleware::from_fn_with_state(state.clone(), load::shed))
+        .layer(middleware::from_fn_with_state(state.clone(), ip_filter::enforce))
+        .with_state(state.clone());
+    // Tenant paths are mapped before the routes see them, while the logs record
+    // what the client asked for, `BASE_URL`'s path included.
+    let scoped = Router::new()
+        .fallback_service(routes)
+        .layer(middleware::from_fn_with_state(state.clone(), tenants::scope))
+        .with_state(state.clone());
+    let mounted = if config.base_path.is_empty() {
+        scoped
+    } else {
+        Router::new().nest_service(&config.base_path, scoped)
+    };
+    let mut app = mounted
+        .layer(middleware::from_fn_with_state(state.clone(), timeouts::limit))
+        .layer(middleware::from_fn_with_state(state.clone(), audit::track))
+        .layer(middleware::from_fn_with_state(state.clone(), access_log::record))
+        .layer(middleware::from_fn_with_state(state.clone(), security_headers::apply));
+    // Preflights are answered here, before tenants or credentials are looked at.
+    if let Some(cors) = &config.cors {
+        app = cors::apply(app, cors);
+    }
+    let app = app
+        .layer(middleware::from_fn_with_state(state.clone(), request_id::tag))
+        .layer(middleware::from_fn_with_state(state.clone(), proxy::forwarded));
 
-    let listener = tokio::net::TcpListener::bind(config.address).await?;
-    info!("listening on {}", config.address);
-    axum::serve(listener, app).await?;
+    let mut listeners = Vec::new();
+    for listen in &config.listeners {
+        let listener = Listener::bind(&listen.bind).await?;
+        info!(tls = listen.tls, "listening on {}", listener.address()?);
+        listeners.push((listener, listen.tls));
+    }
+    let serving = Serving {
+        header_timeout: config.timeouts.header,
+        shutdown: CancellationToken::new(),
+        grace: config.shutdown_timeout,
+    };
+    tokio::spawn(shutdown_signal(serving.shutdown.clone()));
+    let serve = future::try_join_all(listeners.into_iter().map(|(listener, tls)| {
+        let acceptor = acceptor.clone().filter(|_| tls);
+        tls::serve(listener, acceptor, app.clone(), serving.clone())
+    }));
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+    match admin_listener {
+        Some(admin_listener) => {
+            let admin_app = Router::new()
+                .nest("/admin/api", admin::router(state.clone()))
+                .route_layer(middleware::from_fn(request_id::name_span))
+                .layer(middleware::from_fn_with_state(state.clone(), ip_filter::enforce))
+                .layer(middleware::from_fn_with_state(state.clone(), timeouts::limit))
+                .layer(middleware::from_fn_with_state(state.clone(), audit::track))
+                .layer(middleware::from_fn_with_state(state.clone(), access_log::record))
+                .layer(middleware::from_fn_with_state(state.clone(), security_headers::apply))
+                .layer(middleware::from_fn_with_state(state.clone(), request_id::tag))
+                .layer(middleware::from_fn_with_state(state.clone(), proxy::forwarded))
+                .with_state(state.clone());
+            tokio::try_join!(serve, admin_listener.serve(admin_app, serving))?;
+        }
+        None => {
+            serve.await?;
+        }
+    }
 
+    if let Err(err) = state.metadata.flush().await {
+        error!(%err, "failed to flush metadata");
+    }
+    if let Some(exporter) = exporter {
+        exporter.flush().await;
+    }
+    // Dropping the client sends whatever events are still queued.
+    drop(reporter);
+    info!("shut down");
     Ok(())
 }
 
-#[derive(Clone)]
+/// Waits for SIGINT or SIGTERM and starts a graceful shutdown. A second signal
+/// ends the process right away, for when waiting is not wanted after all.
+async fn shutdown_signal(shutdown: CancellationToken) {
+    received_signal().await;
+    info!("shutting down, waiting for requests under way");
+    systemd::notify("STOPPING=1");
+    shutdown.cancel();
+    received_signal().await;
+    warn!("second signal, exiting without waiting");
+    std::process::exit(1);
+}
+
+async fn received_signal() {
+    #[cfg(unix)]
+    {
+        let Ok(mut terminate) =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct FileEntry {
-    path: PathBuf,
+    #[serde(alias = "path")]
+    key: String,
     filename: String,
-    expires_at: Instant,
+    #[serde(with = "metadata::unix_time")]
+    expires_at: SystemTime,
     remaining_hits: u32,
     content_type: Option<String>,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    delete_token: Option<String>,
+    #[serde(default)]
+    owner_token: Option<String>,
+    #[serde(with = "metadata::unix_time", default = "metadata::unix_epoch")]
+    created_at: SystemTime,
+    #[serde(default)]
+    kind: EntryKind,
+    /// Hex SHA-256 of the stored bytes; absent for entries stored before it was recorded.
+    #[serde(default)]
+    sha256: Option<String>,
+    #[serde(default)]
+    scan: ScanStatus,
+    /// What the scanner found, or why it could not scan the file.
+    #[serde(default)]
+    threat: Option<String>,
+    /// How the blob is encoded at rest; `size` and `sha256` always describe the
+    /// bytes as uploaded.
+    #[serde(default)]
+    compression: Option<Codec>,
+    /// Length of the compressed blob, when `compression` is set.
+    #[serde(default)]
+    compressed_size: Option<u64>,
+    /// Id of the API key the entry was uploaded with.
+    #[serde(default)]
+    api_key: Option<String>,
+    /// Keyed hash of the uploader's address; see `admin::uploader_hash`.
+    #[serde(default)]
+    uploader: Option<String>,
+    /// The signed-in account it was uploaded from, as the identity provider's `sub`.
+    #[serde(default)]
+    subject: Option<String>,
+    /// Id of the user it was uploaded by; see `users`.
+    #[serde(default)]
+    user: Option<String>,
+    /// Abuse reports awaiting the operator; see `report`.
+    #[serde(default)]
+    reports: Vec<Report>,
+    /// Taken down by the operator, and served as 451 until restored.
+    #[serde(default)]
+    disabled: bool,
+}
+
+/// What an entry holds, which decides how `/d/:id` presents it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+    #[default]
+    File,
+    /// UTF-8 text shown in a highlighted viewer unless the raw bytes are asked for.
+    Paste,
+    /// A short link; the blob holds the URL that `/d/:id` redirects to.
+    Redirect,
+    /// Ciphertext from the upload page's end-to-end mode; see `e2e`.
+    Encrypted,
+}
+
+impl EntryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Paste => "paste",
+            Self::Redirect => "redirect",
+            Self::Encrypted => "encrypted",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "file" => Some(Self::File),
+            "paste" => Some(Self::Paste),
+            "redirect" => Some(Self::Redirect),
+            "encrypted" => Some(Self::Encrypted),
+            _ => None,
+        }
+    }
+}
+
+impl FileEntry {
+    /// What the blob takes up in storage, which is what the quota counts.
+    fn stored_bytes(&self) -> u64 {
+        self.compressed_size.unwrap_or(self.size)
+    }
+
+    fn is_owner(&self, token: &str) -> bool {
+        self.owner_token
+            .as_deref()
+            .is_some_and(|owner| secret::matches(owner, token))
+    }
+
+    /// The owner token can do everything the delete token can.
+    fn may_delete(&self, token: &str) -> bool {
+        self.is_owner(token)
+            || self
+                .delete_token
+                .as_deref()
+                .is_some_and(|delete| secret::matches(delete, token))
+    }
+
+    /// Whether downloads of the entry have to be refused for now.
+    fn withholds(&self) -> bool {
+        self.disabled || self.scan.withholds()
+    }
+
+    fn check_takedown(&self) -> Result<(), AppError> {
+        if self.disabled {
+            return Err(AppError::TakenDown);
+        }
+        Ok(())
+    }
+
+    /// Refuses to serve an entry the malware scanner has not cleared, or one
+    /// that was taken down.
+    fn check_scan(&self) -> Result<(), AppError> {
+        self.check_takedown()?;
+        match self.scan {
+            ScanStatus::Pending => Err(AppError::ScanPending),
+            ScanStatus::Corrupt => Err(AppError::Corrupted),
+            status if status.is_quarantined() => Err(AppError::Quarantined),
+            _ => Ok(()),
+        }
+    }
 }
 
 struct AppState {
-    entries: Mutex<HashMap<String, FileEntry>>,
+    storage: Arc<dyn StorageBackend>,
+    metadata: Box<dyn MetadataStore>,
+    usage: StorageUsage,
+    tus: TusStore,
+    chunks: ChunkStore,
+    /// Where upload bodies are received before being stored.
+    spool: Spooler,
+    blocklist: Blocklist,
+    bans: Bans,
+    reports: ReportLimiter,
+    address_quota: AddressQuota,
+    load: Load,
+    geoip: Option<GeoIp>,
+    scanner: Option<Scanner>,
+    mailer: Option<Arc<Mailer>>,
+    cache: Option<BlobCache>,
+    scrub: Scrubber,
+    webhooks: Webhooks,
+    live: LiveEvents,
+    feed: AdminFeed,
+    audit: AuditLog,
+    access_log: Option<AccessLog>,
+    oidc: Option<Oidc>,
+    captcha: Option<Captcha>,
     config: AppConfig,
+    settings: RwLock<Arc<Settings>>,
+    /// Held while a blob's reference count changes together with the write or delete
+    /// that goes with it, so a shared blob is never removed under a new upload.
+    blob_lock: tokio::sync::Mutex<()>,
 }
 
 impl AppState {
-    fn new(config: AppConfig) -> Self {
-        Self {
-            entries: Mutex::new(HashMap::new()),
+    fn new(
+        config: AppConfig,
+        storage: Arc<dyn StorageBackend>,
+        metadata: Box<dyn MetadataStore>,
+        usage: StorageUsage,
+        tus: TusStore,
+        chunks: ChunkStore,
+        blocklist: Blocklist,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            storage,
+            metadata,
+            usage,
+            tus,
+            chunks,
+            spool: Spooler::open(config.session_dir.join("spool"))?,
+            blocklist,
+            bans: Bans::default(),
+            reports: ReportLimiter::new(config.reports_per_hour),
+            address_quota: AddressQuota::new(
+                config.daily_uploads_per_ip,
+                config.daily_upload_bytes_per_ip,
+            ),
+            load: Load::new(config.max_concurrent_uploads, config.max_concurrent_downloads),
+            geoip: config.geoip_database.clone().map(GeoIp::open).transpose()?,
+            scanner: config
+                .clamd_address
+                .as_deref()
+                .map(|address| Scanner::new(address, config.clamd_timeout)),
+            mailer: config.smtp.clone().map(Mailer::new).transpose()?.map(Arc::new),
+            cache: (config.blob_cache_bytes > 0).then(|| {
+                BlobCache::new(config.blob_cache_bytes, config.blob_cache_max_file_bytes)
+            }),
+            scrub: Scrubber::default(),
+            webhooks: Webhooks::new(&config),
+            live: LiveEvents::new(),
+            feed: AdminFeed::new(),
+            audit: AuditLog::open(config.audit_log.as_deref())?,
+            access_log: config.access_log.as_ref().map(AccessLog::open).transpose()?,
+            oidc: config
+                .oidc
+                .clone()
+                .map(|oidc| Oidc::new(oidc, &config.upload_signing_key, &config.base_path))
+                .transpose()?,
+            captcha: config.captcha.clone().map(Captcha::new).transpose()?,
             config,
+            settings: RwLock::new(Arc::new(Settings::from_env()?)),
+            blob_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The settings as of now; a request keeps the ones it started with.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    fn replace_settings(&self, settings: Settings) {
+        let mut current = self.settings.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *current = Arc::new(settings);
+    }
+
+    /// Tells webhooks, live event streams, the admin feed and the audit log about
+    /// `event`.
+    fn notify(&self, event: Event, id: &str, entry: &FileEntry) {
+        self.webhooks.notify(event, id, entry);
+        self.live.publish(event, id, entry, None);
+        self.feed.entry(event, id, entry);
+        self.audit.entry(event, id, entry);
+    }
+
+    /// Deletes the stored blob of an entry whose record is already gone and returns
+    /// its bytes to the storage quota, unless other entries still share it. Returns
+    /// how many bytes were freed.
+    async fn discard(&self, entry: &FileEntry) -> u64 {
+        if !self.release_blob(&entry.key).await {
+            return 0;
         }
+        let bytes = entry.stored_bytes();
+        self.usage.release(bytes);
+        bytes
+    }
+
+    /// Drops one reference to a blob and deletes it once nothing refers to it,
+    /// returning whether it was deleted.
+    async fn release_blob(&self, key: &str) -> bool {
+        let _guard = self.blob_lock.lock().await;
+        match self.metadata.release_blob(key).await {
+            Ok(0) => {}
+            Ok(_) => return false,
+            Err(err) => {
+                // Keeping a blob too long beats deleting one that is still shared.
+                warn!(?err, "failed to release stored file {}", key);
+                return false;
+            }
+        }
+        if let Some(cache) = &self.cache {
+            cache.remove(key);
+        }
+        if let Err(err) = self.storage.delete(key).await {
+            warn!(%err, "failed to remove stored file {}", key);
+        }
+        true
+    }
+
+    /// Streams a blob, or the requested part of it, from the cache when the blob
+    /// is small enough to be kept there.
+    async fn stream_blob(
+        &self,
+        entry: &FileEntry,
+        span: Option<Range<u64>>,
+    ) -> std::io::Result<ByteStream> {
+        let Some(cache) = self.cache.as_ref().filter(|cache| cache.admits(entry.size)) else {
+            return compression::open(&*self.storage, entry, span).await;
+        };
+        let data = match cache.get(&entry.key) {
+            Some(data) => data,
+            None => {
+                let chunks: Vec<Bytes> = compression::open(&*self.storage, entry, None)
+                    .await?
+                    .try_collect()
+                    .await?;
+                let data = Bytes::from(chunks.concat());
+                cache.insert(&entry.key, data.clone());
+                data
+            }
+        };
+        let data = match span {
+            Some(span) => {
+                let len = data.len();
+                data.slice((span.start as usize).min(len)..(span.end as usize).min(len))
+            }
+            None => data,
+        };
+        Ok(futures_util::stream::once(async move { Ok(data) }).boxed())
     }
 }
 
@@ -89,6 +752,54 @@ enum AppError {
     NoFileProvided,
     #[error("invalid upload password")]
     Unauthorized,
+    #[error("sign-in required")]
+    SignInRequired,
+    #[error("CAPTCHA not solved")]
+    CaptchaFailed,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("upload exceeds {limit} bytes")]
+    PayloadTooLarge { limit: usize },
+    #[error("storage quota exhausted")]
+    InsufficientStorage,
+    #[error("api key upload quota exhausted")]
+    QuotaExceeded,
+    #[error("user storage quota of {quota} bytes exhausted")]
+    UserQuotaExceeded { quota: u64, used: u64 },
+    #[error("tenant storage quota of {quota} bytes exhausted")]
+    TenantQuotaExceeded { quota: u64, used: u64 },
+    #[error("upload token used for all of its {max} uploads")]
+    TokenUsedUp { max: u32 },
+    #[error("daily upload quota of the client address exhausted")]
+    DailyQuotaExceeded { retry_after: Option<Duration> },
+    #[error("monthly transfer cap reached")]
+    TransferCapExceeded { retry_after: Duration },
+    #[error("request took too long")]
+    RequestTimeout,
+    #[error("too many uploads or downloads in flight")]
+    Overloaded,
+    #[error("too many notification emails")]
+    EmailLimited,
+    #[error("too many abuse reports")]
+    ReportLimited,
+    #[error("upload does not match the expected sha256 {expected}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("file is waiting for its malware scan")]
+    ScanPending,
+    #[error("file was quarantined")]
+    Quarantined,
+    #[error("stored file is damaged")]
+    Corrupted,
+    #[error("file is on the blocklist")]
+    Blocked,
+    #[error("file was taken down")]
+    TakenDown,
+    #[error("client address not allowed")]
+    AddressDenied,
+    #[error("unsupported file type: {0}")]
+    UnsupportedFileType(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
     #[error("multipart error")]
     Multipart {
         #[source]
@@ -97,10 +808,30 @@ enum AppError {
     },
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("identity provider error: {0}")]
+    IdentityProvider(String),
+    #[error("ACME error: {0}")]
+    Acme(String),
+    #[error("CAPTCHA provider error: {0}")]
+    CaptchaUnavailable(String),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let mut response = self.response();
+        if let Some(id) = request_id::current()
+            && let Ok(value) = HeaderValue::from_str(&id)
+        {
+            response.headers_mut().insert(request_id::REQUEST_ID, value);
+        }
+        response
+    }
+}
+
+impl AppError {
+    fn response(self) -> Response {
         match self {
             Self::NotFound => (StatusCode::NOT_FOUND, "file not found").into_response(),
             Self::NoFileProvided => (
@@ -111,6 +842,140 @@ impl IntoResponse for AppError {
             Self::Unauthorized => {
                 (StatusCode::UNAUTHORIZED, "invalid upload password").into_response()
             }
+            Self::SignInRequired => (
+                StatusCode::UNAUTHORIZED,
+                "sign in at /auth/login or use an API key to upload",
+            )
+                .into_response(),
+            Self::CaptchaFailed => (
+                StatusCode::FORBIDDEN,
+                "solve the CAPTCHA on the upload page, or upload with an API key",
+            )
+                .into_response(),
+            Self::InvalidToken => (StatusCode::FORBIDDEN, "invalid token").into_response(),
+            Self::PayloadTooLarge { limit } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("upload exceeds the maximum size of {} bytes", limit),
+            )
+                .into_response(),
+            Self::InsufficientStorage => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                "not enough storage space left for this upload",
+            )
+                .into_response(),
+            Self::QuotaExceeded => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "upload quota exhausted for this API key",
+            )
+                .into_response(),
+            Self::UserQuotaExceeded { quota, used } => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!(
+                    "this upload does not fit in your storage quota of {} bytes ({} in use); \
+                     delete some uploads first",
+                    quota, used
+                ),
+            )
+                .into_response(),
+            Self::TenantQuotaExceeded { quota, used } => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!(
+                    "this upload does not fit in the tenant's storage quota of {} bytes \
+                     ({} in use)",
+                    quota, used
+                ),
+            )
+                .into_response(),
+            Self::TokenUsedUp { max } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("this upload token has been used for all of its {} uploads", max),
+            )
+                .into_response(),
+            Self::DailyQuotaExceeded {
+                retry_after: Some(retry_after),
+            } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, (retry_after.as_secs() + 1).to_string())],
+                "your address has used up its daily upload quota, try again later",
+            )
+                .into_response(),
+            Self::DailyQuotaExceeded { retry_after: None } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "this upload is larger than the daily upload quota of your address",
+            )
+                .into_response(),
+            // 509 Bandwidth Limit Exceeded has no constant of its own.
+            Self::TransferCapExceeded { retry_after } => (
+                StatusCode::from_u16(509).unwrap_or(StatusCode::TOO_MANY_REQUESTS),
+                [(header::RETRY_AFTER, (retry_after.as_secs() + 1).to_string())],
+                "this file's owner has used up their transfer allowance for the month",
+            )
+                .into_response(),
+            Self::RequestTimeout => (
+                StatusCode::REQUEST_TIMEOUT,
+                [(header::CONNECTION, "close")],
+                "the request took too long",
+            )
+                .into_response(),
+            Self::Overloaded => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "5")],
+                "the server is busy, try again shortly",
+            )
+                .into_response(),
+            Self::EmailLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many notification emails were sent recently, try again later",
+            )
+                .into_response(),
+            Self::ReportLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many reports were sent from your address recently, try again later",
+            )
+                .into_response(),
+            Self::ChecksumMismatch { expected, actual } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!(
+                    "upload is corrupted: expected sha256 {} but received {}",
+                    expected, actual
+                ),
+            )
+                .into_response(),
+            Self::ScanPending => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "5")],
+                "file is still being scanned for malware, try again shortly",
+            )
+                .into_response(),
+            Self::Quarantined => (
+                StatusCode::GONE,
+                "file was quarantined by the malware scanner",
+            )
+                .into_response(),
+            Self::Corrupted => (
+                StatusCode::GONE,
+                "file was damaged in storage and can no longer be served",
+            )
+                .into_response(),
+            Self::Blocked => (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                "this file is not allowed on this server",
+            )
+                .into_response(),
+            Self::TakenDown => (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                "this file was taken down after a report",
+            )
+                .into_response(),
+            Self::AddressDenied => (
+                StatusCode::FORBIDDEN,
+                "requests from your address are not allowed here",
+            )
+                .into_response(),
+            Self::UnsupportedFileType(message) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, message).into_response()
+            }
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, message).into_response(),
             Self::Multipart {
                 source,
                 debug_message,
@@ -127,7 +992,31 @@ impl IntoResponse for AppError {
             }
             Self::Io(err) => {
                 error!(%err, "io error");
-                (StatusCode::INTERNAL_SERVER_ERROR, "internal storage error").into_response()
+                let body = request_id::mention("internal storage error");
+                (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+            }
+            Self::Config(message) => {
+                error!(%message, "configuration error");
+                let body = request_id::mention("internal server error");
+                (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+            }
+            Self::IdentityProvider(message) => (
+                StatusCode::BAD_GATEWAY,
+                format!("sign-in failed at the identity provider: {}", message),
+            )
+                .into_response(),
+            Self::Acme(message) => {
+                error!(%message, "ACME error");
+                (StatusCode::BAD_GATEWAY, request_id::mention("certificate authority error"))
+                    .into_response()
+            }
+            Self::CaptchaUnavailable(message) => {
+                warn!(%message, "CAPTCHA verification failed");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "the CAPTCHA could not be checked, try again shortly",
+                )
+                    .into_response()
             }
         }
     }
@@ -135,17 +1024,85 @@ impl IntoResponse for AppError {
 
 #[derive(Serialize)]
 struct UploadResponse {
+    #[serde(skip)]
+    id: String,
     url: String,
+    preview_url: String,
     expires_in_minutes: u64,
+    expires_at: u64,
     remaining_downloads: u32,
+    delete_token: String,
+    owner_token: String,
+    sha256: String,
+    /// Where the link is being emailed, when `notify_email` was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emailed_to: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct UploadParams {
+    expires: Option<String>,
+    password: Option<String>,
+    format: Option<String>,
+    signed_until: Option<u64>,
+    max_bytes: Option<u64>,
+    signature: Option<String>,
+    /// A signed upload token, for clients that cannot set `Authorization`.
+    token: Option<String>,
+    slug: Option<String>,
+    /// Marks the upload as end-to-end encrypted by the client.
+    encrypted: Option<bool>,
+    /// Emails the download link here once the upload is stored.
+    notify_email: Option<String>,
+}
+
+/// A fully received upload, independent of the endpoint it arrived through.
+struct NewUpload {
+    filename: String,
+    content_type: Option<String>,
+    data: Spool,
+    expires: Option<String>,
+    kind: EntryKind,
+    /// Requested download id; the random one is used when it is already taken.
+    slug: Option<String>,
+    /// Digest the client says it sent; the upload is refused if the bytes differ.
+    sha256: Option<String>,
+    notify_email: Option<String>,
+    /// See `FileEntry::uploader`.
+    uploader: Option<String>,
+    /// See `FileEntry::subject`.
+    subject: Option<String>,
+    /// Whose quota and default lifetime apply.
+    user: Option<User>,
+    /// The tenant whose namespace, storage prefix and limits the upload goes in.
+    tenant: Option<Tenant>,
+    /// The upload token whose content types and upload count apply.
+    token: Option<UploadToken>,
 }
 
 async fn upload(
     State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     mut multipart: Multipart,
-) -> Result<Json<UploadResponse>, AppError> {
-    let mut provided_password: Option<String> = None;
-    let mut file_data: Option<(String, Option<String>, Bytes)> = None;
+) -> Result<Response, AppError> {
+    let reply_format = reply_format(&headers, &params);
+    let credential = credential(&state, &headers, &params).await?;
+    // The password may still come in a form field, the CAPTCHA never does.
+    if matches!(credential, Credential::Password) {
+        check_captcha(&state, &headers, peer).await?;
+    }
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    let mut provided_password = params.password;
+    let mut expires = params.expires;
+    let mut slug = params.slug;
+    let mut sha256 = expected_sha256(&headers);
+    let mut encrypted = params.encrypted.unwrap_or(false);
+    let mut notify_email = params.notify_email;
+    let mut files: Vec<(String, Option<String>, Spool)> = Vec::new();
+    let mut remote_url = None;
+    let mut received = 0;
 
     while let Some(field) = multipart
         .next_field()
@@ -160,33 +1117,553 @@ async fn upload(
                     .map_err(|err| to_multipart_error(&state, err))?;
                 provided_password = Some(text);
             }
+            Some("expires") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                expires = Some(text);
+            }
+            Some("url") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                remote_url = Some(text).filter(|url| !url.trim().is_empty());
+            }
+            Some("slug") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                slug = Some(text);
+            }
+            Some("sha256") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                sha256 = Some(text);
+            }
+            Some("encrypted") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                encrypted = matches!(text.trim(), "true" | "1" | "on");
+            }
+            Some("notify_email") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|err| to_multipart_error(&state, err))?;
+                notify_email = Some(text);
+            }
             Some("file") => {
                 let filename = field
                     .file_name()
                     .map(|v| v.to_string())
                     .unwrap_or_else(|| "upload.bin".to_string());
                 let content_type = field.content_type().map(|v| v.to_string());
-                let data = field
-                    .bytes()
+                // Every file of a bundle counts against the same limit.
+                let data = state
+                    .spool
+                    .receive(field, limit - received, |err| to_multipart_error(&state, err))
                     .await
-                    .map_err(|err| to_multipart_error(&state, err))?;
-                file_data = Some((filename, content_type, data));
+                .map_err(|err| match err {
+                    AppError::PayloadTooLarge { .. } => AppError::PayloadTooLarge { limit },
+                    err => err,
+                })?;
+                received += data.len() as usize;
+                files.push((filename, content_type, data));
             }
             _ => {}
         }
     }
-
-    if state.config.upload_page_enabled
-        && state.config.upload_password != provided_password.as_deref().unwrap_or("")
+
+    if matches!(credential, Credential::Password) {
+        check_password(&state, provided_password.as_deref())?;
+    }
+    // The server can neither bundle ciphertext nor encrypt what it fetches.
+    if encrypted && files.len() != 1 {
+        return Err(AppError::BadRequest(
+            "an encrypted upload must be a single file".to_string(),
+        ));
+    }
+
+    let (filename, content_type, data) = match files.len() {
+        0 => match remote_url {
+            Some(url) => remote::fetch(&state, url.trim(), limit).await?,
+            None => return Err(AppError::NoFileProvided),
+        },
+        1 => files.remove(0),
+        count => {
+            // A bundle is refused for any file that would be refused on its own.
+            for (filename, content_type, _) in &files {
+                state
+                    .settings()
+                    .file_types
+                    .check(filename, content_type.as_deref())?;
+            }
+            let files = files
+                .into_iter()
+                .map(|(filename, _, data)| (filename, data))
+                .collect();
+            let target = state.spool.temp_path();
+            let data = tokio::task::spawn_blocking(move || bundle::zip_files(target, files))
+                .await
+                .map_err(std::io::Error::other)??;
+            (
+                format!("bundle-{}-files.zip", count),
+                Some(bundle::BUNDLE_CONTENT_TYPE.to_string()),
+                data,
+            )
+        }
+    };
+
+    let (kind, content_type) = if encrypted {
+        (
+            EntryKind::Encrypted,
+            Some(e2e::ENCRYPTED_CONTENT_TYPE.to_string()),
+        )
+    } else {
+        (EntryKind::File, content_type)
+    };
+    let upload = NewUpload {
+        filename,
+        content_type,
+        data,
+        expires,
+        kind,
+        slug,
+        sha256,
+        notify_email,
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+    Ok(upload_reply(&state.config, &headers, response, reply_format))
+}
+
+/// `PUT /:filename` takes the raw request body as the file, so `curl -T` works
+/// without multipart. The password goes in `X-Upload-Password` or `?password=`,
+/// or an API key in `Authorization: Bearer`; a pre-signed URL needs neither.
+async fn put_upload(
+    State(state): State<Arc<AppState>>,
+    Path(filename): Path<String>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let reply_format = reply_format(&headers, &params);
+    let credential = credential(&state, &headers, &params).await?;
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    let declared_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > limit as u64) {
+        return Err(AppError::PayloadTooLarge { limit });
+    }
+
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let data = state
+        .spool
+        .receive(body.into_data_stream(), limit, |err| {
+            AppError::BadRequest(format!("failed to read upload body: {}", err))
+        })
+        .await?;
+
+    let (kind, content_type) = if params.encrypted.unwrap_or(false) {
+        (
+            EntryKind::Encrypted,
+            Some(e2e::ENCRYPTED_CONTENT_TYPE.to_string()),
+        )
+    } else {
+        (EntryKind::File, content_type)
+    };
+    let upload = NewUpload {
+        filename,
+        content_type,
+        data,
+        expires: params.expires,
+        kind,
+        slug: params.slug,
+        sha256: expected_sha256(&headers),
+        notify_email: params.notify_email,
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+    Ok(upload_reply(&state.config, &headers, response, reply_format))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReplyFormat {
+    Json,
+    Text,
+    ShareX,
+}
+
+/// Shell pipelines get just the URL when they send `Accept: text/plain` or
+/// `?format=text`, and ShareX asks for `?format=sharex`; everything else,
+/// including the web UI, gets JSON.
+fn reply_format(headers: &HeaderMap, params: &UploadParams) -> ReplyFormat {
+    if let Some(format) = &params.format {
+        if format.eq_ignore_ascii_case("text") {
+            return ReplyFormat::Text;
+        }
+        if format.eq_ignore_ascii_case("sharex") {
+            return ReplyFormat::ShareX;
+        }
+        return ReplyFormat::Json;
+    }
+
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return ReplyFormat::Json;
+    };
+
+    let media_types = accept
+        .split(',')
+        .map(|item| item.split(';').next().unwrap_or("").trim());
+    let mut text = false;
+    for media_type in media_types {
+        if media_type.eq_ignore_ascii_case("application/json") {
+            return ReplyFormat::Json;
+        }
+        text |= media_type.eq_ignore_ascii_case("text/plain");
+    }
+    if text {
+        ReplyFormat::Text
+    } else {
+        ReplyFormat::Json
+    }
+}
+
+fn upload_reply(
+    config: &AppConfig,
+    headers: &HeaderMap,
+    response: UploadResponse,
+    format: ReplyFormat,
+) -> Response {
+    match format {
+        ReplyFormat::Text => format!("{}\n", response.url).into_response(),
+        ReplyFormat::ShareX => Json(sharex::ShareXResponse::new(config, headers, &response))
+            .into_response(),
+        ReplyFormat::Json => Json(response).into_response(),
+    }
+}
+
+/// Links handed to other devices or apps need a full URL, so without `URL_PREFIX`
+/// the request's own host and scheme are used, as forwarded by a trusted proxy.
+fn absolute_url(config: &AppConfig, headers: &HeaderMap, path: &str) -> String {
+    if config.url_prefix.is_some() {
+        return config.build_url(path);
+    }
+
+    let header_value = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let own_scheme = if config.serves_https() { "https" } else { "http" };
+    let scheme = header_value("x-forwarded-proto").unwrap_or(own_scheme);
+    match header_value("x-forwarded-host").or_else(|| header_value(header::HOST.as_str())) {
+        Some(host) => format!("{}://{}{}", scheme, host, path),
+        None => path.to_string(),
+    }
+}
+
+/// How an upload request proved it may upload.
+enum Credential {
+    Key(ApiKey),
+    User(User),
+    Tenant(Tenant),
+    /// A pre-signed URL minted through the admin API.
+    Signed { max_bytes: Option<u64> },
+    Token(UploadToken),
+    /// A session from signing in with the OpenID Connect provider.
+    Account(Account),
+    /// Nothing yet; the shared password still has to be checked.
+    Password,
+}
+
+impl Credential {
+    fn upload_limit(&self, limit: usize) -> usize {
+        match self {
+            Self::Key(key) => key.upload_limit(limit),
+            Self::User(user) => user.upload_limit(limit),
+            Self::Tenant(tenant) => tenant.upload_limit(limit),
+            Self::Token(token) => token.upload_limit(limit),
+            Self::Signed {
+                max_bytes: Some(max_bytes),
+            } => (*max_bytes).min(limit as u64) as usize,
+            Self::Signed { max_bytes: None } | Self::Account(_) | Self::Password => limit,
+        }
+    }
+
+    fn api_key(&self) -> Option<&ApiKey> {
+        match self {
+            Self::Key(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn user(&self) -> Option<&User> {
+        match self {
+            Self::User(user) => Some(user),
+            _ => None,
+        }
+    }
+
+    fn tenant(&self) -> Option<&Tenant> {
+        match self {
+            Self::Tenant(tenant) => Some(tenant),
+            _ => None,
+        }
+    }
+
+    fn token(&self) -> Option<&UploadToken> {
+        match self {
+            Self::Token(token) => Some(token),
+            _ => None,
+        }
+    }
+
+    fn subject(&self) -> Option<String> {
+        match self {
+            Self::Account(account) => Some(account.subject.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves an `Authorization: Bearer` API key, user, tenant or upload token, a
+/// signed upload URL or a sign-in session. None of them means the caller falls back to
+/// the shared password; an invalid key or signature is an error.
+async fn credential(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &UploadParams,
+) -> Result<Credential, AppError> {
+    if let Some(provided) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+    {
+        if provided.starts_with(users::TOKEN_PREFIX) {
+            let user = state
+                .metadata
+                .find_user(&keys::hash_key(provided))
+                .await?
+                .ok_or(AppError::InvalidToken)?;
+            return Ok(Credential::User(user));
+        }
+        if provided.starts_with(tenants::TOKEN_PREFIX) {
+            let tenant = state
+                .metadata
+                .find_tenant(&keys::hash_key(provided))
+                .await?
+                .ok_or(AppError::InvalidToken)?;
+            return Ok(Credential::Tenant(tenant));
+        }
+        if upload_tokens::is_token(provided) {
+            return upload_token(state, provided).await;
+        }
+        let key = state
+            .metadata
+            .find_key(&keys::hash_key(provided))
+            .await?
+            .ok_or(AppError::InvalidToken)?;
+        key.check_usable()?;
+        return Ok(Credential::Key(key));
+    }
+
+    if let Some(token) = &params.token {
+        return upload_token(state, token.trim()).await;
+    }
+
+    if let Some(signature) = &params.signature {
+        let until = params.signed_until.ok_or(AppError::InvalidToken)?;
+        presign::verify(
+            &state.config.upload_signing_key,
+            until,
+            params.max_bytes,
+            signature,
+            unix_seconds(SystemTime::now()),
+        )?;
+        return Ok(Credential::Signed {
+            max_bytes: params.max_bytes,
+        });
+    }
+
+    if let Some(account) = state.oidc.as_ref().and_then(|oidc| oidc.account(headers)) {
+        return Ok(Credential::Account(account));
+    }
+
+    Ok(Credential::Password)
+}
+
+/// A token that has no uploads left is refused before its body is read.
+async fn upload_token(state: &AppState, token: &str) -> Result<Credential, AppError> {
+    let token = upload_tokens::verify(
+        &state.config.upload_signing_key,
+        token,
+        unix_seconds(SystemTime::now()),
+    )?;
+    token.check_remaining(state).await?;
+    Ok(Credential::Token(token))
+}
+
+/// The digest a client sent along in `X-Content-Sha256`, if any.
+fn expected_sha256(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-content-sha256")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn check_password(state: &AppState, provided: Option<&str>) -> Result<(), AppError> {
+    let config = &state.config;
+    if !config.upload_page_enabled {
+        return Ok(());
+    }
+    // Signing in replaces the password unless both are allowed.
+    if config.oidc.as_ref().is_some_and(|oidc| !oidc.allow_password) {
+        return Err(AppError::SignInRequired);
+    }
+
+    let provided = provided.unwrap_or("");
+    let settings = state.settings();
+    let valid = match &settings.upload_password_hash {
+        Some(hash) => secret::verify_hash(hash, provided),
+        None => secret::matches(&settings.upload_password, provided),
+    };
+    if !valid {
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// With a CAPTCHA configured, uploads that only have the password to show for
+/// themselves need a solved one as well.
+async fn check_captcha(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) -> Result<(), AppError> {
+    match &state.captcha {
+        Some(captcha) => captcha.verify(headers, peer).await,
+        None => Ok(()),
+    }
+}
+
+async fn store_upload(
+    state: &Arc<AppState>,
+    upload: NewUpload,
+    api_key: Option<&ApiKey>,
+) -> Result<UploadResponse, AppError> {
+    let NewUpload {
+        filename,
+        content_type,
+        data,
+        expires,
+        kind,
+        slug,
+        sha256: expected,
+        notify_email,
+        uploader,
+        subject,
+        user,
+        tenant,
+        token,
+    } = upload;
+
+    let filename = filename::sanitize(&filename).unwrap_or_else(|| "upload.bin".to_string());
+    if matches!(kind, EntryKind::File | EntryKind::Encrypted) {
+        state
+            .settings()
+            .file_types
+            .check(&filename, content_type.as_deref())?;
+    }
+    if let Some(token) = &token {
+        token.check_type(content_type.as_deref())?;
+    }
+    let default_ttl = user
+        .as_ref()
+        .and_then(User::default_ttl)
+        .or(tenant.as_ref().and_then(Tenant::default_ttl));
+    let ttl = match default_ttl {
+        Some(ttl) if expires.as_deref().is_none_or(|v| v.trim().is_empty()) => {
+            ttl.min(state.settings().max_ttl)
+        }
+        _ => resolve_ttl(&state.settings(), expires.as_deref())?,
+    };
+    let slug = slug
+        .filter(|slug| !slug.trim().is_empty())
+        .map(|slug| slug::validate(&state.config, &slug))
+        .transpose()?;
+    let expected = expected
+        .filter(|digest| !digest.trim().is_empty())
+        .map(|digest| {
+            blocklist::normalize(&digest).ok_or_else(|| {
+                AppError::BadRequest("sha256 must be 64 hexadecimal characters".to_string())
+            })
+        })
+        .transpose()?;
+    // Counted as soon as it is asked for, so failing uploads cannot dodge the limit.
+    let notify_email = notify_email
+        .filter(|address| !address.trim().is_empty())
+        .map(|address| match &state.mailer {
+            Some(mailer) => mailer.admit(&address),
+            None => Err(AppError::BadRequest(
+                "this server does not send notification emails".to_string(),
+            )),
+        })
+        .transpose()?;
+    let size = data.len();
+    if let Some(user) = &user {
+        users::check_quota(state, user, size).await?;
+    }
+    if let Some(tenant) = &tenant {
+        tenants::check_quota(state, tenant, size).await?;
+    }
+    if api_key.is_none()
+        && user.is_none()
+        && tenant.is_none()
+        && token.is_none()
+        && let Some(uploader) = &uploader
     {
-        return Err(AppError::Unauthorized);
+        state.address_quota.admit(uploader, size)?;
     }
+    // A tenant's blobs live under its own prefix and are only shared within it.
+    let storage_prefix = tenant
+        .as_ref()
+        .map(Tenant::storage_prefix)
+        .unwrap_or_default();
 
-    let Some((filename, content_type, data)) = file_data else {
-        return Err(AppError::NoFileProvided);
-    };
-
-    let id = Uuid::new_v4().to_string();
+    let blob_id = Uuid::new_v4().to_string();
     let suffix = if state.config.use_filename_suffix {
         FsPath::new(&filename)
             .extension()
@@ -197,48 +1674,326 @@ async fn upload(
         None
     };
 
-    let download_id = suffix
-        .as_deref()
-        .map(|ext| format!("{}{}", id, ext))
-        .unwrap_or_else(|| id.clone());
+    // The blob always gets the random name, so a slug never decides what is
+    // overwritten in storage.
+    let with_suffix = |id: String| match &suffix {
+        Some(ext) => format!("{}{}", id, ext),
+        None => id,
+    };
 
-    let path = state.config.storage_dir.join(&download_id);
-    fs::write(&path, &data).await?;
+    // The digest was taken while the upload was spooled, so nothing is stored
+    // before it is known to be acceptable.
+    let sha256 = check_digest(state, data.sha256(), expected)?;
+    // Ciphertext and short links never compress.
+    let (blob, codec) = if matches!(kind, EntryKind::File | EntryKind::Paste) {
+        compression::encode(&state.config, &state.spool, content_type.as_deref(), data).await?
+    } else {
+        (data, None)
+    };
+    let stored_size = blob.len();
+    let (storage_key, reservation) = if state.config.deduplicate_uploads {
+        let shared_key = match codec {
+            Some(codec) => format!("{}{}.{}", storage_prefix, sha256, codec.extension()),
+            None => format!("{}{}", storage_prefix, sha256),
+        };
+        let reservation = put_shared(state, &shared_key, &blob).await?;
+        (shared_key, reservation)
+    } else {
+        let storage_key = format!("{}{}", storage_prefix, with_suffix(blob_id));
+        let reservation = reserve_space(state, stored_size).await?;
+        state.storage.put(&storage_key, blob.stream().await?).await?;
+        (storage_key, Some(reservation))
+    };
+    drop(blob);
 
     if state.config.upload_debug_logs {
         info!(
             filename = %filename,
-            bytes = data.len(),
+            bytes = size,
             content_type = %content_type.clone().unwrap_or_default(),
             "upload received"
         );
     }
 
-    let expires_at = Instant::now() + state.config.ttl;
+    let expires_at = SystemTime::now() + ttl;
+    let delete_token = Uuid::new_v4().simple().to_string();
+    let owner_token = Uuid::new_v4().simple().to_string();
     let entry = FileEntry {
-        path: path.clone(),
+        key: storage_key.clone(),
         filename,
         expires_at,
-        remaining_hits: state.config.max_downloads,
+        remaining_hits: state.settings().max_downloads,
         content_type,
+        size,
+        delete_token: Some(delete_token.clone()),
+        owner_token: Some(owner_token.clone()),
+        created_at: SystemTime::now(),
+        kind,
+        sha256: Some(sha256.clone()),
+        scan: if state.scanner.is_some() {
+            ScanStatus::Pending
+        } else {
+            ScanStatus::Unscanned
+        },
+        threat: None,
+        compression: codec,
+        compressed_size: codec.map(|_| stored_size),
+        api_key: api_key.map(|key| key.id.clone()),
+        uploader,
+        subject,
+        user: user.map(|user| user.id),
+        reports: Vec::new(),
+        disabled: false,
+    };
+
+    // Claimed last so uploads refused for other reasons do not use the token up.
+    if let Some(token) = &token
+        && let Err(err) = token.claim(state).await
+    {
+        state.release_blob(&entry.key).await;
+        return Err(err);
+    }
+
+    // The slug is tried first; a taken one falls back to generated ids.
+    let candidates = slug
+        .into_iter()
+        .chain((0..ID_ATTEMPTS).map(|_| with_suffix(state.config.id_strategy.generate())))
+        .map(|id| match &tenant {
+            Some(tenant) => tenant.entry_id(&id),
+            None => id,
+        });
+    let mut inserted = Err(AppError::Io(std::io::Error::other(
+        "no free download id was found",
+    )));
+    for candidate in candidates {
+        match state.metadata.insert(&candidate, &entry).await {
+            Ok(true) => {
+                inserted = Ok(candidate);
+                break;
+            }
+            Ok(false) => continue,
+            Err(err) => {
+                inserted = Err(err);
+                break;
+            }
+        }
+    }
+    let download_id = match inserted {
+        Ok(id) => id,
+        Err(err) => {
+            state.release_blob(&entry.key).await;
+            return Err(err);
+        }
+    };
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
+    state.notify(Event::Upload, &download_id, &entry);
+    if state.scanner.is_some() {
+        scan::spawn(state.clone(), download_id.clone());
+    }
+
+    if let Some(key) = api_key
+        && let Err(err) = state
+            .metadata
+            .record_key_usage(&key.id, entry.size, SystemTime::now())
+            .await
+    {
+        warn!(?err, "failed to record usage for api key {}", key.id);
+    }
+
+    let url = state.config.build_download_url(&download_id);
+    if let (Some(mailer), Some(address)) = (&state.mailer, &notify_email) {
+        let announcement = Announcement {
+            id: download_id.clone(),
+            filename: entry.filename.clone(),
+            size: entry.size,
+            url: url.clone(),
+            expires_at,
+            remaining_downloads: entry.remaining_hits,
+        };
+        mailer.spawn_send(address.clone(), announcement);
+    }
+
+    Ok(UploadResponse {
+        id: download_id.clone(),
+        url,
+        preview_url: state.config.build_preview_url(&download_id),
+        expires_in_minutes: ttl.as_secs() / 60,
+        expires_at: unix_seconds(expires_at),
+        remaining_downloads: state.settings().max_downloads,
+        delete_token,
+        owner_token,
+        sha256,
+        emailed_to: notify_email,
+    })
+}
+
+/// Whether a stored name has the shape of a blob key: a random UUID, maybe with
+/// an extension, or a SHA-256 digest. Anything else in the store is left alone.
+fn is_blob_key(name: &str) -> bool {
+    let name = tenants::blob_name(name);
+    let stem = name.split('.').next().unwrap_or_default();
+    (stem.len() == 36 && Uuid::parse_str(stem).is_ok()) || is_shared_key(name)
+}
+
+/// Whether `key` names a content-addressed blob stored under `DEDUPLICATE_UPLOADS`:
+/// the digest, plus the codec's extension when it is compressed.
+fn is_shared_key(key: &str) -> bool {
+    let key = tenants::blob_name(key);
+    let digest = match key.split_once('.') {
+        Some((digest, extension)) if Codec::from_extension(extension).is_some() => digest,
+        Some(_) => return false,
+        None => key,
+    };
+    blocklist::normalize(digest).as_deref() == Some(digest)
+}
+
+/// Accepts the digest of a new upload unless it differs from what the client
+/// announced or the content is banned.
+fn check_digest(
+    state: &AppState,
+    actual: &str,
+    expected: Option<String>,
+) -> Result<String, AppError> {
+    let actual = actual.to_string();
+    match expected {
+        Some(expected) if expected != actual => {
+            Err(AppError::ChecksumMismatch { expected, actual })
+        }
+        _ if state.blocklist.contains(&actual) => Err(AppError::Blocked),
+        _ => Ok(actual),
+    }
+}
+
+/// Stores a content-addressed blob under `DEDUPLICATE_UPLOADS`, or only takes a
+/// reference on it when the same bytes are already stored. Room is reserved only
+/// for blobs that are actually written.
+async fn put_shared(
+    state: &AppState,
+    key: &str,
+    data: &Spool,
+) -> Result<Option<Reservation>, AppError> {
+    let size = data.len();
+    // Evicting takes the blob lock, so room is reserved before holding it.
+    let mut reservation = if state.storage.exists(key).await? {
+        None
+    } else {
+        Some(reserve_space(state, size).await?)
+    };
+
+    let _guard = state.blob_lock.lock().await;
+    let refs = state.metadata.retain_blob(key).await?;
+    let stored = async {
+        if refs > 1 && state.storage.exists(key).await? {
+            return Ok(None);
+        }
+        if reservation.is_none() {
+            // The blob went away in the meantime; no eviction under the lock.
+            let held = state
+                .usage
+                .reserve(size)
+                .map_err(|_| AppError::InsufficientStorage)?;
+            reservation = Some(held);
+        }
+        state.storage.put(key, data.stream().await?).await?;
+        Ok(reservation.take())
+    }
+    .await;
+
+    if stored.is_err()
+        && let Err(err) = state.metadata.release_blob(key).await
+    {
+        warn!(?err, "failed to release stored file {}", key);
+    }
+    stored
+}
+
+/// Holds room for an upload under `MAX_TOTAL_STORAGE_BYTES`, evicting entries first
+/// when the configured policy allows it.
+async fn reserve_space(state: &AppState, size: u64) -> Result<Reservation, AppError> {
+    let needed = match state.usage.reserve(size) {
+        Ok(reservation) => return Ok(reservation),
+        Err(needed) => needed,
+    };
+
+    // Nothing can be evicted to fit a file larger than the whole quota.
+    if state
+        .config
+        .max_total_storage_bytes
+        .is_some_and(|limit| size > limit)
+    {
+        return Err(AppError::InsufficientStorage);
+    }
+
+    let mut candidates = match state.config.storage_full_policy {
+        StorageFullPolicy::Reject => return Err(AppError::InsufficientStorage),
+        StorageFullPolicy::EvictOldest | StorageFullPolicy::EvictExpiring => {
+            state.metadata.list().await?
+        }
     };
+    if state.config.storage_full_policy == StorageFullPolicy::EvictOldest {
+        candidates.sort_by_key(|(_, entry)| entry.created_at);
+    } else {
+        candidates.sort_by_key(|(_, entry)| entry.expires_at);
+    }
+
+    let mut freed = 0;
+    for (id, _) in candidates {
+        if freed >= needed {
+            break;
+        }
+        if let Some(entry) = state.metadata.remove(&id).await? {
+            info!(id = %id, bytes = entry.size, "evicting entry to free storage");
+            state.notify(Event::Delete, &id, &entry);
+            freed += state.discard(&entry).await;
+        }
+    }
 
     state
-        .entries
-        .lock()
-        .await
-        .insert(download_id.clone(), entry);
+        .usage
+        .reserve(size)
+        .map_err(|_| AppError::InsufficientStorage)
+}
+
+/// Picks the lifetime of a new upload, clamped to `MAX_TTL_MINS`.
+fn resolve_ttl(settings: &Settings, requested: Option<&str>) -> Result<Duration, AppError> {
+    match requested.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(requested) => Ok(parse_duration("expires", requested)?.min(settings.max_ttl)),
+        None => Ok(settings.ttl),
+    }
+}
 
-    let response = UploadResponse {
-        url: state.config.build_download_url(&download_id),
-        expires_in_minutes: state.config.ttl.as_secs() / 60,
-        remaining_downloads: state.config.max_downloads,
+/// Parses a number of minutes, or a number followed by `m`, `h` or `d`.
+fn parse_duration(field: &str, value: &str) -> Result<Duration, AppError> {
+    let (amount, unit_secs) = match value.char_indices().last() {
+        Some((idx, 'm' | 'M')) => (&value[..idx], 60),
+        Some((idx, 'h' | 'H')) => (&value[..idx], 60 * 60),
+        Some((idx, 'd' | 'D')) => (&value[..idx], 24 * 60 * 60),
+        _ => (value, 60),
     };
 
-    Ok(Json(response))
+    let amount = amount
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|amount| *amount > 0)
+        .ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "invalid {} value '{}', expected e.g. 30, 30m, 12h or 7d",
+                field, value
+            ))
+        })?;
+
+    Ok(Duration::from_secs(amount.saturating_mul(unit_secs)))
 }
 
 fn to_multipart_error(state: &AppState, err: MultipartError) -> AppError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return AppError::PayloadTooLarge {
+            limit: state.config.max_upload_bytes,
+        };
+    }
     let detail = state.config.upload_debug_logs.then(|| err.to_string());
     AppError::Multipart {
         source: err,
@@ -246,95 +2001,642 @@ fn to_multipart_error(state: &AppState, err: MultipartError) -> AppError {
     }
 }
 
+#[derive(Deserialize)]
+struct DownloadParams {
+    raw: Option<String>,
+    /// Asks for media to be shown in the browser rather than saved.
+    inline: Option<String>,
+}
+
 async fn download(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
+    Query(params): Query<DownloadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    let mut entries = state.entries.lock().await;
-
-    let Some(entry) = entries.get_mut(&id) else {
-        return Err(AppError::NotFound);
-    };
+    let inline = params.inline.is_some();
+    // A range request whose `If-Range` carries the tag of a recent counted
+    // download resumes or seeks within that transfer, so it is served without
+    // consuming one. A resume never starts over at byte 0, so replaying a tag for
+    // the whole file counts. Every other request counts as a download, ranged or
+    // not, and one with an `If-Range` that does not match gets the whole file, as
+    // HTTP asks.
+    let signing_key = &state.config.upload_signing_key;
+    let mut span = None;
+    if let Some(requested) = range::parse(&headers) {
+        let entry = live_entry(&state, &id).await?;
+        entry.check_scan()?;
+        bandwidth::check(&state, &id, &entry).await?;
 
-    if Instant::now() >= entry.expires_at {
-        let removed = entries.remove(&id);
-        drop(entries);
-        if let Some(expired) = removed {
-            delete_file(&expired.path).await;
+        // Entries recorded before sizes were tracked have a size of 0 and are
+        // always served whole. A short link has no bytes to seek within.
+        if entry.size > 0 && entry.kind != EntryKind::Redirect {
+            let Some(resolved) = range::resolve(requested, entry.size) else {
+                return Ok(range_not_satisfiable(entry.size));
+            };
+            let resumed = (resolved.start > 0)
+                .then(|| range::resumes(&headers, signing_key, &id, &entry.key, SystemTime::now()))
+                .flatten();
+            if let Some(tag) = resumed {
+                let mut response =
+                    file_response(&state, &id, entry, Some(resolved), false, inline).await?;
+                response.headers_mut().insert(header::ETAG, tag);
+                return Ok(response);
+            }
+            if range::if_range(&headers).is_none() {
+                span = Some(resolved);
+            }
         }
-        return Err(AppError::NotFound);
     }
 
-    let last_hit = entry.remaining_hits <= 1;
-    let metadata = entry.clone();
+    // The decryptor page is not a download; the ciphertext it fetches is.
+    if span.is_none()
+        && paste::wants_viewer(&headers, params.raw.is_some())
+        && let Ok(entry) = live_entry(&state, &id).await
+        && entry.kind == EntryKind::Encrypted
+    {
+        entry.check_takedown()?;
+        return Ok(e2e::decryptor(&entry));
+    }
+    // Checked before the download is consumed, so a refused one stays available.
+    if let Some(entry) = state.metadata.get(&id).await? {
+        bandwidth::check(&state, &id, &entry).await?;
+    }
 
-    if last_hit {
-        entries.remove(&id);
-    } else {
-        entry.remaining_hits -= 1;
+    let (entry, last_hit) = match state.metadata.take_hit(&id, SystemTime::now()).await? {
+        Hit::Missing => return Err(AppError::NotFound),
+        Hit::Expired(expired) => {
+            state.notify(Event::Expire, &id, &expired);
+            state.discard(&expired).await;
+            return Err(AppError::NotFound);
+        }
+        Hit::Served { entry, last } => {
+            state.webhooks.notify(Event::Download, &id, &entry);
+            state.feed.entry(Event::Download, &id, &entry);
+            state.audit.entry(Event::Download, &id, &entry);
+            let visitor = Visitor::of(&state, &headers, peer);
+            state.live.publish(Event::Download, &id, &entry, Some(visitor));
+            history::record(&state, &id, &entry, &headers, peer).await;
+            (entry, last)
+        }
+        Hit::Withheld(entry) => {
+            entry.check_scan()?;
+            return Err(AppError::NotFound);
+        }
+    };
+
+    if entry.kind == EntryKind::Redirect {
+        return shorten::redirect(&state, entry, last_hit).await;
+    }
+    if entry.kind == EntryKind::Paste
+        && span.is_none()
+        && paste::wants_viewer(&headers, params.raw.is_some())
+    {
+        return paste::viewer(&state, &id, entry, last_hit).await;
     }
+    let tag = range::resume_tag(signing_key, &id, &entry.key, SystemTime::now());
+    let mut response = file_response(&state, &id, entry, span, last_hit, inline).await?;
+    response.headers_mut().insert(header::ETAG, tag);
+    Ok(response)
+}
+
+/// Reads a whole blob into memory, for the small entries that are rendered
+/// rather than passed through.
+async fn read_blob(state: &AppState, entry: &FileEntry) -> Result<Vec<u8>, AppError> {
+    let chunks: Vec<Bytes> = state.stream_blob(entry, None).await?.try_collect().await?;
+    Ok(chunks.concat())
+}
 
-    drop(entries);
+async fn file_response(
+    state: &Arc<AppState>,
+    id: &str,
+    entry: FileEntry,
+    span: Option<Range<u64>>,
+    last_hit: bool,
+    inline: bool,
+) -> Result<Response, AppError> {
+    let stream = match state.stream_blob(&entry, span.clone()).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            if last_hit {
+                state.discard(&entry).await;
+            }
+            return Err(err.into());
+        }
+    };
+
+    let mut headers = entry_headers(state, &entry, inline);
+    let status = match &span {
+        Some(span) => {
+            if let Ok(value) = HeaderValue::from_str(&format!(
+                "bytes {}-{}/{}",
+                span.start,
+                span.end - 1,
+                entry.size
+            )) {
+                headers.insert(header::CONTENT_RANGE, value);
+            }
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(span.end - span.start));
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => {
+            if entry.size > 0 {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(entry.size));
+            }
+            StatusCode::OK
+        }
+    };
+
+    let stream = match state.settings().download_rate_limit {
+        Some(bytes_per_sec) => throttle::Throttled::new(stream, bytes_per_sec).boxed(),
+        None => stream,
+    };
+    let mut meter = bandwidth::Metered::new(state, id, &entry);
+    // The last download deletes the blob only once the body has been fully sent
+    // (or the client went away), so the stream never races the removal.
+    let guard = last_hit.then(|| DiscardOnDrop {
+        state: state.clone(),
+        entry,
+    });
+    let body = Body::from_stream(stream.inspect(move |chunk| {
+        let _ = &guard;
+        if let (Some(meter), Ok(chunk)) = (&mut meter, chunk) {
+            meter.count(chunk.len());
+        }
+    }));
+
+    Ok((status, headers, body).into_response())
+}
 
-    let body = fs::read(&metadata.path).await?;
-    if last_hit {
-        delete_file(&metadata.path).await;
+/// `GET /d/:id/delete?token=` does the same as `DELETE /d/:id` for clients such
+/// as ShareX that can only open a deletion URL in a browser.
+async fn delete_link(
+    path: Path<String>,
+    state: State<Arc<AppState>>,
+    params: Query<TokenParams>,
+    headers: HeaderMap,
+) -> Result<&'static str, AppError> {
+    delete_entry(path, state, params, headers).await?;
+    Ok("file deleted\n")
+}
+
+/// `HEAD /d/:id` describes the file without consuming a download, so link
+/// checkers and download managers can probe it freely.
+async fn head_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DownloadParams>,
+) -> Result<Response, AppError> {
+    let entry = live_entry(&state, &id).await?;
+    entry.check_takedown()?;
+
+    let mut headers = entry_headers(&state, &entry, params.inline.is_some());
+    if entry.size > 0 {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(entry.size));
     }
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// `GET /d/:id/info` returns the entry's metadata as JSON without consuming a
+/// download.
+async fn entry_info(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<EntryView>, AppError> {
+    let entry = live_entry(&state, &id).await?;
+    entry.check_takedown()?;
+    Ok(Json(entry.into()))
+}
+
+/// Looks up an entry that has not expired yet, without touching its hit count.
+async fn live_entry(state: &AppState, id: &str) -> Result<FileEntry, AppError> {
+    state
+        .metadata
+        .get(id)
+        .await?
+        .filter(|entry| SystemTime::now() < entry.expires_at)
+        .ok_or(AppError::NotFound)
+}
+
+/// Media types a browser can display by itself without running anything, which
+/// `?inline=1` may show in place instead of saving.
+const INLINE_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/bmp",
+    "application/pdf",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/wav",
+    "audio/webm",
+    "audio/flac",
+    "audio/aac",
+    "audio/mp4",
+    "video/mp4",
+    "video/webm",
+    "video/ogg",
+];
+
+/// Types a browser runs script from when it opens them directly.
+const ACTIVE_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/xsl",
+    "application/xslt+xml",
+    "text/javascript",
+    "application/javascript",
+    "application/x-javascript",
+    "text/ecmascript",
+    "application/ecmascript",
+];
 
+/// The bare, lowercased media type, without parameters such as `charset`.
+fn media_type(content_type: Option<&str>) -> Option<String> {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+}
+
+fn renders_inline(content_type: Option<&str>) -> bool {
+    media_type(content_type).is_some_and(|value| INLINE_CONTENT_TYPES.contains(&value.as_str()))
+}
+
+fn is_active_content(content_type: Option<&str>) -> bool {
+    media_type(content_type).is_some_and(|value| {
+        ACTIVE_CONTENT_TYPES.contains(&value.as_str()) || value.ends_with("+xml")
+    })
+}
+
+/// Headers shared by every response describing a stored file.
+fn entry_headers(state: &AppState, entry: &FileEntry, inline: bool) -> HeaderMap {
     let mut headers = HeaderMap::new();
-    if let Ok(value) =
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", metadata.filename))
-    {
-        headers.insert(header::CONTENT_DISPOSITION, value);
-    }
+    // Raw pastes are meant to be read in place rather than saved.
+    let disposition = match entry.kind {
+        EntryKind::Paste => "inline",
+        EntryKind::File if inline && renders_inline(entry.content_type.as_deref()) => "inline",
+        EntryKind::File | EntryKind::Redirect | EntryKind::Encrypted => "attachment",
+    };
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        filename::content_disposition(disposition, &entry.filename),
+    );
 
-    let content_type = metadata
+    let mut content_type = entry
         .content_type
-        .unwrap_or_else(|| "application/octet-stream".to_string());
-    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    // Uploaded markup must not run script on this origin: it is served as opaque
+    // bytes, or sandboxed when the operator wants it kept as it is.
+    if is_active_content(Some(content_type)) {
+        if state.settings().serve_active_content {
+            headers.insert(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("sandbox; default-src 'none'"),
+            );
+        } else {
+            content_type = "application/octet-stream";
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(content_type) {
         headers.insert(header::CONTENT_TYPE, value);
     }
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if let Ok(value) = HeaderValue::from_str(&state.config.download_cache_control) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    token: Option<String>,
+}
+
+fn provided_token(headers: &HeaderMap, names: &[&str], params: TokenParams) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name).and_then(|value| value.to_str().ok()))
+        .map(str::to_string)
+        .or(params.token)
+}
+
+/// `DELETE /d/:id` retracts an upload before it expires. The delete (or owner)
+/// token returned at upload time goes in `X-Delete-Token`, `X-Owner-Token` or `?token=`.
+async fn delete_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TokenParams>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let provided = provided_token(&headers, &["x-delete-token", "x-owner-token"], params)
+        .ok_or(AppError::InvalidToken)?;
+
+    let entry = state.metadata.get(&id).await?.ok_or(AppError::NotFound)?;
+    if !entry.may_delete(&provided) {
+        return Err(AppError::InvalidToken);
+    }
+
+    if let Some(removed) = state.metadata.remove(&id).await? {
+        state.notify(Event::Delete, &id, &removed);
+        state.discard(&removed).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct EntryUpdate {
+    filename: Option<String>,
+    content_type: Option<String>,
+    remaining_downloads: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct EntryView {
+    kind: EntryKind,
+    filename: String,
+    size: u64,
+    content_type: Option<String>,
+    remaining_downloads: u32,
+    expires_at: u64,
+    sha256: Option<String>,
+    scan: ScanStatus,
+}
+
+impl From<FileEntry> for EntryView {
+    fn from(entry: FileEntry) -> Self {
+        Self {
+            kind: entry.kind,
+            filename: entry.filename,
+            size: entry.size,
+            content_type: entry.content_type,
+            remaining_downloads: entry.remaining_hits,
+            expires_at: unix_seconds(entry.expires_at),
+            sha256: entry.sha256,
+            scan: entry.scan,
+        }
+    }
+}
+
+/// `PATCH /d/:id` lets the owner rename the file, change its content type, or
+/// adjust how many downloads remain (never above `MAX_DOWNLOADS`).
+async fn patch_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TokenParams>,
+    headers: HeaderMap,
+    Json(update): Json<EntryUpdate>,
+) -> Result<Json<EntryView>, AppError> {
+    let provided =
+        provided_token(&headers, &["x-owner-token"], params).ok_or(AppError::InvalidToken)?;
+
+    let entry = live_entry(&state, &id).await?;
+    if !entry.is_owner(&provided) {
+        return Err(AppError::InvalidToken);
+    }
+
+    let mut patch = EntryPatch::default();
+    if let Some(filename) = update.filename {
+        let Some(filename) = filename::sanitize(&filename) else {
+            return Err(AppError::BadRequest("filename must not be empty".to_string()));
+        };
+        patch.filename = Some(filename);
+    }
+    if let Some(content_type) = update.content_type {
+        if HeaderValue::from_str(&content_type).is_err() {
+            return Err(AppError::BadRequest(format!(
+                "invalid content type '{}'",
+                content_type
+            )));
+        }
+        patch.content_type = Some(content_type);
+    }
+    if let Some(remaining) = update.remaining_downloads {
+        if remaining == 0 {
+            return Err(AppError::BadRequest(
+                "remaining_downloads must be at least 1; use DELETE to remove the file".to_string(),
+            ));
+        }
+        patch.remaining_hits = Some(remaining.min(state.settings().max_downloads.max(1)));
+    }
+
+    let updated = state
+        .metadata
+        .update(&id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    Ok(Json(updated.into()))
+}
+
+#[derive(Deserialize)]
+struct ExtendParams {
+    by: String,
+    token: Option<String>,
+}
+
+/// `POST /d/:id/extend?by=12h` pushes the expiry forward for the owner or delete
+/// token holder. A link never ends up with more than `MAX_TTL_MINS` left.
+async fn extend_entry(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExtendParams>,
+    headers: HeaderMap,
+) -> Result<Json<EntryView>, AppError> {
+    let by = parse_duration("by", params.by.trim())?;
+    let provided = provided_token(
+        &headers,
+        &["x-delete-token", "x-owner-token"],
+        TokenParams {
+            token: params.token,
+        },
+    )
+    .ok_or(AppError::InvalidToken)?;
+
+    let now = SystemTime::now();
+    let entry = state
+        .metadata
+        .get(&id)
+        .await?
+        .filter(|entry| now < entry.expires_at)
+        .ok_or(AppError::NotFound)?;
+    if !entry.may_delete(&provided) {
+        return Err(AppError::InvalidToken);
+    }
+
+    let extended = (entry.expires_at + by)
+        .min(now + state.settings().max_ttl)
+        .max(entry.expires_at);
+    let patch = EntryPatch {
+        expires_at: Some(extended),
+        ..Default::default()
+    };
+    let updated = state
+        .metadata
+        .update(&id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    Ok(Json(updated.into()))
+}
+
+fn range_not_satisfiable(size: u64) -> Response {
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", size)) {
+        headers.insert(header::CONTENT_RANGE, value);
+    }
+    (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+}
+
+struct DiscardOnDrop {
+    state: Arc<AppState>,
+    entry: FileEntry,
+}
 
-    Ok((headers, body).into_response())
+impl Drop for DiscardOnDrop {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let entry = self.entry.clone();
+        tokio::spawn(async move { state.discard(&entry).await });
+    }
 }
 
+/// Instances sharing a metadata store take turns holding this lease; only the
+/// holder removes expired and blocked entries.
+const CLEANUP_LEASE: &str = "cleanup";
+
 fn spawn_cleanup(state: Arc<AppState>) {
     tokio::spawn(async move {
-        let mut ticker = interval(state.config.cleanup_interval);
+        let period = state.config.cleanup_interval;
+        let mut ticker = interval(period);
+        // Outlasts a late renewal, yet lets another instance take over within a
+        // few rounds once the leader is gone.
+        let lease = period * 3;
+        let holder = Uuid::new_v4().simple().to_string();
+        let mut leading = true;
         loop {
             ticker.tick().await;
-            purge_expired(&state).await;
+            let leads = match state
+                .metadata
+                .acquire_lease(CLEANUP_LEASE, &holder, lease)
+                .await
+            {
+                Ok(leads) => leads,
+                Err(err) => {
+                    warn!(?err, "failed to claim the cleanup lease");
+                    false
+                }
+            };
+            if leads != leading {
+                leading = leads;
+                if leads {
+                    info!("took over cleanup of expired entries");
+                } else {
+                    info!("another instance is cleaning up expired entries");
+                }
+            }
+            purge_expired(&state, leads)
+                .instrument(info_span!("cleanup", leading = leads))
+                .await;
         }
     });
 }
 
-async fn purge_expired(state: &Arc<AppState>) {
-    let now = Instant::now();
-    let mut entries = state.entries.lock().await;
-    let expired: Vec<_> = entries
-        .iter()
-        .filter_map(|(id, entry)| {
-            (entry.expires_at <= now).then(|| (id.clone(), entry.path.clone()))
-        })
-        .collect();
-
-    for (id, path) in expired {
-        entries.remove(&id);
-        drop(entries);
-        delete_file(&path).await;
-        entries = state.entries.lock().await;
+/// Upload sessions are kept per instance and always purged; the shared entries
+/// only by the `leading` instance.
+async fn purge_expired(state: &Arc<AppState>, leading: bool) {
+    if leading {
+        match state.metadata.take_expired(SystemTime::now()).await {
+            Ok(expired) => {
+                let count = expired.len();
+                let mut freed = 0;
+                for (id, entry) in expired {
+                    state.notify(Event::Expire, &id, &entry);
+                    freed += state.discard(&entry).await;
+                }
+                state.feed.purge(count, freed);
+            }
+            Err(err) => warn!(?err, "failed to collect expired entries"),
+        }
+        if let Err(err) = state.metadata.purge_download_histories(SystemTime::now()).await {
+            warn!(?err, "failed to purge download histories");
+        }
+        if let Err(err) = state.metadata.purge_token_uploads(SystemTime::now()).await {
+            warn!(?err, "failed to purge upload token counts");
+        }
+    }
+    if let Some(geoip) = &state.geoip {
+        geoip.refresh().await;
+    }
+    if state.blocklist.refresh().await && leading {
+        purge_blocked(state).await;
     }
+
+    let now = SystemTime::now();
+    state
+        .tus
+        .purge_stale(now, state.config.upload_session_ttl)
+        .await;
+    state
+        .chunks
+        .purge_stale(now, state.config.upload_session_ttl)
+        .await;
 }
 
-async fn delete_file(path: &FsPath) {
-    if let Err(err) = fs::remove_file(path).await {
-        if err.kind() != std::io::ErrorKind::NotFound {
-            warn!(%err, "failed to remove file {:?}", path);
+/// Removes stored entries whose content has been put on the blocklist.
+async fn purge_blocked(state: &Arc<AppState>) {
+    let entries = match state.metadata.list().await {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(?err, "failed to list entries for the blocklist");
+            return;
+        }
+    };
+
+    for (id, entry) in entries {
+        if !entry
+            .sha256
+            .as_deref()
+            .is_some_and(|sha256| state.blocklist.contains(sha256))
+        {
+            continue;
+        }
+        match state.metadata.remove(&id).await {
+            Ok(Some(removed)) => {
+                info!(id = %id, "purging blocklisted entry");
+                state.notify(Event::Delete, &id, &removed);
+                state.discard(&removed).await;
+            }
+            Ok(None) => {}
+            Err(err) => warn!(?err, "failed to purge blocklisted entry {}", id),
         }
     }
 }
 
-async fn upload_page(State(state): State<Arc<AppState>>) -> Response {
+/// The page's introduction and password field, which signing in replaces.
+const UPLOAD_PAGE_INTRO: &str = "<p>Upload a file or paste text with the shared password to \
+    receive a download link instantly.</p>";
+/// Stands for `BASE_URL`'s path in the page's own links.
+const UPLOAD_PAGE_BASE: &str = "{base_path}";
+const UPLOAD_PAGE_SUBMIT: &str =
+    r#"<button type="submit" id="submit">Upload &amp; get link</button>"#;
+const UPLOAD_PAGE_PASSWORD: &str = r#"<div>
+        <label for="password">Upload password</label>
+        <input id="password" name="password" type="password" required placeholder="Enter the upload password" />
+      </div>"#;
+
+async fn upload_page(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
     if !state.config.upload_page_enabled {
         return StatusCode::NOT_FOUND.into_response();
     }
@@ -393,6 +2695,19 @@ async fn upload_page(State(state): State<Arc<AppState>>) -> Response {
       color: var(--text);
     }
     input[type="file"] { padding: 0.6rem 0.85rem; }
+    textarea, input[type="text"] {
+      width: 100%;
+      font-size: 0.95rem;
+      padding: 0.75rem 0.85rem;
+      border-radius: 12px;
+      border: 1px solid var(--border);
+      background: rgba(255, 255, 255, 0.06);
+      color: var(--text);
+    }
+    textarea { min-height: 14rem; font-family: ui-monospace, 'SFMono-Regular', Menlo, monospace; resize: vertical; }
+    .tabs { display: flex; gap: 0.5rem; }
+    .tab { background: transparent; color: var(--muted); border: 1px solid var(--border); padding: 0.5rem 0.9rem; }
+    .tab.active { background: rgba(121, 192, 255, 0.18); color: var(--accent); }
     .file-row { display: flex; gap: 0.7rem; align-items: stretch; }
     #file-name { flex: 1; padding: 0.7rem 0.85rem; border-radius: 12px; background: rgba(255, 255, 255, 0.06); border: 1px dashed var(--border); color: var(--muted); min-height: 48px; display: flex; align-items: center; }
     button {
@@ -409,6 +2724,10 @@ async fn upload_page(State(state): State<Arc<AppState>>) -> Response {
     button:active { transform: translateY(1px); }
     #result { margin-top: 1.35rem; }
     pre { background: rgba(0, 0, 0, 0.4); padding: 0.95rem; border-radius: 12px; overflow: auto; border: 1px solid var(--border); }
+    .check { font-weight: 500; margin-top: 0.6rem; }
+    .qr { display: block; margin-top: 1rem; width: 200px; height: 200px; border-radius: 12px; background: #fff; }
+    .live { margin-top: 0.8rem; font-weight: 600; color: var(--accent); }
+    .account a { color: var(--accent); }
   </style>
 </head>
 <body>
@@ -417,19 +2736,30 @@ async fn upload_page(State(state): State<Arc<AppState>>) -> Response {
       <h1>newtemp.sh uploader</h1>
       <span>Secure</span>
     </header>
-    <p>Upload a file with the shared password to receive a download link instantly.</p>
-    <form id="upload-form" action="/upload" method="post" enctype="multipart/form-data">
+    <p>Upload a file or paste text with the shared password to receive a download link instantly.</p>
+    <div class="tabs">
+      <button type="button" class="tab active" data-mode="file">File</button>
+      <button type="button" class="tab" data-mode="text">Text</button>
+    </div>
+    <form id="upload-form" action="{base_path}/upload" method="post" enctype="multipart/form-data">
       <div>
         <label for="password">Upload password</label>
         <input id="password" name="password" type="password" required placeholder="Enter the upload password" />
       </div>
-      <div>
+      <div id="file-section">
         <label for="file">Choose a file</label>
         <div class="file-row">
-          <input id="file" name="file" type="file" required />
+          <input id="file" name="file" type="file" multiple required />
           <button type="button" id="file-button">Browse</button>
         </div>
         <div id="file-name">No file chosen yet</div>
+        <label class="check"><input id="encrypt" type="checkbox" /> Encrypt in this browser (the key stays in the link)</label>
+      </div>
+      <div id="text-section" hidden>
+        <label for="content">Text</label>
+        <textarea id="content" placeholder="Paste a snippet"></textarea>
+        <label for="syntax">Syntax</label>
+        <input id="syntax" type="text" placeholder="e.g. rust, py, json (optional)" />
       </div>
       <button type="submit" id="submit">Upload &amp; get link</button>
     </form>
@@ -441,36 +2771,206 @@ async fn upload_page(State(state): State<Arc<AppState>>) -> Response {
     const fileInput = document.getElementById('file');
     const fileButton = document.getElementById('file-button');
     const fileName = document.getElementById('file-name');
+    const fileSection = document.getElementById('file-section');
+    const textSection = document.getElementById('text-section');
+    const encrypt = document.getElementById('encrypt');
+    let mode = 'file';
+
+    // AES-GCM with a fresh key; the key goes into the link fragment, never to the server.
+    async function encryptFile(file) {
+      const key = await crypto.subtle.generateKey({ name: 'AES-GCM', length: 256 }, true, ['encrypt']);
+      const iv = crypto.getRandomValues(new Uint8Array(12));
+      const header = new TextEncoder().encode(JSON.stringify({ name: file.name, type: file.type }));
+      const length = new Uint8Array(4);
+      new DataView(length.buffer).setUint32(0, header.length);
+      const plain = await new Blob([length, header, file]).arrayBuffer();
+      const sealed = await crypto.subtle.encrypt({ name: 'AES-GCM', iv }, key, plain);
+      const raw = new Uint8Array(await crypto.subtle.exportKey('raw', key));
+      const fragment = btoa(String.fromCharCode(...raw)).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+      return { blob: new Blob([iv, sealed]), fragment };
+    }
+
+    // Follows the link's downloads live; the owner token stays in this page.
+    function watch(reply) {
+      if (!reply.owner_token || !window.EventSource) return;
+      const line = document.createElement('div');
+      line.className = 'live';
+      result.appendChild(line);
+      const link = new URL(reply.url.split('#')[0], location.href);
+      const events = new EventSource(link.pathname + '/events?token=' + encodeURIComponent(reply.owner_token));
+      const left = (data) => data.remaining_downloads + ' download(s) left';
+      events.addEventListener('status', (e) => { line.textContent = 'Waiting for a download, ' + left(JSON.parse(e.data)); });
+      events.addEventListener('download', (e) => {
+        const data = JSON.parse(e.data);
+        const who = [data.visitor?.agent, data.visitor?.network && 'from ' + data.visitor.network].filter(Boolean).join(' ');
+        line.textContent = 'Your file was just picked up' + (who ? ' (' + who + ')' : '') + ', ' + left(data);
+        if (data.remaining_downloads === 0) events.close();
+      });
+      events.addEventListener('expire', () => { line.textContent = 'The link has expired'; events.close(); });
+      events.addEventListener('delete', () => { line.textContent = 'The link was deleted'; events.close(); });
+    }
+
+    document.querySelectorAll('.tab').forEach((tab) => tab.addEventListener('click', () => {
+      mode = tab.dataset.mode;
+      document.querySelectorAll('.tab').forEach((other) => other.classList.toggle('active', other === tab));
+      fileSection.hidden = mode !== 'file';
+      textSection.hidden = mode !== 'text';
+      fileInput.required = mode === 'file';
+    }));
 
     fileButton.addEventListener('click', () => fileInput.click());
     fileInput.addEventListener('change', () => {
-      fileName.textContent = fileInput.files[0]?.name || 'No file chosen yet';
+      const chosen = fileInput.files;
+      fileName.textContent = chosen.length > 1
+        ? chosen.length + ' files, bundled as one zip'
+        : chosen[0]?.name || 'No file chosen yet';
     });
 
     form.addEventListener('submit', async (e) => {
       e.preventDefault();
-      const file = fileInput.files[0];
-      const password = document.getElementById('password').value;
-      if (!file) {
-        fileName.textContent = 'Please choose a file first';
+      const chosen = Array.from(fileInput.files);
+      // Absent once signed in, when the session cookie stands in for it.
+      const password = document.getElementById('password')?.value ?? '';
+      // hCaptcha and Turnstile answer alike; neither is there without CAPTCHA_PROVIDER.
+      const captcha = document.getElementById('captcha') && (window.hcaptcha || window.turnstile);
+      const captchaToken = captcha ? captcha.getResponse() : '';
+      if (captcha && !captchaToken) {
+        result.textContent = 'Please solve the CAPTCHA first';
         return;
       }
-      const data = new FormData();
-      data.append('password', password);
-      data.append('file', file);
+      let request;
+      let sealed = null;
+      if (mode === 'text') {
+        const content = document.getElementById('content').value;
+        if (content === '') {
+          result.textContent = 'Please enter some text first';
+          return;
+        }
+        request = fetch('{base_path}/paste', {
+          method: 'POST',
+          headers: {
+            'Content-Type': 'application/json',
+            'X-Upload-Password': password,
+            'X-Captcha-Token': captchaToken,
+          },
+          body: JSON.stringify({ content, syntax: document.getElementById('syntax').value }),
+        });
+      } else {
+        if (chosen.length === 0) {
+          fileName.textContent = 'Please choose a file first';
+          return;
+        }
+        const data = new FormData();
+        if (password) data.append('password', password);
+        if (encrypt.checked) {
+          if (chosen.length > 1) {
+            fileName.textContent = 'Encrypted uploads take a single file';
+            return;
+          }
+          if (!window.crypto || !crypto.subtle) {
+            result.textContent = 'Encrypting needs a secure (https) connection';
+            return;
+          }
+          result.textContent = 'Encrypting...';
+          sealed = await encryptFile(chosen[0]);
+          data.append('encrypted', 'true');
+          data.append('file', sealed.blob, 'encrypted.bin');
+        } else {
+          chosen.forEach((file) => data.append('file', file));
+        }
+        request = fetch('{base_path}/upload', {
+          method: 'POST',
+          headers: { 'X-Captcha-Token': captchaToken },
+          body: data,
+        });
+      }
       result.textContent = 'Uploading...';
       try {
-        const response = await fetch('/upload', { method: 'POST', body: data });
-        const text = await response.text();
-        result.innerHTML = '<pre>' + text + '</pre>';
+        const response = await request;
+        let text = await response.text();
+        if (response.ok && sealed) {
+          const reply = JSON.parse(text);
+          reply.url += '#' + sealed.fragment;
+          reply.preview_url += '#' + sealed.fragment;
+          text = JSON.stringify(reply, null, 2);
+        }
+        result.innerHTML = '<pre></pre>';
+        result.querySelector('pre').textContent = text;
+        // The QR code would encode the link without its key.
+        if (response.ok && !sealed) {
+          const qr = document.createElement('img');
+          qr.className = 'qr';
+          qr.alt = 'QR code for the download link';
+          qr.src = JSON.parse(text).url + '/qr';
+          result.appendChild(qr);
+        }
+        if (response.ok) {
+          watch(JSON.parse(text));
+        }
       } catch (err) {
         result.textContent = 'Upload failed: ' + err;
       }
+      // A token is only good for one upload.
+      if (captcha) captcha.reset();
     });
   </script>
 </body>
 </html>
 "#;
+    let body = body.replace(UPLOAD_PAGE_BASE, &state.config.base_path);
 
-    Html(body).into_response()
+    let Some(oidc) = &state.oidc else {
+        return Html(with_captcha(&state.config, body)).into_response();
+    };
+    let page = match (oidc.account(&headers), oidc.allows_password()) {
+        (Some(account), _) => {
+            let intro = format!(
+                "<p class=\"account\">Signed in as {} &middot; <a href=\"{}\">Sign out</a></p>",
+                preview::escape_html(&account.name),
+                state.config.site_path("/auth/logout")
+            );
+            body.replace(UPLOAD_PAGE_INTRO, &intro)
+                .replace(UPLOAD_PAGE_PASSWORD, "")
+        }
+        (None, true) => with_captcha(
+            &state.config,
+            body.replace(
+                UPLOAD_PAGE_INTRO,
+                &format!(
+                    "<p class=\"account\"><a href=\"{}\">Sign in</a> to upload, or use the \
+                     shared password.</p>",
+                    state.config.site_path("/auth/login")
+                ),
+            ),
+        ),
+        (None, false) => {
+            let mut headers = HeaderMap::new();
+            if let Ok(login) = HeaderValue::from_str(&state.config.site_path("/auth/login")) {
+                headers.insert(header::LOCATION, login);
+            }
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            return (StatusCode::FOUND, headers).into_response();
+        }
+    };
+    Html(page).into_response()
+}
+
+/// Puts the CAPTCHA widget above the upload button, for those using the password.
+fn with_captcha(config: &AppConfig, page: String) -> String {
+    let Some(captcha) = &config.captcha else {
+        return page;
+    };
+    let widget = format!(
+        r#"<div id="captcha" class="{}" data-sitekey="{}"></div>
+      {}"#,
+        captcha.provider.widget_class(),
+        preview::escape_html(&captcha.site_key),
+        UPLOAD_PAGE_SUBMIT
+    );
+    let script = format!(
+        r#"  <script src="{}" async defer></script>
+</head>"#,
+        captcha.provider.script_url()
+    );
+    page.replace(UPLOAD_PAGE_SUBMIT, &widget).replacen("</head>", &script, 1)
 }
@@ -0,0 +1,673 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::{BuildHasher, RandomState},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex as StdMutex,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use tokio::{fs, sync::Mutex};
+use tracing::warn;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use super::{Download, DownloadHistory, EntryPatch, Hit, MetadataStore, unix_time};
+use crate::{AppError, FileEntry, keys::ApiKey, tenants::Tenant, users::User};
+
+const RECORD_EXTENSION: &str = "json";
+const KEYS_DIR: &str = "keys";
+const USERS_DIR: &str = "users";
+const TENANTS_DIR: &str = "tenants";
+const BLOBS_DIR: &str = "blobs";
+const DOWNLOADS_DIR: &str = "downloads";
+const TOKEN_UPLOADS_DIR: &str = "token-uploads";
+/// One record per month, of the bytes each meter sent in it.
+const TRANSFERS_DIR: &str = "transfers";
+/// The generated signing key, when `UPLOAD_SIGNING_KEY` is not set. Without
+/// the record extension, so it is not taken for an entry.
+const SIGNING_KEY_FILE: &str = "signing-key";
+/// Entries are spread over this many separately locked maps, so requests for
+/// different ids rarely wait on each other or on the cleanup task.
+const SHARDS: usize = 16;
+
+/// Keeps entries in memory and persists one JSON record per entry so links
+/// survive restarts.
+pub struct JsonMetadataStore {
+    dir: PathBuf,
+    entries: Sharded<FileEntry>,
+    /// Every entry by expiry time, so cleanup only visits the ones that are due.
+    /// Kept in step with `entries` under the entry's shard lock.
+    expiry: StdMutex<BTreeSet<(SystemTime, String)>>,
+    keys: Mutex<HashMap<String, ApiKey>>,
+    users: Mutex<HashMap<String, User>>,
+    tenants: Mutex<HashMap<String, Tenant>>,
+    /// Reference counts of deduplicated blobs, one small record each.
+    blobs: Sharded<u64>,
+    downloads: Sharded<DownloadHistory>,
+    token_uploads: Mutex<HashMap<String, TokenUploads>>,
+    transfers: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+/// Uploads made with one upload token, kept for as long as the token is valid.
+#[derive(Clone, Serialize, Deserialize)]
+struct TokenUploads {
+    uploads: u32,
+    #[serde(with = "unix_time")]
+    keep_until: SystemTime,
+}
+
+/// A map split into `SHARDS` parts by key hash. Everything done to one key,
+/// including its record on disk, happens under that key's shard lock.
+struct Sharded<V> {
+    shards: Vec<Mutex<HashMap<String, V>>>,
+    hasher: RandomState,
+}
+
+impl<V> Sharded<V> {
+    fn new(records: HashMap<String, V>) -> Self {
+        let hasher = RandomState::new();
+        let mut shards: Vec<HashMap<String, V>> = (0..SHARDS).map(|_| HashMap::new()).collect();
+        for (key, value) in records {
+            let index = hasher.hash_one(&key) as usize % SHARDS;
+            shards[index].insert(key, value);
+        }
+        Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            hasher,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+}
+
+impl JsonMetadataStore {
+    /// Opens the record directory and reads every record back. Entries that expired
+    /// while the server was down are kept and purged by the first cleanup tick.
+    pub async fn open(dir: PathBuf) -> Result<Self, AppError> {
+        fs::create_dir_all(dir.join(KEYS_DIR)).await?;
+        fs::create_dir_all(dir.join(USERS_DIR)).await?;
+        fs::create_dir_all(dir.join(TENANTS_DIR)).await?;
+        fs::create_dir_all(dir.join(BLOBS_DIR)).await?;
+        fs::create_dir_all(dir.join(DOWNLOADS_DIR)).await?;
+        fs::create_dir_all(dir.join(TOKEN_UPLOADS_DIR)).await?;
+        fs::create_dir_all(dir.join(TRANSFERS_DIR)).await?;
+
+        let entries: HashMap<String, FileEntry> = read_records(&dir).await?;
+        let keys = read_records(&dir.join(KEYS_DIR)).await?;
+        let users = read_records(&dir.join(USERS_DIR)).await?;
+        let tenants = read_records(&dir.join(TENANTS_DIR)).await?;
+        let blobs = read_records(&dir.join(BLOBS_DIR))
+            .await?
+            .into_iter()
+            .map(|(name, refs)| (blob_key(&name), refs))
+            .collect();
+        let downloads = read_records(&dir.join(DOWNLOADS_DIR)).await?;
+        let token_uploads = read_records(&dir.join(TOKEN_UPLOADS_DIR)).await?;
+        let transfers = read_records(&dir.join(TRANSFERS_DIR)).await?;
+        let expiry = entries
+            .iter()
+            .map(|(id, entry)| (entry.expires_at, id.clone()))
+            .collect();
+
+        Ok(Self {
+            dir,
+            entries: Sharded::new(entries),
+            expiry: StdMutex::new(expiry),
+            keys: Mutex::new(keys),
+            users: Mutex::new(users),
+            tenants: Mutex::new(tenants),
+            blobs: Sharded::new(blobs),
+            downloads: Sharded::new(downloads),
+            token_uploads: Mutex::new(token_uploads),
+            transfers: Mutex::new(transfers),
+        })
+    }
+
+    fn expiry(&self) -> std::sync::MutexGuard<'_, BTreeSet<(SystemTime, String)>> {
+        self.expiry.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn schedule(&self, id: &str, at: SystemTime) {
+        self.expiry().insert((at, id.to_string()));
+    }
+
+    fn unschedule(&self, id: &str, at: SystemTime) {
+        self.expiry().remove(&(at, id.to_string()));
+    }
+
+    async fn save(&self, id: &str, entry: &FileEntry) -> Result<(), AppError> {
+        write_record(&self.record_path(id), entry).await
+    }
+
+    async fn save_key(&self, key: &ApiKey) -> Result<(), AppError> {
+        write_record(&self.key_path(&key.id), key).await
+    }
+
+    async fn delete_record(&self, id: &str) {
+        remove_record(&self.record_path(id)).await;
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    fn key_path(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(KEYS_DIR)
+            .join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    fn user_path(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(USERS_DIR)
+            .join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    fn tenant_path(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(TENANTS_DIR)
+            .join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(BLOBS_DIR)
+            .join(format!("{}.{}", blob_record_name(key), RECORD_EXTENSION))
+    }
+
+    fn downloads_path(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(DOWNLOADS_DIR)
+            .join(format!("{}.{}", id, RECORD_EXTENSION))
+    }
+
+    fn token_uploads_path(&self, jti: &str) -> PathBuf {
+        self.dir
+            .join(TOKEN_UPLOADS_DIR)
+            .join(format!("{}.{}", jti, RECORD_EXTENSION))
+    }
+
+    fn transfers_path(&self, month: &str) -> PathBuf {
+        self.dir
+            .join(TRANSFERS_DIR)
+            .join(format!("{}.{}", month, RECORD_EXTENSION))
+    }
+}
+
+/// Reads every `*.json` record in `dir`, keyed by file stem.
+/// Blob keys under a tenant's prefix, such as `tenants/<id>/<sha256>`, have
+/// slashes, which a record name cannot; blob keys never contain `%`.
+fn blob_record_name(key: &str) -> String {
+    key.replace('/', "%2F")
+}
+
+fn blob_key(record_name: &str) -> String {
+    record_name.replace("%2F", "/")
+}
+
+async fn read_records<T: DeserializeOwned>(dir: &Path) -> Result<HashMap<String, T>, AppError> {
+    let mut records = HashMap::new();
+    let mut items = fs::read_dir(dir).await?;
+
+    while let Some(item) = items.next_entry().await? {
+        let path = item.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(RECORD_EXTENSION) {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let id = id.to_string();
+
+        let record = match fs::read(&path).await {
+            Ok(raw) => serde_json::from_slice::<T>(&raw),
+            Err(err) => {
+                warn!(%err, "failed to read metadata record {:?}", path);
+                continue;
+            }
+        };
+
+        match record {
+            Ok(record) => {
+                records.insert(id, record);
+            }
+            Err(err) => warn!(%err, "skipping corrupt metadata record {:?}", path),
+        }
+    }
+
+    Ok(records)
+}
+
+async fn remove_record(path: &Path) {
+    if let Err(err) = fs::remove_file(path).await
+        && err.kind() != ErrorKind::NotFound
+    {
+        warn!(%err, "failed to remove metadata record {:?}", path);
+    }
+}
+
+async fn write_record<T: Serialize>(path: &Path, record: &T) -> Result<(), AppError> {
+    let raw = serde_json::to_vec(record)
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, raw).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl MetadataStore for JsonMetadataStore {
+    async fn insert(&self, id: &str, entry: &FileEntry) -> Result<bool, AppError> {
+        let mut entries = self.entries.shard(id).lock().await;
+        if entries.contains_key(id) {
+            return Ok(false);
+        }
+        self.save(id, entry).await?;
+        entries.insert(id.to_string(), entry.clone());
+        self.schedule(id, entry.expires_at);
+        Ok(true)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        Ok(self.entries.shard(id).lock().await.get(id).cloned())
+    }
+
+    /// Concurrent downloads of one entry are serialised by its shard lock, so
+    /// each takes a distinct hit and exactly one of them gets the last.
+    async fn take_hit(&self, id: &str, now: SystemTime) -> Result<Hit, AppError> {
+        let mut entries = self.entries.shard(id).lock().await;
+
+        let Some(entry) = entries.get_mut(id) else {
+            return Ok(Hit::Missing);
+        };
+
+        if now >= entry.expires_at {
+            self.unschedule(id, entry.expires_at);
+            let expired = entries.remove(id);
+            self.delete_record(id).await;
+            return Ok(expired.map(Hit::Expired).unwrap_or(Hit::Missing));
+        }
+        if entry.withholds() {
+            return Ok(Hit::Withheld(entry.clone()));
+        }
+
+        entry.remaining_hits = entry.remaining_hits.saturating_sub(1);
+        let entry = entry.clone();
+        let last = entry.remaining_hits == 0;
+
+        if last {
+            self.unschedule(id, entry.expires_at);
+            entries.remove(id);
+            self.delete_record(id).await;
+        } else if let Err(err) = self.save(id, &entry).await {
+            warn!(?err, "failed to persist remaining downloads for {}", id);
+        }
+
+        Ok(Hit::Served { entry, last })
+    }
+
+    async fn update(&self, id: &str, patch: &EntryPatch) -> Result<Option<FileEntry>, AppError> {
+        let mut entries = self.entries.shard(id).lock().await;
+        let Some(entry) = entries.get_mut(id) else {
+            return Ok(None);
+        };
+
+        let mut updated = entry.clone();
+        patch.apply(&mut updated);
+        self.save(id, &updated).await?;
+        if updated.expires_at != entry.expires_at {
+            self.unschedule(id, entry.expires_at);
+            self.schedule(id, updated.expires_at);
+        }
+        *entry = updated.clone();
+        Ok(Some(updated))
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        let removed = {
+            let mut entries = self.entries.shard(id).lock().await;
+            let removed = entries.remove(id);
+            if let Some(entry) = &removed {
+                self.unschedule(id, entry.expires_at);
+            }
+            removed
+        };
+        if removed.is_some() {
+            self.delete_record(id).await;
+        }
+        Ok(removed)
+    }
+
+    async fn take_expired(&self, now: SystemTime) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let mut due = Vec::new();
+        {
+            let mut expiry = self.expiry();
+            while expiry.first().is_some_and(|(at, _)| *at <= now) {
+                if let Some((_, id)) = expiry.pop_first() {
+                    due.push(id);
+                }
+            }
+        }
+
+        let mut expired = Vec::with_capacity(due.len());
+        for id in due {
+            let mut entries = self.entries.shard(&id).lock().await;
+            // An entry extended since it was popped has been rescheduled already.
+            if entries.get(&id).is_some_and(|entry| entry.expires_at <= now)
+                && let Some(entry) = entries.remove(&id)
+            {
+                expired.push((id, entry));
+            }
+        }
+
+        for (id, _) in &expired {
+            self.delete_record(id).await;
+        }
+        Ok(expired)
+    }
+
+    async fn list(&self) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let mut listed = Vec::new();
+        for shard in &self.entries.shards {
+            let entries = shard.lock().await;
+            listed.extend(entries.iter().map(|(id, entry)| (id.clone(), entry.clone())));
+        }
+        Ok(listed)
+    }
+
+    async fn retain_blob(&self, key: &str) -> Result<u64, AppError> {
+        let mut blobs = self.blobs.shard(key).lock().await;
+        let refs = blobs.get(key).copied().unwrap_or(0) + 1;
+        write_record(&self.blob_path(key), &refs).await?;
+        blobs.insert(key.to_string(), refs);
+        Ok(refs)
+    }
+
+    async fn release_blob(&self, key: &str) -> Result<u64, AppError> {
+        let mut blobs = self.blobs.shard(key).lock().await;
+        let Some(refs) = blobs.get(key).copied() else {
+            return Ok(0);
+        };
+
+        let left = refs.saturating_sub(1);
+        if left == 0 {
+            blobs.remove(key);
+            remove_record(&self.blob_path(key)).await;
+        } else {
+            write_record(&self.blob_path(key), &left).await?;
+            blobs.insert(key.to_string(), left);
+        }
+        Ok(left)
+    }
+
+    async fn insert_key(&self, key: &ApiKey) -> Result<(), AppError> {
+        let mut keys = self.keys.lock().await;
+        self.save_key(key).await?;
+        keys.insert(key.id.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn find_key(&self, key_hash: &str) -> Result<Option<ApiKey>, AppError> {
+        Ok(self
+            .keys
+            .lock()
+            .await
+            .values()
+            .find(|key| key.key_hash == key_hash)
+            .cloned())
+    }
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, AppError> {
+        Ok(self.keys.lock().await.values().cloned().collect())
+    }
+
+    async fn revoke_key(&self, id: &str, now: SystemTime) -> Result<Option<ApiKey>, AppError> {
+        let mut keys = self.keys.lock().await;
+        let Some(key) = keys.get_mut(id) else {
+            return Ok(None);
+        };
+
+        let mut updated = key.clone();
+        updated.revoked_at.get_or_insert(now);
+        self.save_key(&updated).await?;
+        *key = updated.clone();
+        Ok(Some(updated))
+    }
+
+    async fn remove_key(&self, id: &str) -> Result<Option<ApiKey>, AppError> {
+        let mut keys = self.keys.lock().await;
+        let removed = keys.remove(id);
+        if removed.is_some() {
+            remove_record(&self.key_path(id)).await;
+        }
+        Ok(removed)
+    }
+
+    async fn record_key_usage(
+        &self,
+        id: &str,
+        bytes: u64,
+        now: SystemTime,
+    ) -> Result<(), AppError> {
+        let mut keys = self.keys.lock().await;
+        let Some(key) = keys.get_mut(id) else {
+            return Ok(());
+        };
+
+        let mut updated = key.clone();
+        updated.uploads += 1;
+        updated.uploaded_bytes += bytes;
+        updated.last_used_at = Some(now);
+        self.save_key(&updated).await?;
+        *key = updated;
+        Ok(())
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AppError> {
+        let mut users = self.users.lock().await;
+        write_record(&self.user_path(&user.id), user).await?;
+        users.insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn get_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        Ok(self.users.lock().await.get(id).cloned())
+    }
+
+    async fn find_user(&self, token_hash: &str) -> Result<Option<User>, AppError> {
+        Ok(self
+            .users
+            .lock()
+            .await
+            .values()
+            .find(|user| user.token_hash == token_hash)
+            .cloned())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError> {
+        Ok(self.users.lock().await.values().cloned().collect())
+    }
+
+    async fn remove_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        let mut users = self.users.lock().await;
+        let removed = users.remove(id);
+        if removed.is_some() {
+            remove_record(&self.user_path(id)).await;
+        }
+        Ok(removed)
+    }
+
+    async fn save_tenant(&self, tenant: &Tenant) -> Result<(), AppError> {
+        let mut tenants = self.tenants.lock().await;
+        write_record(&self.tenant_path(&tenant.id), tenant).await?;
+        tenants.insert(tenant.id.clone(), tenant.clone());
+        Ok(())
+    }
+
+    async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        Ok(self.tenants.lock().await.get(id).cloned())
+    }
+
+    async fn find_tenant(&self, token_hash: &str) -> Result<Option<Tenant>, AppError> {
+        Ok(self
+            .tenants
+            .lock()
+            .await
+            .values()
+            .find(|tenant| tenant.token_hash == token_hash)
+            .cloned())
+    }
+
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, AppError> {
+        Ok(self.tenants.lock().await.values().cloned().collect())
+    }
+
+    async fn remove_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        let mut tenants = self.tenants.lock().await;
+        let removed = tenants.remove(id);
+        if removed.is_some() {
+            remove_record(&self.tenant_path(id)).await;
+        }
+        Ok(removed)
+    }
+
+    /// The files are only ever served by one instance, which always leads.
+    async fn acquire_lease(
+        &self,
+        _name: &str,
+        _holder: &str,
+        _ttl: Duration,
+    ) -> Result<bool, AppError> {
+        Ok(true)
+    }
+
+    async fn record_download(
+        &self,
+        id: &str,
+        owner_token: Option<&str>,
+        download: &Download,
+        keep_until: SystemTime,
+    ) -> Result<(), AppError> {
+        let mut histories = self.downloads.shard(id).lock().await;
+        let mut history = histories.get(id).cloned().unwrap_or_else(|| DownloadHistory {
+            owner_token: owner_token.map(str::to_string),
+            keep_until,
+            downloads: Vec::new(),
+        });
+        history.add(owner_token, download, keep_until);
+        write_record(&self.downloads_path(id), &history).await?;
+        histories.insert(id.to_string(), history);
+        Ok(())
+    }
+
+    async fn download_history(&self, id: &str) -> Result<Option<DownloadHistory>, AppError> {
+        Ok(self.downloads.shard(id).lock().await.get(id).cloned())
+    }
+
+    async fn remove_download_history(&self, id: &str) -> Result<(), AppError> {
+        let mut histories = self.downloads.shard(id).lock().await;
+        if histories.remove(id).is_some() {
+            remove_record(&self.downloads_path(id)).await;
+        }
+        Ok(())
+    }
+
+    async fn purge_download_histories(&self, now: SystemTime) -> Result<usize, AppError> {
+        let mut purged = 0;
+        for shard in &self.downloads.shards {
+            let mut histories = shard.lock().await;
+            let due: Vec<String> = histories
+                .iter()
+                .filter(|(_, history)| history.keep_until <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in due {
+                histories.remove(&id);
+                remove_record(&self.downloads_path(&id)).await;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn token_uploads(&self, jti: &str) -> Result<u32, AppError> {
+        let counts = self.token_uploads.lock().await;
+        Ok(counts.get(jti).map_or(0, |count| count.uploads))
+    }
+
+    async fn claim_token_upload(
+        &self,
+        jti: &str,
+        max: u32,
+        keep_until: SystemTime,
+    ) -> Result<bool, AppError> {
+        let mut counts = self.token_uploads.lock().await;
+        let uploads = counts.get(jti).map_or(0, |count| count.uploads);
+        if uploads >= max {
+            return Ok(false);
+        }
+        let count = TokenUploads {
+            uploads: uploads + 1,
+            keep_until,
+        };
+        write_record(&self.token_uploads_path(jti), &count).await?;
+        counts.insert(jti.to_string(), count);
+        Ok(true)
+    }
+
+    async fn purge_token_uploads(&self, now: SystemTime) -> Result<usize, AppError> {
+        let mut counts = self.token_uploads.lock().await;
+        let due: Vec<String> = counts
+            .iter()
+            .filter(|(_, count)| count.keep_until <= now)
+            .map(|(jti, _)| jti.clone())
+            .collect();
+        for jti in &due {
+            counts.remove(jti);
+            remove_record(&self.token_uploads_path(jti)).await;
+        }
+        Ok(due.len())
+    }
+
+    async fn record_transfer(
+        &self,
+        meter: &str,
+        month: &str,
+        bytes: u64,
+    ) -> Result<(), AppError> {
+        let mut transfers = self.transfers.lock().await;
+        let mut sent = transfers.get(month).cloned().unwrap_or_default();
+        *sent.entry(meter.to_string()).or_default() += bytes;
+        write_record(&self.transfers_path(month), &sent).await?;
+        transfers.insert(month.to_string(), sent);
+        Ok(())
+    }
+
+    async fn transfers(&self, month: &str) -> Result<HashMap<String, u64>, AppError> {
+        let transfers = self.transfers.lock().await;
+        Ok(transfers.get(month).cloned().unwrap_or_default())
+    }
+
+    /// Every change is written out as it is made.
+    async fn signing_key(&self, generated: &str) -> Result<String, AppError> {
+        let path = self.dir.join(SIGNING_KEY_FILE);
+        match fs::read(&path).await {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                write_record(&path, &generated).await?;
+                Ok(generated.to_string())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn flush(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppError, FileEntry,
+    config::{AppConfig, MetadataKind},
+    keys::ApiKey,
+    live::Visitor,
+    report::{self, Report},
+    scan::ScanStatus,
+    tenants::Tenant,
+    users::User,
+};
+
+mod json;
+mod redis;
+mod sqlite;
+
+pub use json::JsonMetadataStore;
+pub use redis::RedisMetadataStore;
+pub use sqlite::SqliteMetadataStore;
+
+/// Outcome of trying to consume one download of an entry.
+pub enum Hit {
+    Missing,
+    /// The entry had expired; its record is gone and the blob should be deleted.
+    Expired(FileEntry),
+    /// The download may proceed. When `last` is set the record is already gone and
+    /// the blob should be deleted once served.
+    Served { entry: FileEntry, last: bool },
+    /// The entry is awaiting or failed its malware scan, or was taken down;
+    /// nothing was consumed.
+    Withheld(FileEntry),
+}
+
+/// Field changes applied to an existing entry in one step.
+#[derive(Clone, Default)]
+pub struct EntryPatch {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub remaining_hits: Option<u32>,
+    pub expires_at: Option<SystemTime>,
+    pub scan: Option<ScanStatus>,
+    /// Replaces the recorded threat whenever `scan` is set.
+    pub threat: Option<String>,
+    pub disabled: Option<bool>,
+    /// Added to the entry's reports.
+    pub report: Option<Report>,
+    /// Drops the entry's reports, before `report` is added.
+    pub clear_reports: bool,
+}
+
+impl EntryPatch {
+    pub fn apply(&self, entry: &mut FileEntry) {
+        if let Some(filename) = &self.filename {
+            entry.filename = filename.clone();
+        }
+        if let Some(content_type) = &self.content_type {
+            entry.content_type = Some(content_type.clone());
+        }
+        if let Some(remaining_hits) = self.remaining_hits {
+            entry.remaining_hits = remaining_hits;
+        }
+        if let Some(expires_at) = self.expires_at {
+            entry.expires_at = expires_at;
+        }
+        if let Some(scan) = self.scan {
+            entry.scan = scan;
+            entry.threat = self.threat.clone();
+        }
+        if let Some(disabled) = self.disabled {
+            entry.disabled = disabled;
+        }
+        if self.clear_reports {
+            entry.reports.clear();
+        }
+        if let Some(report) = &self.report {
+            entry.reports.push(report.clone());
+            let excess = entry.reports.len().saturating_sub(report::MAX_REPORTS);
+            entry.reports.drain(..excess);
+        }
+    }
+}
+
+/// Downloads kept per entry; older ones make way for new ones.
+pub const MAX_RECORDED_DOWNLOADS: usize = 1000;
+
+/// The downloads of one entry, which outlive it until `keep_until` so its owner
+/// can still tell whether it was picked up.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DownloadHistory {
+    /// The entry's owner token, as the entry may be gone by the time it is asked.
+    pub owner_token: Option<String>,
+    #[serde(with = "unix_time")]
+    pub keep_until: SystemTime,
+    /// Oldest first.
+    pub downloads: Vec<Download>,
+}
+
+impl DownloadHistory {
+    /// Adds a download of the entry owned by `owner_token`. A history left by an
+    /// earlier entry under the same id is started over.
+    pub fn add(&mut self, owner_token: Option<&str>, download: &Download, keep_until: SystemTime) {
+        if self.owner_token.as_deref() != owner_token {
+            self.owner_token = owner_token.map(str::to_string);
+            self.downloads.clear();
+        }
+        self.keep_until = keep_until;
+        self.downloads.push(download.clone());
+        let excess = self.downloads.len().saturating_sub(MAX_RECORDED_DOWNLOADS);
+        self.downloads.drain(..excess);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Download {
+    #[serde(with = "unix_time")]
+    pub at: SystemTime,
+    /// A keyed hash of the downloader's address, the same for every download of
+    /// the entry from that address but unrelated across entries.
+    pub client: String,
+    #[serde(flatten)]
+    pub visitor: Visitor,
+}
+
+/// Source of truth for file entries. Implementations must make `take_hit` atomic so
+/// concurrent downloads never hand out more than `remaining_hits`.
+#[async_trait]
+pub trait MetadataStore: Send + Sync {
+    /// Adds a new entry. Returns `false`, leaving the store untouched, when `id`
+    /// is already taken.
+    async fn insert(&self, id: &str, entry: &FileEntry) -> Result<bool, AppError>;
+
+    async fn get(&self, id: &str) -> Result<Option<FileEntry>, AppError>;
+
+    async fn take_hit(&self, id: &str, now: SystemTime) -> Result<Hit, AppError>;
+
+    /// Applies `patch` to the entry, returning the updated entry if it exists.
+    async fn update(&self, id: &str, patch: &EntryPatch) -> Result<Option<FileEntry>, AppError>;
+
+    async fn remove(&self, id: &str) -> Result<Option<FileEntry>, AppError>;
+
+    /// Removes and returns every entry whose expiry is at or before `now`.
+    async fn take_expired(&self, now: SystemTime) -> Result<Vec<(String, FileEntry)>, AppError>;
+
+    async fn list(&self) -> Result<Vec<(String, FileEntry)>, AppError>;
+
+    /// Counts one more entry sharing the content-addressed blob `key`, returning
+    /// the new count.
+    async fn retain_blob(&self, key: &str) -> Result<u64, AppError>;
+
+    /// Drops one reference to `key`, returning how many are left. A key that was
+    /// never retained has none, so its blob belongs to a single entry.
+    async fn release_blob(&self, key: &str) -> Result<u64, AppError>;
+
+    async fn insert_key(&self, key: &ApiKey) -> Result<(), AppError>;
+
+    /// Looks a key up by the hash of its plaintext, revoked or not.
+    async fn find_key(&self, key_hash: &str) -> Result<Option<ApiKey>, AppError>;
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, AppError>;
+
+    /// Marks a key revoked, returning it if it exists.
+    async fn revoke_key(&self, id: &str, now: SystemTime) -> Result<Option<ApiKey>, AppError>;
+
+    /// Deletes a key's record altogether, returning it if it existed.
+    async fn remove_key(&self, id: &str) -> Result<Option<ApiKey>, AppError>;
+
+    /// Counts one more upload of `bytes` against the key.
+    async fn record_key_usage(&self, id: &str, bytes: u64, now: SystemTime)
+    -> Result<(), AppError>;
+
+    /// Adds a user, or replaces the record of the one with the same id.
+    async fn save_user(&self, user: &User) -> Result<(), AppError>;
+
+    async fn get_user(&self, id: &str) -> Result<Option<User>, AppError>;
+
+    /// Looks a user up by the hash of their token.
+    async fn find_user(&self, token_hash: &str) -> Result<Option<User>, AppError>;
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError>;
+
+    /// Deletes a user's record, returning it if it existed. Their uploads stay.
+    async fn remove_user(&self, id: &str) -> Result<Option<User>, AppError>;
+
+    /// Adds a tenant, or replaces the record of the one with the same id.
+    async fn save_tenant(&self, tenant: &Tenant) -> Result<(), AppError>;
+
+    async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError>;
+
+    /// Looks a tenant up by the hash of its current token.
+    async fn find_tenant(&self, token_hash: &str) -> Result<Option<Tenant>, AppError>;
+
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, AppError>;
+
+    /// Deletes a tenant's record, returning it if it existed. Its entries are
+    /// removed separately.
+    async fn remove_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError>;
+
+    /// Claims the lease on a job that only one instance should run at a time, or
+    /// renews it for its current `holder`, returning whether `holder` now holds
+    /// it. A lease that is not renewed within `ttl` passes to the next claimant.
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration)
+    -> Result<bool, AppError>;
+
+    /// Adds `download` to the history of entry `id` (see `DownloadHistory::add`)
+    /// and keeps the history until `keep_until`.
+    async fn record_download(
+        &self,
+        id: &str,
+        owner_token: Option<&str>,
+        download: &Download,
+        keep_until: SystemTime,
+    ) -> Result<(), AppError>;
+
+    async fn download_history(&self, id: &str) -> Result<Option<DownloadHistory>, AppError>;
+
+    async fn remove_download_history(&self, id: &str) -> Result<(), AppError>;
+
+    /// Drops histories kept past their `keep_until`, returning how many.
+    async fn purge_download_histories(&self, now: SystemTime) -> Result<usize, AppError>;
+
+    /// How many uploads the upload token `jti` has made.
+    async fn token_uploads(&self, jti: &str) -> Result<u32, AppError>;
+
+    /// Counts one more upload against token `jti` unless it has made `max`
+    /// already, returning whether it was counted. The count is kept until
+    /// `keep_until`, when the token expires.
+    async fn claim_token_upload(
+        &self,
+        jti: &str,
+        max: u32,
+        keep_until: SystemTime,
+    ) -> Result<bool, AppError>;
+
+    /// Drops the counts of tokens that expired, returning how many.
+    async fn purge_token_uploads(&self, now: SystemTime) -> Result<usize, AppError>;
+
+    /// Adds `bytes` to what `meter` sent in `month` (see `bandwidth`).
+    async fn record_transfer(&self, meter: &str, month: &str, bytes: u64)
+    -> Result<(), AppError>;
+
+    /// The bytes every meter sent in `month`, by meter.
+    async fn transfers(&self, month: &str) -> Result<HashMap<String, u64>, AppError>;
+
+    /// The signing key used when `UPLOAD_SIGNING_KEY` is not set, kept with the
+    /// metadata so that address hashes and signed links survive restarts. The
+    /// first call stores `generated`; later ones return what was stored.
+    async fn signing_key(&self, generated: &str) -> Result<String, AppError>;
+
+    /// Persists whatever has not reached its final place yet, before the server
+    /// exits.
+    async fn flush(&self) -> Result<(), AppError>;
+}
+
+pub async fn from_config(config: &AppConfig) -> Result<Box<dyn MetadataStore>, AppError> {
+    match config.metadata_kind {
+        MetadataKind::Json => Ok(Box::new(
+            JsonMetadataStore::open(config.metadata_dir.clone()).await?,
+        )),
+        MetadataKind::Sqlite => Ok(Box::new(
+            SqliteMetadataStore::open(config.sqlite_path.clone()).await?,
+        )),
+        MetadataKind::Redis => {
+            let url = config.redis_url.as_deref().ok_or_else(|| {
+                AppError::Config("METADATA_BACKEND=redis requires REDIS_URL".to_string())
+            })?;
+            Ok(Box::new(
+                RedisMetadataStore::open(url, config.redis_prefix.clone()).await?,
+            ))
+        }
+    }
+}
+
+pub fn unix_epoch() -> SystemTime {
+    UNIX_EPOCH
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Serializes a `SystemTime` as whole seconds since the unix epoch.
+pub mod unix_time {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(super::unix_seconds(*time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let secs = u64::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// The same encoding for optional timestamps, with `null` for `None`.
+    pub mod option {
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            time: &Option<SystemTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match time {
+                Some(time) => serializer.serialize_some(&super::super::unix_seconds(*time)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<SystemTime>, D::Error> {
+            let secs = Option::<u64>::deserialize(deserializer)?;
+            Ok(secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+        }
+    }
+}
@@ -0,0 +1,699 @@
+//! Keeps entries in Redis, so several replicas behind a load balancer share one
+//! view of every link. Records are the same JSON as the JSON store's. Changes that
+//! depend on the current record, such as taking a download, run as
+//! `WATCH`/`MULTI`/`EXEC` transactions and start over when another replica got in
+//! first, so no download is ever handed out twice.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex as StdMutex,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use redis::{
+    AsyncConnectionConfig, Client, Cmd, FromRedisValue, Pipeline, RedisError,
+    aio::{ConnectionManager, ConnectionManagerConfig, MultiplexedConnection},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Download, DownloadHistory, EntryPatch, Hit, MetadataStore, unix_seconds};
+use crate::{AppError, FileEntry, keys::ApiKey, tenants::Tenant, users::User};
+
+/// Idle transaction connections kept for reuse; busier moments open more.
+const POOL_SIZE: usize = 8;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Entries fetched per `MGET` when listing.
+const LIST_BATCH: usize = 500;
+
+pub struct RedisMetadataStore {
+    client: Client,
+    /// Carries every command outside a transaction and reconnects by itself.
+    manager: ConnectionManager,
+    /// A `WATCH` covers everything sent on its connection, so each transaction
+    /// runs on a connection of its own.
+    idle: StdMutex<Vec<MultiplexedConnection>>,
+    prefix: String,
+}
+
+fn into_io(err: RedisError) -> io::Error {
+    if err.is_timeout() {
+        io::Error::new(io::ErrorKind::TimedOut, "redis did not answer in time")
+    } else {
+        io::Error::other(format!("redis: {}", err))
+    }
+}
+
+/// The writes as one `MULTI`/`EXEC` transaction.
+fn atomic(writes: Vec<Cmd>) -> Pipeline {
+    let mut pipeline = redis::pipe();
+    pipeline.atomic();
+    for write in writes {
+        pipeline.add_command(write);
+    }
+    pipeline
+}
+
+fn to_json<T: Serialize>(record: &T) -> io::Result<String> {
+    serde_json::to_string(record).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn from_json<T: DeserializeOwned>(raw: &str) -> io::Result<T> {
+    serde_json::from_str(raw).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Milliseconds from now until `until`, for a key's `PX` expiry.
+fn millis_until(until: SystemTime) -> u64 {
+    let left = until.duration_since(SystemTime::now()).unwrap_or_default();
+    u64::try_from(left.as_millis()).unwrap_or(u64::MAX).max(1)
+}
+
+impl RedisMetadataStore {
+    /// Checks that the server is reachable before the app starts relying on it.
+    pub async fn open(url: &str, prefix: String) -> Result<Self, AppError> {
+        let client = Client::open(url)
+            .map_err(|err| AppError::Config(format!("invalid REDIS_URL: {}", err)))?;
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(Some(COMMAND_TIMEOUT))
+            .set_response_timeout(Some(COMMAND_TIMEOUT));
+        let manager = client
+            .get_connection_manager_with_config(config)
+            .await
+            .map_err(into_io)?;
+        let store = Self {
+            client,
+            manager,
+            idle: StdMutex::new(Vec::new()),
+            prefix,
+        };
+        store.query::<()>(redis::cmd("PING")).await?;
+        Ok(store)
+    }
+
+    async fn query<T: FromRedisValue>(&self, command: Cmd) -> io::Result<T> {
+        command
+            .query_async(&mut self.manager.clone())
+            .await
+            .map_err(into_io)
+    }
+
+    async fn connect(&self) -> io::Result<MultiplexedConnection> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop();
+        if let Some(conn) = idle {
+            return Ok(conn);
+        }
+        let config = AsyncConnectionConfig::new()
+            .set_connection_timeout(Some(COMMAND_TIMEOUT))
+            .set_response_timeout(Some(COMMAND_TIMEOUT));
+        self.client
+            .get_multiplexed_async_connection_with_config(&config)
+            .await
+            .map_err(into_io)
+    }
+
+    /// Hands a healthy connection back; one that failed midway is just dropped.
+    fn release(&self, conn: MultiplexedConnection) {
+        let mut idle = self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if idle.len() < POOL_SIZE {
+            idle.push(conn);
+        }
+    }
+
+    /// Reads a record with `read` while watching `watch`, then applies the writes
+    /// `decide` asks for in one transaction. Starts over when the watched key
+    /// changed in between, so `decide` always sees what it overwrites.
+    async fn transact<T>(
+        &self,
+        watch: &str,
+        read: Cmd,
+        mut decide: impl FnMut(Option<String>) -> Result<(Vec<Cmd>, T), AppError>,
+    ) -> Result<T, AppError> {
+        let mut conn = self.connect().await?;
+        loop {
+            redis::cmd("WATCH")
+                .arg(watch)
+                .exec_async(&mut conn)
+                .await
+                .map_err(into_io)?;
+            let current = read.query_async(&mut conn).await.map_err(into_io)?;
+            let (writes, outcome) = match decide(current) {
+                Ok((writes, outcome)) if !writes.is_empty() => (writes, outcome),
+                decided => {
+                    redis::cmd("UNWATCH")
+                        .exec_async(&mut conn)
+                        .await
+                        .map_err(into_io)?;
+                    self.release(conn);
+                    return decided.map(|(_, outcome)| outcome);
+                }
+            };
+
+            // `EXEC` answers nil when the watched key changed.
+            let applied: Option<()> =
+                atomic(writes).query_async(&mut conn).await.map_err(into_io)?;
+            if applied.is_some() {
+                self.release(conn);
+                return Ok(outcome);
+            }
+        }
+    }
+
+    fn entry_key(&self, id: &str) -> String {
+        format!("{}entry:{}", self.prefix, id)
+    }
+
+    /// Every entry id, scored by expiry; also what `list` walks.
+    fn expiry_key(&self) -> String {
+        format!("{}expiry", self.prefix)
+    }
+
+    fn blob_key(&self, key: &str) -> String {
+        format!("{}blob:{}", self.prefix, key)
+    }
+
+    /// Names the instance running a job such as cleanup; expires unless renewed.
+    fn lease_key(&self, name: &str) -> String {
+        format!("{}lease:{}", self.prefix, name)
+    }
+
+    fn downloads_key(&self, id: &str) -> String {
+        format!("{}downloads:{}", self.prefix, id)
+    }
+
+    fn api_keys_key(&self) -> String {
+        format!("{}apikeys", self.prefix)
+    }
+
+    fn api_key_hashes_key(&self) -> String {
+        format!("{}apikey-hashes", self.prefix)
+    }
+
+    fn users_key(&self) -> String {
+        format!("{}users", self.prefix)
+    }
+
+    fn user_tokens_key(&self) -> String {
+        format!("{}user-tokens", self.prefix)
+    }
+
+    fn tenants_key(&self) -> String {
+        format!("{}tenants", self.prefix)
+    }
+
+    fn tenant_tokens_key(&self) -> String {
+        format!("{}tenant-tokens", self.prefix)
+    }
+
+    /// Uploads made with an upload token; expires along with the token.
+    fn token_uploads_key(&self, jti: &str) -> String {
+        format!("{}token-uploads:{}", self.prefix, jti)
+    }
+
+    /// Bytes sent in `month` by meter.
+    fn transfers_key(&self, month: &str) -> String {
+        format!("{}transfers:{}", self.prefix, month)
+    }
+
+    fn signing_key_key(&self) -> String {
+        format!("{}signing-key", self.prefix)
+    }
+
+    fn forget(&self, id: &str) -> Vec<Cmd> {
+        vec![
+            Cmd::del(self.entry_key(id)),
+            Cmd::zrem(self.expiry_key(), id),
+        ]
+    }
+
+    fn save(&self, id: &str, entry: &FileEntry) -> io::Result<Vec<Cmd>> {
+        Ok(vec![
+            Cmd::set(self.entry_key(id), to_json(entry)?),
+            Cmd::zadd(self.expiry_key(), id, unix_seconds(entry.expires_at)),
+        ])
+    }
+
+    /// Applies `change` to an API key, returning the updated key if it exists.
+    async fn update_key(
+        &self,
+        id: &str,
+        change: impl Fn(&mut ApiKey),
+    ) -> Result<Option<ApiKey>, AppError> {
+        let keys = self.api_keys_key();
+        self.transact(&keys, Cmd::hget(&keys, id), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), None));
+            };
+            let mut key: ApiKey = from_json(&raw)?;
+            change(&mut key);
+            let write = Cmd::hset(&keys, id, to_json(&key)?);
+            Ok((vec![write], Some(key)))
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl MetadataStore for RedisMetadataStore {
+    async fn insert(&self, id: &str, entry: &FileEntry) -> Result<bool, AppError> {
+        let key = self.entry_key(id);
+        self.transact(&key, Cmd::get(&key), |current| match current {
+            Some(_) => Ok((Vec::new(), false)),
+            None => Ok((self.save(id, entry)?, true)),
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        match self.query::<Option<String>>(Cmd::get(self.entry_key(id))).await? {
+            Some(raw) => Ok(Some(from_json(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn take_hit(&self, id: &str, now: SystemTime) -> Result<Hit, AppError> {
+        let key = self.entry_key(id);
+        self.transact(&key, Cmd::get(&key), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), Hit::Missing));
+            };
+            let mut entry: FileEntry = from_json(&raw)?;
+            if now >= entry.expires_at {
+                return Ok((self.forget(id), Hit::Expired(entry)));
+            }
+            if entry.withholds() {
+                return Ok((Vec::new(), Hit::Withheld(entry)));
+            }
+
+            entry.remaining_hits = entry.remaining_hits.saturating_sub(1);
+            let last = entry.remaining_hits == 0;
+            let writes = if last {
+                self.forget(id)
+            } else {
+                vec![Cmd::set(&key, to_json(&entry)?)]
+            };
+            Ok((writes, Hit::Served { entry, last }))
+        })
+        .await
+    }
+
+    async fn update(&self, id: &str, patch: &EntryPatch) -> Result<Option<FileEntry>, AppError> {
+        let key = self.entry_key(id);
+        self.transact(&key, Cmd::get(&key), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), None));
+            };
+            let mut entry: FileEntry = from_json(&raw)?;
+            patch.apply(&mut entry);
+            Ok((self.save(id, &entry)?, Some(entry)))
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        let key = self.entry_key(id);
+        self.transact(&key, Cmd::get(&key), |current| match current {
+            Some(raw) => Ok((self.forget(id), Some(from_json(&raw)?))),
+            None => Ok((Vec::new(), None)),
+        })
+        .await
+    }
+
+    /// Replicas may clean up at the same time; each entry is taken by one of them.
+    async fn take_expired(&self, now: SystemTime) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let due: Vec<String> = self
+            .query(Cmd::zrangebyscore(self.expiry_key(), "-inf", unix_seconds(now)))
+            .await?;
+
+        let mut expired = Vec::new();
+        for id in due {
+            let key = self.entry_key(&id);
+            let taken = self
+                .transact(&key, Cmd::get(&key), |current| {
+                    let Some(raw) = current else {
+                        // Left behind by a crash between the two writes.
+                        return Ok((vec![Cmd::zrem(self.expiry_key(), &id)], None));
+                    };
+                    let entry: FileEntry = from_json(&raw)?;
+                    if entry.expires_at <= now {
+                        Ok((self.forget(&id), Some(entry)))
+                    } else {
+                        Ok((Vec::new(), None))
+                    }
+                })
+                .await?;
+            if let Some(entry) = taken {
+                expired.push((id, entry));
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn list(&self) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let ids: Vec<String> = self.query(Cmd::zrange(self.expiry_key(), 0, -1)).await?;
+
+        let mut listed = Vec::with_capacity(ids.len());
+        for batch in ids.chunks(LIST_BATCH) {
+            let keys: Vec<String> = batch.iter().map(|id| self.entry_key(id)).collect();
+            let mut mget = redis::cmd("MGET");
+            mget.arg(keys);
+            let records: Vec<Option<String>> = self.query(mget).await?;
+            for (id, raw) in batch.iter().zip(records) {
+                if let Some(raw) = raw {
+                    listed.push((id.clone(), from_json(&raw)?));
+                }
+            }
+        }
+        Ok(listed)
+    }
+
+    async fn retain_blob(&self, key: &str) -> Result<u64, AppError> {
+        let refs: i64 = self.query(Cmd::incr(self.blob_key(key), 1)).await?;
+        Ok(refs.max(0) as u64)
+    }
+
+    async fn release_blob(&self, key: &str) -> Result<u64, AppError> {
+        let blob = self.blob_key(key);
+        self.transact(&blob, Cmd::get(&blob), |current| {
+            let Some(refs) = current.and_then(|refs| refs.parse::<u64>().ok()) else {
+                return Ok((Vec::new(), 0));
+            };
+            let left = refs.saturating_sub(1);
+            let write = if left == 0 {
+                Cmd::del(&blob)
+            } else {
+                Cmd::set(&blob, left)
+            };
+            Ok((vec![write], left))
+        })
+        .await
+    }
+
+    async fn insert_key(&self, key: &ApiKey) -> Result<(), AppError> {
+        atomic(vec![
+            Cmd::hset(self.api_keys_key(), &key.id, to_json(key)?),
+            Cmd::hset(self.api_key_hashes_key(), &key.key_hash, &key.id),
+        ])
+        .exec_async(&mut self.manager.clone())
+        .await
+        .map_err(into_io)?;
+        Ok(())
+    }
+
+    async fn find_key(&self, key_hash: &str) -> Result<Option<ApiKey>, AppError> {
+        let Some(id) = self
+            .query::<Option<String>>(Cmd::hget(self.api_key_hashes_key(), key_hash))
+            .await?
+        else {
+            return Ok(None);
+        };
+        match self.query::<Option<String>>(Cmd::hget(self.api_keys_key(), &id)).await? {
+            Some(raw) => Ok(Some(from_json(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, AppError> {
+        let mut keys = Vec::new();
+        for raw in self.query::<Vec<String>>(Cmd::hvals(self.api_keys_key())).await? {
+            keys.push(from_json(&raw)?);
+        }
+        Ok(keys)
+    }
+
+    async fn revoke_key(&self, id: &str, now: SystemTime) -> Result<Option<ApiKey>, AppError> {
+        self.update_key(id, |key| {
+            key.revoked_at.get_or_insert(now);
+        })
+        .await
+    }
+
+    async fn remove_key(&self, id: &str) -> Result<Option<ApiKey>, AppError> {
+        let keys = self.api_keys_key();
+        self.transact(&keys, Cmd::hget(&keys, id), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), None));
+            };
+            let key: ApiKey = from_json(&raw)?;
+            let writes = vec![
+                Cmd::hdel(&keys, id),
+                Cmd::hdel(self.api_key_hashes_key(), &key.key_hash),
+            ];
+            Ok((writes, Some(key)))
+        })
+        .await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AppError> {
+        atomic(vec![
+            Cmd::hset(self.users_key(), &user.id, to_json(user)?),
+            Cmd::hset(self.user_tokens_key(), &user.token_hash, &user.id),
+        ])
+        .exec_async(&mut self.manager.clone())
+        .await
+        .map_err(into_io)?;
+        Ok(())
+    }
+
+    async fn get_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        match self.query::<Option<String>>(Cmd::hget(self.users_key(), id)).await? {
+            Some(raw) => Ok(Some(from_json(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_user(&self, token_hash: &str) -> Result<Option<User>, AppError> {
+        let Some(id) = self
+            .query::<Option<String>>(Cmd::hget(self.user_tokens_key(), token_hash))
+            .await?
+        else {
+            return Ok(None);
+        };
+        self.get_user(&id).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError> {
+        let mut users = Vec::new();
+        for raw in self.query::<Vec<String>>(Cmd::hvals(self.users_key())).await? {
+            users.push(from_json(&raw)?);
+        }
+        Ok(users)
+    }
+
+    async fn remove_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        let users = self.users_key();
+        self.transact(&users, Cmd::hget(&users, id), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), None));
+            };
+            let user: User = from_json(&raw)?;
+            let writes = vec![
+                Cmd::hdel(&users, id),
+                Cmd::hdel(self.user_tokens_key(), &user.token_hash),
+            ];
+            Ok((writes, Some(user)))
+        })
+        .await
+    }
+
+    /// Also unindexes the token the saved record replaces, if any.
+    async fn save_tenant(&self, tenant: &Tenant) -> Result<(), AppError> {
+        let tenants = self.tenants_key();
+        let tokens = self.tenant_tokens_key();
+        let record = to_json(tenant)?;
+        self.transact(&tenants, Cmd::hget(&tenants, &tenant.id), |current| {
+            let mut writes = Vec::new();
+            if let Some(raw) = current {
+                let previous: Tenant = from_json(&raw)?;
+                if previous.token_hash != tenant.token_hash {
+                    writes.push(Cmd::hdel(&tokens, &previous.token_hash));
+                }
+            }
+            writes.push(Cmd::hset(&tenants, &tenant.id, &record));
+            writes.push(Cmd::hset(&tokens, &tenant.token_hash, &tenant.id));
+            Ok((writes, ()))
+        })
+        .await
+    }
+
+    async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        match self.query::<Option<String>>(Cmd::hget(self.tenants_key(), id)).await? {
+            Some(raw) => Ok(Some(from_json(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_tenant(&self, token_hash: &str) -> Result<Option<Tenant>, AppError> {
+        let Some(id) = self
+            .query::<Option<String>>(Cmd::hget(self.tenant_tokens_key(), token_hash))
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(self
+            .get_tenant(&id)
+            .await?
+            .filter(|tenant| tenant.token_hash == token_hash))
+    }
+
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, AppError> {
+        let mut tenants = Vec::new();
+        for raw in self.query::<Vec<String>>(Cmd::hvals(self.tenants_key())).await? {
+            tenants.push(from_json(&raw)?);
+        }
+        Ok(tenants)
+    }
+
+    async fn remove_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        let tenants = self.tenants_key();
+        self.transact(&tenants, Cmd::hget(&tenants, id), |current| {
+            let Some(raw) = current else {
+                return Ok((Vec::new(), None));
+            };
+            let tenant: Tenant = from_json(&raw)?;
+            let writes = vec![
+                Cmd::hdel(&tenants, id),
+                Cmd::hdel(self.tenant_tokens_key(), &tenant.token_hash),
+            ];
+            Ok((writes, Some(tenant)))
+        })
+        .await
+    }
+
+    async fn record_key_usage(
+        &self,
+        id: &str,
+        bytes: u64,
+        now: SystemTime,
+    ) -> Result<(), AppError> {
+        self.update_key(id, |key| {
+            key.uploads += 1;
+            key.uploaded_bytes += bytes;
+            key.last_used_at = Some(now);
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> Result<bool, AppError> {
+        let key = self.lease_key(name);
+        let millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1);
+        self.transact(&key, Cmd::get(&key), |current| match current {
+            Some(owner) if owner != holder => Ok((Vec::new(), false)),
+            _ => Ok((vec![Cmd::pset_ex(&key, holder, millis)], true)),
+        })
+        .await
+    }
+
+    /// The history's key expires at `keep_until`, so Redis purges it by itself.
+    async fn record_download(
+        &self,
+        id: &str,
+        owner_token: Option<&str>,
+        download: &Download,
+        keep_until: SystemTime,
+    ) -> Result<(), AppError> {
+        let key = self.downloads_key(id);
+        let millis = millis_until(keep_until);
+        self.transact(&key, Cmd::get(&key), |current| {
+            let mut history = match current {
+                Some(raw) => from_json(&raw)?,
+                None => DownloadHistory {
+                    owner_token: owner_token.map(str::to_string),
+                    keep_until,
+                    downloads: Vec::new(),
+                },
+            };
+            history.add(owner_token, download, keep_until);
+            let write = Cmd::pset_ex(&key, to_json(&history)?, millis);
+            Ok((vec![write], ()))
+        })
+        .await
+    }
+
+    async fn download_history(&self, id: &str) -> Result<Option<DownloadHistory>, AppError> {
+        match self.query::<Option<String>>(Cmd::get(self.downloads_key(id))).await? {
+            Some(raw) => Ok(Some(from_json(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn remove_download_history(&self, id: &str) -> Result<(), AppError> {
+        self.query::<()>(Cmd::del(self.downloads_key(id))).await?;
+        Ok(())
+    }
+
+    async fn purge_download_histories(&self, _now: SystemTime) -> Result<usize, AppError> {
+        Ok(0)
+    }
+
+    async fn token_uploads(&self, jti: &str) -> Result<u32, AppError> {
+        let count: Option<String> = self.query(Cmd::get(self.token_uploads_key(jti))).await?;
+        Ok(count.and_then(|count| count.parse().ok()).unwrap_or(0))
+    }
+
+    async fn claim_token_upload(
+        &self,
+        jti: &str,
+        max: u32,
+        keep_until: SystemTime,
+    ) -> Result<bool, AppError> {
+        let key = self.token_uploads_key(jti);
+        let millis = millis_until(keep_until);
+        self.transact(&key, Cmd::get(&key), |current| {
+            let uploads: u32 = current.and_then(|count| count.parse().ok()).unwrap_or(0);
+            if uploads >= max {
+                return Ok((Vec::new(), false));
+            }
+            Ok((vec![Cmd::pset_ex(&key, uploads + 1, millis)], true))
+        })
+        .await
+    }
+
+    /// Counts expire at `keep_until`, so Redis purges them by itself.
+    async fn purge_token_uploads(&self, _now: SystemTime) -> Result<usize, AppError> {
+        Ok(0)
+    }
+
+    async fn record_transfer(
+        &self,
+        meter: &str,
+        month: &str,
+        bytes: u64,
+    ) -> Result<(), AppError> {
+        self.query::<i64>(Cmd::hincr(self.transfers_key(month), meter, bytes))
+            .await?;
+        Ok(())
+    }
+
+    async fn transfers(&self, month: &str) -> Result<HashMap<String, u64>, AppError> {
+        let fields: HashMap<String, String> =
+            self.query(Cmd::hgetall(self.transfers_key(month))).await?;
+        Ok(fields
+            .into_iter()
+            .filter_map(|(meter, bytes)| Some((meter, bytes.parse().ok()?)))
+            .collect())
+    }
+
+    /// Every instance sharing the prefix ends up with the key the first one set.
+    async fn signing_key(&self, generated: &str) -> Result<String, AppError> {
+        let key = self.signing_key_key();
+        self.query::<()>(Cmd::set_nx(&key, generated)).await?;
+        let stored: Option<String> = self.query(Cmd::get(&key)).await?;
+        Ok(stored.unwrap_or_else(|| generated.to_string()))
+    }
+
+    /// Redis has the data already; persisting it is up to its own settings.
+    async fn flush(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
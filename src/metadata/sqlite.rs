@@ -0,0 +1,947 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use tokio::task;
+
+use super::{
+    Download, DownloadHistory, EntryPatch, Hit, MAX_RECORDED_DOWNLOADS, MetadataStore, unix_seconds,
+};
+use crate::{
+    AppError, EntryKind, FileEntry, compression::Codec, keys::ApiKey, live::Visitor,
+    report::Report, scan::ScanStatus, tenants::Tenant, users::User,
+};
+
+/// Schema changes applied in order; `PRAGMA user_version` records how many ran.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE entries (
+        id TEXT PRIMARY KEY,
+        storage_key TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        expires_at INTEGER NOT NULL,
+        remaining_hits INTEGER NOT NULL,
+        content_type TEXT
+    );
+    CREATE INDEX entries_expires_at ON entries (expires_at);",
+    "ALTER TABLE entries ADD COLUMN size INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE entries ADD COLUMN delete_token TEXT;",
+    "ALTER TABLE entries ADD COLUMN owner_token TEXT;",
+    "ALTER TABLE entries ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE api_keys (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL,
+        revoked_at INTEGER,
+        max_upload_bytes INTEGER,
+        max_uploads INTEGER,
+        uploads INTEGER NOT NULL DEFAULT 0,
+        uploaded_bytes INTEGER NOT NULL DEFAULT 0,
+        last_used_at INTEGER
+    );",
+    "ALTER TABLE entries ADD COLUMN kind TEXT NOT NULL DEFAULT 'file';",
+    "ALTER TABLE entries ADD COLUMN sha256 TEXT;",
+    "CREATE TABLE blobs (
+        key TEXT PRIMARY KEY,
+        refs INTEGER NOT NULL
+    );",
+    "ALTER TABLE entries ADD COLUMN scan TEXT NOT NULL DEFAULT 'unscanned';
+    ALTER TABLE entries ADD COLUMN threat TEXT;",
+    "ALTER TABLE entries ADD COLUMN compression TEXT;
+    ALTER TABLE entries ADD COLUMN compressed_size INTEGER;",
+    "CREATE TABLE download_histories (
+        entry_id TEXT PRIMARY KEY,
+        owner_token TEXT,
+        keep_until INTEGER NOT NULL
+    );
+    CREATE TABLE downloads (
+        entry_id TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        network TEXT,
+        agent TEXT
+    );
+    CREATE INDEX downloads_entry_id ON downloads (entry_id);",
+    "ALTER TABLE entries ADD COLUMN api_key TEXT;
+    ALTER TABLE entries ADD COLUMN uploader TEXT;",
+    "ALTER TABLE entries ADD COLUMN subject TEXT;",
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL,
+        storage_quota_bytes INTEGER,
+        max_upload_bytes INTEGER,
+        default_ttl_secs INTEGER
+    );
+    ALTER TABLE entries ADD COLUMN user_id TEXT;",
+    "CREATE TABLE tenants (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL,
+        storage_quota_bytes INTEGER,
+        max_upload_bytes INTEGER,
+        default_ttl_secs INTEGER
+    );",
+    "CREATE TABLE token_uploads (
+        jti TEXT PRIMARY KEY,
+        uploads INTEGER NOT NULL,
+        keep_until INTEGER NOT NULL
+    );",
+    "ALTER TABLE downloads ADD COLUMN country TEXT;",
+    "ALTER TABLE entries ADD COLUMN reports TEXT;
+    ALTER TABLE entries ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE transfers (
+        meter TEXT NOT NULL,
+        month TEXT NOT NULL,
+        bytes INTEGER NOT NULL,
+        PRIMARY KEY (meter, month)
+    );
+    ALTER TABLE api_keys ADD COLUMN monthly_transfer_bytes INTEGER;
+    ALTER TABLE tenants ADD COLUMN monthly_transfer_bytes INTEGER;",
+    "CREATE TABLE settings (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+];
+
+const ENTRY_COLUMNS: &str = "id, storage_key, filename, expires_at, remaining_hits, \
+    content_type, size, delete_token, owner_token, created_at, kind, sha256, scan, threat, \
+    compression, compressed_size, api_key, uploader, subject, user_id, reports, disabled";
+
+const KEY_COLUMNS: &str = "id, name, key_hash, created_at, revoked_at, max_upload_bytes, \
+    max_uploads, uploads, uploaded_bytes, last_used_at, monthly_transfer_bytes";
+
+const USER_COLUMNS: &str = "id, name, token_hash, created_at, storage_quota_bytes, \
+    max_upload_bytes, default_ttl_secs";
+
+const TENANT_COLUMNS: &str = "id, name, token_hash, created_at, storage_quota_bytes, \
+    max_upload_bytes, default_ttl_secs, monthly_transfer_bytes";
+
+/// Stores entries in a SQLite database so they can be inspected out-of-band.
+pub struct SqliteMetadataStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteMetadataStore {
+    pub async fn open(path: PathBuf) -> Result<Self, AppError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let conn = task::spawn_blocking(move || -> rusqlite::Result<Connection> {
+            let mut conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            migrate(&mut conn)?;
+            Ok(conn)
+        })
+        .await
+        .map_err(join_error)?
+        .map_err(sql_error)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut conn)
+        })
+        .await
+        .map_err(join_error)?
+        .map_err(sql_error)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn row_to_entry(row: &Row<'_>) -> rusqlite::Result<(String, FileEntry)> {
+    Ok((
+        row.get(0)?,
+        FileEntry {
+            key: row.get(1)?,
+            filename: row.get(2)?,
+            expires_at: from_timestamp(row.get(3)?),
+            remaining_hits: row.get(4)?,
+            content_type: row.get(5)?,
+            size: row.get::<_, i64>(6)?.max(0) as u64,
+            delete_token: row.get(7)?,
+            owner_token: row.get(8)?,
+            created_at: from_timestamp(row.get(9)?),
+            kind: EntryKind::parse(&row.get::<_, String>(10)?).unwrap_or_default(),
+            sha256: row.get(11)?,
+            scan: ScanStatus::parse(&row.get::<_, String>(12)?).unwrap_or_default(),
+            threat: row.get(13)?,
+            compression: row
+                .get::<_, Option<String>>(14)?
+                .and_then(|codec| Codec::parse(&codec)),
+            compressed_size: row.get::<_, Option<i64>>(15)?.map(|bytes| bytes.max(0) as u64),
+            api_key: row.get(16)?,
+            uploader: row.get(17)?,
+            subject: row.get(18)?,
+            user: row.get(19)?,
+            reports: row
+                .get::<_, Option<String>>(20)?
+                .and_then(|reports| serde_json::from_str(&reports).ok())
+                .unwrap_or_default(),
+            disabled: row.get(21)?,
+        },
+    ))
+}
+
+fn row_to_key(row: &Row<'_>) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        key_hash: row.get(2)?,
+        created_at: from_timestamp(row.get(3)?),
+        revoked_at: row.get::<_, Option<i64>>(4)?.map(from_timestamp),
+        max_upload_bytes: row.get::<_, Option<i64>>(5)?.map(|bytes| bytes.max(0) as u64),
+        max_uploads: row.get::<_, Option<i64>>(6)?.map(|count| count.max(0) as u64),
+        uploads: row.get::<_, i64>(7)?.max(0) as u64,
+        uploaded_bytes: row.get::<_, i64>(8)?.max(0) as u64,
+        last_used_at: row.get::<_, Option<i64>>(9)?.map(from_timestamp),
+        monthly_transfer_bytes: row.get::<_, Option<i64>>(10)?.map(|bytes| bytes.max(0) as u64),
+    })
+}
+
+fn row_to_user(row: &Row<'_>) -> rusqlite::Result<User> {
+    let limit = |value: Option<i64>| value.map(|value| value.max(0) as u64);
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        token_hash: row.get(2)?,
+        created_at: from_timestamp(row.get(3)?),
+        storage_quota_bytes: limit(row.get(4)?),
+        max_upload_bytes: limit(row.get(5)?),
+        default_ttl_secs: limit(row.get(6)?),
+    })
+}
+
+fn select_user(conn: &Connection, filter: &str, value: &str) -> rusqlite::Result<Option<User>> {
+    conn.query_row(
+        &format!("SELECT {} FROM users WHERE {} = ?1", USER_COLUMNS, filter),
+        [value],
+        row_to_user,
+    )
+    .optional()
+}
+
+fn row_to_tenant(row: &Row<'_>) -> rusqlite::Result<Tenant> {
+    let limit = |value: Option<i64>| value.map(|value| value.max(0) as u64);
+    Ok(Tenant {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        token_hash: row.get(2)?,
+        created_at: from_timestamp(row.get(3)?),
+        storage_quota_bytes: limit(row.get(4)?),
+        max_upload_bytes: limit(row.get(5)?),
+        default_ttl_secs: limit(row.get(6)?),
+        monthly_transfer_bytes: limit(row.get(7)?),
+    })
+}
+
+fn select_tenant(
+    conn: &Connection,
+    filter: &str,
+    value: &str,
+) -> rusqlite::Result<Option<Tenant>> {
+    conn.query_row(
+        &format!("SELECT {} FROM tenants WHERE {} = ?1", TENANT_COLUMNS, filter),
+        [value],
+        row_to_tenant,
+    )
+    .optional()
+}
+
+fn select_key(conn: &Connection, filter: &str, value: &str) -> rusqlite::Result<Option<ApiKey>> {
+    conn.query_row(
+        &format!("SELECT {} FROM api_keys WHERE {} = ?1", KEY_COLUMNS, filter),
+        [value],
+        row_to_key,
+    )
+    .optional()
+}
+
+fn select_entry(conn: &Connection, id: &str) -> rusqlite::Result<Option<FileEntry>> {
+    conn.query_row(
+        &format!("SELECT {} FROM entries WHERE id = ?1", ENTRY_COLUMNS),
+        [id],
+        row_to_entry,
+    )
+    .optional()
+    .map(|row| row.map(|(_, entry)| entry))
+}
+
+/// Reports are only ever read along with their entry, so they are kept in it.
+fn reports_json(reports: &[Report]) -> Option<String> {
+    (!reports.is_empty())
+        .then(|| serde_json::to_string(reports).ok())
+        .flatten()
+}
+
+fn timestamp(time: SystemTime) -> i64 {
+    unix_seconds(time) as i64
+}
+
+fn from_timestamp(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+fn sql_error(err: rusqlite::Error) -> AppError {
+    AppError::Io(std::io::Error::other(err))
+}
+
+fn join_error(err: task::JoinError) -> AppError {
+    AppError::Io(std::io::Error::other(err))
+}
+
+#[async_trait]
+impl MetadataStore for SqliteMetadataStore {
+    async fn insert(&self, id: &str, entry: &FileEntry) -> Result<bool, AppError> {
+        let id = id.to_string();
+        let entry = entry.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO entries ({}) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, \
+                     ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)",
+                    ENTRY_COLUMNS
+                ),
+                params![
+                    id,
+                    entry.key,
+                    entry.filename,
+                    timestamp(entry.expires_at),
+                    entry.remaining_hits,
+                    entry.content_type,
+                    entry.size as i64,
+                    entry.delete_token,
+                    entry.owner_token,
+                    timestamp(entry.created_at),
+                    entry.kind.as_str(),
+                    entry.sha256,
+                    entry.scan.as_str(),
+                    entry.threat,
+                    entry.compression.map(Codec::as_str),
+                    entry.compressed_size.map(|bytes| bytes as i64),
+                    entry.api_key,
+                    entry.uploader,
+                    entry.subject,
+                    entry.user,
+                    reports_json(&entry.reports),
+                    entry.disabled,
+                ],
+            )
+            .map(|changed| changed > 0)
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| select_entry(conn, &id)).await
+    }
+
+    async fn take_hit(&self, id: &str, now: SystemTime) -> Result<Hit, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(mut entry) = select_entry(&tx, &id)? else {
+                return Ok(Hit::Missing);
+            };
+
+            if now >= entry.expires_at {
+                tx.execute("DELETE FROM entries WHERE id = ?1", [&id])?;
+                tx.commit()?;
+                return Ok(Hit::Expired(entry));
+            }
+            if entry.withholds() {
+                return Ok(Hit::Withheld(entry));
+            }
+
+            entry.remaining_hits = entry.remaining_hits.saturating_sub(1);
+            let last = entry.remaining_hits == 0;
+            if last {
+                tx.execute("DELETE FROM entries WHERE id = ?1", [&id])?;
+            } else {
+                tx.execute(
+                    "UPDATE entries SET remaining_hits = ?2 WHERE id = ?1",
+                    params![id, entry.remaining_hits],
+                )?;
+            }
+            tx.commit()?;
+
+            Ok(Hit::Served { entry, last })
+        })
+        .await
+    }
+
+    async fn update(&self, id: &str, patch: &EntryPatch) -> Result<Option<FileEntry>, AppError> {
+        let id = id.to_string();
+        let patch = patch.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(mut entry) = select_entry(&tx, &id)? else {
+                return Ok(None);
+            };
+
+            patch.apply(&mut entry);
+            tx.execute(
+                "UPDATE entries SET filename = ?2, content_type = ?3, remaining_hits = ?4, \
+                 expires_at = ?5, scan = ?6, threat = ?7, reports = ?8, disabled = ?9 \
+                 WHERE id = ?1",
+                params![
+                    id,
+                    entry.filename,
+                    entry.content_type,
+                    entry.remaining_hits,
+                    timestamp(entry.expires_at),
+                    entry.scan.as_str(),
+                    entry.threat,
+                    reports_json(&entry.reports),
+                    entry.disabled,
+                ],
+            )?;
+            tx.commit()?;
+            Ok(Some(entry))
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &str) -> Result<Option<FileEntry>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let entry = select_entry(&tx, &id)?;
+            if entry.is_some() {
+                tx.execute("DELETE FROM entries WHERE id = ?1", [&id])?;
+            }
+            tx.commit()?;
+            Ok(entry)
+        })
+        .await
+    }
+
+    async fn take_expired(&self, now: SystemTime) -> Result<Vec<(String, FileEntry)>, AppError> {
+        let now = timestamp(now);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let expired = tx
+                .prepare(&format!(
+                    "SELECT {} FROM entries WHERE expires_at <= ?1",
+                    ENTRY_COLUMNS
+                ))?
+                .query_map([now], row_to_entry)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            tx.execute("DELETE FROM entries WHERE expires_at <= ?1", [now])?;
+            tx.commit()?;
+            Ok(expired)
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<(String, FileEntry)>, AppError> {
+        self.with_conn(|conn| {
+            conn.prepare(&format!("SELECT {} FROM entries", ENTRY_COLUMNS))?
+                .query_map([], row_to_entry)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn retain_blob(&self, key: &str) -> Result<u64, AppError> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO blobs (key, refs) VALUES (?1, 1) \
+                 ON CONFLICT (key) DO UPDATE SET refs = refs + 1",
+                [&key],
+            )?;
+            let refs: i64 = tx.query_row("SELECT refs FROM blobs WHERE key = ?1", [&key], |row| {
+                row.get(0)
+            })?;
+            tx.commit()?;
+            Ok(refs.max(0) as u64)
+        })
+        .await
+    }
+
+    async fn release_blob(&self, key: &str) -> Result<u64, AppError> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(refs) = tx
+                .query_row("SELECT refs FROM blobs WHERE key = ?1", [&key], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .optional()?
+            else {
+                return Ok(0);
+            };
+
+            let left = (refs - 1).max(0);
+            if left == 0 {
+                tx.execute("DELETE FROM blobs WHERE key = ?1", [&key])?;
+            } else {
+                tx.execute(
+                    "UPDATE blobs SET refs = ?2 WHERE key = ?1",
+                    params![key, left],
+                )?;
+            }
+            tx.commit()?;
+            Ok(left as u64)
+        })
+        .await
+    }
+
+    async fn insert_key(&self, key: &ApiKey) -> Result<(), AppError> {
+        let key = key.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT INTO api_keys ({}) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    KEY_COLUMNS
+                ),
+                params![
+                    key.id,
+                    key.name,
+                    key.key_hash,
+                    timestamp(key.created_at),
+                    key.revoked_at.map(timestamp),
+                    key.max_upload_bytes.map(|bytes| bytes as i64),
+                    key.max_uploads.map(|count| count as i64),
+                    key.uploads as i64,
+                    key.uploaded_bytes as i64,
+                    key.last_used_at.map(timestamp),
+                    key.monthly_transfer_bytes.map(|bytes| bytes as i64),
+                ],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn find_key(&self, key_hash: &str) -> Result<Option<ApiKey>, AppError> {
+        let key_hash = key_hash.to_string();
+        self.with_conn(move |conn| select_key(conn, "key_hash", &key_hash))
+            .await
+    }
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, AppError> {
+        self.with_conn(|conn| {
+            conn.prepare(&format!("SELECT {} FROM api_keys", KEY_COLUMNS))?
+                .query_map([], row_to_key)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn revoke_key(&self, id: &str, now: SystemTime) -> Result<Option<ApiKey>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?2) WHERE id = ?1",
+                params![id, timestamp(now)],
+            )?;
+            let key = select_key(&tx, "id", &id)?;
+            tx.commit()?;
+            Ok(key)
+        })
+        .await
+    }
+
+    async fn remove_key(&self, id: &str) -> Result<Option<ApiKey>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let key = select_key(&tx, "id", &id)?;
+            tx.execute("DELETE FROM api_keys WHERE id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(key)
+        })
+        .await
+    }
+
+    async fn record_key_usage(
+        &self,
+        id: &str,
+        bytes: u64,
+        now: SystemTime,
+    ) -> Result<(), AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE api_keys SET uploads = uploads + 1, uploaded_bytes = uploaded_bytes + ?2, \
+                 last_used_at = ?3 WHERE id = ?1",
+                params![id, bytes as i64, timestamp(now)],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AppError> {
+        let user = user.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO users ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    USER_COLUMNS
+                ),
+                params![
+                    user.id,
+                    user.name,
+                    user.token_hash,
+                    timestamp(user.created_at),
+                    user.storage_quota_bytes.map(|bytes| bytes as i64),
+                    user.max_upload_bytes.map(|bytes| bytes as i64),
+                    user.default_ttl_secs.map(|secs| secs as i64),
+                ],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| select_user(conn, "id", &id)).await
+    }
+
+    async fn find_user(&self, token_hash: &str) -> Result<Option<User>, AppError> {
+        let token_hash = token_hash.to_string();
+        self.with_conn(move |conn| select_user(conn, "token_hash", &token_hash))
+            .await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError> {
+        self.with_conn(|conn| {
+            conn.prepare(&format!("SELECT {} FROM users", USER_COLUMNS))?
+                .query_map([], row_to_user)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn remove_user(&self, id: &str) -> Result<Option<User>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let user = select_user(&tx, "id", &id)?;
+            tx.execute("DELETE FROM users WHERE id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn save_tenant(&self, tenant: &Tenant) -> Result<(), AppError> {
+        let tenant = tenant.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO tenants ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    TENANT_COLUMNS
+                ),
+                params![
+                    tenant.id,
+                    tenant.name,
+                    tenant.token_hash,
+                    timestamp(tenant.created_at),
+                    tenant.storage_quota_bytes.map(|bytes| bytes as i64),
+                    tenant.max_upload_bytes.map(|bytes| bytes as i64),
+                    tenant.default_ttl_secs.map(|secs| secs as i64),
+                    tenant.monthly_transfer_bytes.map(|bytes| bytes as i64),
+                ],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| select_tenant(conn, "id", &id)).await
+    }
+
+    async fn find_tenant(&self, token_hash: &str) -> Result<Option<Tenant>, AppError> {
+        let token_hash = token_hash.to_string();
+        self.with_conn(move |conn| select_tenant(conn, "token_hash", &token_hash))
+            .await
+    }
+
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, AppError> {
+        self.with_conn(|conn| {
+            conn.prepare(&format!("SELECT {} FROM tenants", TENANT_COLUMNS))?
+                .query_map([], row_to_tenant)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn remove_tenant(&self, id: &str) -> Result<Option<Tenant>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let tenant = select_tenant(&tx, "id", &id)?;
+            tx.execute("DELETE FROM tenants WHERE id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(tenant)
+        })
+        .await
+    }
+
+    /// The database is only ever opened by one instance, which always leads.
+    async fn acquire_lease(
+        &self,
+        _name: &str,
+        _holder: &str,
+        _ttl: Duration,
+    ) -> Result<bool, AppError> {
+        Ok(true)
+    }
+
+    async fn record_download(
+        &self,
+        id: &str,
+        owner_token: Option<&str>,
+        download: &Download,
+        keep_until: SystemTime,
+    ) -> Result<(), AppError> {
+        let id = id.to_string();
+        let owner_token = owner_token.map(str::to_string);
+        let download = download.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let previous: Option<Option<String>> = tx
+                .query_row(
+                    "SELECT owner_token FROM download_histories WHERE entry_id = ?1",
+                    [&id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if previous.is_some_and(|previous| previous != owner_token) {
+                tx.execute("DELETE FROM downloads WHERE entry_id = ?1", [&id])?;
+            }
+            tx.execute(
+                "INSERT INTO download_histories (entry_id, owner_token, keep_until) \
+                 VALUES (?1, ?2, ?3) ON CONFLICT (entry_id) DO UPDATE SET \
+                 owner_token = excluded.owner_token, keep_until = excluded.keep_until",
+                params![id, owner_token, timestamp(keep_until)],
+            )?;
+            tx.execute(
+                "INSERT INTO downloads (entry_id, at, client, network, agent, country) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    id,
+                    timestamp(download.at),
+                    download.client,
+                    download.visitor.network,
+                    download.visitor.agent,
+                    download.visitor.country,
+                ],
+            )?;
+            tx.execute(
+                "DELETE FROM downloads WHERE entry_id = ?1 AND rowid NOT IN \
+                 (SELECT rowid FROM downloads WHERE entry_id = ?1 ORDER BY rowid DESC LIMIT ?2)",
+                params![id, MAX_RECORDED_DOWNLOADS as i64],
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn download_history(&self, id: &str) -> Result<Option<DownloadHistory>, AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let Some((owner_token, keep_until)) = conn
+                .query_row(
+                    "SELECT owner_token, keep_until FROM download_histories WHERE entry_id = ?1",
+                    [&id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            let downloads = conn
+                .prepare(
+                    "SELECT at, client, network, agent, country FROM downloads \
+                     WHERE entry_id = ?1 ORDER BY rowid",
+                )?
+                .query_map([&id], |row| {
+                    Ok(Download {
+                        at: from_timestamp(row.get(0)?),
+                        client: row.get(1)?,
+                        visitor: Visitor {
+                            network: row.get(2)?,
+                            agent: row.get(3)?,
+                            country: row.get(4)?,
+                        },
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(Some(DownloadHistory {
+                owner_token,
+                keep_until: from_timestamp(keep_until),
+                downloads,
+            }))
+        })
+        .await
+    }
+
+    async fn remove_download_history(&self, id: &str) -> Result<(), AppError> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM downloads WHERE entry_id = ?1", [&id])?;
+            tx.execute("DELETE FROM download_histories WHERE entry_id = ?1", [&id])?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn purge_download_histories(&self, now: SystemTime) -> Result<usize, AppError> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM downloads WHERE entry_id IN \
+                 (SELECT entry_id FROM download_histories WHERE keep_until <= ?1)",
+                [timestamp(now)],
+            )?;
+            let purged = tx.execute(
+                "DELETE FROM download_histories WHERE keep_until <= ?1",
+                [timestamp(now)],
+            )?;
+            tx.commit()?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    async fn token_uploads(&self, jti: &str) -> Result<u32, AppError> {
+        let jti = jti.to_string();
+        self.with_conn(move |conn| {
+            let uploads: Option<u32> = conn
+                .query_row(
+                    "SELECT uploads FROM token_uploads WHERE jti = ?1",
+                    [&jti],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(uploads.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn claim_token_upload(
+        &self,
+        jti: &str,
+        max: u32,
+        keep_until: SystemTime,
+    ) -> Result<bool, AppError> {
+        let jti = jti.to_string();
+        self.with_conn(move |conn| {
+            let claimed = conn.execute(
+                "INSERT INTO token_uploads (jti, uploads, keep_until) \
+                 SELECT ?1, 1, ?3 WHERE ?2 > 0 \
+                 ON CONFLICT (jti) DO UPDATE SET uploads = uploads + 1 WHERE uploads < ?2",
+                params![jti, max, timestamp(keep_until)],
+            )?;
+            Ok(claimed == 1)
+        })
+        .await
+    }
+
+    async fn purge_token_uploads(&self, now: SystemTime) -> Result<usize, AppError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM token_uploads WHERE keep_until <= ?1",
+                [timestamp(now)],
+            )
+        })
+        .await
+    }
+
+    async fn record_transfer(
+        &self,
+        meter: &str,
+        month: &str,
+        bytes: u64,
+    ) -> Result<(), AppError> {
+        let (meter, month) = (meter.to_string(), month.to_string());
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO transfers (meter, month, bytes) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT (meter, month) DO UPDATE SET bytes = bytes + ?3",
+                params![meter, month, bytes as i64],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn transfers(&self, month: &str) -> Result<HashMap<String, u64>, AppError> {
+        let month = month.to_string();
+        self.with_conn(move |conn| {
+            conn.prepare("SELECT meter, bytes FROM transfers WHERE month = ?1")?
+                .query_map([&month], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+                })?
+                .collect()
+        })
+        .await
+    }
+
+    async fn signing_key(&self, generated: &str) -> Result<String, AppError> {
+        let generated = generated.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO settings (name, value) VALUES ('signing_key', ?1)",
+                [&generated],
+            )?;
+            conn.query_row(
+                "SELECT value FROM settings WHERE name = 'signing_key'",
+                [],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// Moves the write-ahead log into the database file, so the file alone has
+    /// everything once the server is stopped.
+    async fn flush(&self) -> Result<(), AppError> {
+        self.with_conn(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+        })
+        .await
+    }
+}
@@ -0,0 +1,211 @@
+//! Form API compatible with 0x0.st ("The Null Pointer"), so existing scripts and
+//! shell aliases can point at this server.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{ConnectInfo, Multipart, Path, State, multipart::Field},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use crate::{
+    AppError, AppState, Credential, EntryKind, NewUpload, UploadParams, absolute_url, admin,
+    check_captcha, check_password, credential, expected_sha256,
+    metadata::{EntryPatch, unix_seconds},
+    remote, store_upload, tenants, to_multipart_error,
+    webhook::Event,
+};
+
+/// 0x0.st takes `expires` as hours, or as milliseconds since the epoch once the
+/// number is this large.
+const EPOCH_MILLIS_THRESHOLD: u64 = 1_000_000_000_000;
+
+/// `POST /` with `file=@...` or `url=...`, answering with the bare URL and the
+/// management token in `X-Token`.
+pub async fn upload(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    let credential = credential(&state, &headers, &UploadParams::default()).await?;
+    // The password may still come in a form field, the CAPTCHA never does.
+    if matches!(credential, Credential::Password) {
+        check_captcha(&state, &headers, peer).await?;
+    }
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+
+    let mut password = headers
+        .get("x-upload-password")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut expires = None;
+    let mut sha256 = expected_sha256(&headers);
+    let mut remote_url = None;
+    let mut file_data = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| to_multipart_error(&state, err))?
+    {
+        match field.name() {
+            Some("file") => {
+                let filename = field
+                    .file_name()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "upload.bin".to_string());
+                let content_type = field.content_type().map(|v| v.to_string());
+                let data = state
+                    .spool
+                    .receive(field, limit, |err| to_multipart_error(&state, err))
+                    .await?;
+                file_data = Some((filename, content_type, data));
+            }
+            Some("url") => remote_url = Some(field_text(&state, field).await?),
+            Some("expires") => expires = Some(field_text(&state, field).await?),
+            Some("password") => password = Some(field_text(&state, field).await?),
+            Some("sha256") => sha256 = Some(field_text(&state, field).await?),
+            // `secret` asks 0x0.st for a hard-to-guess URL, which every URL here already is.
+            _ => {}
+        }
+    }
+
+    if matches!(credential, Credential::Password) {
+        check_password(&state, password.as_deref())?;
+    }
+
+    let (filename, content_type, data) = match (file_data, remote_url) {
+        (Some(file), _) => file,
+        (None, Some(url)) => remote::fetch(&state, url.trim(), limit).await?,
+        (None, None) => return Err(AppError::NoFileProvided),
+    };
+
+    let expires = expires
+        .map(|value| expires_duration(value.trim(), SystemTime::now()))
+        .transpose()?
+        .map(|ttl| format!("{}m", ttl.as_secs().div_ceil(60).max(1)));
+
+    let upload = NewUpload {
+        filename,
+        content_type,
+        data,
+        expires,
+        kind: EntryKind::File,
+        slug: None,
+        sha256,
+        notify_email: None,
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+
+    let mut reply_headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&response.delete_token) {
+        reply_headers.insert("x-token", value);
+    }
+    reply_headers.insert(
+        "x-expires",
+        HeaderValue::from(response.expires_at.saturating_mul(1000)),
+    );
+    let url = absolute_url(&state.config, &headers, &tenants::entry_path("d", &response.id));
+    Ok((reply_headers, format!("{}\n", url)).into_response())
+}
+
+/// `POST /d/:id` with `token=...` and either `delete=` or `expires=...`, the way
+/// 0x0.st manages a file after upload.
+pub async fn manage(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<StatusCode, AppError> {
+    let mut token = None;
+    let mut delete = false;
+    let mut expires = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| to_multipart_error(&state, err))?
+    {
+        match field.name() {
+            Some("token") => token = Some(field_text(&state, field).await?),
+            Some("delete") => delete = true,
+            Some("expires") => expires = Some(field_text(&state, field).await?),
+            _ => {}
+        }
+    }
+
+    let token = token.ok_or(AppError::InvalidToken)?;
+    let now = SystemTime::now();
+    let entry = state
+        .metadata
+        .get(&id)
+        .await?
+        .filter(|entry| now < entry.expires_at)
+        .ok_or(AppError::NotFound)?;
+    if !entry.may_delete(token.trim()) {
+        return Err(AppError::InvalidToken);
+    }
+
+    if delete {
+        if let Some(removed) = state.metadata.remove(&id).await? {
+            state.notify(Event::Delete, &id, &removed);
+            state.discard(&removed).await;
+        }
+        return Ok(StatusCode::OK);
+    }
+
+    let Some(expires) = expires else {
+        return Err(AppError::BadRequest(
+            "expected a 'delete' or 'expires' field".to_string(),
+        ));
+    };
+    let ttl = expires_duration(expires.trim(), now)?.min(state.settings().max_ttl);
+    let patch = EntryPatch {
+        expires_at: Some(now + ttl),
+        ..Default::default()
+    };
+    state
+        .metadata
+        .update(&id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(StatusCode::OK)
+}
+
+async fn field_text(state: &AppState, field: Field<'_>) -> Result<String, AppError> {
+    field
+        .text()
+        .await
+        .map_err(|err| to_multipart_error(state, err))
+}
+
+/// Reads `expires` as a number of hours, or as an absolute time in epoch
+/// milliseconds.
+fn expires_duration(value: &str, now: SystemTime) -> Result<Duration, AppError> {
+    let invalid = || {
+        AppError::BadRequest(format!(
+            "invalid expires value '{}', expected hours or milliseconds since the epoch",
+            value
+        ))
+    };
+    let number = value.parse::<u64>().map_err(|_| invalid())?;
+
+    let ttl = if number >= EPOCH_MILLIS_THRESHOLD {
+        Duration::from_secs((number / 1000).saturating_sub(unix_seconds(now)))
+    } else {
+        Duration::from_secs(number.saturating_mul(60 * 60))
+    };
+    if ttl.is_zero() {
+        return Err(invalid());
+    }
+    Ok(ttl)
+}
@@ -0,0 +1,424 @@
+//! Sign-in with an OpenID Connect provider, so the upload page can sit behind
+//! corporate SSO instead of the shared password. `/auth/login` sends the browser
+//! to the provider with the authorization code flow and PKCE, `/auth/callback`
+//! redeems the code and `/auth/logout` forgets the session.
+//!
+//! Sessions live in a cookie signed with `UPLOAD_SIGNING_KEY`, so nothing is
+//! stored server-side. It is `SameSite=Lax`, which keeps other sites from
+//! uploading in a signed-in user's name. The id token arrives straight from the
+//! token endpoint over TLS, so its claims are checked but not its signature, as
+//! OpenID Connect Core (3.1.3.7) allows.
+
+use std::{sync::Arc, time::SystemTime};
+
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::{AppError, AppState, config::OidcConfig, metadata::unix_seconds, preview, secret};
+
+const SESSION_COOKIE: &str = "newtemp_session";
+const LOGIN_COOKIE: &str = "newtemp_login";
+/// How long a sign-in may take at the provider.
+const LOGIN_SECS: u64 = 600;
+
+/// Who a session belongs to.
+#[derive(Clone)]
+pub struct Account {
+    /// The provider's `sub`, which is what entries record.
+    pub subject: String,
+    /// Email, username or name, whichever the provider sent, for display.
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    sub: String,
+    name: String,
+    until: u64,
+}
+
+/// What the callback needs to finish a sign-in, kept in a short-lived cookie.
+#[derive(Serialize, Deserialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    verifier: String,
+    next: String,
+    until: u64,
+}
+
+/// The parts of the provider's discovery document that are used.
+#[derive(Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    azp: Option<String>,
+    email: Option<String>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Self::One(audience) => audience == client_id,
+            Self::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+
+    fn is_many(&self) -> bool {
+        matches!(self, Self::Many(audiences) if audiences.len() > 1)
+    }
+}
+
+pub struct Oidc {
+    config: OidcConfig,
+    signing_key: String,
+    /// `BASE_URL`'s path, which cookies and redirects back to the site are under.
+    base_path: String,
+    client: reqwest::Client,
+    /// Fetched on the first sign-in, so the server starts while the provider is
+    /// down; a failed fetch is tried again with the next one.
+    provider: OnceCell<Provider>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig, signing_key: &str, base_path: &str) -> Result<Self, AppError> {
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(|err| AppError::Config(format!("invalid OIDC settings: {}", err)))?;
+        Ok(Self {
+            config,
+            signing_key: signing_key.to_string(),
+            base_path: base_path.to_string(),
+            client,
+            provider: OnceCell::new(),
+        })
+    }
+
+    /// Whether the shared password is still accepted from those not signed in.
+    pub fn allows_password(&self) -> bool {
+        self.config.allow_password
+    }
+
+    /// The account signed in with this request's session cookie, if any.
+    pub fn account(&self, headers: &HeaderMap) -> Option<Account> {
+        let session: Session = self.open(SESSION_COOKIE, cookie(headers, SESSION_COOKIE)?)?;
+        (unix_seconds(SystemTime::now()) < session.until).then_some(Account {
+            subject: session.sub,
+            name: session.name,
+        })
+    }
+
+    async fn provider(&self) -> Result<&Provider, AppError> {
+        self.provider.get_or_try_init(|| self.discover()).await
+    }
+
+    async fn discover(&self) -> Result<Provider, AppError> {
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+        let body = self.fetch(self.client.get(&url)).await?;
+        let provider: Provider = serde_json::from_slice(&body).map_err(|err| {
+            AppError::IdentityProvider(format!("unreadable discovery document: {}", err))
+        })?;
+        if provider.issuer.trim_end_matches('/') != self.config.issuer {
+            return Err(AppError::IdentityProvider(format!(
+                "discovery document names issuer {} rather than {}",
+                provider.issuer, self.config.issuer
+            )));
+        }
+        info!(issuer = %provider.issuer, "discovered OpenID Connect provider");
+        Ok(provider)
+    }
+
+    async fn fetch(&self, request: reqwest::RequestBuilder) -> Result<Vec<u8>, AppError> {
+        let unreachable = |err: reqwest::Error| AppError::IdentityProvider(err.to_string());
+        let response = request.send().await.map_err(unreachable)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(unreachable)?;
+        if !status.is_success() {
+            let mut detail = String::from_utf8_lossy(&body).into_owned();
+            detail.truncate(200);
+            return Err(AppError::IdentityProvider(format!("{}: {}", status, detail)));
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Redeems an authorization code for the claims of its id token.
+    async fn redeem(&self, code: &str, login: &Login) -> Result<Claims, AppError> {
+        let provider = self.provider().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("code_verifier", login.verifier.as_str()),
+        ];
+        let request = self.client.post(&provider.token_endpoint);
+        let request = match &self.config.client_secret {
+            Some(client_secret) => request.basic_auth(&self.config.client_id, Some(client_secret)),
+            None => {
+                form.push(("client_id", self.config.client_id.as_str()));
+                request
+            }
+        };
+        let body = self.fetch(request.form(&form)).await?;
+        let tokens: Tokens = serde_json::from_slice(&body).map_err(|err| {
+            AppError::IdentityProvider(format!("unreadable token response: {}", err))
+        })?;
+        let claims = decode_claims(&tokens.id_token).ok_or_else(|| {
+            AppError::IdentityProvider("unreadable id token".to_string())
+        })?;
+
+        let issuer_matches = claims.iss.trim_end_matches('/') == self.config.issuer;
+        let audience_matches = claims.aud.contains(&self.config.client_id)
+            && (!claims.aud.is_many() || claims.azp.as_deref() == Some(&self.config.client_id));
+        let nonce_matches = claims
+            .nonce
+            .as_deref()
+            .is_some_and(|nonce| secret::matches(&login.nonce, nonce));
+        if !issuer_matches || !audience_matches || !nonce_matches {
+            return Err(AppError::IdentityProvider(
+                "id token was not issued for this sign-in".to_string(),
+            ));
+        }
+        if claims.exp <= unix_seconds(SystemTime::now()) {
+            return Err(AppError::IdentityProvider("id token has expired".to_string()));
+        }
+        Ok(claims)
+    }
+
+    fn seal<T: Serialize>(&self, purpose: &str, value: &T) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap_or_default());
+        let signature = self.signature(purpose, &payload);
+        format!("{}.{}", payload, signature)
+    }
+
+    fn open<T: DeserializeOwned>(&self, purpose: &str, sealed: &str) -> Option<T> {
+        let (payload, signature) = sealed.split_once('.')?;
+        if !secret::matches(&self.signature(purpose, payload), signature) {
+            return None;
+        }
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+
+    /// Keyed with the purpose too, so a login cookie cannot pass for a session.
+    fn signature(&self, purpose: &str, payload: &str) -> String {
+        let purpose = format!("oidc {}", purpose);
+        hex::encode(secret::hmac_sha256(&self.signing_key, &[&purpose, payload]))
+    }
+
+    fn set_cookie(&self, name: &str, value: &str, path: &str, max_age: u64) -> HeaderValue {
+        let secure = if self.config.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        let path = format!("{}{}", self.base_path, path);
+        let cookie = format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            name, value, path, max_age, secure
+        );
+        HeaderValue::from_str(&cookie).expect("cookie values are url-safe")
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// The payload of a compact JWT, without checking its signature.
+fn decode_claims(token: &str) -> Option<Claims> {
+    let payload = token.split('.').nth(1)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?).ok()
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Only paths on this server, so a crafted link cannot send the user elsewhere;
+/// without one, the upload page.
+fn local_path(next: Option<String>, base_path: &str) -> String {
+    next.filter(|next| next.starts_with('/') && !next.starts_with("//") && !next.contains('\\'))
+        .unwrap_or_else(|| format!("{}/", base_path))
+}
+
+fn redirect(location: &str, cookies: Vec<HeaderValue>) -> Result<Response, AppError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(location).map_err(std::io::Error::other)?,
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    for cookie in cookies {
+        headers.append(header::SET_COOKIE, cookie);
+    }
+    Ok((StatusCode::FOUND, headers).into_response())
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/auth/login", get(login))
+        .route("/auth/callback", get(callback))
+        .route("/auth/logout", get(logout).post(logout))
+}
+
+#[derive(Deserialize)]
+struct LoginParams {
+    next: Option<String>,
+}
+
+async fn login(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<LoginParams>,
+) -> Result<Response, AppError> {
+    let oidc = state.oidc.as_ref().ok_or(AppError::NotFound)?;
+    let provider = oidc.provider().await?;
+    let login = Login {
+        state: random_token(),
+        nonce: random_token(),
+        verifier: random_token(),
+        next: local_path(params.next, &oidc.base_path),
+        until: unix_seconds(SystemTime::now()) + LOGIN_SECS,
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(login.verifier.as_bytes()));
+    let url = reqwest::Url::parse_with_params(
+        &provider.authorization_endpoint,
+        [
+            ("response_type", "code"),
+            ("client_id", oidc.config.client_id.as_str()),
+            ("redirect_uri", oidc.config.redirect_url.as_str()),
+            ("scope", oidc.config.scopes.as_str()),
+            ("state", login.state.as_str()),
+            ("nonce", login.nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|err| AppError::IdentityProvider(format!("invalid authorization endpoint: {}", err)))?;
+
+    let sealed = oidc.seal(LOGIN_COOKIE, &login);
+    let cookie = oidc.set_cookie(LOGIN_COOKIE, &sealed, "/auth", LOGIN_SECS);
+    redirect(url.as_str(), vec![cookie])
+}
+
+#[derive(Deserialize)]
+struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+async fn callback(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CallbackParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let oidc = state.oidc.as_ref().ok_or(AppError::NotFound)?;
+    if let Some(error) = params.error {
+        return Err(AppError::BadRequest(format!(
+            "sign-in failed: {}",
+            params.error_description.unwrap_or(error)
+        )));
+    }
+    let login: Login = cookie(&headers, LOGIN_COOKIE)
+        .and_then(|sealed| oidc.open(LOGIN_COOKIE, sealed))
+        .filter(|login: &Login| unix_seconds(SystemTime::now()) < login.until)
+        .filter(|login| {
+            params
+                .state
+                .as_deref()
+                .is_some_and(|provided| secret::matches(&login.state, provided))
+        })
+        .ok_or_else(|| {
+            AppError::BadRequest("sign-in expired or was not started here, try again".to_string())
+        })?;
+    let code = params
+        .code
+        .ok_or_else(|| AppError::BadRequest("missing authorization code".to_string()))?;
+
+    let claims = match oidc.redeem(&code, &login).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            warn!(%err, "OpenID Connect sign-in failed");
+            return Err(err);
+        }
+    };
+    let name = claims
+        .email
+        .or(claims.preferred_username)
+        .or(claims.name)
+        .unwrap_or_else(|| claims.sub.clone());
+    info!(subject = %claims.sub, %name, "signed in");
+    let lifetime = oidc.config.session_lifetime.as_secs();
+    let session = Session {
+        sub: claims.sub,
+        name,
+        until: unix_seconds(SystemTime::now()) + lifetime,
+    };
+    let sealed = oidc.seal(SESSION_COOKIE, &session);
+    redirect(
+        &login.next,
+        vec![
+            oidc.set_cookie(SESSION_COOKIE, &sealed, "/", lifetime),
+            oidc.set_cookie(LOGIN_COOKIE, "", "/auth", 0),
+        ],
+    )
+}
+
+/// Ends the session here only. Going back to the upload page right away would
+/// sign in again at once while the provider's own session lasts, so this says
+/// so instead of redirecting.
+async fn logout(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let oidc = state.oidc.as_ref().ok_or(AppError::NotFound)?;
+    let headers = [
+        (header::SET_COOKIE, oidc.set_cookie(SESSION_COOKIE, "", "/", 0)),
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+    ];
+    let body = format!(
+        "<!doctype html>\n<title>Signed out</title>\n\
+         <p>You are signed out. <a href=\"{}/auth/login\">Sign in again</a></p>\n",
+        preview::escape_html(&oidc.base_path)
+    );
+    Ok((headers, Html(body)).into_response())
+}
@@ -0,0 +1,265 @@
+//! Text snippets: `POST /paste` stores them as ordinary entries, and `/d/:id`
+//! shows them with syntax highlighting in a browser (the raw text otherwise, or
+//! with `?raw=1`).
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{Html, IntoResponse, Response},
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::{SyntaxReference, SyntaxSet},
+};
+
+use crate::{
+    AppError, AppState, Credential, EntryKind, FileEntry, NewUpload, UploadParams, admin,
+    check_captcha, check_password, credential, expected_sha256,
+    metadata::unix_seconds,
+    preview::{escape_html, format_size},
+    read_blob, reply_format, store_upload, upload_reply,
+};
+
+pub const PASTE_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Larger pastes are shown without highlighting, which gets slow on big inputs.
+const MAX_HIGHLIGHT_BYTES: usize = 512 * 1024;
+const THEME: &str = "base16-ocean.dark";
+
+#[derive(Default, Deserialize)]
+pub struct PasteParams {
+    syntax: Option<String>,
+    filename: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PasteRequest {
+    content: String,
+    syntax: Option<String>,
+    filename: Option<String>,
+    expires: Option<String>,
+    slug: Option<String>,
+}
+
+/// `POST /paste` takes the text either as the raw body (with `?syntax=`,
+/// `?filename=` and `?expires=`) or as JSON `{"content": ..., "syntax": ...}`.
+/// The syntax is a language name or file extension; credentials work as for
+/// `PUT` uploads.
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    Query(paste): Query<PasteParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let reply_format = reply_format(&headers, &params);
+    let credential = credential(&state, &headers, &params).await?;
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    if body.len() > limit {
+        return Err(AppError::PayloadTooLarge { limit });
+    }
+
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let request = if is_json {
+        serde_json::from_slice::<PasteRequest>(&body)
+            .map_err(|err| AppError::BadRequest(format!("invalid paste request: {}", err)))?
+    } else {
+        PasteRequest {
+            content: String::from_utf8(body.to_vec())
+                .map_err(|_| AppError::BadRequest("paste must be UTF-8 text".to_string()))?,
+            syntax: paste.syntax,
+            filename: paste.filename,
+            expires: params.expires,
+            slug: params.slug,
+        }
+    };
+    if request.content.is_empty() {
+        return Err(AppError::NoFileProvided);
+    }
+
+    // The extension carries the syntax, so the viewer needs nothing else.
+    let filename = match request.filename.filter(|name| !name.trim().is_empty()) {
+        Some(filename) => filename,
+        None => {
+            let extension = match request.syntax.as_deref().map(str::trim) {
+                Some(token) if !token.is_empty() => syntaxes()
+                    .find_syntax_by_token(token)
+                    .and_then(|syntax| syntax.file_extensions.first())
+                    .ok_or_else(|| AppError::BadRequest(format!("unknown syntax '{}'", token)))?
+                    .as_str(),
+                _ => "txt",
+            };
+            format!("paste.{}", extension)
+        }
+    };
+
+    let upload = NewUpload {
+        filename,
+        content_type: Some(PASTE_CONTENT_TYPE.to_string()),
+        data: state.spool.write(request.content.as_bytes()).await?,
+        expires: request.expires,
+        kind: EntryKind::Paste,
+        slug: request.slug,
+        sha256: expected_sha256(&headers),
+        notify_email: params.notify_email,
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+    Ok(upload_reply(&state.config, &headers, response, reply_format))
+}
+
+/// Whether a paste download should get the viewer rather than the raw text.
+pub fn wants_viewer(headers: &HeaderMap, raw: bool) -> bool {
+    !raw && headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Renders a paste whose download was already counted. When this was the last
+/// one the blob is discarded after it has been read.
+pub async fn viewer(
+    state: &Arc<AppState>,
+    id: &str,
+    entry: FileEntry,
+    last_hit: bool,
+) -> Result<Response, AppError> {
+    let data = read_blob(state, &entry).await;
+    if last_hit {
+        state.discard(&entry).await;
+    }
+    let content = String::from_utf8_lossy(&data?).into_owned();
+
+    let extension = std::path::Path::new(&entry.filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("txt")
+        .to_string();
+    let (highlighted, content) = tokio::task::spawn_blocking(move || {
+        let code = highlight(&content, &extension);
+        (code, content)
+    })
+    .await
+    .map_err(std::io::Error::other)?;
+
+    // `</` would end the script element early; `<\/` is the same JSON string.
+    let raw_json = serde_json::to_string(&content)
+        .unwrap_or_default()
+        .replace("</", "<\\/");
+    let filename = escape_html(&entry.filename);
+    let filename_json = serde_json::to_string(&entry.filename)
+        .unwrap_or_default()
+        .replace("</", "<\\/");
+    let raw_link = if last_hit {
+        r#"<span class="muted">This was the last view; the paste is gone now.</span>"#.to_string()
+    } else {
+        format!(
+            r#"<a href="{}?raw=1">Raw</a><span class="muted">{} views left</span>"#,
+            escape_html(&state.config.build_download_url(id)),
+            entry.remaining_hits
+        )
+    };
+
+    let body = format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <meta name="robots" content="noindex" />
+  <title>{filename} · newtemp.sh</title>
+  <style>
+    :root {{ color-scheme: dark; }}
+    body {{ margin: 0; background: #0d1117; color: #f6f8fa; font-family: 'Inter', 'Segoe UI', system-ui, -apple-system, sans-serif; }}
+    header {{ display: flex; flex-wrap: wrap; align-items: center; gap: 0.75rem; padding: 0.9rem 1.25rem; border-bottom: 1px solid rgba(255, 255, 255, 0.18); }}
+    h1 {{ margin: 0 auto 0 0; font-size: 1.05rem; word-break: break-all; }}
+    header a, header button {{ font: inherit; font-size: 0.9rem; padding: 0.4rem 0.8rem; border-radius: 8px; border: 1px solid rgba(255, 255, 255, 0.18); background: rgba(121, 192, 255, 0.12); color: #79c0ff; text-decoration: none; cursor: pointer; }}
+    .muted {{ color: #c9d1d9; font-size: 0.85rem; }}
+    pre {{ margin: 0; padding: 1.25rem; overflow: auto; font-size: 0.9rem; line-height: 1.45; tab-size: 4; }}
+  </style>
+</head>
+<body>
+  <header>
+    <h1>{filename}</h1>
+    <span class="muted">{size} · expires <span id="expires" data-at="{expires_at}">{expires_at}</span></span>
+    <button type="button" id="copy">Copy</button>
+    <button type="button" id="save">Download</button>
+    {raw_link}
+  </header>
+  {highlighted}
+  <script>
+    const raw = {raw_json};
+    const expires = document.getElementById('expires');
+    expires.textContent = new Date(Number(expires.dataset.at) * 1000).toLocaleString();
+    document.getElementById('copy').addEventListener('click', () => navigator.clipboard.writeText(raw));
+    document.getElementById('save').addEventListener('click', () => {{
+      const link = document.createElement('a');
+      link.href = URL.createObjectURL(new Blob([raw], {{ type: 'text/plain' }}));
+      link.download = {filename_json};
+      link.click();
+      URL.revokeObjectURL(link.href);
+    }});
+  </script>
+</body>
+</html>
+"#,
+        size = format_size(entry.size),
+        expires_at = unix_seconds(entry.expires_at),
+    );
+
+    let mut response = Html(body).into_response();
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+fn highlight(content: &str, extension: &str) -> String {
+    let plain = || format!("<pre>{}</pre>", escape_html(content));
+    if content.len() > MAX_HIGHLIGHT_BYTES {
+        return plain();
+    }
+
+    let syntaxes = syntaxes();
+    let syntax: &SyntaxReference = syntaxes
+        .find_syntax_by_extension(extension)
+        .or_else(|| syntaxes.find_syntax_by_first_line(content))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    highlighted_html_for_string(content, syntaxes, syntax, theme()).unwrap_or_else(|_| plain())
+}
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    &THEME_SET.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
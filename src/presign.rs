@@ -0,0 +1,35 @@
+use crate::{AppError, secret};
+
+/// Signs the constraints of a pre-signed upload URL. The signature covers the
+/// deadline and the optional size cap, so neither can be edited by the holder.
+fn sign(secret: &str, until: u64, max_bytes: Option<u64>) -> String {
+    let until = until.to_string();
+    let max_bytes = max_bytes.map(|bytes| bytes.to_string()).unwrap_or_default();
+    hex::encode(secret::hmac_sha256(secret, &["upload", &until, &max_bytes]))
+}
+
+/// Query string carrying a signed upload grant.
+pub fn query(secret: &str, until: u64, max_bytes: Option<u64>) -> String {
+    let signature = sign(secret, until, max_bytes);
+    match max_bytes {
+        Some(max_bytes) => format!(
+            "signed_until={}&max_bytes={}&signature={}",
+            until, max_bytes, signature
+        ),
+        None => format!("signed_until={}&signature={}", until, signature),
+    }
+}
+
+/// Checks a signature in constant time and that its deadline has not passed.
+pub fn verify(
+    secret: &str,
+    until: u64,
+    max_bytes: Option<u64>,
+    signature: &str,
+    now: u64,
+) -> Result<(), AppError> {
+    if !secret::matches(&sign(secret, until, max_bytes), signature) || now > until {
+        return Err(AppError::InvalidToken);
+    }
+    Ok(())
+}
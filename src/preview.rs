@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::Html,
+};
+
+use crate::{AppError, AppState, live_entry, metadata::unix_seconds};
+
+/// `GET /p/:id` shows what a link points to with an explicit download button,
+/// so opening a shared link out of curiosity doesn't use up a download.
+pub async fn preview_page(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let entry = live_entry(&state, &id).await?;
+    entry.check_takedown()?;
+
+    let filename = escape_html(&entry.filename);
+    let content_type = escape_html(
+        entry
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream"),
+    );
+    let size = if entry.size > 0 {
+        format_size(entry.size)
+    } else {
+        "unknown".to_string()
+    };
+    let download_url = escape_html(&state.config.build_download_url(&id));
+
+    let body = format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <meta name="robots" content="noindex" />
+  <title>{filename} · newtemp.sh</title>
+  <style>
+    :root {{
+      color-scheme: light dark;
+      --bg: linear-gradient(145deg, #0d1117 0%, #0f172a 40%, #0b1221 100%);
+      --card: rgba(255, 255, 255, 0.08);
+      --border: rgba(255, 255, 255, 0.18);
+      --text: #f6f8fa;
+      --muted: #c9d1d9;
+    }}
+    * {{ box-sizing: border-box; }}
+    body {{
+      margin: 0;
+      min-height: 100vh;
+      font-family: 'Inter', 'Segoe UI', system-ui, -apple-system, sans-serif;
+      background: var(--bg);
+      color: var(--text);
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      padding: 2.5rem 1.5rem;
+    }}
+    .shell {{
+      width: min(560px, 100%);
+      background: var(--card);
+      border: 1px solid var(--border);
+      border-radius: 20px;
+      box-shadow: 0 24px 70px rgba(0, 0, 0, 0.45);
+      padding: 2rem 2.25rem;
+    }}
+    h1 {{ margin: 0 0 1rem; font-size: 1.4rem; word-break: break-all; }}
+    dl {{ display: grid; grid-template-columns: max-content 1fr; gap: 0.5rem 1rem; margin: 0 0 1.5rem; }}
+    dt {{ color: var(--muted); }}
+    dd {{ margin: 0; word-break: break-all; }}
+    a.download {{
+      display: inline-block;
+      font-weight: 750;
+      padding: 0.85rem 1.1rem;
+      border-radius: 12px;
+      text-decoration: none;
+      background: linear-gradient(120deg, #4096ff, #6ec1ff);
+      color: #0b1221;
+    }}
+    p {{ color: var(--muted); font-size: 0.9rem; }}
+  </style>
+</head>
+<body>
+  <div class="shell">
+    <h1>{filename}</h1>
+    <dl>
+      <dt>Size</dt><dd>{size}</dd>
+      <dt>Type</dt><dd>{content_type}</dd>
+      <dt>Expires</dt><dd id="expires" data-at="{expires_at}">{expires_at}</dd>
+      <dt>Downloads left</dt><dd>{remaining}</dd>
+    </dl>
+    <a class="download" href="{download_url}">Download</a>
+    <p>Each download counts against the remaining downloads.</p>
+  </div>
+  <script>
+    const expires = document.getElementById('expires');
+    expires.textContent = new Date(Number(expires.dataset.at) * 1000).toLocaleString();
+    // The key of an end-to-end encrypted file rides along in the fragment.
+    document.querySelector('a.download').href += location.hash;
+  </script>
+</body>
+</html>
+"#,
+        expires_at = unix_seconds(entry.expires_at),
+        remaining = entry.remaining_hits,
+    );
+
+    Ok(Html(body))
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
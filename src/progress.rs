@@ -0,0 +1,148 @@
+//! Server-side progress of chunked and tus upload sessions, streamed as
+//! server-sent events from `GET /upload/:session/events` and `GET /files/:id/events`.
+//! Each `progress` event carries the bytes received so far, counting parts still
+//! streaming in, and what the session is doing; the stream ends once the upload
+//! is stored or abandoned. Like the upload requests themselves, the session id is
+//! all it takes to follow one.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::Mutex,
+    time::Duration,
+};
+
+use axum::response::sse::{self, KeepAlive, Sse};
+use futures_util::{Stream, stream};
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// Events for one stream are at least this far apart; changes in between are
+/// folded into the next one.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Serialize)]
+pub struct Progress {
+    received_bytes: u64,
+    /// The announced size of the whole file, when the client gave one.
+    total_bytes: Option<u64>,
+    #[serde(flatten)]
+    phase: Phase,
+    /// Bytes in parts or chunks that have been accepted.
+    #[serde(skip)]
+    committed: u64,
+    /// Bytes of parts or chunks still streaming in.
+    #[serde(skip)]
+    in_flight: u64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum Phase {
+    Receiving,
+    /// All bytes are in and the file is being stored.
+    Assembling,
+    Completed { url: String },
+    /// Storing the file failed; the session is still there to retry or abandon.
+    Failed { error: String },
+    Aborted,
+}
+
+impl Progress {
+    pub fn new(committed: u64, total_bytes: Option<u64>, phase: Phase) -> Self {
+        Self {
+            received_bytes: committed,
+            total_bytes,
+            phase,
+            committed,
+            in_flight: 0,
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        matches!(self.phase, Phase::Completed { .. } | Phase::Aborted)
+    }
+}
+
+/// The live progress of every open session of one upload flow.
+#[derive(Default)]
+pub struct ProgressBoard {
+    sessions: Mutex<HashMap<String, watch::Sender<Progress>>>,
+}
+
+impl ProgressBoard {
+    pub fn track(&self, id: &str, progress: Progress) {
+        self.lock().insert(id.to_string(), watch::channel(progress).0);
+    }
+
+    /// Counts bytes of a part or chunk as they arrive.
+    pub fn advance(&self, id: &str, bytes: u64) {
+        self.modify(id, |progress| progress.in_flight += bytes);
+    }
+
+    /// Records a part or chunk that finished streaming, accepted or not, leaving
+    /// `committed` bytes received in total. Flows with a single writer can skip
+    /// `advance` and pass a `streamed` of 0 as bytes arrive.
+    pub fn settle(&self, id: &str, streamed: u64, committed: u64) {
+        self.modify(id, |progress| {
+            progress.in_flight = progress.in_flight.saturating_sub(streamed);
+            progress.committed = committed;
+            progress.phase = Phase::Receiving;
+        });
+    }
+
+    pub fn enter(&self, id: &str, phase: Phase) {
+        self.modify(id, |progress| progress.phase = phase);
+    }
+
+    /// Stops tracking a session; streams still following it end with `aborted`
+    /// unless it was completed.
+    pub fn forget(&self, id: &str) {
+        if let Some(sender) = self.lock().remove(id) {
+            sender.send_if_modified(|progress| {
+                let over = progress.is_over();
+                if !over {
+                    progress.phase = Phase::Aborted;
+                }
+                !over
+            });
+        }
+    }
+
+    pub fn subscribe(&self, id: &str) -> Option<watch::Receiver<Progress>> {
+        self.lock().get(id).map(watch::Sender::subscribe)
+    }
+
+    fn modify(&self, id: &str, change: impl FnOnce(&mut Progress)) {
+        if let Some(sender) = self.lock().get(id) {
+            sender.send_modify(|progress| {
+                change(progress);
+                progress.received_bytes = progress.committed + progress.in_flight;
+            });
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, watch::Sender<Progress>>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub fn events(
+    receiver: watch::Receiver<Progress>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let updates = stream::unfold(Some((receiver, true)), |state| async move {
+        let (mut receiver, first) = state?;
+        if !first {
+            tokio::time::sleep(INTERVAL).await;
+            receiver.changed().await.ok()?;
+        }
+        let progress = receiver.borrow_and_update().clone();
+        let data = serde_json::to_string(&progress).unwrap_or_else(|_| "{}".to_string());
+        let event = sse::Event::default().event("progress").data(data);
+        let next = (!progress.is_over()).then_some((receiver, false));
+        Some((Ok(event), next))
+    });
+    Sse::new(updates).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,179 @@
+//! Which `X-Forwarded-*` headers to believe. Only a peer within
+//! `TRUSTED_PROXIES` may set them; from anyone else they are dropped before any
+//! handler reads them. `X-Forwarded-For` is reduced to the client it names, the
+//! last address in it that is not a trusted proxy itself, so links, logs,
+//! download histories and uploader hashes all agree on who sent a request.
+//! Without a forwarded scheme, `X-Forwarded-Proto` is set to that of the listener
+//! the request came in on, so each of `ADDRESS` makes links of its own scheme.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, uri::Authority},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{AppError, AppState, request_id::{REQUEST_ID, TRACEPARENT}};
+
+/// A proxy on the same host, which is how most nginx setups look.
+pub const DEFAULT_TRUSTED: &str = "127.0.0.0/8,::1";
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED_PROTO: &str = "x-forwarded-proto";
+const FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// An address range such as `10.0.0.0/8`; a bare address is a range of one.
+#[derive(Clone, Copy)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address = address.parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Self { address, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, address, bits) = match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            _ => return false,
+        };
+        self.prefix == 0 || (network ^ address) >> (bits - self.prefix) == 0
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = if self.address.is_ipv4() { 32 } else { 128 };
+        match self.prefix {
+            prefix if prefix == bits => write!(f, "{}", self.address),
+            prefix => write!(f, "{}/{}", self.address, prefix),
+        }
+    }
+}
+
+/// Comma-separated addresses and CIDR ranges from `var`.
+pub fn parse_networks(var: &str, value: &str) -> Result<Vec<Network>, AppError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            Network::parse(entry)
+                .ok_or_else(|| AppError::Config(format!("invalid {} entry '{}'", var, entry)))
+        })
+        .collect()
+}
+
+/// The peers whose forwarding headers are believed.
+#[derive(Clone, Default)]
+pub struct TrustedProxies(Vec<Network>);
+
+impl TrustedProxies {
+    /// Comma-separated addresses and CIDR ranges, or `none` to trust no one.
+    pub fn parse(value: &str) -> Result<Self, AppError> {
+        if value.trim().eq_ignore_ascii_case("none") {
+            return Ok(Self::default());
+        }
+        parse_networks("TRUSTED_PROXIES", value).map(Self)
+    }
+
+    pub fn trusts(&self, address: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(address))
+    }
+
+    /// Walks `X-Forwarded-For` back from the peer past trusted proxies. An entry
+    /// that is not an address ends the walk, as nothing before it can be told.
+    fn client(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        let mut client = peer;
+        let hops = headers
+            .get_all(FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            let Ok(address) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = address;
+            if !self.trusts(address) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// The scheme of the listener a connection was accepted on, `http` or `https`.
+#[derive(Clone, Copy)]
+pub struct OwnScheme(pub &'static str);
+
+/// Middleware run before everything else that leaves the forwarding headers in
+/// one checked value each, or removes them when the peer is not trusted.
+pub async fn forwarded(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let proxies = &state.config.trusted_proxies;
+    let own_scheme = request.extensions().get::<OwnScheme>().map(|scheme| scheme.0);
+    let headers = request.headers_mut();
+    if !proxies.trusts(peer.ip()) {
+        for name in [FORWARDED_FOR, FORWARDED_PROTO, FORWARDED_HOST, REQUEST_ID, TRACEPARENT] {
+            headers.remove(name);
+        }
+        if let Some(scheme) = own_scheme {
+            headers.insert(FORWARDED_PROTO, HeaderValue::from_static(scheme));
+        }
+        return next.run(request).await;
+    }
+
+    let client = proxies.client(headers, peer.ip());
+    if let Ok(value) = HeaderValue::from_str(&client.to_canonical().to_string()) {
+        headers.insert(FORWARDED_FOR, value);
+    }
+    // The proxy nearest the client speaks first.
+    let proto = first(headers, FORWARDED_PROTO)
+        .map(str::to_ascii_lowercase)
+        .filter(|proto| proto == "http" || proto == "https")
+        .or_else(|| own_scheme.map(str::to_string));
+    let host = first(headers, FORWARDED_HOST)
+        .filter(|host| !host.contains('@') && host.parse::<Authority>().is_ok())
+        .map(str::to_string);
+    for (name, value) in [(FORWARDED_PROTO, proto), (FORWARDED_HOST, host)] {
+        match value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            Some(value) => headers.insert(name, value),
+            None => headers.remove(name),
+        };
+    }
+    next.run(request).await
+}
+
+fn first<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+    Some(value.split(',').next()?.trim()).filter(|value| !value.is_empty())
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use qrcode::{QrCode, render::svg};
+
+use crate::{AppError, AppState, absolute_url, live_entry, tenants};
+
+/// `GET /d/:id/qr` renders the download URL as an SVG QR code for handing a link
+/// from one device to another. It does not consume a download.
+pub async fn qr_code(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    live_entry(&state, &id).await?;
+
+    let url = absolute_url(&state.config, &headers, &tenants::entry_path("d", &id));
+    let code = QrCode::new(url.as_bytes()).map_err(std::io::Error::other)?;
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .quiet_zone(true)
+        .build();
+
+    Ok((
+        [(header::CONTENT_TYPE, HeaderValue::from_static("image/svg+xml"))],
+        image,
+    )
+        .into_response())
+}
@@ -0,0 +1,111 @@
+use std::{
+    ops::Range,
+    time::{Duration, SystemTime},
+};
+
+use axum::http::{HeaderMap, HeaderValue, header};
+
+use crate::{metadata::unix_seconds, secret};
+
+/// A single `Range: bytes=...` request before it is resolved against a file size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=start-` or `bytes=start-end` (inclusive end).
+    From { start: u64, end: Option<u64> },
+    /// `bytes=-len`, the trailing `len` bytes.
+    Suffix(u64),
+}
+
+/// Parses the `Range` header. Anything other than one well-formed byte range
+/// (including multi-range requests) is ignored and the full body is served.
+pub fn parse(headers: &HeaderMap) -> Option<ByteRange> {
+    let value = headers.get(header::RANGE)?.to_str().ok()?;
+    let spec = value.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        return end.parse().ok().map(ByteRange::Suffix);
+    }
+
+    let start = start.parse().ok()?;
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+
+    Some(ByteRange::From { start, end })
+}
+
+/// Resolves the request against the file size, returning the half-open byte span
+/// to send, or `None` when the range is unsatisfiable.
+pub fn resolve(range: ByteRange, size: u64) -> Option<Range<u64>> {
+    match range {
+        ByteRange::From { start, end } => {
+            if start >= size {
+                return None;
+            }
+            let end = end.map_or(size, |end| end.saturating_add(1).min(size));
+            Some(start..end)
+        }
+        ByteRange::Suffix(len) if len > 0 && size > 0 => Some(size.saturating_sub(len)..size),
+        ByteRange::Suffix(_) => None,
+    }
+}
+
+/// How long after a counted download its tag still lets the client resume it.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The `ETag` sent with a download that was counted at `issued`. A client
+/// resuming it sends the tag back in `If-Range`, which is how a range request
+/// proves it continues a transfer that consumed a download rather than starting
+/// a new one for free. The tag names the moment of that download and is only
+/// honored for `RESUME_WINDOW` after it. Keyed with the blob key as well as the
+/// id, so it does not carry over to a later upload under the same slug.
+pub fn resume_tag(signing_key: &str, id: &str, blob_key: &str, issued: SystemTime) -> HeaderValue {
+    let issued = unix_seconds(issued);
+    let tag = format!("\"{}-{}\"", issued, resume_mac(signing_key, id, blob_key, issued));
+    HeaderValue::from_str(&tag).expect("a quoted tag of digits and hex is a valid header value")
+}
+
+fn resume_mac(signing_key: &str, id: &str, blob_key: &str, issued: u64) -> String {
+    let mac = secret::hmac_sha256(signing_key, &[id, blob_key, &issued.to_string()]);
+    hex::encode(&mac[..16])
+}
+
+/// `If-Range` as sent, if any.
+pub fn if_range(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::IF_RANGE)?.to_str().ok().map(str::trim)
+}
+
+/// The tag in `If-Range`, if it is one issued for a counted download of this
+/// entry no longer than `RESUME_WINDOW` before `now`.
+pub fn resumes(
+    headers: &HeaderMap,
+    signing_key: &str,
+    id: &str,
+    blob_key: &str,
+    now: SystemTime,
+) -> Option<HeaderValue> {
+    let provided = if_range(headers)?;
+    let (issued, mac) = provided
+        .strip_prefix('"')?
+        .strip_suffix('"')?
+        .split_once('-')?;
+    let issued: u64 = issued.parse().ok()?;
+    let age = unix_seconds(now).checked_sub(issued)?;
+    if age > RESUME_WINDOW.as_secs()
+        || !secret::matches(&resume_mac(signing_key, id, blob_key, issued), mac)
+    {
+        return None;
+    }
+    HeaderValue::from_str(provided).ok()
+}
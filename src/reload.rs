@@ -0,0 +1,37 @@
+//! Applying changes without a restart. On SIGHUP the config file is read again,
+//! and the `Settings` from it apply to every request that comes after: lifetimes,
+//! download counts and speed, the upload password and the file type rules. The
+//! rest of the configuration only changes on restart. A file that fails to load
+//! changes nothing.
+
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use crate::{AppState, config};
+
+pub fn spawn(state: Arc<AppState>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                warn!(%err, "cannot reload the config on SIGHUP");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match config::reload_settings() {
+                Ok(settings) => {
+                    state.replace_settings(settings);
+                    info!("reloaded settings from the config file");
+                }
+                Err(err) => warn!(%err, "kept the previous settings"),
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    drop(state);
+}
@@ -0,0 +1,261 @@
+//! Uploads fetched by the server itself from a URL the caller names, via the `url`
+//! form field or `POST /fetch`.
+//!
+//! Unless `REMOTE_FETCH_ALLOW_PRIVATE` is set, hosts that resolve to loopback,
+//! private, link-local or other non-public addresses are refused, including on
+//! redirects, so the feature cannot be used to reach the server's own network.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, OnceLock},
+};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, header},
+    response::Response,
+};
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use serde::Deserialize;
+
+use crate::{
+    AppError, AppState, Credential, EntryKind, NewUpload, UploadParams, admin, check_captcha,
+    check_password, config::AppConfig, credential, expected_sha256, reply_format, spool::Spool,
+    store_upload, upload_reply,
+};
+
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Deserialize)]
+pub struct FetchRequest {
+    url: String,
+    filename: Option<String>,
+    expires: Option<String>,
+    slug: Option<String>,
+    /// Expected digest of what the URL serves.
+    sha256: Option<String>,
+    notify_email: Option<String>,
+}
+
+/// `POST /fetch` with `{"url": "..."}` stores whatever the URL serves as a new
+/// entry. Credentials work as for `PUT` uploads.
+pub async fn fetch_upload(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<FetchRequest>,
+) -> Result<Response, AppError> {
+    let reply_format = reply_format(&headers, &params);
+    let credential = credential(&state, &headers, &params).await?;
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    let (filename, content_type, data) = fetch(&state, request.url.trim(), limit).await?;
+    let upload = NewUpload {
+        filename: request
+            .filename
+            .filter(|name| !name.is_empty())
+            .unwrap_or(filename),
+        content_type,
+        data,
+        expires: request.expires.or(params.expires),
+        kind: EntryKind::File,
+        slug: request.slug.or(params.slug),
+        sha256: request.sha256.or_else(|| expected_sha256(&headers)),
+        notify_email: request.notify_email.or(params.notify_email),
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+    Ok(upload_reply(&state.config, &headers, response, reply_format))
+}
+
+/// Downloads `url` on the caller's behalf, capped at `limit` bytes, returning the
+/// file name taken from the URL path along with the served content type.
+pub async fn fetch(
+    state: &AppState,
+    url: &str,
+    limit: usize,
+) -> Result<(String, Option<String>, Spool), AppError> {
+    let config = &state.config;
+    if !config.remote_url_uploads {
+        return Err(AppError::BadRequest(
+            "uploading from a URL is disabled on this server".to_string(),
+        ));
+    }
+
+    let parsed = Url::parse(url)
+        .ok()
+        .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+        .ok_or_else(|| AppError::BadRequest(format!("invalid url '{}'", url)))?;
+    if !config.remote_fetch_allow_private && !literal_host_allowed(&parsed) {
+        return Err(AppError::BadRequest(
+            "url points to a non-public address".to_string(),
+        ));
+    }
+
+    let remote_error = |err: reqwest::Error| {
+        // The reason a connection was refused sits at the bottom of the chain.
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            message = format!("{}: {}", message, cause);
+            source = cause.source();
+        }
+        AppError::BadRequest(format!("failed to fetch url: {}", message))
+    };
+    let response = client(config)
+        .get(parsed.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(remote_error)?;
+
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(AppError::PayloadTooLarge { limit });
+    }
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // Named after the URL that was asked for, not wherever a redirect ended up.
+    let filename = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("upload.bin")
+        .to_string();
+
+    let data = state
+        .spool
+        .receive(response.bytes_stream(), limit, remote_error)
+        .await?;
+    Ok((filename, content_type, data))
+}
+
+/// One client for the whole process; the settings it is built from never change
+/// after startup.
+fn client(config: &AppConfig) -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let allow_private = config.remote_fetch_allow_private;
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.remote_fetch_timeout)
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !matches!(attempt.url().scheme(), "http" | "https") {
+                    attempt.error("redirect to a non-http url")
+                } else if !allow_private && !literal_host_allowed(attempt.url()) {
+                    attempt.error("redirect to a non-public address")
+                } else {
+                    attempt.follow()
+                }
+            }));
+        if !allow_private {
+            // A proxy would resolve names itself, out of reach of the check below.
+            builder = builder.no_proxy().dns_resolver(Arc::new(PublicResolver));
+        }
+        builder.build().unwrap_or_default()
+    })
+}
+
+/// Names go through the resolver, but IP literals in a URL never do, so they are
+/// checked up front.
+fn literal_host_allowed(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => true,
+    }
+}
+
+/// System DNS resolution that drops every non-public address, failing the
+/// connection when nothing is left.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // NAT64 and IPv4-compatible forms can smuggle private IPv4 addresses.
+        || (first == 0x0064 && ip.segments()[1] == 0xff9b)
+        || ip.to_ipv4().is_some())
+}
@@ -0,0 +1,191 @@
+//! Abuse reports. Anyone with a link can flag it at `POST /report/:id`, a few
+//! times an hour per address; reports wait on the entry until the operator
+//! takes it down (served as 451), purges it or dismisses them through
+//! `/admin/api/reports`. Reporters who leave an address are emailed what was
+//! done, when `SMTP_HOST` is set.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    AppError, AppState, FileEntry,
+    admin::uploader_hash,
+    live::client_address,
+    live_entry, mail,
+    metadata::{self, EntryPatch},
+    webhook::Event,
+};
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Reports kept per entry; older ones make way for new ones.
+pub const MAX_REPORTS: usize = 100;
+const MAX_REASON_CHARS: usize = 2000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Report {
+    #[serde(with = "metadata::unix_time")]
+    pub at: SystemTime,
+    pub reason: String,
+    /// Keyed hash of the reporter's address, as for uploaders.
+    pub reporter: String,
+    /// Where to tell the reporter what came of it.
+    #[serde(default)]
+    pub notify_email: Option<String>,
+}
+
+/// What the operator did about an entry's reports, as told to the reporters.
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    Disabled,
+    Purged,
+    Dismissed,
+}
+
+impl Outcome {
+    fn verdict(self) -> &'static str {
+        match self {
+            Self::Disabled => "has been taken down",
+            Self::Purged => "has been removed",
+            Self::Dismissed => "was reviewed and left up",
+        }
+    }
+}
+
+/// Recent reports per client, so one client cannot bury the operator in them.
+pub struct ReportLimiter {
+    per_hour: usize,
+    recent: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl ReportLimiter {
+    pub fn new(per_hour: usize) -> Self {
+        Self {
+            per_hour,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a report from `address` unless it made too many this hour. IPv6
+    /// clients are counted per `/64`, which one of them can hop around freely.
+    fn admit(&self, address: IpAddr) -> Result<(), AppError> {
+        let address = match address.to_canonical() {
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & !0u128 << 64).into()),
+            v4 => v4,
+        };
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recent.retain(|_, times| {
+            while times.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = recent.entry(address).or_default();
+        if times.len() >= self.per_hour {
+            return Err(AppError::ReportLimited);
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReportRequest {
+    reason: String,
+    notify_email: Option<String>,
+}
+
+/// `POST /report/:id` flags an entry for the operator.
+pub async fn create(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<ReportRequest>,
+) -> Result<StatusCode, AppError> {
+    if state.config.reports_per_hour == 0 {
+        return Err(AppError::NotFound);
+    }
+    let entry = live_entry(&state, &id).await?;
+    entry.check_takedown()?;
+    let reason = request.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_CHARS {
+        return Err(AppError::BadRequest(format!(
+            "reason must be between 1 and {} characters",
+            MAX_REASON_CHARS
+        )));
+    }
+    let notify_email = request
+        .notify_email
+        .filter(|address| !address.trim().is_empty())
+        .map(|address| match &state.mailer {
+            Some(_) => mail::check_address(&address),
+            None => Err(AppError::BadRequest(
+                "this server does not send notification emails".to_string(),
+            )),
+        })
+        .transpose()?;
+    state.reports.admit(client_address(&headers, peer))?;
+
+    let reporter = uploader_hash(&state, &headers, peer);
+    let report = Report {
+        at: SystemTime::now(),
+        reason: reason.to_string(),
+        reporter,
+        notify_email,
+    };
+    let patch = EntryPatch {
+        report: Some(report),
+        ..EntryPatch::default()
+    };
+    let entry = state
+        .metadata
+        .update(&id, &patch)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    state.webhooks.notify(Event::Report, &id, &entry);
+    state.feed.entry(Event::Report, &id, &entry);
+    state.audit.entry(Event::Report, &id, &entry);
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Emails everyone who reported `entry` and left an address what `outcome` was,
+/// within the same hourly limits as upload notifications.
+pub fn notify_reporters(state: &AppState, id: &str, entry: &FileEntry, outcome: Outcome) {
+    let Some(mailer) = &state.mailer else {
+        return;
+    };
+    let mut addresses: Vec<&str> = entry
+        .reports
+        .iter()
+        .filter_map(|report| report.notify_email.as_deref())
+        .collect();
+    addresses.sort_unstable_by_key(|address| address.to_ascii_lowercase());
+    addresses.dedup_by_key(|address| address.to_ascii_lowercase());
+    // The filename may be the abuse itself, so the email only names the link.
+    let url = state.config.build_download_url(id);
+    let subject = "Your report about a shared file";
+    let body = format!(
+        "Thank you for your report. The file at\n\n{}\n\n{}.\n",
+        url,
+        outcome.verdict()
+    );
+    for address in addresses {
+        match mailer.admit(address) {
+            Ok(to) => mailer.spawn_notice(to, subject, &body),
+            Err(_) => debug!("not telling a reporter about {}, too many emails", id),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Request ids. Every request gets one, the `X-Request-Id` a trusted proxy sent
+//! or else a new one, which is returned in the response's `X-Request-Id`, named
+//! in the body of server errors and written to the access log.
+//! The request is handled inside a `request` span with the id, the client
+//! address, method, path and the entry it is about, so every log line written
+//! meanwhile names its request: as span fields in text logs, as fields of their
+//! own with `LOG_FORMAT=json`. In that mode a `request finished` line adds the
+//! status and the latency up to the response headers; the access log has it up
+//! to the last byte. The span is also what `telemetry` exports as the request's
+//! trace span, and the scope of a Sentry hub of its own carries the same details
+//! for the errors it reports.
+
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use sentry::{Hub, SentryFutureExt};
+use tracing::{Instrument, Span, field, info, info_span};
+use uuid::Uuid;
+
+use crate::{AppState, config::LogFormat, live::client_address, telemetry, tenants};
+
+pub const REQUEST_ID: &str = "x-request-id";
+/// The W3C trace context of the proxy, for trace export.
+pub const TRACEPARENT: &str = "traceparent";
+/// Longer ids from a proxy are replaced rather than logged.
+const MAX_LEN: usize = 128;
+
+/// The id of the request, in its extensions.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    /// The id of the request the task is handling, for code far from it.
+    static CURRENT: String;
+}
+
+/// The id of the request being handled, if any.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(String::clone).ok()
+}
+
+/// `message` with the id of the request being handled, so that whoever reports
+/// a server error can name the request it happened to.
+pub fn mention(message: &str) -> String {
+    match current() {
+        Some(id) => format!("{} (request id {})", message, id),
+        None => message.to_string(),
+    }
+}
+
+/// Middleware run right after `proxy::forwarded`, so the forwarded client and
+/// request id are the checked ones.
+pub async fn tag(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let path = request.uri().path();
+    let client_ip = client_address(request.headers(), peer).to_canonical();
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("request_id", &id);
+        scope.set_tag("method", request.method());
+        scope.set_extra("path", path.into());
+        scope.set_extra("client_ip", client_ip.to_string().into());
+    });
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        client_ip = %client_ip,
+        method = %request.method(),
+        path,
+        content_length = field::Empty,
+        entry_id = field::Empty,
+        route = field::Empty,
+        status = field::Empty,
+        otel.name = field::Empty,
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+    );
+    if let Some(length) = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        span.record("content_length", length);
+        hub.configure_scope(|scope| scope.set_extra("content_length", length.into()));
+    }
+    if let Some(traceparent) = request
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+    {
+        telemetry::continue_trace(&span, traceparent);
+    }
+    let local = path
+        .strip_prefix(state.config.base_path.as_str())
+        .unwrap_or(path);
+    if let Some(entry) = tenants::addressed_entry(local) {
+        span.record("entry_id", entry.as_str());
+        hub.configure_scope(|scope| scope.set_tag("entry_id", entry));
+    }
+
+    let started = Instant::now();
+    let mut response = CURRENT
+        .scope(id.clone(), next.run(request).instrument(span.clone()).bind_hub(hub))
+        .await;
+    span.record("status", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+    if state.config.log_format == LogFormat::Json {
+        span.in_scope(|| {
+            info!(
+                status = response.status().as_u16(),
+                latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+                "request finished"
+            )
+        });
+    }
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Middleware on the routes that names the request's span after the route it
+/// matched, such as `GET /d/:id`, for trace export and as the transaction of
+/// the errors it reports to Sentry.
+pub async fn name_span(matched: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    if let Some(matched) = matched {
+        let name = format!("{} {}", request.method(), matched.as_str());
+        let span = Span::current();
+        span.record("route", matched.as_str());
+        span.record("otel.name", name.as_str());
+        sentry::configure_scope(|scope| {
+            scope.set_tag("route", matched.as_str());
+            scope.set_transaction(Some(&name));
+        });
+    }
+    next.run(request).await
+}
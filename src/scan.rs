@@ -0,0 +1,196 @@
+//! Malware scanning through clamd. With `CLAMD_ADDRESS` set, every upload starts
+//! out `pending` and is streamed to clamd in the background. Downloads wait for a
+//! clean verdict; infected files, and files that could not be scanned, stay
+//! quarantined until the admin API releases or purges them.
+
+use std::{io, sync::Arc, time::Duration};
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    sync::Semaphore,
+};
+use tracing::warn;
+
+use crate::{AppState, compression, metadata::EntryPatch, storage::ByteStream};
+
+/// Scans beyond this many wait their turn rather than piling onto clamd.
+const MAX_CONCURRENT_SCANS: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    /// Stored while scanning was off.
+    #[default]
+    Unscanned,
+    Pending,
+    Clean,
+    Infected,
+    /// clamd could not be reached or gave up on the file.
+    Failed,
+    /// The integrity scrub found the stored bytes damaged; see `scrub`.
+    Corrupt,
+}
+
+impl ScanStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Unscanned => "unscanned",
+            Self::Pending => "pending",
+            Self::Clean => "clean",
+            Self::Infected => "infected",
+            Self::Failed => "failed",
+            Self::Corrupt => "corrupt",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "unscanned" => Some(Self::Unscanned),
+            "pending" => Some(Self::Pending),
+            "clean" => Some(Self::Clean),
+            "infected" => Some(Self::Infected),
+            "failed" => Some(Self::Failed),
+            "corrupt" => Some(Self::Corrupt),
+            _ => None,
+        }
+    }
+
+    /// Whether downloads of the entry have to be refused for now.
+    pub fn withholds(self) -> bool {
+        !matches!(self, Self::Unscanned | Self::Clean)
+    }
+
+    pub fn is_quarantined(self) -> bool {
+        matches!(self, Self::Infected | Self::Failed | Self::Corrupt)
+    }
+}
+
+enum Verdict {
+    Clean,
+    Infected(String),
+}
+
+enum ClamdAddress {
+    Tcp(String),
+    Unix(String),
+}
+
+pub struct Scanner {
+    address: ClamdAddress,
+    timeout: Duration,
+    permits: Semaphore,
+}
+
+impl Scanner {
+    /// `address` is `host:port`, or the path of clamd's local socket.
+    pub fn new(address: &str, timeout: Duration) -> Self {
+        let address = if address.starts_with('/') {
+            ClamdAddress::Unix(address.to_string())
+        } else {
+            ClamdAddress::Tcp(address.to_string())
+        };
+        Self {
+            address,
+            timeout,
+            permits: Semaphore::new(MAX_CONCURRENT_SCANS),
+        }
+    }
+
+    async fn scan(&self, data: ByteStream) -> io::Result<Verdict> {
+        let scan = async {
+            match &self.address {
+                ClamdAddress::Tcp(address) => {
+                    instream(TcpStream::connect(address).await?, data).await
+                }
+                ClamdAddress::Unix(path) => {
+                    instream(UnixStream::connect(path).await?, data).await
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "clamd scan timed out"))?
+    }
+}
+
+/// Sends the blob with clamd's `INSTREAM` command and reads the verdict.
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+    mut data: ByteStream,
+) -> io::Result<Verdict> {
+    conn.write_all(b"zINSTREAM\0").await?;
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk?;
+        if chunk.is_empty() {
+            continue;
+        }
+        conn.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+        conn.write_all(&chunk).await?;
+    }
+    conn.write_all(&0u32.to_be_bytes()).await?;
+    conn.flush().await?;
+
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply).await?;
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.trim().to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd replied '{}'", reply)))
+    }
+}
+
+/// Scans an entry in the background and records the verdict on it.
+pub fn spawn(state: Arc<AppState>, id: String) {
+    tokio::spawn(async move { scan_entry(&state, &id).await });
+}
+
+async fn scan_entry(state: &AppState, id: &str) {
+    let Some(scanner) = &state.scanner else {
+        return;
+    };
+    let Ok(_permit) = scanner.permits.acquire().await else {
+        return;
+    };
+    let entry = match state.metadata.get(id).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return,
+        Err(err) => {
+            warn!(?err, "failed to look up {} for scanning", id);
+            return;
+        }
+    };
+
+    let verdict = match compression::open(&*state.storage, &entry, None).await {
+        Ok(stream) => scanner.scan(stream).await,
+        Err(err) => Err(err),
+    };
+    let (scan, threat) = match verdict {
+        Ok(Verdict::Clean) => (ScanStatus::Clean, None),
+        Ok(Verdict::Infected(signature)) => {
+            warn!(id = %id, signature = %signature, "quarantined infected upload");
+            (ScanStatus::Infected, Some(signature))
+        }
+        Err(err) => {
+            warn!(%err, "failed to scan upload {}", id);
+            (ScanStatus::Failed, Some(err.to_string()))
+        }
+    };
+
+    let patch = EntryPatch {
+        scan: Some(scan),
+        threat,
+        ..EntryPatch::default()
+    };
+    if let Err(err) = state.metadata.update(id, &patch).await {
+        warn!(?err, "failed to record scan result for {}", id);
+    }
+}
@@ -0,0 +1,197 @@
+//! Periodic integrity scrub. Every `SCRUB_INTERVAL_MINS`, each stored blob is read
+//! back and hashed against the SHA-256 recorded at upload, so bit rot and partial
+//! writes are caught before a recipient downloads a broken file. Damaged entries
+//! are quarantined as `corrupt`, or deleted with `SCRUB_ACTION=remove`; the last
+//! run is reported at `/admin/api/scrub`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, FileEntry, compression,
+    config::ScrubAction,
+    metadata::{EntryPatch, unix_seconds},
+    scan::ScanStatus,
+    webhook::Event,
+};
+
+#[derive(Clone, Default, Serialize)]
+pub struct ScrubReport {
+    running: bool,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    /// Entries whose blob was read back and hashed.
+    checked: u64,
+    /// Entries whose blob no longer matches its digest.
+    corrupt: u64,
+    /// Entries whose blob could not be read at all.
+    unreadable: u64,
+    /// Damaged entries deleted under `SCRUB_ACTION=remove`.
+    removed: u64,
+}
+
+#[derive(Default)]
+pub struct Scrubber {
+    running: AtomicBool,
+    report: Mutex<ScrubReport>,
+}
+
+impl Scrubber {
+    pub fn report(&self) -> ScrubReport {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ScrubReport> {
+        self.report.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub fn spawn_periodic(state: Arc<AppState>) {
+    let Some(period) = state.config.scrub_interval else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick fires at once; the startup checks have just run.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            run(&state).await;
+        }
+    });
+}
+
+/// Starts a scrub in the background unless one is already running; returns
+/// whether it started.
+pub fn spawn(state: Arc<AppState>) -> bool {
+    if state.scrub.running.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    tokio::spawn(async move { scrub(&state).await });
+    true
+}
+
+async fn run(state: &AppState) {
+    if !state.scrub.running.swap(true, Ordering::SeqCst) {
+        scrub(state).await;
+    }
+}
+
+/// The caller has set `running`.
+async fn scrub(state: &AppState) {
+    *state.scrub.lock() = ScrubReport {
+        running: true,
+        started_at: Some(unix_seconds(SystemTime::now())),
+        ..ScrubReport::default()
+    };
+
+    let entries = match state.metadata.list().await {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(?err, "failed to list entries for the integrity scrub");
+            Vec::new()
+        }
+    };
+
+    // Deduplicated entries share a blob, which only needs hashing once.
+    let mut verdicts: HashMap<String, Result<bool, String>> = HashMap::new();
+    for (id, entry) in entries {
+        // Withheld entries are not served anyway, and old ones have no digest.
+        let Some(expected) = entry.sha256.clone() else {
+            continue;
+        };
+        if entry.scan.withholds() {
+            continue;
+        }
+
+        let verdict = match verdicts.get(&entry.key) {
+            Some(verdict) => verdict.clone(),
+            None => {
+                let verdict = hash_blob(state, &entry)
+                    .await
+                    .map(|actual| actual == expected);
+                verdicts.insert(entry.key.clone(), verdict.clone());
+                verdict
+            }
+        };
+
+        let problem = match verdict {
+            Ok(true) => {
+                state.scrub.lock().checked += 1;
+                continue;
+            }
+            Ok(false) => {
+                let mut report = state.scrub.lock();
+                report.checked += 1;
+                report.corrupt += 1;
+                "stored data no longer matches its sha256".to_string()
+            }
+            Err(err) => {
+                state.scrub.lock().unreadable += 1;
+                format!("stored data could not be read: {}", err)
+            }
+        };
+        warn!(id = %id, problem = %problem, "integrity scrub found a damaged entry");
+
+        match state.config.scrub_action {
+            ScrubAction::Remove => match state.metadata.remove(&id).await {
+                Ok(Some(removed)) => {
+                    state.notify(Event::Delete, &id, &removed);
+                    state.discard(&removed).await;
+                    state.scrub.lock().removed += 1;
+                }
+                Ok(None) => {}
+                Err(err) => warn!(?err, "failed to remove damaged entry {}", id),
+            },
+            ScrubAction::Flag => {
+                let patch = EntryPatch {
+                    scan: Some(ScanStatus::Corrupt),
+                    threat: Some(problem),
+                    ..EntryPatch::default()
+                };
+                if let Err(err) = state.metadata.update(&id, &patch).await {
+                    warn!(?err, "failed to flag damaged entry {}", id);
+                }
+            }
+        }
+    }
+
+    let report = {
+        let mut report = state.scrub.lock();
+        report.running = false;
+        report.finished_at = Some(unix_seconds(SystemTime::now()));
+        report.clone()
+    };
+    state.scrub.running.store(false, Ordering::SeqCst);
+    info!(
+        checked = report.checked,
+        corrupt = report.corrupt,
+        unreadable = report.unreadable,
+        removed = report.removed,
+        "integrity scrub finished"
+    );
+}
+
+async fn hash_blob(state: &AppState, entry: &FileEntry) -> Result<String, String> {
+    let mut stream = compression::open(&*state.storage, entry, None)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk.map_err(|err| err.to_string())?);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,54 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Compares two secrets without leaking how many leading bytes matched.
+pub fn matches(expected: &str, provided: &str) -> bool {
+    expected.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+/// HMAC-SHA256 with `key` over `parts` joined by newlines, which is how every
+/// tag, token and signature the server issues is made.
+pub fn hmac_sha256(key: &str, parts: &[&str]) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac accepts keys of any length");
+    for (index, part) in parts.iter().enumerate() {
+        if index > 0 {
+            mac.update(b"\n");
+        }
+        mac.update(part.as_bytes());
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks that `hash` is an Argon2 PHC string or a bcrypt hash.
+pub fn validate_hash(hash: &str) -> Result<(), String> {
+    if is_bcrypt(hash) {
+        hash.parse::<bcrypt::HashParts>()
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    } else {
+        PasswordHash::new(hash)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Verifies `provided` against a hash accepted by `validate_hash`. Both
+/// algorithms compare the derived digest in constant time.
+pub fn verify_hash(hash: &str, provided: &str) -> bool {
+    if is_bcrypt(hash) {
+        bcrypt::verify(provided, hash).unwrap_or(false)
+    } else {
+        PasswordHash::new(hash)
+            .and_then(|parsed| Argon2::default().verify_password(provided.as_bytes(), &parsed))
+            .is_ok()
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
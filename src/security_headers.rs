@@ -0,0 +1,46 @@
+//! The security headers every response carries, from `SECURITY_HEADERS` and the
+//! variables next to it. A header a handler already set wins, so a download
+//! keeps its sandboxing policy and the decrypting page its referrer policy.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::AppState;
+
+/// Middleware adding the configured headers to whatever the routes answered.
+pub async fn apply(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let Some(config) = &state.config.security_headers else {
+        return response;
+    };
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let headers = response.headers_mut();
+    set(headers, header::X_CONTENT_TYPE_OPTIONS, Some(&HeaderValue::from_static("nosniff")));
+    set(headers, header::REFERRER_POLICY, config.referrer_policy.as_ref());
+    set(headers, header::X_FRAME_OPTIONS, config.frame_options.as_ref());
+    set(headers, header::STRICT_TRANSPORT_SECURITY, config.strict_transport_security.as_ref());
+    // Other responses run no script, and a policy on them would only get in the
+    // way of the ones the downloads set.
+    if is_html {
+        set(headers, header::CONTENT_SECURITY_POLICY, config.content_security_policy.as_ref());
+    }
+    response
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: Option<&HeaderValue>) {
+    if let Some(value) = value
+        && !headers.contains_key(&name)
+    {
+        headers.insert(name, value.clone());
+    }
+}
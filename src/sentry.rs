@@ -0,0 +1,56 @@
+//! Error reporting to Sentry, when `SENTRY_DSN` is set, through the Sentry SDK.
+//! Every error logged, such as the storage failure behind a 500 (`AppError::Io`)
+//! or a change the storage mirror gave up on, and every panic becomes a Sentry
+//! event, with the info and warning lines before it as breadcrumbs. A request
+//! is handled with a hub of its own (see `request_id::tag`), so its events carry
+//! its id, route, method and entry as tags. Past `SENTRY_MAX_EVENTS_PER_MINUTE`
+//! events are dropped, and the SDK backs off by itself while Sentry asks it to,
+//! so a failing disk does not flood the project.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use sentry::{ClientInitGuard, ClientOptions};
+use sentry_tracing::SentryLayer;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::SentryConfig;
+
+/// How long the events still queued at shutdown may take to be sent.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The layer to add to the subscriber and the client behind it, which sends
+/// what is still queued when dropped. Panics are reported from then on.
+pub fn start<S>(config: &SentryConfig) -> (SentryLayer<S>, ClientInitGuard)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // The SDK's HTTP client comes without a TLS backend; ring is the one used elsewhere.
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+
+    let limit = config.max_events_per_minute;
+    // When the current minute started and how many events were sent in it.
+    let window = Mutex::new((Instant::now(), 0));
+    let mut options = ClientOptions::new();
+    options.dsn = Some(config.dsn.clone());
+    options.release = Some(config.release.clone().into());
+    options.environment = config.environment.clone().map(Into::into);
+    options.shutdown_timeout = FLUSH_TIMEOUT;
+    options.before_send = Some(Arc::new(move |event| {
+        let mut window = window.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        if now.duration_since(window.0) >= Duration::from_secs(60) {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        (window.1 <= limit).then_some(event)
+    }));
+    let client = sentry::init(options);
+
+    // Spans are exported through `telemetry` if at all, so none become Sentry spans.
+    let layer = sentry_tracing::layer().span_filter(|_| false);
+    (layer, client)
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{AppState, UploadResponse, absolute_url, config::AppConfig, tenants};
+
+/// Upload reply for `?format=sharex`: absolute URLs only, with a deletion URL
+/// ShareX can open in a browser.
+#[derive(Serialize)]
+pub struct ShareXResponse {
+    url: String,
+    preview_url: String,
+    deletion_url: String,
+    expires_at: u64,
+}
+
+impl ShareXResponse {
+    pub fn new(config: &AppConfig, headers: &HeaderMap, response: &UploadResponse) -> Self {
+        Self {
+            url: absolute_url(config, headers, &tenants::entry_path("d", &response.id)),
+            preview_url: absolute_url(config, headers, &tenants::entry_path("p", &response.id)),
+            deletion_url: absolute_url(
+                config,
+                headers,
+                &format!(
+                    "{}/delete?token={}",
+                    tenants::entry_path("d", &response.id),
+                    response.delete_token
+                ),
+            ),
+            expires_at: response.expires_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SxcuParams {
+    /// Embedded as the `password` form field.
+    password: Option<String>,
+    /// An API key, embedded as an `Authorization: Bearer` header instead.
+    key: Option<String>,
+}
+
+/// `GET /sharex.sxcu` emits a ShareX custom uploader pointing at this instance.
+/// Credentials passed in the query string are written into the file so it works
+/// right after import.
+pub async fn sxcu(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SxcuParams>,
+    headers: HeaderMap,
+) -> Response {
+    let mut config = json!({
+        "Version": "15.0.0",
+        "Name": "newtemp.sh",
+        "DestinationType": "ImageUploader, TextUploader, FileUploader",
+        "RequestMethod": "POST",
+        "RequestURL": absolute_url(&state.config, &headers, "/upload"),
+        "Parameters": { "format": "sharex" },
+        "Body": "MultipartFormData",
+        "FileFormName": "file",
+        "URL": "{json:url}",
+        "DeletionURL": "{json:deletion_url}",
+        "ErrorMessage": "{response}",
+    });
+
+    if let Some(key) = params.key.filter(|key| !key.is_empty()) {
+        config["Headers"] = json!({ "Authorization": format!("Bearer {}", key) });
+    } else if state.config.upload_page_enabled {
+        config["Arguments"] = json!({ "password": params.password.unwrap_or_default() });
+    }
+
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"newtemp.sxcu\""),
+        )],
+        Json(config),
+    )
+        .into_response()
+}
@@ -0,0 +1,120 @@
+//! Self-destructing short links: `POST /shorten` records a target URL, and
+//! `/d/:id` redirects to it under the same expiry and download limits as files.
+//! The target is kept as the entry's (tiny) blob so nothing else needs to know
+//! about it.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    AppError, AppState, Credential, EntryKind, FileEntry, NewUpload, UploadParams, admin,
+    check_captcha, check_password, credential, read_blob, reply_format, store_upload, upload_reply,
+};
+
+const LINK_CONTENT_TYPE: &str = "text/uri-list";
+const MAX_TARGET_LEN: usize = 8 * 1024;
+
+#[derive(Deserialize)]
+struct ShortenRequest {
+    url: String,
+    expires: Option<String>,
+    slug: Option<String>,
+}
+
+/// `POST /shorten` takes the target as JSON `{"url": ..., "expires": ...}` or as
+/// the plain request body. Credentials work as for `PUT` uploads.
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let reply_format = reply_format(&headers, &params);
+    let credential = credential(&state, &headers, &params).await?;
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let request = if is_json {
+        serde_json::from_slice::<ShortenRequest>(&body)
+            .map_err(|err| AppError::BadRequest(format!("invalid shorten request: {}", err)))?
+    } else {
+        ShortenRequest {
+            url: String::from_utf8_lossy(&body).into_owned(),
+            expires: params.expires,
+            slug: params.slug,
+        }
+    };
+
+    let target = parse_target(request.url.trim())?;
+    let upload = NewUpload {
+        filename: "link".to_string(),
+        content_type: Some(LINK_CONTENT_TYPE.to_string()),
+        data: state.spool.write(target.as_str().as_bytes()).await?,
+        expires: request.expires,
+        kind: EntryKind::Redirect,
+        slug: request.slug,
+        sha256: None,
+        notify_email: None,
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().cloned(),
+        tenant: credential.tenant().cloned(),
+        token: credential.token().cloned(),
+    };
+    let response = store_upload(&state, upload, credential.api_key()).await?;
+    Ok(upload_reply(&state.config, &headers, response, reply_format))
+}
+
+/// Only web URLs are accepted, so a short link can never run script in the
+/// browser that follows it.
+fn parse_target(target: &str) -> Result<Url, AppError> {
+    if target.len() > MAX_TARGET_LEN {
+        return Err(AppError::BadRequest("target url is too long".to_string()));
+    }
+    Url::parse(target)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| AppError::BadRequest(format!("invalid url '{}'", target)))
+}
+
+/// Answers a redirect entry whose download was already counted, discarding it
+/// when this was the last one.
+pub async fn redirect(
+    state: &AppState,
+    entry: FileEntry,
+    last_hit: bool,
+) -> Result<Response, AppError> {
+    let data = read_blob(state, &entry).await;
+    if last_hit {
+        state.discard(&entry).await;
+    }
+    let target = String::from_utf8(data?).map_err(std::io::Error::other)?;
+    let location = HeaderValue::from_str(target.trim()).map_err(std::io::Error::other)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::LOCATION, location);
+    // Every visit has to reach the server to be counted.
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    Ok((StatusCode::FOUND, headers).into_response())
+}
@@ -0,0 +1,40 @@
+//! Custom download ids requested with `slug`, e.g. `/d/quarterly-report.pdf`.
+
+use regex::Regex;
+
+use crate::{AppError, config::AppConfig};
+
+/// Used when `SLUG_PATTERN` is not set: URL-safe characters only, starting with an
+/// alphanumeric so a slug never looks like a relative path.
+pub const DEFAULT_PATTERN: &str = r"^[A-Za-z0-9][A-Za-z0-9._~-]{0,127}$";
+
+/// Checks a requested slug against the configured patterns. Separators and dot
+/// segments are refused whatever the patterns say, since the id also names the
+/// metadata record on disk, and so is `:`, which sets off a tenant's entries.
+pub fn validate(config: &AppConfig, slug: &str) -> Result<String, AppError> {
+    let slug = slug.trim();
+    let invalid = || AppError::BadRequest(format!("slug '{}' is not allowed", slug));
+
+    if slug.is_empty()
+        || slug == "."
+        || slug == ".."
+        || slug.chars().any(|ch| matches!(ch, '/' | '\\' | ':') || ch.is_control())
+    {
+        return Err(invalid());
+    }
+    if !config.slug_pattern.is_match(slug) {
+        return Err(invalid());
+    }
+    if config
+        .slug_deny_pattern
+        .as_ref()
+        .is_some_and(|deny| deny.is_match(slug))
+    {
+        return Err(invalid());
+    }
+    Ok(slug.to_string())
+}
+
+pub fn compile(name: &str, pattern: &str) -> Result<Regex, AppError> {
+    Regex::new(pattern).map_err(|err| AppError::Config(format!("invalid {}: {}", name, err)))
+}
@@ -0,0 +1,175 @@
+//! Upload bodies are received into temporary files under `UPLOAD_SESSION_DIR`
+//! rather than memory, and hashed while they arrive, so a large upload costs
+//! disk space instead of RAM and is never read twice just to be digested.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{AppError, storage::ByteStream};
+
+/// Spooled files are read back in chunks this large on their way to storage.
+const READ_CHUNK_BYTES: usize = 256 * 1024;
+
+/// The directory uploads are spooled in.
+pub struct Spooler {
+    dir: PathBuf,
+}
+
+impl Spooler {
+    /// Creates the directory, clearing out whatever an earlier run left behind.
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        match std::fs::remove_dir_all(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// A fresh name in the directory, for a file written by other means and
+    /// then taken over with `Spool::adopt`.
+    pub fn temp_path(&self) -> PathBuf {
+        self.dir.join(Uuid::new_v4().simple().to_string())
+    }
+
+    pub async fn create(&self) -> io::Result<SpoolWriter> {
+        let temp = TempFile(self.temp_path());
+        let file = fs::File::create(&temp.0).await?;
+        Ok(SpoolWriter {
+            temp,
+            file,
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+
+    /// Spools a request body, bailing out with 413 as soon as it grows past
+    /// `limit` instead of reading the rest of it.
+    pub async fn receive<S, E>(
+        &self,
+        mut stream: S,
+        limit: usize,
+        map_err: impl Fn(E) -> AppError,
+    ) -> Result<Spool, AppError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+    {
+        let mut writer = self.create().await?;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(&map_err)?;
+            if writer.len + chunk.len() as u64 > limit as u64 {
+                return Err(AppError::PayloadTooLarge { limit });
+            }
+            writer.write(&chunk).await?;
+        }
+        Ok(writer.finish().await?)
+    }
+
+    /// Spools a body that is small and in memory already, such as a paste.
+    pub async fn write(&self, data: &[u8]) -> io::Result<Spool> {
+        let mut writer = self.create().await?;
+        writer.write(data).await?;
+        writer.finish().await
+    }
+
+    /// Spools a copy of a file kept elsewhere, such as a finished resumable upload.
+    pub async fn copy(&self, path: &Path) -> io::Result<Spool> {
+        let mut writer = self.create().await?;
+        writer.append(path).await?;
+        writer.finish().await
+    }
+}
+
+/// Removed when dropped, so a failed or finished upload leaves nothing behind.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A file being spooled, hashed as it is written.
+pub struct SpoolWriter {
+    temp: TempFile,
+    file: fs::File,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl SpoolWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.hasher.update(chunk);
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Appends the contents of another file.
+    pub async fn append(&mut self, path: &Path) -> io::Result<()> {
+        let file = fs::File::open(path).await?;
+        let mut chunks = ReaderStream::with_capacity(file, READ_CHUNK_BYTES);
+        while let Some(chunk) = chunks.next().await {
+            self.write(&chunk?).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> io::Result<Spool> {
+        self.file.flush().await?;
+        Ok(Spool {
+            temp: self.temp,
+            len: self.len,
+            sha256: hex::encode(self.hasher.finalize()),
+        })
+    }
+}
+
+/// A fully received upload body on disk, with its size and SHA-256 digest.
+pub struct Spool {
+    temp: TempFile,
+    len: u64,
+    sha256: String,
+}
+
+impl Spool {
+    /// Takes over a file written at a `Spooler::temp_path`, such as a bundle
+    /// zipped in place, and hashes it. Blocks; the file is removed on failure too.
+    pub fn adopt(path: PathBuf) -> io::Result<Self> {
+        let temp = TempFile(path);
+        let mut hasher = Sha256::new();
+        let len = io::copy(&mut std::fs::File::open(&temp.0)?, &mut hasher)?;
+        Ok(Self {
+            temp,
+            len,
+            sha256: hex::encode(hasher.finalize()),
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.temp.0
+    }
+
+    /// Reads the file back, for handing it to `StorageBackend::put`.
+    pub async fn stream(&self) -> io::Result<ByteStream> {
+        let file = fs::File::open(self.path()).await?;
+        Ok(ReaderStream::with_capacity(file, READ_CHUNK_BYTES).boxed())
+    }
+}
@@ -0,0 +1,138 @@
+//! Keeps a copy of every blob on a second backend under `STORAGE_MIRROR`. Writes
+//! and deletes reach the mirror in the background, in the order they happened, so
+//! uploads never wait on it; a written blob is copied over from the primary, so
+//! the queue holds keys rather than bytes. Reads and existence checks go to the
+//! primary and fall back to the mirror when it does not have the blob, which also
+//! keeps startup from dropping entries whose blobs only the mirror still has.
+
+use std::{collections::BTreeSet, io, ops::Range, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use super::{ByteStream, StorageBackend};
+
+/// Changes waiting for the mirror; uploads wait for room once it falls this far
+/// behind.
+const QUEUE_LEN: usize = 1024;
+/// Tries per change before it is given up on.
+const ATTEMPTS: u32 = 3;
+
+enum Change {
+    Put(String),
+    Delete(String),
+}
+
+pub struct MirroredStorage {
+    primary: Arc<dyn StorageBackend>,
+    mirror: Arc<dyn StorageBackend>,
+    changes: mpsc::Sender<Change>,
+}
+
+impl MirroredStorage {
+    pub fn new(primary: Arc<dyn StorageBackend>, mirror: Arc<dyn StorageBackend>) -> Self {
+        let (changes, queue) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(replicate(primary.clone(), mirror.clone(), queue));
+        Self {
+            primary,
+            mirror,
+            changes,
+        }
+    }
+
+    async fn forward(&self, change: Change) {
+        if self.changes.send(change).await.is_err() {
+            warn!("storage mirror is no longer being updated");
+        }
+    }
+}
+
+async fn replicate(
+    primary: Arc<dyn StorageBackend>,
+    mirror: Arc<dyn StorageBackend>,
+    mut queue: mpsc::Receiver<Change>,
+) {
+    while let Some(change) = queue.recv().await {
+        for attempt in 1..=ATTEMPTS {
+            let (key, result) = match &change {
+                Change::Put(key) => (key, copy(&*primary, &*mirror, key).await),
+                Change::Delete(key) => (key, mirror.delete(key).await),
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) if attempt == ATTEMPTS => {
+                    error!(%err, "failed to mirror the change to {}", key);
+                }
+                Err(_) => tokio::time::sleep(Duration::from_secs(attempt.into())).await,
+            }
+        }
+    }
+}
+
+/// A blob deleted before its turn came has nothing left to copy; the delete
+/// queued after it settles the mirror.
+async fn copy(
+    primary: &dyn StorageBackend,
+    mirror: &dyn StorageBackend,
+    key: &str,
+) -> io::Result<()> {
+    match primary.stream(key, None).await {
+        Ok(data) => mirror.put(key, data).await,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MirroredStorage {
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<()> {
+        self.primary.put(key, data).await?;
+        self.forward(Change::Put(key.to_string())).await;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        match self.primary.get(key).await {
+            Ok(data) => Ok(data),
+            Err(err) => {
+                warn!(%err, "reading {} from the storage mirror", key);
+                self.mirror.get(key).await.map_err(|_| err)
+            }
+        }
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        match self.primary.stream(key, range.clone()).await {
+            Ok(stream) => Ok(stream),
+            Err(err) => {
+                warn!(%err, "reading {} from the storage mirror", key);
+                self.mirror.stream(key, range).await.map_err(|_| err)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.primary.delete(key).await?;
+        self.forward(Change::Delete(key.to_string())).await;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        if self.primary.exists(key).await? {
+            return Ok(true);
+        }
+        let mirrored = self.mirror.exists(key).await?;
+        if mirrored {
+            warn!("{} is missing from the primary storage, serving it from the mirror", key);
+        }
+        Ok(mirrored)
+    }
+
+    async fn list_keys(&self) -> io::Result<Vec<String>> {
+        let mut keys: BTreeSet<String> = self.primary.list_keys().await?.into_iter().collect();
+        keys.extend(self.mirror.list_keys().await?);
+        Ok(keys.into_iter().collect())
+    }
+}
@@ -0,0 +1,105 @@
+use std::{io, ops::Range, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, stream::BoxStream};
+
+use crate::{
+    AppError,
+    config::{AppConfig, StorageKind},
+};
+
+mod local;
+mod mirror;
+mod object;
+mod traced;
+
+pub use local::LocalStorage;
+pub use mirror::MirroredStorage;
+pub use object::ObjectStorage;
+pub use traced::TracedStorage;
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// A blob that is in memory already, in the shape `StorageBackend::put` takes.
+pub fn once(data: Bytes) -> ByteStream {
+    futures_util::stream::once(async { Ok(data) }).boxed()
+}
+
+/// Where uploaded blobs live. Keys are the download ids handed out by `upload`.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Writes the blob as it streams in; nothing is visible under `key` unless
+    /// the whole stream was stored.
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<()>;
+
+    #[allow(dead_code)]
+    async fn get(&self, key: &str) -> io::Result<Bytes>;
+
+    /// Streams the blob, or only the given half-open byte span of it.
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ByteStream>;
+
+    /// Removes the blob; a missing key is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Every key in the store, for reconciling it with the metadata at startup.
+    async fn list_keys(&self) -> io::Result<Vec<String>>;
+}
+
+pub async fn from_config(config: &AppConfig) -> Result<Arc<dyn StorageBackend>, AppError> {
+    let primary = open(config, config.storage_kind, "STORAGE_BACKEND").await?;
+    match config.storage_mirror {
+        Some(kind) => {
+            let mirror = open(config, kind, "STORAGE_MIRROR").await?;
+            Ok(Arc::new(MirroredStorage::new(primary, mirror)))
+        }
+        None => Ok(primary),
+    }
+}
+
+/// `setting` names the variable that chose `kind`, for the error messages. With
+/// trace export on, each call to the backend gets a span of its own.
+async fn open(
+    config: &AppConfig,
+    kind: StorageKind,
+    setting: &str,
+) -> Result<Arc<dyn StorageBackend>, AppError> {
+    let storage = open_backend(config, kind, setting).await?;
+    Ok(match config.otlp {
+        Some(_) => Arc::new(TracedStorage::new(storage, kind.name())),
+        None => storage,
+    })
+}
+
+async fn open_backend(
+    config: &AppConfig,
+    kind: StorageKind,
+    setting: &str,
+) -> Result<Arc<dyn StorageBackend>, AppError> {
+    match kind {
+        StorageKind::Local => Ok(Arc::new(
+            LocalStorage::open(&config.storage_dir, config.storage_fsync, config.storage_layout)
+                .await?,
+        )),
+        StorageKind::S3 => {
+            let s3 = config.s3.as_ref().ok_or_else(|| {
+                AppError::Config(format!("{}=s3 requires S3_BUCKET", setting))
+            })?;
+            Ok(Arc::new(ObjectStorage::s3(s3)?))
+        }
+        StorageKind::Azure => {
+            let azure = config.azure.as_ref().ok_or_else(|| {
+                AppError::Config(format!("{}=azure requires AZURE_CONTAINER", setting))
+            })?;
+            Ok(Arc::new(ObjectStorage::azure(azure)?))
+        }
+        StorageKind::Gcs => {
+            let gcs = config.gcs.as_ref().ok_or_else(|| {
+                AppError::Config(format!("{}=gcs requires GCS_BUCKET", setting))
+            })?;
+            Ok(Arc::new(ObjectStorage::gcs(gcs)?))
+        }
+    }
+}
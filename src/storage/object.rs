@@ -0,0 +1,234 @@
+use std::{io, ops::Range, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::{
+    GetOptions, GetRange, ObjectStore, PutPayload, WriteMultipart, aws::AmazonS3Builder,
+    azure::{AzureConfigKey, MicrosoftAzureBuilder},
+    gcp::GoogleCloudStorageBuilder,
+    path::Path,
+};
+
+use super::{ByteStream, StorageBackend};
+use crate::{
+    AppError,
+    config::{AzureConfig, GcsConfig, S3Config},
+};
+
+/// Blobs above this size are uploaded in parts rather than in a single request.
+const MULTIPART_THRESHOLD: usize = 16 * 1024 * 1024;
+/// The size of each part of a multipart upload.
+const PART_BYTES: usize = 8 * 1024 * 1024;
+/// How many parts of one upload may be in flight at once.
+const PARTS_IN_FLIGHT: usize = 4;
+
+/// Stores blobs in an object store bucket, optionally under a key prefix.
+pub struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Option<String>,
+}
+
+impl ObjectStorage {
+    /// Builds an S3 (or S3-compatible, e.g. MinIO) client. Standard `AWS_*`
+    /// variables are honored and the dedicated `S3_*` settings take precedence.
+    pub fn s3(config: &S3Config) -> Result<Self, AppError> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_allow_http(config.allow_http);
+
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        let store = builder
+            .build()
+            .map_err(|err| AppError::Config(format!("invalid S3 settings: {}", err)))?;
+
+        Ok(Self {
+            store: Arc::new(store),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    /// Builds an Azure Blob Storage client. Standard `AZURE_*` variables are
+    /// honored too; with neither a SAS token nor a key, the host's managed
+    /// identity is used.
+    pub fn azure(config: &AzureConfig) -> Result<Self, AppError> {
+        let mut builder = MicrosoftAzureBuilder::from_env()
+            .with_container_name(&config.container)
+            .with_allow_http(config.allow_http);
+
+        if let Some(account) = &config.account {
+            builder = builder.with_account(account);
+        }
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint.clone());
+        }
+        if let Some(client_id) = &config.client_id {
+            builder = builder.with_client_id(client_id);
+        }
+        if let Some(sas_token) = &config.sas_token {
+            builder = builder.with_config(AzureConfigKey::SasKey, sas_token);
+        }
+
+        let store = builder
+            .build()
+            .map_err(|err| AppError::Config(format!("invalid Azure settings: {}", err)))?;
+
+        Ok(Self {
+            store: Arc::new(store),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    /// Builds a Google Cloud Storage client. Standard `GOOGLE_*` variables are
+    /// honored too, `GOOGLE_APPLICATION_CREDENTIALS` among them; with no key at
+    /// all, tokens come from the metadata server (workload identity on GKE).
+    ///
+    /// Emulators such as fake-gcs-server are reached through a key file that
+    /// carries `gcs_base_url` and `disable_oauth`, the form the client documents.
+    pub fn gcs(config: &GcsConfig) -> Result<Self, AppError> {
+        let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(&config.bucket);
+        if let Some(path) = &config.service_account_path {
+            builder = builder.with_service_account_path(path);
+        }
+
+        let store = builder
+            .build()
+            .map_err(|err| AppError::Config(format!("invalid GCS settings: {}", err)))?;
+
+        Ok(Self {
+            store: Arc::new(store),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    fn location(&self, key: &str) -> Path {
+        match &self.prefix {
+            Some(prefix) => Path::from(format!("{}/{}", prefix, key)),
+            None => Path::from(key),
+        }
+    }
+}
+
+fn into_io(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::other(err),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ObjectStorage {
+    /// Small blobs go up in one request once the stream ends; a stream that
+    /// outgrows the threshold switches to a multipart upload on the way.
+    async fn put(&self, key: &str, mut data: ByteStream) -> io::Result<()> {
+        let location = self.location(key);
+        let mut head = Vec::new();
+        let mut buffered = 0;
+        while buffered <= MULTIPART_THRESHOLD {
+            match data.next().await.transpose()? {
+                Some(chunk) => {
+                    buffered += chunk.len();
+                    head.push(chunk);
+                }
+                None => {
+                    let payload = head.into_iter().collect::<PutPayload>();
+                    return self
+                        .store
+                        .put(&location, payload)
+                        .await
+                        .map(|_| ())
+                        .map_err(into_io);
+                }
+            }
+        }
+
+        let upload = self.store.put_multipart(&location).await.map_err(into_io)?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_BYTES);
+        for chunk in head {
+            writer.put(chunk);
+        }
+        while let Some(chunk) = data.next().await {
+            let err = match chunk {
+                Ok(chunk) => match writer.wait_for_capacity(PARTS_IN_FLIGHT).await {
+                    Ok(()) => {
+                        writer.put(chunk);
+                        continue;
+                    }
+                    Err(err) => into_io(err),
+                },
+                Err(err) => err,
+            };
+            let _ = writer.abort().await;
+            return Err(err);
+        }
+        // A failed upload is aborted by `finish`, so no parts are left behind.
+        writer.finish().await.map(|_| ()).map_err(into_io)
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        let result = self.store.get(&self.location(key)).await.map_err(into_io)?;
+        result.bytes().await.map_err(into_io)
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let options = GetOptions {
+            range: range.map(GetRange::Bounded),
+            ..Default::default()
+        };
+        let result = self
+            .store
+            .get_opts(&self.location(key), options)
+            .await
+            .map_err(into_io)?;
+        Ok(result.into_stream().map_err(into_io).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.store.delete(&self.location(key)).await {
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            result => result.map_err(into_io),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.store.head(&self.location(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(err) => Err(into_io(err)),
+        }
+    }
+
+    async fn list_keys(&self) -> io::Result<Vec<String>> {
+        let prefix = self.prefix.as_deref().map(Path::from);
+        let objects: Vec<_> = self
+            .store
+            .list(prefix.as_ref())
+            .try_collect()
+            .await
+            .map_err(into_io)?;
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| {
+                let location = object.location.as_ref();
+                let key = match &prefix {
+                    Some(prefix) => location.strip_prefix(prefix.as_ref())?.strip_prefix('/')?,
+                    None => location,
+                };
+                // Blob keys never contain a slash; deeper objects are not ours.
+                (!key.contains('/')).then(|| key.to_string())
+            })
+            .collect())
+    }
+}
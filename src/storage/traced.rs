@@ -0,0 +1,86 @@
+//! Wraps a backend in a span per call when traces are exported, so the time a
+//! request spent waiting on storage shows in its trace.
+
+use std::{io, ops::Range, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::{Instrument, info_span};
+
+use super::{ByteStream, StorageBackend};
+
+pub struct TracedStorage {
+    inner: Arc<dyn StorageBackend>,
+    /// Such as `s3`, to tell a mirror's calls from the primary's.
+    backend: &'static str,
+}
+
+impl TracedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, backend: &'static str) -> Self {
+        Self { inner, backend }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for TracedStorage {
+    /// The span covers the whole upload, which is streamed through.
+    async fn put(&self, key: &str, data: ByteStream) -> io::Result<()> {
+        let span = info_span!(
+            "storage.put",
+            otel.kind = "client",
+            backend = self.backend,
+            key
+        );
+        self.inner.put(key, data).instrument(span).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        let span = info_span!(
+            "storage.get",
+            otel.kind = "client",
+            backend = self.backend,
+            key
+        );
+        self.inner.get(key).instrument(span).await
+    }
+
+    /// The span covers opening the stream, not reading it.
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let span = info_span!(
+            "storage.stream",
+            otel.kind = "client",
+            backend = self.backend,
+            key
+        );
+        self.inner.stream(key, range).instrument(span).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let span = info_span!(
+            "storage.delete",
+            otel.kind = "client",
+            backend = self.backend,
+            key
+        );
+        self.inner.delete(key).instrument(span).await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        let span = info_span!(
+            "storage.exists",
+            otel.kind = "client",
+            backend = self.backend,
+            key
+        );
+        self.inner.exists(key).instrument(span).await
+    }
+
+    async fn list_keys(&self) -> io::Result<Vec<String>> {
+        let span = info_span!(
+            "storage.list_keys",
+            otel.kind = "client",
+            backend = self.backend
+        );
+        self.inner.list_keys().instrument(span).await
+    }
+}
@@ -0,0 +1,158 @@
+//! Running as a systemd service. With socket activation (`LISTEN_FDS`) the server
+//! takes the sockets systemd passed instead of binding its own: the one named
+//! `admin` (`FileDescriptorName=admin`) for `ADMIN_ADDRESS`, the one named `acme`
+//! for `ACME_HTTP_ADDRESS`, and the first other one for `ADDRESS`. Whatever was
+//! not passed is bound as usual. With `NOTIFY_SOCKET` (`Type=notify`) it reports
+//! when it is ready to serve and when it is stopping, and pings the watchdog when
+//! the unit sets `WatchdogSec=`.
+
+use std::{env, io, net::SocketAddr, sync::Mutex, time::Duration};
+
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// What a listener is for, which decides the passed socket it takes.
+#[derive(Clone, Copy)]
+pub enum Socket {
+    Main,
+    Admin,
+    Acme,
+}
+
+impl Socket {
+    fn matches(self, name: &str) -> bool {
+        match self {
+            Self::Main => !matches!(name, "admin" | "acme"),
+            Self::Admin => name == "admin",
+            Self::Acme => name == "acme",
+        }
+    }
+}
+
+/// The sockets systemd passed that no listener took yet, with their names.
+static PASSED: Mutex<Option<Vec<(String, std::net::TcpListener)>>> = Mutex::new(None);
+
+/// Takes the socket systemd passed for `socket`, or binds `address` when there
+/// is none.
+pub async fn listen(socket: Socket, address: SocketAddr) -> io::Result<TcpListener> {
+    let passed = {
+        let mut passed = PASSED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let passed = passed.get_or_insert_with(passed_sockets);
+        passed
+            .iter()
+            .position(|(name, _)| socket.matches(name))
+            .map(|index| passed.remove(index))
+    };
+    match passed {
+        Some((name, listener)) => {
+            info!(name = %name, "using the socket passed by systemd");
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        }
+        None => TcpListener::bind(address).await,
+    }
+}
+
+/// The listening TCP sockets in `LISTEN_FDS`, if they were meant for this process.
+#[cfg(unix)]
+fn passed_sockets() -> Vec<(String, std::net::TcpListener)> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    /// Passed sockets start right after stdin, stdout and stderr.
+    const FIRST_FD: RawFd = 3;
+
+    if env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        != Some(std::process::id())
+    {
+        return Vec::new();
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (FIRST_FD..FIRST_FD.saturating_add(count))
+        .filter_map(|fd| {
+            let name = names.next().unwrap_or_default().to_string();
+            // SAFETY: systemd hands these descriptors to this process alone, and
+            // `PASSED` makes sure each is taken over once.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(_) => Some((name, listener)),
+                Err(err) => {
+                    warn!(%err, fd, name = %name, "ignoring a passed socket that is not TCP");
+                    std::mem::forget(listener);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn passed_sockets() -> Vec<(String, std::net::TcpListener)> {
+    Vec::new()
+}
+
+/// Sends `state`, such as `READY=1`, to the service manager, if there is one
+/// listening.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("NOTIFY_SOCKET")
+        && let Err(err) = send(&path, state)
+    {
+        warn!(%err, "failed to notify systemd");
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    // A leading `@` stands for Linux's abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &address)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// Pings the watchdog at half the interval the unit asks for. The pings come
+/// from the runtime that serves requests, so they stop when it gets stuck.
+pub fn spawn_watchdog() {
+    let Some(interval) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+    else {
+        return;
+    };
+    if env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid != std::process::id())
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
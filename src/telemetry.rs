@@ -0,0 +1,107 @@
+//! Trace export with OTLP through the OpenTelemetry SDK, when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set.
+//! Every span that passes the log filter becomes an OpenTelemetry span: the one
+//! around each request, named after its route, the cleanup rounds, and each call
+//! to the storage backend, with the events logged inside them attached. A
+//! request from a trusted proxy that carries a W3C `traceparent` continues the
+//! proxy's trace, so a trace shows the whole way from the proxy down to the
+//! storage calls. The exporter and the SDK read the other `OTEL_*` variables
+//! themselves, such as the headers and timeout of the export or the sampler
+//! and batch sizes.
+//!
+//! Fields named `otel.name`, `otel.kind` and `otel.status_code` set the span's
+//! name, kind and status; all other fields become attributes.
+
+use std::{collections::HashMap, time::Duration};
+
+use opentelemetry::{
+    Key, KeyValue,
+    propagation::TextMapPropagator,
+    trace::{TraceContextExt, TracerProvider},
+};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use tracing::{Span, Subscriber, warn};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{AppError, config::OtlpConfig, request_id::TRACEPARENT};
+
+/// How long the spans still queued at shutdown may take to be exported.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exports the spans the layer hands it in batches until shut down.
+pub struct Exporter {
+    provider: SdkTracerProvider,
+}
+
+impl Exporter {
+    /// Exports whatever spans are still queued, for at most a few seconds.
+    pub async fn flush(self) {
+        let provider = self.provider;
+        let shutdown =
+            tokio::task::spawn_blocking(move || provider.shutdown_with_timeout(FLUSH_TIMEOUT));
+        if let Ok(Err(err)) = shutdown.await {
+            warn!(%err, "gave up exporting the last spans");
+        }
+    }
+}
+
+/// The layer to add to the subscriber and the exporter behind it.
+pub fn start<S>(
+    config: &OtlpConfig,
+) -> Result<(OpenTelemetryLayer<S, SdkTracer>, Exporter), AppError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // The exporter's HTTP client comes without a TLS backend; ring is the one used elsewhere.
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+
+    let exporter = match config.protocol {
+        Protocol::Grpc => SpanExporter::builder().with_tonic().build(),
+        protocol => SpanExporter::builder()
+            .with_http()
+            .with_protocol(protocol)
+            .build(),
+    }
+    .map_err(|err| AppError::Config(format!("invalid OTLP exporter settings: {}", err)))?;
+
+    // Named after the binary unless OTEL_SERVICE_NAME or OTEL_RESOURCE_ATTRIBUTES
+    // name the service.
+    let mut resource = Resource::builder();
+    let detected = Resource::builder().build();
+    let named = detected
+        .get(&Key::from_static_str("service.name"))
+        .is_some_and(|name| !name.as_str().starts_with("unknown_service"));
+    if !named {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let resource = resource
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    // A span is started once it has children or closes rather than when first
+    // entered, after `request_id::name_span` has named it after its route.
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .with_context_activation(false);
+    Ok((layer, Exporter { provider }))
+}
+
+/// Continues the trace of the `traceparent` a trusted proxy sent, before `span`
+/// has children. Makes no difference unless traces are exported.
+pub fn continue_trace(span: &Span, traceparent: &str) {
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let context = TraceContextPropagator::new().extract(&carrier);
+    if context.span().span_context().is_remote() {
+        let _ = span.set_parent(context);
+    }
+}
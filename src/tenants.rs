@@ -0,0 +1,265 @@
+//! Tenants partition one server into separate namespaces, each with its own
+//! upload token, limits and storage prefix. A tenant's entries are stored under
+//! the id `<tenant>:<id>` and served at `/t/<tenant>/d/<id>`; `scope` maps the one
+//! onto the other before routing, so every route that takes an id keeps tenants
+//! apart without knowing about them.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Uri, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{AppError, AppState, FileEntry, keys, metadata};
+
+/// Tells tenant tokens apart from API keys and user tokens.
+pub const TOKEN_PREFIX: &str = "ntt_";
+/// Blobs of tenant `acme` are stored under `tenants/acme/`.
+pub const STORAGE_DIR: &str = "tenants";
+/// Between the tenant and the id under it; ids and slugs never contain it.
+const SEPARATOR: char = ':';
+const MAX_ID_LEN: usize = 63;
+
+/// Only the SHA-256 of the token is kept; the plaintext is shown when the tenant
+/// is created or its token replaced.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    /// Also the tenant's path segment under `/t/` and its storage directory.
+    pub id: String,
+    pub name: String,
+    pub token_hash: String,
+    #[serde(with = "metadata::unix_time")]
+    pub created_at: SystemTime,
+    /// Bytes the tenant's unexpired uploads may take up together, as uploaded.
+    #[serde(default)]
+    pub storage_quota_bytes: Option<u64>,
+    /// Per-file cap; the global `MAX_UPLOAD_BYTES` still applies on top.
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    /// Lifetime of uploads that do not ask for one; `MAX_TTL_MINS` still applies.
+    #[serde(default)]
+    pub default_ttl_secs: Option<u64>,
+    /// Bytes downloads of the tenant's entries may send per month.
+    #[serde(default)]
+    pub monthly_transfer_bytes: Option<u64>,
+}
+
+impl Tenant {
+    /// Builds a new tenant record and returns it along with the plaintext token.
+    pub fn generate(id: String, name: String) -> (Self, String) {
+        let mut tenant = Self {
+            id,
+            name,
+            token_hash: String::new(),
+            created_at: SystemTime::now(),
+            storage_quota_bytes: None,
+            max_upload_bytes: None,
+            default_ttl_secs: None,
+            monthly_transfer_bytes: None,
+        };
+        let token = tenant.replace_token();
+        (tenant, token)
+    }
+
+    /// Gives the tenant a new token, which stops the old one from working once
+    /// the record is saved.
+    pub fn replace_token(&mut self) -> String {
+        let token = format!("{}{}", TOKEN_PREFIX, Uuid::new_v4().simple());
+        self.token_hash = keys::hash_key(&token);
+        token
+    }
+
+    /// The largest file this tenant may upload given the server-wide `limit`.
+    pub fn upload_limit(&self, limit: usize) -> usize {
+        self.max_upload_bytes
+            .map(|max| max.min(limit as u64) as usize)
+            .unwrap_or(limit)
+    }
+
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl_secs.map(Duration::from_secs)
+    }
+
+    /// What the storage keys of this tenant's blobs start with.
+    pub fn storage_prefix(&self) -> String {
+        format!("{}/{}/", STORAGE_DIR, self.id)
+    }
+
+    /// The id an entry that is `local` under `/t/<tenant>/` is stored under.
+    pub fn entry_id(&self, local: &str) -> String {
+        format!("{}{}{}", self.id, SEPARATOR, local)
+    }
+}
+
+/// Tenant ids end up in URLs and directory names, so they are kept to lowercase
+/// letters, digits and dashes.
+pub fn validate_id(id: &str) -> Result<String, AppError> {
+    let id = id.trim();
+    if !is_valid_id(id) {
+        return Err(AppError::BadRequest(format!(
+            "tenant id '{}' must be 1 to {} lowercase letters, digits or dashes, \
+             starting with a letter or digit",
+            id, MAX_ID_LEN
+        )));
+    }
+    Ok(id.to_string())
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .next()
+            .is_some_and(|first| first.is_ascii_lowercase() || first.is_ascii_digit())
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+}
+
+/// Splits a stored entry id into its tenant, if any, and the id under it.
+pub fn split(id: &str) -> (Option<&str>, &str) {
+    match id.split_once(SEPARATOR) {
+        Some((tenant, local)) => (Some(tenant), local),
+        None => (None, id),
+    }
+}
+
+/// The path of entry `id` under `section`, e.g. `/d/report.pdf` or, for one of
+/// tenant `acme`'s, `/t/acme/d/report.pdf`.
+pub fn entry_path(section: &str, id: &str) -> String {
+    match split(id) {
+        (Some(tenant), local) => format!("/t/{}/{}/{}", tenant, section, local),
+        (None, id) => format!("/{}/{}", section, id),
+    }
+}
+
+/// The name of a blob without the tenant directory its key may start with.
+pub fn blob_name(key: &str) -> &str {
+    key.strip_prefix(STORAGE_DIR)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(key, |(_, name)| name)
+}
+
+/// Whether a stored entry id belongs to `tenant`.
+pub fn owns(tenant: &str, id: &str) -> bool {
+    split(id).0 == Some(tenant)
+}
+
+/// The tenant's unexpired entries among `entries`.
+pub fn owned(tenant: &Tenant, entries: &[(String, FileEntry)]) -> Vec<(String, FileEntry)> {
+    let now = SystemTime::now();
+    entries
+        .iter()
+        .filter(|(id, entry)| owns(&tenant.id, id) && now < entry.expires_at)
+        .cloned()
+        .collect()
+}
+
+/// Refuses an upload of `bytes` that would take the tenant past its quota.
+/// Uploads finishing at the same moment are not counted against each other.
+pub async fn check_quota(state: &AppState, tenant: &Tenant, bytes: u64) -> Result<(), AppError> {
+    let Some(quota) = tenant.storage_quota_bytes else {
+        return Ok(());
+    };
+    let entries = owned(tenant, &state.metadata.list().await?);
+    let used: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+    if used.saturating_add(bytes) > quota {
+        return Err(AppError::TenantQuotaExceeded { quota, used });
+    }
+    Ok(())
+}
+
+/// The tenant named by a `ntt_` bearer token, if the request carries one.
+async fn bearer(state: &AppState, headers: &HeaderMap) -> Result<Option<Tenant>, AppError> {
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| token.starts_with(TOKEN_PREFIX))
+    else {
+        return Ok(None);
+    };
+    state.metadata.find_tenant(&keys::hash_key(token)).await
+}
+
+/// The id segment of `/d/:id...`, `/p/:id` and `/report/:id`.
+fn entry_segment(path: &str) -> Option<&str> {
+    let rest = path
+        .strip_prefix("/d/")
+        .or_else(|| path.strip_prefix("/p/"))
+        .or_else(|| path.strip_prefix("/report/"))?;
+    Some(rest.split('/').next().unwrap_or(rest))
+}
+
+/// The stored id of the entry a request for `path` is about, under a tenant's
+/// paths too: `acme:abc` for `/t/acme/d/abc/info`.
+pub fn addressed_entry(path: &str) -> Option<String> {
+    let id = match path.strip_prefix("/t/") {
+        Some(rest) => {
+            let (tenant, rest) = rest.split_once('/')?;
+            let local = entry_segment(&format!("/{}", rest))?.to_string();
+            format!("{}{}{}", tenant, SEPARATOR, local)
+        }
+        None => entry_segment(path)?.to_string(),
+    };
+    Some(id).filter(|id| !id.is_empty() && !id.ends_with(SEPARATOR))
+}
+
+/// Middleware run before routing. `/t/<tenant>/d/<id>...`, `/t/<tenant>/p/<id>`
+/// and `/t/<tenant>/report/<id>` become the routes of entry `<tenant>:<id>`;
+/// anything else under `/t/<tenant>/` is the same endpoint as at the root, open
+/// only with the tenant's token, which decides where an upload goes anyway.
+/// Tenant entries are not reachable from the root.
+pub async fn scope(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let Some(rest) = path.strip_prefix("/t/") else {
+        if entry_segment(path).is_some_and(|id| {
+            id.contains(SEPARATOR) || id.to_ascii_lowercase().contains("%3a")
+        }) {
+            return AppError::NotFound.into_response();
+        }
+        return next.run(request).await;
+    };
+    let (tenant, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    if !is_valid_id(tenant) {
+        return AppError::NotFound.into_response();
+    }
+
+    let rewritten = match rest.split_once('/') {
+        Some((section @ ("d" | "p" | "report"), local)) if !local.is_empty() => {
+            format!("/{}/{}{}{}", section, tenant, SEPARATOR, local)
+        }
+        _ => {
+            match bearer(&state, request.headers()).await {
+                Ok(Some(found)) if found.id == tenant => {}
+                Ok(_) => return AppError::InvalidToken.into_response(),
+                Err(err) => return err.into_response(),
+            }
+            format!("/{}", rest)
+        }
+    };
+    let query = request
+        .uri()
+        .query()
+        .map(|query| format!("?{}", query))
+        .unwrap_or_default();
+    match format!("{}{}", rewritten, query).parse::<Uri>() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return AppError::NotFound.into_response(),
+    }
+    next.run(request).await
+}
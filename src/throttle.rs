@@ -0,0 +1,68 @@
+//! Caps how fast one download is sent, from `DOWNLOAD_RATE_LIMIT_MBPS`, so a
+//! single large file cannot take the whole uplink. Each response body is paced
+//! on its own; many downloads at once still add up.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use tokio::time::{Instant, Sleep, sleep_until};
+
+/// How far a download may run ahead after it was held up, say by a slow client,
+/// so it catches up in a short burst rather than at full speed for long.
+const BURST: Duration = Duration::from_secs(1);
+
+/// A body that waits between chunks as long as it takes to keep the average
+/// rate at `bytes_per_sec`.
+pub struct Throttled<S> {
+    inner: S,
+    bytes_per_sec: u64,
+    /// When a download at exactly the rate would have started.
+    started: Instant,
+    sent: u64,
+    pause: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_sec: bytes_per_sec.max(1),
+            started: Instant::now(),
+            sent: 0,
+            pause: None,
+        }
+    }
+}
+
+impl<S, E> Stream for Throttled<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(pause) = self.pause.as_mut() {
+            ready!(pause.as_mut().poll(cx));
+            self.pause = None;
+        }
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            self.sent += chunk.len() as u64;
+            let on_schedule = self.sent as f64 / self.bytes_per_sec as f64;
+            let due = self.started + Duration::from_secs_f64(on_schedule);
+            let now = Instant::now();
+            if due > now {
+                self.pause = Some(Box::pin(sleep_until(due)));
+            } else if now - due > BURST {
+                self.started += now - due - BURST;
+            }
+        }
+        Poll::Ready(item)
+    }
+}
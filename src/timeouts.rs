@@ -0,0 +1,91 @@
+//! Protection from slow clients. Each request gets `REQUEST_TIMEOUT_SECS` to be
+//! answered, and its body may not stall for longer than
+//! `REQUEST_BODY_TIMEOUT_SECS` while a handler waits for it, so a client
+//! trickling bytes cannot hold an upload slot for hours. The time allowed for
+//! the headers is enforced by the connection itself (see `tls::serve`).
+
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use tokio::time::{Sleep, sleep, timeout};
+
+use crate::{AppError, AppState};
+
+/// A request body that fails once no data arrived for `idle` while it is being
+/// read. The clock only runs while a handler waits, not while it is busy.
+struct Stalling {
+    body: Body,
+    idle: Duration,
+    waiting: Option<Pin<Box<Sleep>>>,
+}
+
+impl http_body::Body for Stalling {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.body).poll_frame(cx) {
+            self.waiting = None;
+            return Poll::Ready(frame);
+        }
+        let idle = self.idle;
+        let waiting = self.waiting.get_or_insert_with(|| Box::pin(sleep(idle)));
+        if waiting.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Poll::Ready(Some(Err(axum::Error::new(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "request body stalled",
+        )))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Middleware applying the body and total timeouts; a request that runs out of
+/// time is answered with 408.
+pub async fn limit(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let timeouts = state.config.timeouts;
+    let request = match timeouts.body_idle {
+        Some(idle) => request.map(|body| {
+            Body::new(Stalling {
+                body,
+                idle,
+                waiting: None,
+            })
+        }),
+        None => request,
+    };
+    match timeouts.total {
+        Some(total) => timeout(total, next.run(request))
+            .await
+            .map_err(|_| AppError::RequestTimeout),
+        None => Ok(next.run(request).await),
+    }
+}
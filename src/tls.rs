@@ -0,0 +1,340 @@
+//! Accepting connections on each of `ADDRESS` and the admin listener, and HTTPS
+//! without a reverse proxy: the `https://` ones of `ADDRESS` with `TLS_CERT_PATH`
+//! and `TLS_KEY_PATH`, and the admin one with `ADMIN_TLS_CERT` and `ADMIN_TLS_KEY`.
+//! Certificates are read once at startup, so a renewed one takes a restart;
+//! those from ACME are swapped in as they are renewed. Clients that offer it
+//! over ALPN are served HTTP/2 unless `HTTP2_ENABLED=false`; plain HTTP is always
+//! HTTP/1.1.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    Extension, Router,
+    extract::{ConnectInfo, Request},
+    http::{HeaderValue, header},
+    middleware::map_request,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ResolvesServerCert, WebPkiClientVerifier},
+    },
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, warn};
+
+use crate::{
+    AppError,
+    config::Bind,
+    proxy::OwnScheme,
+    systemd::{self, Socket},
+};
+
+/// A PEM file along with the variable that named it, for error messages.
+#[derive(Clone, Copy)]
+pub struct PemFile<'a> {
+    pub var: &'static str,
+    pub path: &'a Path,
+}
+
+/// Loads the certificate chain and key, and with `client_ca` only lets in
+/// clients presenting a certificate it issued.
+pub fn acceptor(
+    cert: PemFile,
+    key: PemFile,
+    client_ca: Option<PemFile>,
+    http2: bool,
+) -> Result<TlsAcceptor, AppError> {
+    let certs = read_certs(cert)?;
+    let private_key =
+        PrivateKeyDer::from_pem_slice(&read(key)?).map_err(|err| invalid(key, err))?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for issuer in read_certs(client_ca)? {
+                roots.add(issuer).map_err(|err| invalid(client_ca, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|err| invalid(client_ca, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, private_key)
+        .map_err(|err| invalid(cert, err))?;
+    config.alpn_protocols = alpn_protocols(http2);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves whatever certificate `resolver` has at the time of each handshake.
+pub fn resolving_acceptor(resolver: Arc<dyn ResolvesServerCert>, http2: bool) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn_protocols(http2);
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// The protocols offered in the handshake, the preferred one first.
+fn alpn_protocols(http2: bool) -> Vec<Vec<u8>> {
+    if http2 {
+        vec![ALPN_H2.to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    }
+}
+
+const ALPN_H2: &[u8] = b"h2";
+
+/// How connections are served, the same for the public and the admin listener.
+#[derive(Clone)]
+pub struct Serving {
+    /// A client that takes longer over the handshake or a request's headers is
+    /// disconnected, so slow ones cannot hold connections open for free.
+    pub header_timeout: Option<Duration>,
+    /// Cancelled to stop taking connections and finish the open ones.
+    pub shutdown: CancellationToken,
+    /// How long open connections get to finish, after which they are dropped.
+    pub grace: Duration,
+}
+
+/// A bound socket of `ADDRESS` or the admin listener.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// Whom connections over a Unix socket count as coming from: this host, as the
+/// reverse proxy they are meant for.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+impl Listener {
+    /// Binds an address of `ADDRESS`, unless systemd passed a socket for it.
+    pub async fn bind(bind: &Bind) -> io::Result<Self> {
+        match bind {
+            Bind::Tcp(address) => Ok(Self::Tcp(systemd::listen(Socket::Main, *address).await?)),
+            #[cfg(unix)]
+            Bind::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // One left behind by an earlier run would keep it from binding.
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Self::Unix(tokio::net::UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Bind::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not available on this system",
+            )),
+        }
+    }
+
+    /// What it is bound to, which for a socket from systemd is not in `ADDRESS`.
+    pub fn address(&self) -> io::Result<Bind> {
+        match self {
+            Self::Tcp(listener) => Ok(Bind::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let address = listener.local_addr()?;
+                Ok(Bind::Unix(address.as_pathname().unwrap_or(Path::new("")).to_path_buf()))
+            }
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(Connection, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), peer))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Connection::Unix(stream), UNIX_PEER))
+            }
+        }
+    }
+}
+
+/// Serves `app` on `listener`, over TLS when there is an `acceptor`. Handlers
+/// see the peer address through `ConnectInfo` either way. Returns once shut
+/// down and the open connections finished what they were doing (or ran out of
+/// time): a keep-alive connection closes after its current response, so no
+/// upload or download is cut off halfway.
+pub async fn serve(
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
+    app: Router,
+    serving: Serving,
+) -> io::Result<()> {
+    let connections = TaskTracker::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = serving.shutdown.cancelled() => break,
+        };
+        let (connection, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually out of file descriptors, which takes a moment to pass.
+                warn!(%err, "failed to accept a connection");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let serving = serving.clone();
+        connections.spawn(async move {
+            match connection {
+                Connection::Tcp(stream) => handle(stream, peer, acceptor, app, &serving).await,
+                #[cfg(unix)]
+                Connection::Unix(stream) => handle(stream, peer, acceptor, app, &serving).await,
+            }
+        });
+    }
+
+    drop(listener);
+    connections.close();
+    if !connections.is_empty() {
+        info!(connections = connections.len(), "waiting for open connections to finish");
+    }
+    if timeout(serving.grace, connections.wait()).await.is_err() {
+        warn!("gave up waiting for connections after {:?}", serving.grace);
+    }
+    Ok(())
+}
+
+/// Serves one connection, after the TLS handshake when there is an `acceptor`.
+async fn handle<I>(
+    io: I,
+    peer: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    app: Router,
+    serving: &Serving,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(acceptor) = acceptor else {
+        let app = app.layer(Extension(OwnScheme("http")));
+        return serve_connection(io, peer, app, serving, false).await;
+    };
+    let handshake = acceptor.accept(io);
+    let accepted = match serving.header_timeout {
+        Some(limit) => timeout(limit, handshake)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => handshake.await,
+    };
+    match accepted {
+        Ok(stream) => {
+            let http2 = stream.get_ref().1.alpn_protocol() == Some(ALPN_H2);
+            let app = app.layer(Extension(OwnScheme("https")));
+            serve_connection(stream, peer, app, serving, http2).await
+        }
+        Err(err) => warn!(%err, %peer, "TLS handshake failed"),
+    }
+}
+
+/// Serves one connection, over HTTP/2 when the handshake settled on it.
+async fn serve_connection<I>(io: I, peer: SocketAddr, app: Router, serving: &Serving, http2: bool)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let app = app
+        .layer(map_request(authority_as_host))
+        .layer(Extension(ConnectInfo(peer)));
+    let service = TowerToHyperService::new(app);
+    let builder = Builder::new(TokioExecutor::new());
+    let mut builder = if http2 {
+        builder.http2_only()
+    } else {
+        builder.http1_only()
+    };
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(serving.header_timeout);
+    // Growing the flow control window with the measured bandwidth keeps large
+    // transfers fast over links with a long round trip.
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .adaptive_window(true);
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        () = serving.shutdown.cancelled() => {
+            // Lets the request under way finish, then closes instead of waiting
+            // for another one.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!(%err, %peer, "connection ended with an error");
+    }
+}
+
+/// HTTP/2 requests name their host in `:authority` rather than `Host`, which is
+/// copied over so links to uploads come out the same either way.
+async fn authority_as_host(mut request: Request) -> Request {
+    if !request.headers().contains_key(header::HOST)
+        && let Some(host) = request
+            .uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    {
+        request.headers_mut().insert(header::HOST, host);
+    }
+    request
+}
+
+fn read_certs(file: PemFile) -> Result<Vec<CertificateDer<'static>>, AppError> {
+    let certs = CertificateDer::pem_slice_iter(&read(file)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid(file, err))?;
+    if certs.is_empty() {
+        return Err(invalid(file, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn read(file: PemFile) -> Result<Vec<u8>, AppError> {
+    std::fs::read(file.path).map_err(|err| invalid(file, err))
+}
+
+fn invalid(file: PemFile, err: impl std::fmt::Display) -> AppError {
+    AppError::Config(format!("{} {}: {}", file.var, file.path.display(), err))
+}
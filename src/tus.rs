@@ -0,0 +1,648 @@
+//! Resumable uploads following the tus 1.0 protocol (core, creation, checksum and
+//! termination extensions) under `/files/`.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, options},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppError, AppState, Credential, EntryKind, NewUpload, UploadParams, admin, check_captcha,
+    check_password, credential, expected_sha256, metadata,
+    progress::{self, Phase, Progress, ProgressBoard},
+    store_upload,
+    upload_tokens::UploadToken,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,checksum,termination";
+const CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Partial uploads and their state, kept on local disk so sessions survive restarts.
+pub struct TusStore {
+    dir: PathBuf,
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Session>>>>,
+    progress: ProgressBoard,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    length: u64,
+    offset: u64,
+    filename: String,
+    content_type: Option<String>,
+    expires: Option<String>,
+    /// Hash of the API key that created the session, so usage is counted on completion.
+    key_hash: Option<String>,
+    /// See `FileEntry::uploader`.
+    #[serde(default)]
+    uploader: Option<String>,
+    /// See `FileEntry::subject`.
+    #[serde(default)]
+    subject: Option<String>,
+    /// Id of the user who created the session, whose quota applies on completion.
+    #[serde(default)]
+    user: Option<String>,
+    /// Id of the tenant whose token created the session.
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Claims of the upload token that created the session, which are signed and
+    /// so need no lookup on completion.
+    #[serde(default)]
+    token: Option<UploadToken>,
+    #[serde(default)]
+    slug: Option<String>,
+    /// Digest of the whole file from `Upload-Metadata`, checked on completion.
+    #[serde(default)]
+    sha256: Option<String>,
+    #[serde(with = "metadata::unix_time")]
+    updated_at: SystemTime,
+    completed: Option<Completed>,
+}
+
+impl Session {
+    fn progress(&self) -> Progress {
+        let phase = match &self.completed {
+            Some(completed) => Phase::Completed {
+                url: completed.url.clone(),
+            },
+            None => Phase::Receiving,
+        };
+        Progress::new(self.offset, Some(self.length), phase)
+    }
+}
+
+/// Kept after the file is stored so a client that lost the final response can
+/// still learn the download link.
+#[derive(Clone, Serialize, Deserialize)]
+struct Completed {
+    url: String,
+    delete_token: String,
+    owner_token: String,
+}
+
+impl TusStore {
+    pub async fn open(dir: PathBuf) -> Result<Self, AppError> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut sessions = HashMap::new();
+        let progress = ProgressBoard::default();
+        let mut items = fs::read_dir(&dir).await?;
+        while let Some(item) = items.next_entry().await? {
+            let path = item.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let id = id.to_string();
+
+            match fs::read(&path)
+                .await
+                .map(|raw| serde_json::from_slice::<Session>(&raw))
+            {
+                Ok(Ok(session)) => {
+                    progress.track(&id, session.progress());
+                    sessions.insert(id, Arc::new(tokio::sync::Mutex::new(session)));
+                }
+                Ok(Err(err)) => warn!(%err, "skipping corrupt upload session {:?}", path),
+                Err(err) => warn!(%err, "failed to read upload session {:?}", path),
+            }
+        }
+
+        Ok(Self {
+            dir,
+            sessions: Mutex::new(sessions),
+            progress,
+        })
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<Session>>> {
+        self.lock().get(id).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<tokio::sync::Mutex<Session>>>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn state_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    async fn save(&self, id: &str, session: &Session) -> Result<(), AppError> {
+        let raw = serde_json::to_vec(session)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        let path = self.state_path(id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, raw).await?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) {
+        self.lock().remove(id);
+        self.progress.forget(id);
+        for path in [self.data_path(id), self.state_path(id)] {
+            if let Err(err) = fs::remove_file(&path).await
+                && err.kind() != ErrorKind::NotFound
+            {
+                warn!(%err, "failed to remove upload session file {:?}", path);
+            }
+        }
+    }
+
+    /// Drops sessions that have not seen a chunk (or were completed) more than
+    /// `max_idle` ago.
+    pub async fn purge_stale(&self, now: SystemTime, max_idle: Duration) {
+        let sessions: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect();
+
+        for (id, session) in sessions {
+            // A session busy with a chunk is not idle.
+            let Ok(session) = session.try_lock() else {
+                continue;
+            };
+            if session.updated_at + max_idle <= now {
+                drop(session);
+                info!(id = %id, "removing abandoned upload session");
+                self.remove(&id).await;
+            }
+        }
+    }
+}
+
+/// Routes for `/files/`. Clients address the creation URL both with and without
+/// the trailing slash, so both are routed.
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/files", options(capabilities).post(create))
+        .route("/files/", options(capabilities).post(create))
+        .route(
+            "/files/:id",
+            options(capabilities)
+                .head(status)
+                .patch(append)
+                .delete(terminate),
+        )
+        .route("/files/:id/events", get(events))
+}
+
+/// Protocol-level failures that have their own status codes in the tus spec.
+enum TusError {
+    App(AppError),
+    Protocol(StatusCode, &'static str),
+}
+
+impl From<AppError> for TusError {
+    fn from(err: AppError) -> Self {
+        Self::App(err)
+    }
+}
+
+impl From<std::io::Error> for TusError {
+    fn from(err: std::io::Error) -> Self {
+        Self::App(err.into())
+    }
+}
+
+impl IntoResponse for TusError {
+    fn into_response(self) -> Response {
+        let mut response = match self {
+            Self::App(err) => err.into_response(),
+            Self::Protocol(status, message) => (status, message).into_response(),
+        };
+        response
+            .headers_mut()
+            .insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+        response
+    }
+}
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    headers
+}
+
+fn check_version(headers: &HeaderMap) -> Result<(), TusError> {
+    match headers.get("tus-resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(TusError::Protocol(
+            StatusCode::PRECONDITION_FAILED,
+            "unsupported tus version",
+        )),
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// `OPTIONS /files/` advertises what this server supports.
+async fn capabilities(State(state): State<Arc<AppState>>) -> Response {
+    let mut headers = tus_headers();
+    headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("tus-extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    headers.insert(
+        "tus-checksum-algorithm",
+        HeaderValue::from_static(CHECKSUM_ALGORITHMS),
+    );
+    headers.insert(
+        "tus-max-size",
+        HeaderValue::from(state.config.max_upload_bytes as u64),
+    );
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+/// `POST /files/` opens a session for an upload of `Upload-Length` bytes. The
+/// file name, type and lifetime come from `Upload-Metadata` (`filename`,
+/// `filetype`, `expires`); credentials work as for `PUT` uploads.
+async fn create(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, TusError> {
+    check_version(&headers)?;
+
+    let credential = credential(&state, &headers, &params).await?;
+    if matches!(credential, Credential::Password) {
+        let provided_password = headers
+            .get("x-upload-password")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or(params.password);
+        check_password(&state, provided_password.as_deref())?;
+        check_captcha(&state, &headers, peer).await?;
+    }
+
+    if headers.contains_key("upload-defer-length") {
+        return Err(TusError::Protocol(
+            StatusCode::BAD_REQUEST,
+            "Upload-Defer-Length is not supported",
+        ));
+    }
+    let length = header_u64(&headers, "upload-length").ok_or(TusError::Protocol(
+        StatusCode::BAD_REQUEST,
+        "missing or invalid Upload-Length",
+    ))?;
+    let limit = credential.upload_limit(state.config.max_upload_bytes);
+    if length > limit as u64 {
+        return Err(AppError::PayloadTooLarge { limit }.into());
+    }
+
+    let metadata = parse_metadata(&headers)?;
+    let session = Session {
+        length,
+        offset: 0,
+        filename: metadata
+            .get("filename")
+            .cloned()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "upload.bin".to_string()),
+        content_type: metadata.get("filetype").cloned().filter(|t| !t.is_empty()),
+        expires: metadata.get("expires").cloned(),
+        key_hash: credential.api_key().map(|key| key.key_hash.clone()),
+        uploader: Some(admin::uploader_hash(&state, &headers, peer)),
+        subject: credential.subject(),
+        user: credential.user().map(|user| user.id.clone()),
+        tenant: credential.tenant().map(|tenant| tenant.id.clone()),
+        token: credential.token().cloned(),
+        slug: metadata.get("slug").cloned().or(params.slug),
+        sha256: metadata
+            .get("sha256")
+            .cloned()
+            .or_else(|| expected_sha256(&headers)),
+        updated_at: SystemTime::now(),
+        completed: None,
+    };
+
+    let id = Uuid::new_v4().simple().to_string();
+    let tus = &state.tus;
+    fs::write(tus.data_path(&id), b"").await?;
+    tus.save(&id, &session).await?;
+    tus.progress.track(&id, session.progress());
+    tus.lock()
+        .insert(id.clone(), Arc::new(tokio::sync::Mutex::new(session)));
+
+    let mut reply = tus_headers();
+    if let Ok(location) = HeaderValue::from_str(&state.config.build_url(&format!("/files/{}", id)))
+    {
+        reply.insert(header::LOCATION, location);
+    }
+    Ok((StatusCode::CREATED, reply).into_response())
+}
+
+/// `HEAD /files/:id` reports how much has been received so a client can resume.
+async fn status(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, TusError> {
+    check_version(&headers)?;
+    let session = state.tus.get(&id).ok_or(AppError::NotFound)?;
+    let session = session.lock().await;
+
+    let mut reply = progress_headers(&session);
+    reply.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((StatusCode::OK, reply).into_response())
+}
+
+/// `GET /files/:id/events` streams the upload's progress. Browsers cannot send
+/// `Tus-Resumable` from `EventSource`, so it is not required here.
+async fn events(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    match state.tus.progress.subscribe(&id) {
+        Some(receiver) => progress::events(receiver).into_response(),
+        None => AppError::NotFound.into_response(),
+    }
+}
+
+fn progress_headers(session: &Session) -> HeaderMap {
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(session.offset));
+    headers.insert("upload-length", HeaderValue::from(session.length));
+    if let Some(completed) = &session.completed {
+        for (name, value) in [
+            ("x-download-url", &completed.url),
+            ("x-delete-token", &completed.delete_token),
+            ("x-owner-token", &completed.owner_token),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+    headers
+}
+
+/// `PATCH /files/:id` appends a chunk at `Upload-Offset`, optionally verified by
+/// `Upload-Checksum`. The chunk that completes the upload stores the file and
+/// returns the download link in `X-Download-Url`.
+async fn append(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, TusError> {
+    check_version(&headers)?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some(OFFSET_CONTENT_TYPE) {
+        return Err(TusError::Protocol(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type: application/offset+octet-stream",
+        ));
+    }
+    let offset = header_u64(&headers, "upload-offset").ok_or(TusError::Protocol(
+        StatusCode::BAD_REQUEST,
+        "missing or invalid Upload-Offset",
+    ))?;
+    let mut checksum = Checksum::parse(&headers)?;
+
+    let session = state.tus.get(&id).ok_or(AppError::NotFound)?;
+    let mut session = session.lock().await;
+    if offset != session.offset {
+        return Err(TusError::Protocol(
+            StatusCode::CONFLICT,
+            "Upload-Offset does not match the current offset",
+        ));
+    }
+    if session.completed.is_some() {
+        return Ok((StatusCode::NO_CONTENT, progress_headers(&session)).into_response());
+    }
+
+    let path = state.tus.data_path(&id);
+    let mut file = fs::OpenOptions::new().append(true).open(&path).await?;
+    let remaining = session.length - session.offset;
+    let mut written = 0u64;
+    let mut stream = body.into_data_stream();
+    let mut failure = None;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            // The client went away; keep what arrived so it can resume from there.
+            Err(_) => break,
+        };
+        if written + chunk.len() as u64 > remaining {
+            failure = Some(TusError::Protocol(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "chunk exceeds Upload-Length",
+            ));
+            break;
+        }
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&chunk);
+        }
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+        // The session lock keeps this the only writer, so bytes count as they land.
+        state.tus.progress.settle(&id, 0, session.offset + written);
+    }
+
+    if failure.is_none()
+        && let Some(checksum) = checksum
+        && !checksum.matches()
+    {
+        failure = Some(TusError::Protocol(
+            StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST),
+            "checksum mismatch",
+        ));
+    }
+
+    if let Some(failure) = failure {
+        // Roll back the rejected chunk so the offset stays where it was.
+        file.set_len(session.offset).await?;
+        state.tus.progress.settle(&id, 0, session.offset);
+        return Err(failure);
+    }
+
+    file.flush().await?;
+    drop(file);
+    session.offset += written;
+    session.updated_at = SystemTime::now();
+
+    if session.offset == session.length {
+        let data = state.spool.copy(&path).await?;
+        let api_key = match &session.key_hash {
+            Some(hash) => Some(
+                state
+                    .metadata
+                    .find_key(hash)
+                    .await?
+                    .ok_or(AppError::InvalidToken)?,
+            ),
+            None => None,
+        };
+        let user = match &session.user {
+            Some(id) => Some(state.metadata.get_user(id).await?.ok_or(AppError::InvalidToken)?),
+            None => None,
+        };
+        let tenant = match &session.tenant {
+            Some(id) => Some(
+                state
+                    .metadata
+                    .get_tenant(id)
+                    .await?
+                    .ok_or(AppError::InvalidToken)?,
+            ),
+            None => None,
+        };
+        let upload = NewUpload {
+            filename: session.filename.clone(),
+            content_type: session.content_type.clone(),
+            data,
+            expires: session.expires.clone(),
+            kind: EntryKind::File,
+            slug: session.slug.clone(),
+            sha256: session.sha256.clone(),
+            notify_email: None,
+            uploader: session.uploader.clone(),
+            subject: session.subject.clone(),
+            user,
+            tenant,
+            token: session.token.clone(),
+        };
+        state.tus.progress.enter(&id, Phase::Assembling);
+        let response = store_upload(&state, upload, api_key.as_ref())
+            .await
+            .inspect_err(|err| {
+                let error = err.to_string();
+                state.tus.progress.enter(&id, Phase::Failed { error });
+            })?;
+        state.tus.progress.enter(
+            &id,
+            Phase::Completed {
+                url: response.url.clone(),
+            },
+        );
+        session.completed = Some(Completed {
+            url: response.url,
+            delete_token: response.delete_token,
+            owner_token: response.owner_token,
+        });
+        if let Err(err) = fs::remove_file(&path).await {
+            warn!(%err, "failed to remove completed upload data {:?}", path);
+        }
+    }
+
+    state.tus.save(&id, &session).await?;
+    Ok((StatusCode::NO_CONTENT, progress_headers(&session)).into_response())
+}
+
+/// `DELETE /files/:id` abandons an upload and frees its partial data.
+async fn terminate(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, TusError> {
+    check_version(&headers)?;
+    let session = state.tus.get(&id).ok_or(AppError::NotFound)?;
+    let _session = session.lock().await;
+    state.tus.remove(&id).await;
+    Ok((StatusCode::NO_CONTENT, tus_headers()).into_response())
+}
+
+/// Decodes `Upload-Metadata`: comma-separated `key base64value` pairs.
+fn parse_metadata(headers: &HeaderMap) -> Result<HashMap<String, String>, TusError> {
+    let invalid = || TusError::Protocol(StatusCode::BAD_REQUEST, "invalid Upload-Metadata");
+    let Some(raw) = headers.get("upload-metadata") else {
+        return Ok(HashMap::new());
+    };
+    let raw = raw.to_str().map_err(|_| invalid())?;
+
+    let mut metadata = HashMap::new();
+    for pair in raw.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = BASE64.decode(value.trim()).map_err(|_| invalid())?;
+        let value = String::from_utf8(value).map_err(|_| invalid())?;
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+/// A running `Upload-Checksum` check over one chunk.
+struct Checksum {
+    hasher: Hasher,
+    expected: Vec<u8>,
+}
+
+impl Checksum {
+    fn parse(headers: &HeaderMap) -> Result<Option<Self>, TusError> {
+        let invalid = || TusError::Protocol(StatusCode::BAD_REQUEST, "invalid Upload-Checksum");
+        let Some(raw) = headers.get("upload-checksum") else {
+            return Ok(None);
+        };
+        let raw = raw.to_str().map_err(|_| invalid())?;
+        let (algorithm, expected) = raw.trim().split_once(' ').ok_or_else(invalid)?;
+        let expected = BASE64.decode(expected.trim()).map_err(|_| invalid())?;
+
+        let hasher = match algorithm {
+            "sha1" => Hasher::Sha1(Sha1::new()),
+            "sha256" => Hasher::Sha256(Sha256::new()),
+            _ => {
+                return Err(TusError::Protocol(
+                    StatusCode::BAD_REQUEST,
+                    "unsupported checksum algorithm",
+                ));
+            }
+        };
+        Ok(Some(Self { hasher, expected }))
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Sha1(hasher) => hasher.update(chunk),
+            Hasher::Sha256(hasher) => hasher.update(chunk),
+        }
+    }
+
+    fn matches(self) -> bool {
+        let digest = match self.hasher {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        digest == self.expected
+    }
+}
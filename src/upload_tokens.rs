@@ -0,0 +1,165 @@
+//! Upload tokens are JWTs signed with `UPLOAD_SIGNING_KEY` (HS256) whose claims
+//! say what they may upload: how large a file, how many files, which content
+//! types and until when. The server keeps no record of a token, so anyone with
+//! the signing key can mint them; only the number of uploads made with a token
+//! is counted, under its `jti`, and forgotten when the token expires.
+
+use std::time::{Duration, SystemTime};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    AppError, AppState, file_types,
+    metadata::{unix_epoch, unix_seconds},
+    secret,
+};
+
+/// The only header tokens are issued with and the only algorithm accepted.
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+const MAX_ID_LEN: usize = 64;
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+/// The claims of a token, which are all the server knows about it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UploadToken {
+    /// Names the token for counting its uploads; letters, digits, `-` and `_`.
+    pub jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    /// Unix time after which the token no longer uploads.
+    pub exp: u64,
+    /// Per-file cap; the global `MAX_UPLOAD_BYTES` still applies on top.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uploads: Option<u32>,
+    /// Declared types the token accepts, such as `application/pdf` or `image/*`.
+    /// Empty accepts any type the server does.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_types: Vec<String>,
+}
+
+impl UploadToken {
+    /// Claims for a new token that is valid for `valid_for` from now.
+    pub fn new(valid_for: Duration) -> Self {
+        let now = SystemTime::now();
+        Self {
+            jti: Uuid::new_v4().simple().to_string(),
+            iat: Some(unix_seconds(now)),
+            exp: unix_seconds(now + valid_for),
+            max_bytes: None,
+            max_uploads: None,
+            content_types: Vec::new(),
+        }
+    }
+
+    /// The largest file this token may upload given the server-wide `limit`.
+    pub fn upload_limit(&self, limit: usize) -> usize {
+        self.max_bytes
+            .map(|max| max.min(limit as u64) as usize)
+            .unwrap_or(limit)
+    }
+
+    /// When the token expires, which is as long as its upload count is kept.
+    pub fn expires_at(&self) -> SystemTime {
+        unix_epoch() + Duration::from_secs(self.exp)
+    }
+
+    /// Refuses a file whose declared type the token does not accept with a 415.
+    pub fn check_type(&self, content_type: Option<&str>) -> Result<(), AppError> {
+        if self.content_types.is_empty()
+            || file_types::matches_any(&self.content_types, content_type)
+        {
+            return Ok(());
+        }
+        Err(AppError::UnsupportedFileType(format!(
+            "this upload token only accepts files of type {}",
+            self.content_types.join(", ")
+        )))
+    }
+
+    /// Refuses a token that has already made all of its uploads.
+    pub async fn check_remaining(&self, state: &AppState) -> Result<(), AppError> {
+        let Some(max) = self.max_uploads else {
+            return Ok(());
+        };
+        if state.metadata.token_uploads(&self.jti).await? >= max {
+            return Err(AppError::TokenUsedUp { max });
+        }
+        Ok(())
+    }
+
+    /// Counts one upload against the token, unless that would be one too many.
+    pub async fn claim(&self, state: &AppState) -> Result<(), AppError> {
+        let Some(max) = self.max_uploads else {
+            return Ok(());
+        };
+        if !state
+            .metadata
+            .claim_token_upload(&self.jti, max, self.expires_at())
+            .await?
+        {
+            return Err(AppError::TokenUsedUp { max });
+        }
+        Ok(())
+    }
+}
+
+/// Whether a bearer credential has the shape of a JWT rather than a key.
+pub fn is_token(value: &str) -> bool {
+    value.split('.').count() == 3
+}
+
+/// Signs `token` into its compact form.
+pub fn issue(secret: &str, token: &UploadToken) -> String {
+    let header = URL_SAFE_NO_PAD.encode(HEADER);
+    let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(token).unwrap_or_default());
+    let signed = format!("{}.{}", header, claims);
+    let signature = sign(secret, &signed);
+    format!("{}.{}", signed, signature)
+}
+
+/// Checks the signature in constant time and that the token has not expired.
+pub fn verify(secret: &str, token: &str, now: u64) -> Result<UploadToken, AppError> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(AppError::InvalidToken)?;
+    let (header, claims) = signed.split_once('.').ok_or(AppError::InvalidToken)?;
+    let header: Header = decode(header)?;
+    if header.alg != "HS256" {
+        return Err(AppError::InvalidToken);
+    }
+    if !secret::matches(&sign(secret, signed), signature) {
+        return Err(AppError::InvalidToken);
+    }
+
+    let token: UploadToken = decode(claims)?;
+    if !is_valid_id(&token.jti) || now >= token.exp {
+        return Err(AppError::InvalidToken);
+    }
+    Ok(token)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AppError> {
+    let raw = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AppError::InvalidToken)?;
+    serde_json::from_slice(&raw).map_err(|_| AppError::InvalidToken)
+}
+
+/// The id also names a record file, so it is kept to a safe alphabet.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+fn sign(secret: &str, signed: &str) -> String {
+    URL_SAFE_NO_PAD.encode(secret::hmac_sha256(secret, &[signed]))
+}
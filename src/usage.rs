@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+
+/// Bytes held by stored blobs plus bytes reserved by uploads still in flight,
+/// checked against `MAX_TOTAL_STORAGE_BYTES`.
+pub struct StorageUsage {
+    limit: Option<u64>,
+    inner: Arc<Mutex<Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    stored: u64,
+    pending: u64,
+}
+
+impl StorageUsage {
+    pub fn new(limit: Option<u64>, stored: u64) -> Self {
+        Self {
+            limit,
+            inner: Arc::new(Mutex::new(Usage { stored, pending: 0 })),
+        }
+    }
+
+    pub fn stored(&self) -> u64 {
+        self.lock().stored
+    }
+
+    /// Reserves room for `size` more bytes. On failure returns how many bytes
+    /// would have to be freed first.
+    pub fn reserve(&self, size: u64) -> Result<Reservation, u64> {
+        let mut usage = self.lock();
+        if let Some(limit) = self.limit {
+            let wanted = usage.stored + usage.pending + size;
+            if wanted > limit {
+                return Err(wanted - limit);
+            }
+        }
+        usage.pending += size;
+        Ok(Reservation {
+            usage: self.inner.clone(),
+            size,
+            committed: false,
+        })
+    }
+
+    pub fn release(&self, size: u64) {
+        let mut usage = self.lock();
+        usage.stored = usage.stored.saturating_sub(size);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Usage> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Room held for an upload; it is given back unless `commit` is called.
+pub struct Reservation {
+    usage: Arc<Mutex<Usage>>,
+    size: u64,
+    committed: bool,
+}
+
+impl Reservation {
+    /// Turns the reservation into stored bytes once the entry exists.
+    pub fn commit(mut self) {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        usage.pending = usage.pending.saturating_sub(self.size);
+        usage.stored += self.size;
+        self.committed = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            usage.pending = usage.pending.saturating_sub(self.size);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! User accounts created through the admin API, each with its own bearer token,
+//! storage quota, per-file cap and default lifetime. A user sees and deletes
+//! their own uploads through `GET /user` and `DELETE /user/uploads/:id`.
+
+use std::{
+    cmp::Reverse,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{AppError, AppState, EntryKind, FileEntry, keys, metadata, webhook::Event};
+
+/// Tells user tokens apart from API keys, which share `Authorization: Bearer`.
+pub const TOKEN_PREFIX: &str = "ntu_";
+
+/// Only the SHA-256 of the token is kept; the plaintext is shown once when the
+/// user is created.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub token_hash: String,
+    #[serde(with = "metadata::unix_time")]
+    pub created_at: SystemTime,
+    /// Bytes the user's unexpired uploads may take up together, as uploaded.
+    #[serde(default)]
+    pub storage_quota_bytes: Option<u64>,
+    /// Per-file cap; the global `MAX_UPLOAD_BYTES` still applies on top.
+    #[serde(default)]
+    pub max_upload_bytes: Option<u64>,
+    /// Lifetime of uploads that do not ask for one; `MAX_TTL_MINS` still applies.
+    #[serde(default)]
+    pub default_ttl_secs: Option<u64>,
+}
+
+impl User {
+    /// Builds a new user record and returns it along with the plaintext token.
+    pub fn generate(name: String) -> (Self, String) {
+        let token = format!("{}{}", TOKEN_PREFIX, Uuid::new_v4().simple());
+        let record = Self {
+            id: Uuid::new_v4().simple().to_string(),
+            name,
+            token_hash: keys::hash_key(&token),
+            created_at: SystemTime::now(),
+            storage_quota_bytes: None,
+            max_upload_bytes: None,
+            default_ttl_secs: None,
+        };
+        (record, token)
+    }
+
+    /// The largest file this user may upload given the server-wide `limit`.
+    pub fn upload_limit(&self, limit: usize) -> usize {
+        self.max_upload_bytes
+            .map(|max| max.min(limit as u64) as usize)
+            .unwrap_or(limit)
+    }
+
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl_secs.map(Duration::from_secs)
+    }
+
+    fn owns(&self, entry: &FileEntry) -> bool {
+        entry.user.as_deref() == Some(&self.id)
+    }
+}
+
+/// The user's unexpired entries among `entries`, newest first.
+pub fn owned(user: &User, entries: &[(String, FileEntry)]) -> Vec<(String, FileEntry)> {
+    let now = SystemTime::now();
+    let mut owned: Vec<(String, FileEntry)> = entries
+        .iter()
+        .filter(|(_, entry)| user.owns(entry) && now < entry.expires_at)
+        .cloned()
+        .collect();
+    owned.sort_by_key(|(_, entry)| Reverse(entry.created_at));
+    owned
+}
+
+pub async fn uploads(state: &AppState, user: &User) -> Result<Vec<(String, FileEntry)>, AppError> {
+    Ok(owned(user, &state.metadata.list().await?))
+}
+
+/// Bytes the user's unexpired uploads take up, as uploaded.
+pub fn used_bytes(entries: &[(String, FileEntry)]) -> u64 {
+    entries.iter().map(|(_, entry)| entry.size).sum()
+}
+
+/// Refuses an upload of `bytes` that would take the user past their quota.
+/// Uploads finishing at the same moment are not counted against each other.
+pub async fn check_quota(state: &AppState, user: &User, bytes: u64) -> Result<(), AppError> {
+    let Some(quota) = user.storage_quota_bytes else {
+        return Ok(());
+    };
+    let used = used_bytes(&uploads(state, user).await?);
+    if used.saturating_add(bytes) > quota {
+        return Err(AppError::UserQuotaExceeded { quota, used });
+    }
+    Ok(())
+}
+
+/// The user named by a `ntu_` bearer token, if the request carries one.
+pub async fn bearer(state: &AppState, headers: &HeaderMap) -> Result<Option<User>, AppError> {
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| token.starts_with(TOKEN_PREFIX))
+    else {
+        return Ok(None);
+    };
+    state.metadata.find_user(&keys::hash_key(token)).await
+}
+
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<User, AppError> {
+    bearer(state, headers).await?.ok_or(AppError::InvalidToken)
+}
+
+#[derive(Serialize)]
+pub struct Upload {
+    id: String,
+    url: String,
+    kind: EntryKind,
+    filename: String,
+    size: u64,
+    created_at: u64,
+    expires_at: u64,
+    remaining_downloads: u32,
+}
+
+impl Upload {
+    fn new(state: &AppState, id: String, entry: FileEntry) -> Self {
+        Self {
+            url: state.config.build_download_url(&id),
+            id,
+            kind: entry.kind,
+            filename: entry.filename,
+            size: entry.size,
+            created_at: metadata::unix_seconds(entry.created_at),
+            expires_at: metadata::unix_seconds(entry.expires_at),
+            remaining_downloads: entry.remaining_hits,
+        }
+    }
+}
+
+/// What a user is shown about themselves, and the admin about every user.
+#[derive(Serialize)]
+pub struct UserView {
+    id: String,
+    name: String,
+    created_at: u64,
+    storage_quota_bytes: Option<u64>,
+    max_upload_bytes: Option<u64>,
+    default_ttl_secs: Option<u64>,
+    used_bytes: u64,
+    uploads: usize,
+}
+
+impl UserView {
+    pub fn new(user: User, entries: &[(String, FileEntry)]) -> Self {
+        Self {
+            id: user.id,
+            name: user.name,
+            created_at: metadata::unix_seconds(user.created_at),
+            storage_quota_bytes: user.storage_quota_bytes,
+            max_upload_bytes: user.max_upload_bytes,
+            default_ttl_secs: user.default_ttl_secs,
+            used_bytes: used_bytes(entries),
+            uploads: entries.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Profile {
+    #[serde(flatten)]
+    user: UserView,
+    /// Newest first.
+    entries: Vec<Upload>,
+}
+
+/// `GET /user` returns the caller's limits, usage and unexpired uploads.
+pub async fn profile(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Profile>, AppError> {
+    let user = authenticate(&state, &headers).await?;
+    let entries = uploads(&state, &user).await?;
+    let view = UserView::new(user, &entries);
+    Ok(Json(Profile {
+        user: view,
+        entries: entries
+            .into_iter()
+            .map(|(id, entry)| Upload::new(&state, id, entry))
+            .collect(),
+    }))
+}
+
+/// `DELETE /user/uploads/:id` removes one of the caller's uploads.
+pub async fn delete_upload(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AppError> {
+    let user = authenticate(&state, &headers).await?;
+    let entry = state.metadata.get(&id).await?.ok_or(AppError::NotFound)?;
+    // Someone else's upload looks the same as a missing one.
+    if !user.owns(&entry) {
+        return Err(AppError::NotFound);
+    }
+    if let Some(removed) = state.metadata.remove(&id).await? {
+        state.notify(Event::Delete, &id, &removed);
+        state.discard(&removed).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
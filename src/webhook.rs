@@ -0,0 +1,319 @@
+//! Webhooks listed in `WEBHOOKS_FILE`, a JSON array such as
+//! `[{"url": "https://example.com/hook", "secret": "…", "events": ["upload"]}]`.
+//! Each one is sent a JSON `POST` when a link is uploaded, downloaded, expires, is
+//! deleted or is reported, optionally only for the events it lists. Deliveries leave from a
+//! background queue and are retried with backoff, so a slow or failing receiver
+//! never holds up a request. With a `secret`, the body is signed with HMAC-SHA256
+//! in `X-Newtemp-Signature: sha256=<hex>`.
+//!
+//! A `kind` of `slack`, `discord` or `matrix` posts a chat message instead, such
+//! as "Someone downloaded build.zip", worded per event through `messages`.
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, mpsc};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    AppError, EntryView, FileEntry, config::AppConfig, metadata::unix_seconds,
+    preview::format_size, secret, tenants,
+};
+
+/// Events waiting to be sent; further ones are dropped while it is full.
+const QUEUE_LEN: usize = 1024;
+/// Deliveries in flight at once, across all receivers.
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+/// Tries per delivery, waiting 1, 2, 4 then 8 seconds in between.
+const ATTEMPTS: u32 = 5;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Upload,
+    Download,
+    Expire,
+    Delete,
+    /// Someone reported the link as abuse; see `report`.
+    Report,
+}
+
+impl Event {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Download => "download",
+            Self::Expire => "expire",
+            Self::Delete => "delete",
+            Self::Report => "report",
+        }
+    }
+
+    /// The chat message sent when a hook has none of its own for the event.
+    fn default_message(self) -> &'static str {
+        match self {
+            Self::Upload => "{filename} ({size}) was uploaded",
+            Self::Download => "Someone downloaded {filename}",
+            Self::Expire => "{filename} expired",
+            Self::Delete => "{filename} was deleted",
+            Self::Report => "{filename} was reported: {url}",
+        }
+    }
+}
+
+/// What a hook is sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HookKind {
+    /// The signed event payload.
+    #[default]
+    Json,
+    /// A Slack incoming webhook.
+    Slack,
+    /// A Discord channel webhook.
+    Discord,
+    /// A Matrix room; `url` is the homeserver.
+    Matrix,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Hook {
+    #[serde(default)]
+    kind: HookKind,
+    url: String,
+    secret: Option<String>,
+    /// Every event when left out or empty.
+    #[serde(default)]
+    events: Vec<Event>,
+    /// Chat message per event, with `{filename}`, `{size}`, `{id}`, `{url}` and
+    /// `{remaining_downloads}` filled in.
+    #[serde(default)]
+    messages: HashMap<Event, String>,
+    /// The Matrix room id, such as `!abc123:example.org`.
+    room: Option<String>,
+    access_token: Option<String>,
+}
+
+impl Hook {
+    pub fn from_env() -> Result<Vec<Self>, AppError> {
+        let Some(path) = env::var_os("WEBHOOKS_FILE").filter(|path| !path.is_empty()) else {
+            return Ok(Vec::new());
+        };
+        let raw = std::fs::read(&path)?;
+        let hooks: Vec<Self> = serde_json::from_slice(&raw)
+            .map_err(|err| AppError::Config(format!("invalid WEBHOOKS_FILE: {}", err)))?;
+        for hook in &hooks {
+            if hook.kind == HookKind::Matrix
+                && (hook.room.is_none() || hook.access_token.is_none())
+            {
+                return Err(AppError::Config(format!(
+                    "matrix webhook {} needs a room and an access_token",
+                    hook.url
+                )));
+            }
+        }
+        Ok(hooks)
+    }
+
+    fn wants(&self, event: Event) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    /// The request body for this hook, given the event payload.
+    fn body(&self, payload: &Payload, json: &Arc<String>) -> serde_json::Result<Arc<String>> {
+        let message = || {
+            let template = self
+                .messages
+                .get(&payload.event)
+                .map_or(payload.event.default_message(), String::as_str);
+            template
+                .replace("{filename}", &payload.entry.filename)
+                .replace("{size}", &format_size(payload.entry.size))
+                .replace("{id}", payload.id)
+                .replace("{url}", &payload.url)
+                .replace(
+                    "{remaining_downloads}",
+                    &payload.entry.remaining_downloads.to_string(),
+                )
+        };
+        let body = match self.kind {
+            HookKind::Json => return Ok(json.clone()),
+            HookKind::Slack => serde_json::json!({ "text": message() }),
+            HookKind::Discord => serde_json::json!({ "content": message() }),
+            HookKind::Matrix => serde_json::json!({ "msgtype": "m.text", "body": message() }),
+        };
+        serde_json::to_string(&body).map(Arc::new)
+    }
+
+    /// Where a delivery goes; Matrix messages are sent under the delivery id, so
+    /// the homeserver drops repeats of one that did arrive.
+    fn request(&self, client: &reqwest::Client, delivery_id: &str) -> reqwest::RequestBuilder {
+        if self.kind != HookKind::Matrix {
+            return client.post(&self.url);
+        }
+        let room = self.room.as_deref().unwrap_or_default();
+        let mut url = match reqwest::Url::parse(&self.url) {
+            Ok(url) => url,
+            // Fails when sent, which is where bad URLs of other kinds surface too.
+            Err(_) => return client.put(&self.url),
+        };
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room,
+                "send",
+                "m.room.message",
+                delivery_id,
+            ]);
+        }
+        client
+            .put(url)
+            .bearer_auth(self.access_token.as_deref().unwrap_or_default())
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: Event,
+    id: &'a str,
+    url: String,
+    occurred_at: u64,
+    entry: EntryView,
+}
+
+struct Delivery {
+    hook: Arc<Hook>,
+    event: Event,
+    body: Arc<String>,
+}
+
+pub struct Webhooks {
+    hooks: Vec<Arc<Hook>>,
+    queue: Option<mpsc::Sender<Delivery>>,
+    /// `URL_PREFIX`, for the download link in each payload.
+    url_prefix: String,
+}
+
+impl Webhooks {
+    /// Starts the delivery queue, unless there are no webhooks to deliver to.
+    pub fn new(config: &AppConfig) -> Self {
+        let hooks: Vec<Arc<Hook>> = config.webhooks.iter().cloned().map(Arc::new).collect();
+        let queue = (!hooks.is_empty()).then(|| {
+            let client = reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default();
+            let (queue, deliveries) = mpsc::channel(QUEUE_LEN);
+            tokio::spawn(deliver_all(client, deliveries));
+            queue
+        });
+        Self {
+            hooks,
+            queue,
+            url_prefix: config.url_prefix.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Queues `event` for every webhook that wants it.
+    pub fn notify(&self, event: Event, id: &str, entry: &FileEntry) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if !self.hooks.iter().any(|hook| hook.wants(event)) {
+            return;
+        }
+
+        let payload = Payload {
+            event,
+            id,
+            url: format!("{}{}", self.url_prefix, tenants::entry_path("d", id)),
+            occurred_at: unix_seconds(SystemTime::now()),
+            entry: entry.clone().into(),
+        };
+        let json = match serde_json::to_string(&payload) {
+            Ok(json) => Arc::new(json),
+            Err(err) => {
+                warn!(%err, "failed to encode a webhook payload");
+                return;
+            }
+        };
+        for hook in self.hooks.iter().filter(|hook| hook.wants(event)) {
+            let body = match hook.body(&payload, &json) {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!(%err, "failed to encode a webhook message");
+                    continue;
+                }
+            };
+            let delivery = Delivery {
+                hook: hook.clone(),
+                event,
+                body,
+            };
+            if queue.try_send(delivery).is_err() {
+                warn!("webhook queue is full, dropping a {} event", event.as_str());
+            }
+        }
+    }
+}
+
+async fn deliver_all(client: reqwest::Client, mut deliveries: mpsc::Receiver<Delivery>) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+    while let Some(delivery) = deliveries.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let client = client.clone();
+        tokio::spawn(async move {
+            deliver(&client, &delivery).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn deliver(client: &reqwest::Client, delivery: &Delivery) {
+    let hook = &delivery.hook;
+    // Stays the same across retries, so receivers can tell repeats apart.
+    let id = Uuid::new_v4().simple().to_string();
+    let signature = hook.secret.as_deref().map(|secret| {
+        let mac = secret::hmac_sha256(secret, &[&delivery.body]);
+        format!("sha256={}", hex::encode(mac))
+    });
+
+    for attempt in 1..=ATTEMPTS {
+        let mut request = hook
+            .request(client, &id)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("x-newtemp-event", delivery.event.as_str())
+            .header("x-newtemp-delivery", &id)
+            .body(delivery.body.to_string());
+        if let Some(signature) = &signature {
+            request = request.header("x-newtemp-signature", signature);
+        }
+        let failure = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("status {}", response.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt == ATTEMPTS {
+            warn!(
+                "giving up on the {} webhook to {}: {}",
+                delivery.event.as_str(),
+                hook.url,
+                failure
+            );
+        } else {
+            tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+        }
+    }
+}